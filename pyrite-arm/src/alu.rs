@@ -515,15 +515,15 @@ pub fn arm_alu_rrr_s(cpu: &mut ArmCpu, lhs: u32, rhs: u32) -> u32 {
     if rhs == 0 {
         return lhs;
     }
-    // ROR by 32 has result equal to Rm, carry out equal to bit 31 of Rm.
-    if rhs == 32 {
+    // ROR by n where n is greater than 32 will give the same result and carry out as ROR by n-32;
+    // therefore repeatedly subtract 32 from n until the amount is in the range 1 to 32 and see below.
+    let rhs = rhs & 31;
+
+    // ROR by 32 (or any other multiple of 32) has result equal to Rm, carry out equal to bit 31 of Rm.
+    if rhs == 0 {
         cpu.registers.putfi_c(lhs & 0x80000000);
         lhs
     } else {
-        // ROR by n where n is greater than 32 will give the same result and carry out as ROR by n-32;
-        // therefore repeatedly subtract 32 from n until the amount is in the range 1 to 32 and see above.
-        let rhs = rhs & 31; // This might not be right?
-
         cpu.registers.putfi_c((lhs >> (rhs - 1)) & 1);
         lhs.arm_ror(rhs)
     }
@@ -112,10 +112,16 @@ macro_rules! arm_gen_bdt {
 
                 if (register_list & (1 << 15)) != 0 {
                     let dest_pc = cpu.registers.read(15);
-                    if cpu.registers.getf_t() {
-                        cycles += cpu.thumb_branch_to(dest_pc, memory);
+                    if $s_bit {
+                        // The state was restored from the SPSR along with the rest of the CPSR.
+                        if cpu.registers.getf_t() {
+                            cycles += cpu.thumb_branch_to(dest_pc, memory);
+                        } else {
+                            cycles += cpu.arm_branch_to(dest_pc, memory);
+                        }
+                        cpu.check_irq_line();
                     } else {
-                        cycles += cpu.arm_branch_to(dest_pc, memory);
+                        cycles += cpu.load_branch_to(dest_pc, memory);
                     }
                 }
             }
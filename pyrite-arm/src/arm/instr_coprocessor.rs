@@ -1,117 +1,243 @@
-//! Stubs for ARM _instructions that have yet to be implemented.
+//! Coprocessor instructions. These are forwarded to the coprocessor attached to the CPU using
+//! `ArmCpu::attach_coprocessor`. If there is no coprocessor with the instruction's number or if
+//! the coprocessor does not accept the instruction the Undefined Instruction exception is taken.
 
+use super::super::coprocessor::Handshake;
+use super::super::cpu::CpuException;
 use super::super::{ArmCpu, ArmMemory};
 
-// Perform coprocessor data operation
-pub fn arm_cdp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Negative offset
-pub fn arm_ldc_ofm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Positive offset
-pub fn arm_ldc_ofp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Pre-decrement
-pub fn arm_ldc_prm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Pre-increment
-pub fn arm_ldc_prp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Post-decrement
-pub fn arm_ldc_ptm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Post-increment
-pub fn arm_ldc_ptp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Unindexed, bits 7-0 available for copro use
-pub fn arm_ldc_unm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Load coprocessor data from memory, Unindexed, bits 7-0 available for copro use
-pub fn arm_ldc_unp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Write coprocessor register from ARM register
-pub fn arm_mcr(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Read coprocessor register to ARM register
-pub fn arm_mrc(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Negative offset
-pub fn arm_stc_ofm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Positive offset
-pub fn arm_stc_ofp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Pre-decrement
-pub fn arm_stc_prm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Pre-increment
-pub fn arm_stc_prp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Post-decrement
-pub fn arm_stc_ptm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Post-increment
-pub fn arm_stc_ptp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Unindexed, bits 7-0 available for copro use
-pub fn arm_stc_unm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
-
-// Store coprocessor data to memory, Unindexed, bits 7-0 available for copro use
-pub fn arm_stc_unp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
-    gba_error!("coprocessor instructions not implemented"); // #COPROCESSOR_FN
-    return cpu.arm_prefetch(memory);
-}
+const LOAD: bool = true;
+const STORE: bool = false;
+
+/// Called after a coprocessor failed to respond to the handshake (CPA high).
+/// The CPU takes the undefined instruction trap: 2S + 1I + 1N
+fn coprocessor_absent(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, mut cycles: u32) -> u32 {
+    cycles += 1;
+    memory.on_internal_cycles(1);
+    cycles += cpu
+        .handle_exception(
+            CpuException::Undefined,
+            memory,
+            cpu.registers.read(15).wrapping_sub(4),
+        )
+        .1;
+    return cycles;
+}
+
+/// Busy-waits the CPU for the number of cycles that a coprocessor requested.
+#[inline]
+fn busy_wait(memory: &mut dyn ArmMemory, busy_cycles: u32) -> u32 {
+    if busy_cycles > 0 {
+        memory.on_internal_cycles(busy_cycles);
+    }
+    return busy_cycles;
+}
+
+// #NOTE The addressing mode suffixes of the LDC/STC functions in the opcode table don't line up
+//       with the P and W bits of the rows that they are in, so the addressing mode is always
+//       decoded from the instruction itself instead.
+macro_rules! arm_gen_cdt {
+    ($name:ident, $transfer_type:expr) => {
+        pub fn $name(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+            let cycles = cpu.arm_prefetch(memory);
+            return coprocessor_data_transfer(cpu, memory, instr, cycles, $transfer_type);
+        }
+    };
+}
+
+/// Common implementation of LDC and STC.
+///
+/// Cycles: (n-1)S + 2N + bI
+fn coprocessor_data_transfer(
+    cpu: &mut ArmCpu,
+    memory: &mut dyn ArmMemory,
+    instr: u32,
+    mut cycles: u32,
+    transfer_type: bool,
+) -> u32 {
+    let offset = bits!(instr, 0, 7);
+    let cp_num = bits!(instr, 8, 11);
+    let crd = bits!(instr, 12, 15);
+    let rn = bits!(instr, 16, 19);
+    let writeback = bits_b!(instr, 21);
+    let long = bits_b!(instr, 22);
+    let increment = bits_b!(instr, 23);
+    let pre_index = bits_b!(instr, 24);
+
+    // The offset field is passed to the coprocessor as an option when the transfer is unindexed
+    // (post-indexed without writeback).
+    let unindexed = !pre_index && !writeback;
+    let option = if unindexed { offset } else { 0 };
+
+    let handshake = match cpu.coprocessor_mut(cp_num) {
+        Some(coprocessor) => {
+            if transfer_type == LOAD {
+                coprocessor.begin_load(crd, long, option)
+            } else {
+                coprocessor.begin_store(crd, long, option)
+            }
+        }
+        None => Handshake::Absent,
+    };
+
+    let (busy_cycles, words) = match handshake {
+        Handshake::Accept { busy_cycles, value } => (busy_cycles, value.max(1)),
+        Handshake::Absent => return coprocessor_absent(cpu, memory, cycles),
+    };
+    cycles += busy_wait(memory, busy_cycles);
+
+    let base = cpu.registers.read(rn);
+    let offset_addr = if unindexed {
+        base
+    } else if increment {
+        base.wrapping_add(offset << 2)
+    } else {
+        base.wrapping_sub(offset << 2)
+    };
+    let start_addr = if pre_index { offset_addr } else { base };
+
+    if writeback {
+        cpu.registers.write(rn, offset_addr);
+    }
+
+    let coprocessor = cpu
+        .coprocessor_mut(cp_num)
+        .expect("coprocessor detached during transfer");
+    let mut addr = start_addr & 0xFFFFFFFC;
+    for index in 0..words {
+        let seq = index != 0;
+        if transfer_type == LOAD {
+            let value = memory.read_data_word(addr, seq, &mut cycles);
+            coprocessor.load_word(crd, index, value);
+        } else {
+            let value = coprocessor.store_word(crd, index);
+            memory.write_data_word(addr, value, seq, &mut cycles);
+        }
+        addr = addr.wrapping_add(4);
+    }
+
+    return cycles;
+}
+
+/// Perform coprocessor data operation
+///
+/// Cycles: 1S + bI
+pub fn arm_cdp(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch(memory);
+
+    let crm = bits!(instr, 0, 3);
+    let opcode2 = bits!(instr, 5, 7);
+    let cp_num = bits!(instr, 8, 11);
+    let crd = bits!(instr, 12, 15);
+    let crn = bits!(instr, 16, 19);
+    let opcode1 = bits!(instr, 20, 23);
+
+    let handshake = match cpu.coprocessor_mut(cp_num) {
+        Some(coprocessor) => coprocessor.data_operation(opcode1, crd, crn, crm, opcode2),
+        None => Handshake::Absent,
+    };
+
+    match handshake {
+        Handshake::Accept { busy_cycles, .. } => cycles += busy_wait(memory, busy_cycles),
+        Handshake::Absent => return coprocessor_absent(cpu, memory, cycles),
+    }
+
+    return cycles;
+}
+
+arm_gen_cdt!(arm_ldc_ofm, LOAD);
+arm_gen_cdt!(arm_ldc_ofp, LOAD);
+arm_gen_cdt!(arm_ldc_prm, LOAD);
+arm_gen_cdt!(arm_ldc_prp, LOAD);
+arm_gen_cdt!(arm_ldc_ptm, LOAD);
+arm_gen_cdt!(arm_ldc_ptp, LOAD);
+arm_gen_cdt!(arm_ldc_unm, LOAD);
+arm_gen_cdt!(arm_ldc_unp, LOAD);
+
+/// Write coprocessor register from ARM register
+///
+/// Cycles: 1S + bI + 1C
+pub fn arm_mcr(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let crm = bits!(instr, 0, 3);
+    let opcode2 = bits!(instr, 5, 7);
+    let cp_num = bits!(instr, 8, 11);
+    let rd = bits!(instr, 12, 15);
+    let crn = bits!(instr, 16, 19);
+    let opcode1 = bits!(instr, 21, 23);
+
+    // R15 is read as PC+12 when it is the source register of an MCR instruction. This is read
+    // before the prefetch (PC+4 at this point) and adjusted.
+    let value = if rd == 15 {
+        cpu.registers.read(15).wrapping_add(8)
+    } else {
+        cpu.registers.read(rd)
+    };
+
+    let mut cycles = cpu.arm_prefetch(memory);
+
+    let handshake = match cpu.coprocessor_mut(cp_num) {
+        Some(coprocessor) => coprocessor.write_register(opcode1, crn, crm, opcode2, value),
+        None => Handshake::Absent,
+    };
+
+    match handshake {
+        Handshake::Accept { busy_cycles, .. } => {
+            cycles += busy_wait(memory, busy_cycles);
+            // the coprocessor register transfer cycle
+            cycles += 1;
+            memory.on_internal_cycles(1);
+        }
+        Handshake::Absent => return coprocessor_absent(cpu, memory, cycles),
+    }
+
+    return cycles;
+}
+
+/// Read coprocessor register to ARM register
+///
+/// Cycles: 1S + (b+1)I + 1C
+pub fn arm_mrc(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch(memory);
+
+    let crm = bits!(instr, 0, 3);
+    let opcode2 = bits!(instr, 5, 7);
+    let cp_num = bits!(instr, 8, 11);
+    let rd = bits!(instr, 12, 15);
+    let crn = bits!(instr, 16, 19);
+    let opcode1 = bits!(instr, 21, 23);
+
+    let handshake = match cpu.coprocessor_mut(cp_num) {
+        Some(coprocessor) => coprocessor.read_register(opcode1, crn, crm, opcode2),
+        None => Handshake::Absent,
+    };
+
+    match handshake {
+        Handshake::Accept { busy_cycles, value } => {
+            cycles += busy_wait(memory, busy_cycles);
+            // the coprocessor register transfer cycle + the internal cycle used to write to Rd
+            cycles += 2;
+            memory.on_internal_cycles(2);
+
+            if rd == 15 {
+                // Only the N, Z, C and V flags are changed when the destination is R15.
+                let cpsr = cpu.registers.read_cpsr();
+                cpu.registers
+                    .write_cpsr((cpsr & !0xF0000000) | (value & 0xF0000000));
+            } else {
+                cpu.registers.write(rd, value);
+            }
+        }
+        Handshake::Absent => return coprocessor_absent(cpu, memory, cycles),
+    }
+
+    return cycles;
+}
+
+arm_gen_cdt!(arm_stc_ofm, STORE);
+arm_gen_cdt!(arm_stc_ofp, STORE);
+arm_gen_cdt!(arm_stc_prm, STORE);
+arm_gen_cdt!(arm_stc_prp, STORE);
+arm_gen_cdt!(arm_stc_ptm, STORE);
+arm_gen_cdt!(arm_stc_ptp, STORE);
+arm_gen_cdt!(arm_stc_unm, STORE);
+arm_gen_cdt!(arm_stc_unp, STORE);
@@ -44,6 +44,7 @@ macro_rules! dataproc {
                 let spsr = cpu.registers.read_spsr();
                 cpu.registers.write_cpsr(spsr);
                 cycles += cpu.branch_to(res, memory);
+                cpu.check_irq_line();
             } else {
                 let rhs = $get_operand(cpu, instr);
                 let res = $operation(cpu, lhs, rhs);
@@ -108,6 +109,7 @@ macro_rules! dataproc_no_write {
                     let dest = cpu.registers.read(15) & 0xFFFFFFFC;
                     cycles += cpu.arm_branch_to(dest, memory);
                 }
+                cpu.check_irq_line();
             }
 
             return cycles;
@@ -68,7 +68,10 @@ macro_rules! arm_gen_sdt {
             }
 
             if $transfer_type == LOAD {
-                if rd == 15 || ($writeback == WRITEBACK && rn == 15) {
+                if rd == 15 {
+                    let dest_pc = cpu.registers.read(15);
+                    cycles += cpu.load_branch_to(dest_pc, memory);
+                } else if $writeback == WRITEBACK && rn == 15 {
                     let dest_pc = cpu.registers.read(15);
                     cycles += cpu.arm_branch_to(dest_pc, memory);
                 }
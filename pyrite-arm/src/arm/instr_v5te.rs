@@ -0,0 +1,356 @@
+//! ARMv5TE additions to the ARM instruction set. These are only available when the `armv5te`
+//! feature is enabled and are patched into the ARMv4T opcode table by `extend_opcode_table`.
+
+use super::super::cpu::{CpuException, ExecutionFn};
+use super::super::{ArmCpu, ArmMemory};
+
+/// Replaces entries of the ARMv4T opcode table with the ARMv5TE instructions that occupy them.
+/// Instructions with the condition code `NV` (0b1111) are handled separately by
+/// `arm_unconditional`.
+pub const fn extend_opcode_table(mut table: [ExecutionFn; 4096]) -> [ExecutionFn; 4096] {
+    const fn idx(row: usize, col: usize) -> usize {
+        (row * 16) + col
+    }
+
+    table[idx(0x12, 0x3)] = arm_blx_reg;
+    table[idx(0x12, 0x7)] = arm_bkpt;
+    table[idx(0x16, 0x1)] = arm_clz;
+
+    table[idx(0x10, 0x5)] = arm_qadd;
+    table[idx(0x12, 0x5)] = arm_qsub;
+    table[idx(0x14, 0x5)] = arm_qdadd;
+    table[idx(0x16, 0x5)] = arm_qdsub;
+
+    // Signed halfword multiplies (bits 5 and 6 select the halves of the operands)
+    let mut col = 0x8;
+    while col <= 0xE {
+        table[idx(0x10, col)] = arm_smlaxy;
+        table[idx(0x14, col)] = arm_smlalxy;
+        table[idx(0x16, col)] = arm_smulxy;
+        if (col & 0x2) == 0 {
+            table[idx(0x12, col)] = arm_smlawy;
+        } else {
+            table[idx(0x12, col)] = arm_smulwy;
+        }
+        col += 2;
+    }
+
+    // LDRD and STRD are encoded as halfword transfers with the L bit cleared and the S bit set.
+    let mut row = 0x00;
+    while row <= 0x1E {
+        table[idx(row, 0xD)] = arm_ldrd;
+        table[idx(row, 0xF)] = arm_strd;
+        row += 2;
+    }
+
+    table
+}
+
+/// Executes an instruction with the condition code `NV` (0b1111). ARMv5TE uses this space for
+/// BLX (immediate) and PLD. Everything else is undefined.
+pub fn arm_unconditional(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    if (instr & 0x0E000000) == 0x0A000000 {
+        arm_blx_imm(cpu, memory, instr)
+    } else if (instr & 0x0D70F000) == 0x0550F000 {
+        arm_pld(cpu, memory, instr)
+    } else {
+        super::arm_undefined(cpu, memory, instr)
+    }
+}
+
+/// Saturates a 64-bit signed result to 32 bits. Returns the saturated value and true if
+/// saturation occurred.
+#[inline]
+fn saturate(value: i64) -> (u32, bool) {
+    if value > i32::MAX as i64 {
+        (i32::MAX as u32, true)
+    } else if value < i32::MIN as i64 {
+        (i32::MIN as u32, true)
+    } else {
+        (value as i32 as u32, false)
+    }
+}
+
+/// Returns the top (`top` = true) or bottom halfword of a register as a signed value.
+#[inline]
+fn halfword(value: u32, top: bool) -> i32 {
+    if top {
+        (value as i32) >> 16
+    } else {
+        value as i16 as i32
+    }
+}
+
+/// Branch with Link and Exchange (immediate)
+///
+/// BLX <offset>
+fn arm_blx_imm(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch_cycles(memory);
+
+    let offset = sign_extend_32!(instr & 0xFFFFFF, 24).wrapping_shl(2) | (bits!(instr, 24) << 1);
+    let pc = cpu.registers.read(15);
+    let dest = pc.wrapping_add(offset);
+    cpu.registers.write(14, (pc.wrapping_sub(4)) & 0xFFFFFFFC);
+    cpu.registers.setf_t();
+    cycles += cpu.thumb_branch_to(dest & 0xFFFFFFFE, memory);
+    return cycles;
+}
+
+/// Branch with Link and Exchange (register)
+///
+/// BLX Rm
+fn arm_blx_reg(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch_cycles(memory);
+    let dest = cpu.registers.read(instr & 0xF);
+    let pc = cpu.registers.read(15);
+    cpu.registers.write(14, (pc.wrapping_sub(4)) & 0xFFFFFFFC);
+    if (dest & 1) == 0 {
+        cycles += cpu.arm_branch_to(dest & 0xFFFFFFFC, memory);
+    } else {
+        cpu.registers.setf_t();
+        cycles += cpu.thumb_branch_to(dest & 0xFFFFFFFE, memory);
+    }
+    return cycles;
+}
+
+/// Breakpoint
+///
+/// BKPT <#immediate>
+fn arm_bkpt(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch(memory);
+    // The breakpoint is treated as a prefetch abort of the BKPT instruction itself.
+    cycles += cpu
+        .handle_exception(
+            CpuException::PrefetchAbort,
+            memory,
+            cpu.registers.read(15).wrapping_sub(8),
+        )
+        .1;
+    return cycles;
+}
+
+/// Preload data (a hint that is treated as a NOP here)
+///
+/// PLD [<address>]
+fn arm_pld(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u32 {
+    cpu.arm_prefetch(memory)
+}
+
+/// Count leading zeros
+///
+/// CLZ Rd, Rm
+fn arm_clz(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let cycles = cpu.arm_prefetch(memory);
+    let rm = bits!(instr, 0, 3);
+    let rd = bits!(instr, 12, 15);
+    let value = cpu.registers.read(rm).leading_zeros();
+    cpu.registers.write(rd, value);
+    return cycles;
+}
+
+macro_rules! arm_gen_qarith {
+    ($name:ident, $subtract:expr, $double:expr) => {
+        fn $name(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+            let cycles = cpu.arm_prefetch(memory);
+
+            let rm = bits!(instr, 0, 3);
+            let rd = bits!(instr, 12, 15);
+            let rn = bits!(instr, 16, 19);
+
+            let lhs = cpu.registers.read(rm) as i32 as i64;
+            let mut rhs = cpu.registers.read(rn) as i32 as i64;
+            let mut saturated = false;
+
+            if $double {
+                let (doubled, sat) = saturate(rhs * 2);
+                rhs = doubled as i32 as i64;
+                saturated |= sat;
+            }
+
+            let (result, sat) = if $subtract {
+                saturate(lhs - rhs)
+            } else {
+                saturate(lhs + rhs)
+            };
+            saturated |= sat;
+
+            cpu.registers.write(rd, result);
+            if saturated {
+                cpu.registers.setf_q();
+            }
+            return cycles;
+        }
+    };
+}
+
+arm_gen_qarith!(arm_qadd, false, false);
+arm_gen_qarith!(arm_qsub, true, false);
+arm_gen_qarith!(arm_qdadd, false, true);
+arm_gen_qarith!(arm_qdsub, true, true);
+
+/// Signed multiply accumulate (16x16+32)
+///
+/// SMLA<x><y> Rd, Rm, Rs, Rn
+fn arm_smlaxy(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let cycles = cpu.arm_prefetch(memory);
+
+    let rm = bits!(instr, 0, 3);
+    let rs = bits!(instr, 8, 11);
+    let rn = bits!(instr, 12, 15);
+    let rd = bits!(instr, 16, 19);
+
+    let lhs = halfword(cpu.registers.read(rm), bits_b!(instr, 5));
+    let rhs = halfword(cpu.registers.read(rs), bits_b!(instr, 6));
+    let acc = cpu.registers.read(rn) as i32;
+    let (result, overflow) = (lhs * rhs).overflowing_add(acc);
+    cpu.registers.write(rd, result as u32);
+    if overflow {
+        cpu.registers.setf_q();
+    }
+    return cycles;
+}
+
+/// Signed multiply (16x16)
+///
+/// SMUL<x><y> Rd, Rm, Rs
+fn arm_smulxy(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let cycles = cpu.arm_prefetch(memory);
+
+    let rm = bits!(instr, 0, 3);
+    let rs = bits!(instr, 8, 11);
+    let rd = bits!(instr, 16, 19);
+
+    let lhs = halfword(cpu.registers.read(rm), bits_b!(instr, 5));
+    let rhs = halfword(cpu.registers.read(rs), bits_b!(instr, 6));
+    cpu.registers.write(rd, (lhs * rhs) as u32);
+    return cycles;
+}
+
+/// Signed multiply accumulate word by halfword (32x16+32)
+///
+/// SMLAW<y> Rd, Rm, Rs, Rn
+fn arm_smlawy(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let cycles = cpu.arm_prefetch(memory);
+
+    let rm = bits!(instr, 0, 3);
+    let rs = bits!(instr, 8, 11);
+    let rn = bits!(instr, 12, 15);
+    let rd = bits!(instr, 16, 19);
+
+    let lhs = cpu.registers.read(rm) as i32 as i64;
+    let rhs = halfword(cpu.registers.read(rs), bits_b!(instr, 6)) as i64;
+    let product = ((lhs * rhs) >> 16) as i32;
+    let acc = cpu.registers.read(rn) as i32;
+    let (result, overflow) = product.overflowing_add(acc);
+    cpu.registers.write(rd, result as u32);
+    if overflow {
+        cpu.registers.setf_q();
+    }
+    return cycles;
+}
+
+/// Signed multiply word by halfword (32x16)
+///
+/// SMULW<y> Rd, Rm, Rs
+fn arm_smulwy(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let cycles = cpu.arm_prefetch(memory);
+
+    let rm = bits!(instr, 0, 3);
+    let rs = bits!(instr, 8, 11);
+    let rd = bits!(instr, 16, 19);
+
+    let lhs = cpu.registers.read(rm) as i32 as i64;
+    let rhs = halfword(cpu.registers.read(rs), bits_b!(instr, 6)) as i64;
+    cpu.registers.write(rd, ((lhs * rhs) >> 16) as u32);
+    return cycles;
+}
+
+/// Signed multiply accumulate long (16x16+64)
+///
+/// SMLAL<x><y> RdLo, RdHi, Rm, Rs
+fn arm_smlalxy(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch(memory);
+
+    let rm = bits!(instr, 0, 3);
+    let rs = bits!(instr, 8, 11);
+    let rd_lo = bits!(instr, 12, 15);
+    let rd_hi = bits!(instr, 16, 19);
+
+    let lhs = halfword(cpu.registers.read(rm), bits_b!(instr, 5)) as i64;
+    let rhs = halfword(cpu.registers.read(rs), bits_b!(instr, 6)) as i64;
+    let acc = ((cpu.registers.read(rd_hi) as u64) << 32) | (cpu.registers.read(rd_lo) as u64);
+    let result = (acc as i64).wrapping_add(lhs * rhs) as u64;
+    cpu.registers.write(rd_lo, result as u32);
+    cpu.registers.write(rd_hi, (result >> 32) as u32);
+
+    cycles += 1;
+    memory.on_internal_cycles(1);
+    return cycles;
+}
+
+/// Returns the address used by an LDRD/STRD instruction and performs base register writeback.
+/// These use the same addressing modes as halfword data transfers.
+fn doubleword_transfer_address(cpu: &mut ArmCpu, instr: u32) -> u32 {
+    let rn = bits!(instr, 16, 19);
+    let pre_index = bits_b!(instr, 24);
+    let increment = bits_b!(instr, 23);
+    let writeback = bits_b!(instr, 21) || !pre_index;
+
+    let offset = if bits_b!(instr, 22) {
+        bits!(instr, 0, 3) | (bits!(instr, 8, 11) << 4)
+    } else {
+        cpu.registers.read(bits!(instr, 0, 3))
+    };
+
+    let base = cpu.registers.read(rn);
+    let offset_addr = if increment {
+        base.wrapping_add(offset)
+    } else {
+        base.wrapping_sub(offset)
+    };
+
+    if writeback {
+        cpu.registers.write(rn, offset_addr);
+    }
+
+    if pre_index {
+        offset_addr
+    } else {
+        base
+    }
+}
+
+/// Load doubleword
+///
+/// LDRD Rd, <address>
+fn arm_ldrd(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch(memory);
+
+    // #NOTE Rd must be even and not R14, but I don't check it.
+    let rd = bits!(instr, 12, 15) & !1;
+    let addr = doubleword_transfer_address(cpu, instr) & 0xFFFFFFFC;
+    let lo = memory.read_data_word(addr, false, &mut cycles);
+    let hi = memory.read_data_word(addr.wrapping_add(4), true, &mut cycles);
+    cpu.registers.write(rd, lo);
+    cpu.registers.write(rd + 1, hi);
+
+    cycles += 1;
+    memory.on_internal_cycles(1);
+    return cycles;
+}
+
+/// Store doubleword
+///
+/// STRD Rd, <address>
+fn arm_strd(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u32 {
+    let mut cycles = cpu.arm_prefetch(memory);
+
+    // #NOTE Rd must be even and not R14, but I don't check it.
+    let rd = bits!(instr, 12, 15) & !1;
+    let lo = cpu.registers.read(rd);
+    let hi = cpu.registers.read(rd + 1);
+    let addr = doubleword_transfer_address(cpu, instr) & 0xFFFFFFFC;
+    memory.write_data_word(addr, lo, false, &mut cycles);
+    memory.write_data_word(addr.wrapping_add(4), hi, true, &mut cycles);
+    return cycles;
+}
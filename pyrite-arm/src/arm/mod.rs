@@ -4,6 +4,8 @@ mod instr_dataproc;
 mod instr_hws_data_transfer;
 mod instr_mul;
 mod instr_single_data_transfer;
+#[cfg(feature = "armv5te")]
+mod instr_v5te;
 
 use self::instr_block_data_transfer::*;
 use self::instr_coprocessor::*;
@@ -11,7 +13,10 @@ use self::instr_dataproc::*;
 use self::instr_hws_data_transfer::*;
 use self::instr_mul::*;
 use self::instr_single_data_transfer::*;
+#[cfg(feature = "armv5te")]
+pub use self::instr_v5te::arm_unconditional;
 
+use super::cpu::ExecutionFn;
 use super::{ArmCpu, ArmMemory};
 
 /// Branch and Exchange
@@ -152,6 +157,7 @@ pub fn arm_msr_rc(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, instr: u32) -> u
     } else {
         // CPSR_all
         cpu.registers.write_cpsr(src);
+        cpu.check_irq_line();
     }
 
     return cycles;
@@ -236,8 +242,15 @@ fn arm_undefined(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _instr: u32) -> u
     return cycles;
 }
 
+#[cfg(not(feature = "armv5te"))]
+pub static ARM_OPCODE_TABLE: [ExecutionFn; 4096] = ARMV4T_OPCODE_TABLE;
+
+#[cfg(feature = "armv5te")]
+pub static ARM_OPCODE_TABLE: [ExecutionFn; 4096] =
+    instr_v5te::extend_opcode_table(ARMV4T_OPCODE_TABLE);
+
 #[allow(dead_code)]
-pub const ARM_OPCODE_TABLE: [fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32; 4096] = [
+pub const ARMV4T_OPCODE_TABLE: [ExecutionFn; 4096] = [
     arm_and_lli,
     arm_and_llr,
     arm_and_lri,
@@ -0,0 +1,389 @@
+//! Cache of pre-decoded instruction blocks used by the CPU's cached interpreter mode.
+//!
+//! When the cache is enabled with `ArmCpu::enable_block_cache` the CPU decodes straight-line runs
+//! of instructions into blocks the first time that they are executed. Later prefetches take their
+//! opcodes and handlers from the block instead of reading and decoding memory again. A block
+//! ends at an unconditional branch or at the end of the 256 byte page that it started in.
+//!
+//! The CPU can't see memory being written by anything but itself (DMA for instance), so the
+//! memory implementation is responsible for reporting writes to RAM that may contain code using
+//! the `BlockInvalidator` returned by `ArmCpu::enable_block_cache`.
+
+#[cfg(feature = "jit")]
+use super::jit::BlockJitState;
+use super::{ArmCpu, ArmMemory};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+/// Blocks never cross a page boundary and invalidation is tracked per page.
+pub(crate) const PAGE_SHIFT: u32 = 8;
+const PAGE_COUNT: usize = 1 << (32 - PAGE_SHIFT);
+
+/// A single pre-decoded instruction in a block.
+#[derive(Clone, Copy)]
+pub(crate) struct BlockEntry {
+    /// The word that would be on the bus when the opcode is fetched. For THUMB blocks this is
+    /// the word containing the opcode.
+    pub(crate) word: u32,
+    pub(crate) opcode: u32,
+    pub(crate) handler: fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32,
+}
+
+/// A straight-line run of pre-decoded instructions.
+pub(crate) struct Block {
+    /// The block's key (see `block_key`).
+    pub(crate) key: u32,
+    pub(crate) entries: Vec<BlockEntry>,
+
+    /// Whether the block has been compiled by the JIT (or how close it is to being compiled).
+    #[cfg(feature = "jit")]
+    pub(crate) jit: BlockJitState,
+}
+
+impl Block {
+    fn decode(address: u32, thumb: bool, memory: &dyn ArmMemory) -> Block {
+        let page_end = (address | ((1 << PAGE_SHIFT) - 1)).wrapping_add(1);
+        let mut entries = Vec::new();
+        let mut addr = address;
+        let mut ending = false;
+
+        loop {
+            let word = memory.view_word(addr & 0xFFFFFFFC);
+            let (opcode, handler, ends_block) = if thumb {
+                let opcode = if (addr & 2) == 0 {
+                    word & 0xFFFF
+                } else {
+                    word >> 16
+                };
+                (
+                    opcode,
+                    ArmCpu::decode_thumb(opcode),
+                    thumb_ends_block(opcode),
+                )
+            } else {
+                (word, ArmCpu::decode_arm(word), arm_ends_block(word))
+            };
+
+            entries.push(BlockEntry {
+                word,
+                opcode,
+                handler,
+            });
+
+            addr = addr.wrapping_add(if thumb { 2 } else { 4 });
+            if ending || addr == page_end || !memory.code_cacheable(addr) {
+                break;
+            }
+
+            // The instruction after an unconditional branch is still decoded because it is
+            // prefetched when something branches to the branch itself (e.g. `b .`).
+            ending = ends_block;
+        }
+
+        Block {
+            key: block_key(address, thumb),
+            entries,
+            #[cfg(feature = "jit")]
+            jit: BlockJitState::default(),
+        }
+    }
+}
+
+/// Blocks are keyed by their address with bit 0 set for THUMB blocks. This is also used by the
+/// CPU to check if the next fetch is the next entry of its current block.
+#[inline(always)]
+pub(crate) fn block_key(address: u32, thumb: bool) -> u32 {
+    address | (thumb as u32)
+}
+
+/// Unconditional B, BL and BX. These never fall through so there is no point in decoding much
+/// past them. Branches don't prefetch past themselves either (see `arm_prefetch_cycles`).
+fn arm_ends_block(opcode: u32) -> bool {
+    if (opcode >> 28) != 0xE {
+        return false;
+    }
+    return bits!(opcode, 25, 27) == 0b101 || (opcode & 0x0FFFFFF0) == 0x012FFF10;
+}
+
+/// Unconditional B, BX and the second half of BL.
+fn thumb_ends_block(opcode: u32) -> bool {
+    let op = opcode >> 11;
+    return op == 0b11100 || op == 0b11111 || (opcode >> 8) == 0x47;
+}
+
+/// Shared between the CPU's block cache and the memory that the CPU is running from. Memory
+/// implementations call `on_write` for writes to memory that might contain code.
+#[derive(Clone)]
+pub struct BlockInvalidator {
+    inner: Rc<InvalidatorInner>,
+}
+
+struct InvalidatorInner {
+    /// One bit for each page that has at least one block decoded from it.
+    code_pages: Box<[Cell<u64>]>,
+
+    /// Pages with code that have been written to since the last time the cache was checked.
+    dirty_pages: RefCell<Vec<u32>>,
+
+    /// True if there are dirty pages or if the entire cache should be flushed.
+    pending: Cell<bool>,
+    flush_all: Cell<bool>,
+}
+
+impl BlockInvalidator {
+    fn new() -> BlockInvalidator {
+        BlockInvalidator {
+            inner: Rc::new(InvalidatorInner {
+                code_pages: std::iter::repeat_with(|| Cell::new(0))
+                    .take(PAGE_COUNT / 64)
+                    .collect(),
+                dirty_pages: RefCell::new(Vec::new()),
+                pending: Cell::new(false),
+                flush_all: Cell::new(false),
+            }),
+        }
+    }
+
+    /// Reports a write to the given address. This is cheap for pages that don't contain any
+    /// cached code, so it can be called for every write to RAM.
+    #[inline]
+    pub fn on_write(&self, addr: u32) {
+        let page = addr >> PAGE_SHIFT;
+        let bits = &self.inner.code_pages[(page >> 6) as usize];
+        let mask = 1u64 << (page & 63);
+        if (bits.get() & mask) != 0 {
+            bits.set(bits.get() & !mask);
+            self.inner.dirty_pages.borrow_mut().push(page);
+            self.inner.pending.set(true);
+        }
+    }
+
+    /// Throws away every cached block. Used when memory is remapped or replaced without being
+    /// written to (e.g. loading a new ROM).
+    pub fn invalidate_all(&self) {
+        self.inner.flush_all.set(true);
+        self.inner.pending.set(true);
+    }
+
+    #[inline]
+    fn pending(&self) -> bool {
+        self.inner.pending.get()
+    }
+
+    fn mark_code_page(&self, page: u32) {
+        let bits = &self.inner.code_pages[(page >> 6) as usize];
+        bits.set(bits.get() | (1u64 << (page & 63)));
+    }
+
+    fn clear_code_pages(&self) {
+        self.inner.code_pages.iter().for_each(|bits| bits.set(0));
+    }
+}
+
+type BlockMap<V> = HashMap<u32, V, BuildHasherDefault<BlockKeyHasher>>;
+
+/// The keys are just addresses so there is no need for SipHash here.
+#[derive(Default)]
+struct BlockKeyHasher(u64);
+
+impl Hasher for BlockKeyHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.0 = (self.0.rotate_left(5) ^ (*byte as u64)).wrapping_mul(0x517cc1b727220a95);
+        }
+    }
+
+    #[inline]
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (value as u64).wrapping_mul(0x517cc1b727220a95);
+    }
+}
+
+/// Number of entries in the direct mapped table that is checked before the block map.
+const JUMP_TABLE_SIZE: usize = 4096;
+
+/// Key used for unused slots. Real keys are always at least halfword aligned (besides the THUMB
+/// bit) so this can never match one.
+pub(crate) const NO_BLOCK: u32 = 0xFFFFFFFF;
+
+pub(crate) struct BlockCache {
+    /// Storage for all blocks. Blocks that have been invalidated are left in place with their key
+    /// set to `NO_BLOCK` and their slot is reused by the next decoded block.
+    blocks: Vec<Block>,
+    free_slots: Vec<u32>,
+
+    /// Maps block keys to their slot in `blocks`.
+    slots: BlockMap<u32>,
+
+    /// (key, slot) pairs indexed by the lower bits of the key. This takes care of most lookups
+    /// for branches without having to go through the map.
+    jump_table: Box<[(u32, u32)]>,
+
+    /// The slots of the blocks that were decoded from each page.
+    page_slots: BlockMap<Vec<u32>>,
+
+    invalidator: BlockInvalidator,
+
+    /// Incremented whenever blocks are removed from the cache. Code compiled by the JIT uses this
+    /// to notice that the block it is running from might not exist anymore.
+    #[cfg(feature = "jit")]
+    generation: u32,
+}
+
+impl BlockCache {
+    pub(crate) fn new() -> BlockCache {
+        BlockCache {
+            blocks: Vec::new(),
+            free_slots: Vec::new(),
+            slots: BlockMap::default(),
+            jump_table: vec![(NO_BLOCK, 0); JUMP_TABLE_SIZE].into_boxed_slice(),
+            page_slots: BlockMap::default(),
+            invalidator: BlockInvalidator::new(),
+            #[cfg(feature = "jit")]
+            generation: 0,
+        }
+    }
+
+    pub(crate) fn invalidator(&self) -> BlockInvalidator {
+        self.invalidator.clone()
+    }
+
+    #[inline(always)]
+    pub(crate) fn block(&self, slot: u32) -> &Block {
+        &self.blocks[slot as usize]
+    }
+
+    #[cfg(feature = "jit")]
+    #[inline(always)]
+    pub(crate) fn block_mut(&mut self, slot: u32) -> Option<&mut Block> {
+        self.blocks.get_mut(slot as usize)
+    }
+
+    #[cfg(feature = "jit")]
+    #[inline(always)]
+    pub(crate) fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Forgets the compiled code of every block. Used when the JIT's code buffer is cleared.
+    #[cfg(feature = "jit")]
+    pub(crate) fn reset_jit(&mut self) {
+        self.blocks
+            .iter_mut()
+            .for_each(|block| block.jit = BlockJitState::default());
+    }
+
+    #[inline(always)]
+    fn jump_table_index(key: u32) -> usize {
+        ((key >> 1) as usize) & (JUMP_TABLE_SIZE - 1)
+    }
+
+    /// Returns the slot of the block starting at the given address, decoding it first if it isn't
+    /// cached. Returns `None` if the memory at the address can't be cached.
+    #[inline]
+    pub(crate) fn get(&mut self, address: u32, thumb: bool, memory: &dyn ArmMemory) -> Option<u32> {
+        let key = block_key(address, thumb);
+        let (jump_key, jump_slot) = self.jump_table[Self::jump_table_index(key)];
+        if jump_key == key {
+            return Some(jump_slot);
+        }
+        return self.get_slow(address, thumb, memory);
+    }
+
+    #[inline(never)]
+    fn get_slow(&mut self, address: u32, thumb: bool, memory: &dyn ArmMemory) -> Option<u32> {
+        if !memory.code_cacheable(address) {
+            return None;
+        }
+
+        let key = block_key(address, thumb);
+        let slot = if let Some(&slot) = self.slots.get(&key) {
+            slot
+        } else {
+            let block = Block::decode(address, thumb, memory);
+            let slot = if let Some(slot) = self.free_slots.pop() {
+                self.blocks[slot as usize] = block;
+                slot
+            } else {
+                self.blocks.push(block);
+                (self.blocks.len() - 1) as u32
+            };
+
+            let page = address >> PAGE_SHIFT;
+            self.invalidator.mark_code_page(page);
+            self.page_slots.entry(page).or_default().push(slot);
+            self.slots.insert(key, slot);
+            slot
+        };
+
+        self.jump_table[Self::jump_table_index(key)] = (key, slot);
+        return Some(slot);
+    }
+
+    /// Returns true if memory with cached code has been written to since the last call to
+    /// `process_invalidations`.
+    #[inline(always)]
+    pub(crate) fn invalidations_pending(&self) -> bool {
+        self.invalidator.pending()
+    }
+
+    /// Throws away blocks from pages that have been written to since the last call.
+    pub(crate) fn process_invalidations(&mut self) {
+        self.invalidator.inner.pending.set(false);
+        #[cfg(feature = "jit")]
+        {
+            self.generation = self.generation.wrapping_add(1);
+        }
+
+        if self.invalidator.inner.flush_all.replace(false) {
+            self.invalidator.inner.dirty_pages.borrow_mut().clear();
+            self.clear();
+            return;
+        }
+
+        let mut dirty_pages = self.invalidator.inner.dirty_pages.borrow_mut();
+        for page in dirty_pages.drain(..) {
+            if let Some(slots) = self.page_slots.remove(&page) {
+                for slot in slots {
+                    let block = &mut self.blocks[slot as usize];
+                    let jump_entry = &mut self.jump_table[Self::jump_table_index(block.key)];
+                    if jump_entry.0 == block.key {
+                        *jump_entry = (NO_BLOCK, 0);
+                    }
+                    self.slots.remove(&block.key);
+                    block.key = NO_BLOCK;
+                    block.entries = Vec::new();
+                    #[cfg(feature = "jit")]
+                    {
+                        block.jit = BlockJitState::default();
+                    }
+                    self.free_slots.push(slot);
+                }
+            }
+        }
+    }
+
+    /// Removes all blocks from the cache.
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.free_slots.clear();
+        self.slots.clear();
+        self.jump_table
+            .iter_mut()
+            .for_each(|entry| *entry = (NO_BLOCK, 0));
+        self.page_slots.clear();
+        self.invalidator.clear_code_pages();
+        #[cfg(feature = "jit")]
+        {
+            self.generation = self.generation.wrapping_add(1);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Interface used by the CPU to talk to attached coprocessors (CP0-CP15).
+//!
+//! The ARM7TDMI doesn't know anything about the coprocessors connected to it. When it encounters
+//! a CDP, LDC, STC, MCR or MRC instruction it offers it to the coprocessor bus and the coprocessor
+//! with the matching number either accepts it (possibly after busy-waiting the CPU for a few
+//! cycles) or leaves the CPA (coprocessor absent) line high, in which case the CPU takes the
+//! Undefined Instruction exception.
+
+/// The number of coprocessors that can be attached to an ARM CPU.
+pub const COPROCESSOR_COUNT: usize = 16;
+
+/// The response of a coprocessor to the CPU's handshake for a coprocessor instruction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Handshake<T = ()> {
+    /// The coprocessor does not implement the instruction (CPA high). The CPU will take the
+    /// Undefined Instruction exception.
+    Absent,
+
+    /// The coprocessor accepted the instruction after busy-waiting the CPU for `busy_cycles`
+    /// internal cycles. `value` is the data that the instruction produces (if any).
+    Accept { busy_cycles: u32, value: T },
+}
+
+impl<T> Handshake<T> {
+    /// Accepts an instruction immediately, without busy-waiting the CPU.
+    #[inline]
+    pub fn accept(value: T) -> Handshake<T> {
+        Handshake::Accept {
+            busy_cycles: 0,
+            value,
+        }
+    }
+
+    /// Accepts an instruction after busy-waiting the CPU for the given number of cycles.
+    #[inline]
+    pub fn accept_after(busy_cycles: u32, value: T) -> Handshake<T> {
+        Handshake::Accept { busy_cycles, value }
+    }
+}
+
+/// A coprocessor that can be attached to an `ArmCpu` using `ArmCpu::attach_coprocessor`.
+///
+/// Every method has a default implementation that returns `Handshake::Absent` so implementors
+/// only need to provide the instructions that their coprocessor actually supports.
+pub trait Coprocessor {
+    /// CDP: Coprocessor data operation.
+    ///
+    /// Cycles: 1S + bI
+    fn data_operation(
+        &mut self,
+        _opcode1: u32,
+        _crd: u32,
+        _crn: u32,
+        _crm: u32,
+        _opcode2: u32,
+    ) -> Handshake {
+        Handshake::Absent
+    }
+
+    /// MCR: Move a value from an ARM register into a coprocessor register.
+    ///
+    /// Cycles: 1S + bI + 1C
+    fn write_register(
+        &mut self,
+        _opcode1: u32,
+        _crn: u32,
+        _crm: u32,
+        _opcode2: u32,
+        _value: u32,
+    ) -> Handshake {
+        Handshake::Absent
+    }
+
+    /// MRC: Move a value from a coprocessor register into an ARM register.
+    ///
+    /// Cycles: 1S + bI + 1C + 1I
+    fn read_register(
+        &mut self,
+        _opcode1: u32,
+        _crn: u32,
+        _crm: u32,
+        _opcode2: u32,
+    ) -> Handshake<u32> {
+        Handshake::Absent
+    }
+
+    /// LDC: Begins a load from memory into coprocessor register `crd`. `long` is the N bit
+    /// of the instruction and `option` is the 8-bit immediate field (only meaningful for
+    /// unindexed transfers). The accepted value is the number of words that the coprocessor
+    /// wants to transfer (which must be at least 1). The words are then passed to `load_word`.
+    ///
+    /// Cycles: (n-1)S + 2N + bI
+    fn begin_load(&mut self, _crd: u32, _long: bool, _option: u32) -> Handshake<u32> {
+        Handshake::Absent
+    }
+
+    /// Receives the word at `index` of an LDC transfer that was accepted by `begin_load`.
+    fn load_word(&mut self, _crd: u32, _index: u32, _value: u32) {}
+
+    /// STC: Begins a store from coprocessor register `crd` to memory. `long` is the N bit
+    /// of the instruction and `option` is the 8-bit immediate field (only meaningful for
+    /// unindexed transfers). The accepted value is the number of words that the coprocessor
+    /// wants to transfer (which must be at least 1). The words are then requested from
+    /// `store_word`.
+    ///
+    /// Cycles: (n-1)S + 2N + bI
+    fn begin_store(&mut self, _crd: u32, _long: bool, _option: u32) -> Handshake<u32> {
+        Handshake::Absent
+    }
+
+    /// Returns the word at `index` of an STC transfer that was accepted by `begin_store`.
+    fn store_word(&mut self, _crd: u32, _index: u32) -> u32 {
+        0
+    }
+}
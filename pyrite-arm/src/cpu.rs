@@ -1,9 +1,27 @@
+use super::block_cache::{
+    block_key, BlockCache, BlockEntry, BlockInvalidator, NO_BLOCK, PAGE_SHIFT,
+};
+use super::coprocessor::{Coprocessor, COPROCESSOR_COUNT};
+#[cfg(feature = "jit")]
+use super::jit::{BlockJitState, Jit, JitContext, PIPELINE_VALID};
 use super::memory::ArmMemory;
 use super::registers::{ArmRegisters, CpuMode};
 use super::{arm, thumb};
+use pyrite_common::{StateError, StateReader, StateWriter};
 
 pub const EXCEPTION_BASE: u32 = 0;
 
+/// The version of the state written by `ArmCpu::save_state`.
+const CPU_STATE_VERSION: u32 = 2;
+
+// What `decoded_fn` was set to when a state was saved. Overrides are saved as
+// `PIPELINE_OVERRIDE` plus their index in the list passed to `save_state`.
+const PIPELINE_RUNNING: u8 = 0;
+const PIPELINE_NOP: u8 = 1;
+const PIPELINE_EXCEPTION: u8 = 2;
+const PIPELINE_IDLE: u8 = 3;
+const PIPELINE_OVERRIDE: u8 = 4;
+
 pub struct ArmCpu {
     /// The last opcode that was fetched.
     fetched: u32,
@@ -26,10 +44,52 @@ pub struct ArmCpu {
     /// instruction.
     pending_exception: Option<CpuException>,
 
+    /// The level of the IRQ input. The IRQ exception is taken whenever this is set and IRQs are
+    /// not disabled in the CPSR.
+    irq_line: bool,
+
     /// Called by the CPU when an exception (trap) has occurred in the CPU.
     /// If false is returned the CPU will continue execution of the exception
     /// and jump to the exception's vector. If true is returned execution is stopped.
     on_exception: Option<ExceptionHandler>,
+
+    /// Coprocessors attached to the CPU, indexed by coprocessor number.
+    coprocessors: [Option<Box<dyn Coprocessor>>; COPROCESSOR_COUNT],
+
+    /// Pre-decoded instruction blocks. The CPU only uses these when this is not `None`.
+    block_cache: Option<Box<BlockCache>>,
+
+    /// The next entry of the block that the last opcode was fetched from and the end of that
+    /// block. These are only valid while `block_next_key` is not `NO_BLOCK`, which is reset
+    /// whenever blocks are removed from the cache.
+    block_next: *const BlockEntry,
+    block_end: *const BlockEntry,
+
+    /// The key (see `block_key`) that the next fetch has to match to use `block_next`.
+    block_next_key: u32,
+
+    /// The key of the next fetch if the last one was from memory that can't be cached. This saves
+    /// asking the memory about every fetch from the same page.
+    uncached_next_key: u32,
+
+    /// The cached handler for `fetched` when running from the block cache.
+    fetched_handler: fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32,
+
+    /// The cached handler for `decoded_op` when running from the block cache. This is only used
+    /// by ARM instructions which still need to check their condition before running.
+    decoded_handler: fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32,
+
+    /// Compiles blocks from the block cache. The CPU only uses this when it is not `None`.
+    #[cfg(feature = "jit")]
+    jit: Option<Box<Jit>>,
+
+    /// The slot of the block that `block_next` points into.
+    #[cfg(feature = "jit")]
+    block_slot: u32,
+
+    /// Set whenever an exception is taken so that compiled code can tell that it has to exit.
+    #[cfg(feature = "jit")]
+    exception_taken: bool,
 }
 
 impl ArmCpu {
@@ -43,7 +103,22 @@ impl ArmCpu {
             idle: false,
             idle_cycles: 1,
             pending_exception: None,
+            irq_line: false,
             on_exception: None,
+            coprocessors: Default::default(),
+            block_cache: None,
+            block_next: std::ptr::null(),
+            block_end: std::ptr::null(),
+            block_next_key: NO_BLOCK,
+            uncached_next_key: NO_BLOCK,
+            fetched_handler: Self::step_nop,
+            decoded_handler: Self::step_nop,
+            #[cfg(feature = "jit")]
+            jit: None,
+            #[cfg(feature = "jit")]
+            block_slot: 0,
+            #[cfg(feature = "jit")]
+            exception_taken: false,
         }
     }
 
@@ -57,6 +132,10 @@ impl ArmCpu {
     #[must_use]
     #[inline]
     pub(crate) fn arm_branch_to(&mut self, pc: u32, memory: &mut dyn ArmMemory) -> u32 {
+        if self.block_cache.is_some() {
+            return self.arm_branch_to_cached(pc, memory);
+        }
+
         let mut cycles = 0;
         let next_pc = pc.wrapping_add(4);
         self.registers.write(15, next_pc);
@@ -72,6 +151,10 @@ impl ArmCpu {
     #[must_use]
     #[inline]
     pub(crate) fn thumb_branch_to(&mut self, pc: u32, memory: &mut dyn ArmMemory) -> u32 {
+        if self.block_cache.is_some() {
+            return self.thumb_branch_to_cached(pc, memory);
+        }
+
         let mut cycles = 0;
         let next_pc = pc.wrapping_add(2);
         self.registers.write(15, next_pc);
@@ -93,6 +176,25 @@ impl ArmCpu {
         }
     }
 
+    /// Used by instructions that load the program counter from memory (LDR, LDM and POP).
+    /// ARMv4T stays in the current instruction set.
+    #[cfg(not(feature = "armv5te"))]
+    #[must_use]
+    #[inline]
+    pub(crate) fn load_branch_to(&mut self, pc: u32, memory: &mut dyn ArmMemory) -> u32 {
+        self.branch_to(pc, memory)
+    }
+
+    /// Used by instructions that load the program counter from memory (LDR, LDM and POP).
+    /// On ARMv5TE bit 0 of the loaded value selects THUMB (1) or ARM (0) state like BX.
+    #[cfg(feature = "armv5te")]
+    #[must_use]
+    #[inline]
+    pub(crate) fn load_branch_to(&mut self, pc: u32, memory: &mut dyn ArmMemory) -> u32 {
+        self.registers.putf_t((pc & 1) != 0);
+        self.branch_to(pc, memory)
+    }
+
     /// Flushes the CPU's pipeline, sets the program counter
     /// and "fetches" and "decodes" the next instruction.
     #[must_use]
@@ -121,6 +223,10 @@ impl ArmCpu {
 
     #[must_use]
     pub fn arm_prefetch(&mut self, memory: &mut dyn ArmMemory) -> u32 {
+        if self.block_cache.is_some() {
+            return self.arm_prefetch_cached(memory);
+        }
+
         let mut cycles = 0;
         let next_pc = self.registers.read(15).wrapping_add(4);
         self.registers.write(15, next_pc);
@@ -143,6 +249,10 @@ impl ArmCpu {
     #[must_use]
     #[inline(always)]
     pub fn thumb_prefetch(&mut self, memory: &mut dyn ArmMemory) -> u32 {
+        if self.block_cache.is_some() {
+            return self.thumb_prefetch_cached(memory);
+        }
+
         let mut cycles = 0;
         let next_pc = self.registers.read(15).wrapping_add(2);
         self.registers.write(15, next_pc);
@@ -162,6 +272,409 @@ impl ArmCpu {
         return memory.code_cycles_halfword(next_pc, true);
     }
 
+    /// Switches the CPU to its cached interpreter mode. Straight-line runs of instructions are
+    /// decoded into blocks the first time that they are executed and later fetches are served
+    /// from those blocks. Timing is the same as the normal interpreter because the fetch cycles
+    /// are still taken from the memory (see `ArmMemory::cached_code_cycles_word`).
+    ///
+    /// The returned `BlockInvalidator` must be told about any writes to memory that might
+    /// contain code or the CPU will keep running stale instructions.
+    pub fn enable_block_cache(&mut self) -> BlockInvalidator {
+        if let Some(ref cache) = self.block_cache {
+            return cache.invalidator();
+        }
+
+        // The opcode that is already in the pipeline was fetched without the cache.
+        self.fetched_handler = if self.registers.getf_t() {
+            Self::decode_thumb(self.fetched)
+        } else {
+            Self::decode_arm(self.fetched)
+        };
+        self.decoded_handler = Self::decode_arm(self.decoded_op);
+
+        let cache = Box::new(BlockCache::new());
+        let invalidator = cache.invalidator();
+        self.block_cache = Some(cache);
+        self.block_next_key = NO_BLOCK;
+        return invalidator;
+    }
+
+    /// Switches the CPU back to the normal interpreter and throws away all cached blocks.
+    pub fn disable_block_cache(&mut self) {
+        #[cfg(feature = "jit")]
+        {
+            self.jit = None;
+        }
+        if let Some(mut cache) = self.block_cache.take() {
+            cache.clear();
+        }
+        self.block_next_key = NO_BLOCK;
+    }
+
+    #[inline]
+    pub fn block_cache_enabled(&self) -> bool {
+        self.block_cache.is_some()
+    }
+
+    /// Enables the block cache (see `enable_block_cache`) and compiles blocks that are run often
+    /// into native code. Timing is the same as the interpreter but `ArmMemory::jit_budget` and
+    /// `ArmMemory::jit_advance` have to be implemented for anything that depends on being called
+    /// back between instructions (e.g. scheduled events).
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) -> BlockInvalidator {
+        let invalidator = self.enable_block_cache();
+        if self.jit.is_none() {
+            self.jit = Some(Box::new(Jit::new()));
+        }
+        return invalidator;
+    }
+
+    /// Throws away all compiled code and goes back to the cached interpreter.
+    #[cfg(feature = "jit")]
+    pub fn disable_jit(&mut self) {
+        if self.jit.take().is_some() {
+            if let Some(ref mut cache) = self.block_cache {
+                cache.reset_jit();
+            }
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[inline]
+    pub fn jit_enabled(&self) -> bool {
+        self.jit.is_some()
+    }
+
+    #[must_use]
+    fn arm_branch_to_cached(&mut self, pc: u32, memory: &mut dyn ArmMemory) -> u32 {
+        let next_pc = pc.wrapping_add(4);
+        self.registers.write(15, next_pc);
+        let mut cycles = self.fetch_cached(pc, false, memory);
+        self.decoded_op = self.fetched;
+        self.decoded_handler = self.fetched_handler;
+        self.decoded_fn = Self::step_arm_cached;
+        cycles += self.fetch_cached(next_pc, true, memory);
+        return cycles;
+    }
+
+    #[must_use]
+    fn thumb_branch_to_cached(&mut self, pc: u32, memory: &mut dyn ArmMemory) -> u32 {
+        let next_pc = pc.wrapping_add(2);
+        self.registers.write(15, next_pc);
+        let mut cycles = self.fetch_cached(pc, false, memory);
+        self.decoded_op = self.fetched;
+        self.decoded_fn = self.fetched_handler;
+        cycles += self.fetch_cached(next_pc, true, memory);
+        return cycles;
+    }
+
+    #[must_use]
+    #[inline]
+    fn arm_prefetch_cached(&mut self, memory: &mut dyn ArmMemory) -> u32 {
+        let next_pc = self.registers.read(15).wrapping_add(4);
+        self.registers.write(15, next_pc);
+        self.decoded_op = self.fetched;
+        self.decoded_handler = self.fetched_handler;
+        self.decoded_fn = Self::step_arm_cached;
+        return self.fetch_cached(next_pc, true, memory);
+    }
+
+    #[must_use]
+    #[inline]
+    fn thumb_prefetch_cached(&mut self, memory: &mut dyn ArmMemory) -> u32 {
+        let next_pc = self.registers.read(15).wrapping_add(2);
+        self.registers.write(15, next_pc);
+        self.decoded_op = self.fetched;
+        self.decoded_fn = self.fetched_handler;
+        return self.fetch_cached(next_pc, true, memory);
+    }
+
+    /// Fetches the opcode at `addr` and its handler from the block cache into `fetched` and
+    /// `fetched_handler`. If `addr` is not the next entry of the current block, the block starting
+    /// at `addr` is used instead (and decoded if it isn't cached yet).
+    #[inline(always)]
+    fn fetch_cached(&mut self, addr: u32, seq: bool, memory: &mut dyn ArmMemory) -> u32 {
+        let thumb = self.registers.getf_t();
+        let key = block_key(addr, thumb);
+
+        let pending = match self.block_cache {
+            Some(ref cache) => cache.invalidations_pending(),
+            None => unreachable!("fetched from block cache while it was disabled"),
+        };
+
+        if key != self.block_next_key || pending {
+            return self.fetch_cached_slow(addr, seq, memory);
+        }
+
+        // SAFETY: `block_next_key` is only set while `block_next` points into the entries of a
+        // block that is still in the cache.
+        let entry = unsafe { *self.block_next };
+        return self.use_block_entry(entry, key, addr, seq, memory);
+    }
+
+    /// Used by `fetch_cached` when the fetch is not from the next entry of the current block or if
+    /// the cache has to process some invalidations first.
+    #[inline(never)]
+    fn fetch_cached_slow(&mut self, addr: u32, seq: bool, memory: &mut dyn ArmMemory) -> u32 {
+        let thumb = self.registers.getf_t();
+        let key = block_key(addr, thumb);
+        let cache = self
+            .block_cache
+            .as_deref_mut()
+            .expect("fetched from block cache while it was disabled");
+
+        if cache.invalidations_pending() {
+            cache.process_invalidations();
+            // The current block might not exist anymore.
+            self.block_next_key = NO_BLOCK;
+            self.uncached_next_key = NO_BLOCK;
+        }
+
+        if key == self.uncached_next_key {
+            return self.fetch_uncached(addr, seq, memory);
+        }
+
+        if key != self.block_next_key {
+            match cache.get(addr, thumb, memory) {
+                Some(slot) => {
+                    #[cfg(feature = "jit")]
+                    {
+                        self.block_slot = slot;
+                    }
+                    let entries = &cache.block(slot).entries;
+                    self.block_next = entries.as_ptr();
+                    // SAFETY: blocks always have at least one entry.
+                    self.block_end = unsafe { entries.as_ptr().add(entries.len()) };
+                }
+
+                None => {
+                    self.block_next_key = NO_BLOCK;
+                    return self.fetch_uncached(addr, seq, memory);
+                }
+            }
+        }
+
+        // SAFETY: `block_next` was just set or is still valid (see `fetch_cached`).
+        let entry = unsafe { *self.block_next };
+        return self.use_block_entry(entry, key, addr, seq, memory);
+    }
+
+    #[inline(always)]
+    fn use_block_entry(
+        &mut self,
+        entry: BlockEntry,
+        key: u32,
+        addr: u32,
+        seq: bool,
+        memory: &mut dyn ArmMemory,
+    ) -> u32 {
+        // SAFETY: this will at most point one past the end of the block's entries.
+        self.block_next = unsafe { self.block_next.add(1) };
+        self.block_next_key = if self.block_next != self.block_end {
+            key.wrapping_add(if (key & 1) != 0 { 2 } else { 4 })
+        } else {
+            NO_BLOCK
+        };
+
+        self.fetched = entry.opcode;
+        self.fetched_handler = entry.handler;
+        if (key & 1) != 0 {
+            return memory.cached_code_cycles_halfword(addr, entry.word, seq);
+        } else {
+            return memory.cached_code_cycles_word(addr, entry.word, seq);
+        }
+    }
+
+    /// Used by `fetch_cached` for memory that can't be cached.
+    fn fetch_uncached(&mut self, addr: u32, seq: bool, memory: &mut dyn ArmMemory) -> u32 {
+        let thumb = self.registers.getf_t();
+        let next_addr = addr.wrapping_add(if thumb { 2 } else { 4 });
+        // Cacheability is checked again at the start of every page.
+        self.uncached_next_key = if (next_addr & ((1 << PAGE_SHIFT) - 1)) != 0 {
+            block_key(next_addr, thumb)
+        } else {
+            NO_BLOCK
+        };
+
+        let mut cycles = 0;
+        if thumb {
+            self.fetched = memory.read_code_halfword(addr, seq, &mut cycles) as u32;
+            self.fetched_handler = Self::decode_thumb(self.fetched);
+        } else {
+            self.fetched = memory.read_code_word(addr, seq, &mut cycles);
+            self.fetched_handler = Self::decode_arm(self.fetched);
+        }
+        return cycles;
+    }
+
+    /// Runs the compiled code of the block that starts with the instruction in the decode stage if
+    /// there is any. Returns `None` if the instruction should be run by the interpreter instead.
+    #[cfg(feature = "jit")]
+    fn step_jit(&mut self, memory: &mut dyn ArmMemory) -> Option<u32> {
+        if self.block_next_key == NO_BLOCK || self.pending_exception.is_some() || self.idle {
+            return None;
+        }
+
+        let thumb = self.registers.getf_t();
+        let start = self.next_exec_address();
+        let slot = self.block_slot;
+        let cpsr_offset = self.registers.cpsr_offset();
+        let cache = self.block_cache.as_deref_mut()?;
+        if cache.invalidations_pending() {
+            return None;
+        }
+        let generation = cache.generation();
+
+        // Compiled code is only entered at the start of a block with the pipeline filled from
+        // the block's first two entries.
+        let block = cache.block_mut(slot)?;
+        if block.key != block_key(start, thumb)
+            || self.block_next != block.entries.as_ptr().wrapping_add(2)
+            || self.decoded_op != block.entries[0].opcode
+        {
+            return None;
+        }
+        let expected_fn = if thumb {
+            block.entries[0].handler as usize
+        } else {
+            Self::step_arm_cached as *const () as usize
+        };
+        if self.decoded_fn as usize != expected_fn {
+            return None;
+        }
+
+        if Jit::should_compile(&mut block.jit) {
+            let jit = self.jit.as_deref_mut()?;
+            if !jit.compile(&block.entries, thumb, cpsr_offset, &mut block.jit) {
+                jit.clear();
+                cache.reset_jit();
+                let block = cache.block_mut(slot)?;
+                if !jit.compile(&block.entries, thumb, cpsr_offset, &mut block.jit) {
+                    block.jit = BlockJitState::Interpreted;
+                }
+            }
+        }
+
+        let block = cache.block_mut(slot)?;
+        let code = match block.jit {
+            BlockJitState::Compiled(code) => code,
+            _ => return None,
+        };
+        let entries = block.entries.as_ptr();
+        let entry_count = block.entries.len() as u32;
+
+        let budget = memory.jit_budget();
+        if budget == 0 {
+            return None;
+        }
+
+        let mut ctx = JitContext {
+            cpu: self,
+            memory,
+            entries,
+            entry_count,
+            start,
+            thumb,
+            slot,
+            generation,
+            pending: 0,
+            budget,
+        };
+
+        self.exception_taken = false;
+        let registers = self.registers.gp_registers_ptr();
+        // SAFETY: the code was compiled for this block, which is still in the cache.
+        let result = unsafe { code(&mut ctx, registers) };
+        if result != PIPELINE_VALID {
+            self.jit_sync_pipeline(&ctx, result);
+        }
+        return Some(ctx.pending);
+    }
+
+    /// Puts the CPU's pipeline in the state that the interpreter would have left it in before
+    /// running the entry at `index` of the block that compiled code is running from. Compiled
+    /// code only updates the registers.
+    #[cfg(feature = "jit")]
+    pub(crate) fn jit_sync_pipeline(&mut self, ctx: &JitContext, index: u32) {
+        let decoded = ctx.entry(index);
+        let fetched = ctx.entry(index + 1);
+
+        self.registers.write(15, ctx.address(index + 1));
+        self.decoded_op = decoded.opcode;
+        self.decoded_handler = decoded.handler;
+        self.decoded_fn = if ctx.thumb {
+            decoded.handler
+        } else {
+            Self::step_arm_cached
+        };
+        self.fetched = fetched.opcode;
+        self.fetched_handler = fetched.handler;
+
+        // SAFETY: see `JitContext::entry`. This will at most point one past the end of the
+        // block's entries.
+        unsafe {
+            self.block_next = ctx.entries.add(index as usize + 2);
+            self.block_end = ctx.entries.add(ctx.entry_count as usize);
+        }
+        self.block_next_key = if index + 2 < ctx.entry_count {
+            block_key(ctx.address(index + 2), ctx.thumb)
+        } else {
+            NO_BLOCK
+        };
+        self.uncached_next_key = NO_BLOCK;
+        self.block_slot = ctx.slot;
+    }
+
+    /// Used by compiled code to run a single instruction with the interpreter.
+    #[cfg(feature = "jit")]
+    #[inline]
+    pub(crate) fn jit_step_interpreter(&mut self, memory: &mut dyn ArmMemory) -> u32 {
+        (self.decoded_fn)(self, memory, self.decoded_op)
+    }
+
+    /// Returns the index of the entry that compiled code should continue with after the
+    /// interpreter ran the entry at `index`. That is either the next entry or, if the entry
+    /// branched back to the start of the block, the first one. Returns `None` if the block has to
+    /// exit.
+    #[cfg(feature = "jit")]
+    pub(crate) fn jit_next_entry(&self, ctx: &JitContext, index: u32) -> Option<u32> {
+        if self.exception_taken
+            || self.pending_exception.is_some()
+            || self.idle
+            || self.registers.getf_t() != ctx.thumb
+        {
+            return None;
+        }
+
+        let cache = self.block_cache.as_deref()?;
+        if cache.invalidations_pending() || cache.generation() != ctx.generation {
+            return None;
+        }
+
+        let next =
+            if self.registers.read(15) == ctx.address(index + 2) && index + 1 < ctx.entry_count {
+                index + 1
+            } else if self.registers.read(15) == ctx.address(1)
+                && self.block_slot == ctx.slot
+                && self.block_next == ctx.entries.wrapping_add(2)
+            {
+                0
+            } else {
+                return None;
+            };
+
+        // Anything that overrides the CPU's execution (e.g. DMA) has to run first.
+        let expected_fn = if ctx.thumb {
+            ctx.entry(next).handler as usize
+        } else {
+            Self::step_arm_cached as *const () as usize
+        };
+        if self.decoded_fn as usize != expected_fn || self.decoded_op != ctx.entry(next).opcode {
+            return None;
+        }
+        return Some(next);
+    }
+
     /// Resets a CPU's registers
     pub fn reset_registers(&mut self) {
         self.registers = ArmRegisters::new(CpuMode::Supervisor);
@@ -174,6 +687,15 @@ impl ArmCpu {
     /// but might not always be.
     #[inline]
     pub fn step(&mut self, memory: &mut dyn ArmMemory) -> u32 {
+        #[cfg(feature = "jit")]
+        {
+            if self.jit.is_some() {
+                if let Some(cycles) = self.step_jit(memory) {
+                    return cycles;
+                }
+            }
+        }
+
         (self.decoded_fn)(self, memory, self.decoded_op)
     }
 
@@ -232,11 +754,38 @@ impl ArmCpu {
             }
         }
 
+        // The decoded opcode is kept so that the IRQ can still be taken back.
         self.pending_exception = Some(exception);
-        self.decoded_op = 0xECEAAECE;
         self.decoded_fn = Self::step_exception;
     }
 
+    /// Sets the level of the IRQ input. Unlike `set_pending_exception` the IRQ stays requested
+    /// while the line is asserted so it is taken as soon as an instruction enables IRQs in the
+    /// CPSR. An IRQ that hasn't been taken yet is dropped when the line goes low again.
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+        if asserted {
+            self.set_pending_exception(CpuException::IRQ);
+        } else if self.pending_exception == Some(CpuException::IRQ) {
+            self.pending_exception = None;
+            self.resume_execution();
+        }
+    }
+
+    #[inline]
+    pub fn irq_line(&self) -> bool {
+        self.irq_line
+    }
+
+    /// Called after an instruction wrote to the CPSR, which might have enabled IRQs while the
+    /// IRQ line is asserted.
+    #[inline]
+    pub(crate) fn check_irq_line(&mut self) {
+        if unlikely!(self.irq_line) && !self.registers.getf_i() {
+            self.set_pending_exception(CpuException::IRQ);
+        }
+    }
+
     fn step_idle(cpu: &mut ArmCpu, _memory: &mut dyn ArmMemory, _opcode: u32) -> u32 {
         cpu.idle_cycles
     }
@@ -263,18 +812,45 @@ impl ArmCpu {
             let arm_fn = Self::decode_arm(opcode);
             return arm_fn(cpu, memory, opcode);
         } else {
-            return cpu.arm_prefetch(memory);
+            return Self::step_arm_skipped(cpu, memory, opcode);
+        }
+    }
+
+    /// Like `step_arm` but uses the handler that was decoded ahead of time by the block cache.
+    fn step_arm_cached(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, opcode: u32) -> u32 {
+        if check_condition(opcode >> 28, &cpu.registers) {
+            return (cpu.decoded_handler)(cpu, memory, opcode);
+        } else {
+            return Self::step_arm_skipped(cpu, memory, opcode);
+        }
+    }
+
+    /// Called for ARM instructions that failed their condition check.
+    #[inline]
+    #[cfg_attr(not(feature = "armv5te"), allow(unused_variables))]
+    fn step_arm_skipped(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, opcode: u32) -> u32 {
+        // ARMv5TE uses the NV condition code for unconditional instructions (BLX, PLD).
+        #[cfg(feature = "armv5te")]
+        {
+            if (opcode >> 28) == 0xF {
+                return arm::arm_unconditional(cpu, memory, opcode);
+            }
         }
+        return cpu.arm_prefetch(memory);
     }
 
-    fn decode_arm(opcode: u32) -> fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32 {
+    pub(crate) fn decode_arm(
+        opcode: u32,
+    ) -> fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32 {
         let opcode_row = bits!(opcode, 20, 27);
         let opcode_col = bits!(opcode, 4, 7);
         let opcode_idx = (opcode_row * 16) + opcode_col;
         return arm::ARM_OPCODE_TABLE[opcode_idx as usize];
     }
 
-    fn decode_thumb(opcode: u32) -> fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32 {
+    pub(crate) fn decode_thumb(
+        opcode: u32,
+    ) -> fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32 {
         let opcode_row = bits!(opcode, 12, 15);
         let opcode_col = bits!(opcode, 8, 11);
         let opcode_idx = (opcode_row * 16) + opcode_col;
@@ -296,6 +872,150 @@ impl ArmCpu {
         self.on_exception.take()
     }
 
+    /// Attaches a coprocessor to the CPU as coprocessor `number` (0-15). Coprocessor instructions
+    /// with this number will be forwarded to it. This will return the coprocessor that was
+    /// previously attached with the same number (if there was one).
+    pub fn attach_coprocessor(
+        &mut self,
+        number: u32,
+        coprocessor: Box<dyn Coprocessor>,
+    ) -> Option<Box<dyn Coprocessor>> {
+        self.coprocessors[number as usize].replace(coprocessor)
+    }
+
+    /// Removes the coprocessor with the given number from the CPU and returns it (if there is
+    /// one). Coprocessor instructions with this number will take the Undefined Instruction
+    /// exception.
+    pub fn detach_coprocessor(&mut self, number: u32) -> Option<Box<dyn Coprocessor>> {
+        self.coprocessors[number as usize].take()
+    }
+
+    /// Returns the coprocessor attached with the given number (if there is one).
+    #[inline]
+    pub fn coprocessor_mut(&mut self, number: u32) -> Option<&mut (dyn Coprocessor + 'static)> {
+        self.coprocessors[number as usize].as_deref_mut()
+    }
+
+    /// Writes the CPU's registers, pipeline and pending exception. `overrides` should contain
+    /// every function that might currently be set with `override_execution`. The same list has
+    /// to be passed to `load_state`. Coprocessors, the exception handler and cached blocks are not
+    /// part of the state.
+    pub fn save_state(
+        &self,
+        state: &mut StateWriter,
+        overrides: &[ExecutionFn],
+    ) -> Result<(), StateError> {
+        let pipeline = self.pipeline_state(overrides)?;
+
+        state.write_u32(CPU_STATE_VERSION);
+        self.registers.save_state(state);
+        state.write_u32(self.fetched);
+        state.write_u32(self.decoded_op);
+        state.write_u8(pipeline);
+        state.write_bool(self.idle);
+        state.write_u32(self.idle_cycles);
+        state.write_u8(match self.pending_exception {
+            Some(exception) => exception.index(),
+            None => 0xFF,
+        });
+        state.write_bool(self.irq_line);
+        return Ok(());
+    }
+
+    /// Reads a state written by `save_state` and rebuilds the pipeline from it. If the block
+    /// cache is enabled all cached blocks (and compiled code) are thrown away.
+    pub fn load_state(
+        &mut self,
+        state: &mut StateReader,
+        overrides: &[ExecutionFn],
+    ) -> Result<(), StateError> {
+        let version = state.read_version("ARM CPU", CPU_STATE_VERSION)?;
+        self.registers.load_state(state)?;
+        self.fetched = state.read_u32()?;
+        self.decoded_op = state.read_u32()?;
+        let pipeline = state.read_u8()?;
+        self.idle = state.read_bool()?;
+        self.idle_cycles = state.read_u32()?;
+        self.pending_exception =
+            match state.read_u8()? {
+                0xFF => None,
+                index => Some(CpuException::from_index(index).ok_or_else(|| {
+                    StateError::InvalidData(format!("bad CPU exception: {}", index))
+                })?),
+            };
+        self.irq_line = version >= 2 && state.read_bool()?;
+
+        if let Some(ref mut cache) = self.block_cache {
+            cache.clear();
+            #[cfg(feature = "jit")]
+            {
+                if let Some(ref mut jit) = self.jit {
+                    jit.clear();
+                }
+            }
+            self.block_next_key = NO_BLOCK;
+            self.uncached_next_key = NO_BLOCK;
+            self.fetched_handler = if self.registers.getf_t() {
+                Self::decode_thumb(self.fetched)
+            } else {
+                Self::decode_arm(self.fetched)
+            };
+            self.decoded_handler = Self::decode_arm(self.decoded_op);
+        }
+
+        self.decoded_fn = match pipeline {
+            PIPELINE_RUNNING if self.registers.getf_t() => Self::decode_thumb(self.decoded_op),
+            PIPELINE_RUNNING if self.block_cache.is_some() => Self::step_arm_cached,
+            PIPELINE_RUNNING => Self::step_arm,
+            PIPELINE_NOP => Self::step_nop,
+            PIPELINE_EXCEPTION if self.pending_exception.is_some() => Self::step_exception,
+            PIPELINE_IDLE => Self::step_idle,
+            _ => match overrides.get(pipeline.wrapping_sub(PIPELINE_OVERRIDE) as usize) {
+                Some(&ov_fn) if pipeline >= PIPELINE_OVERRIDE => ov_fn,
+                _ => {
+                    return Err(StateError::InvalidData(format!(
+                        "bad CPU pipeline state: {}",
+                        pipeline
+                    )))
+                }
+            },
+        };
+
+        return Ok(());
+    }
+
+    /// Figures out what `decoded_fn` is for `save_state`.
+    fn pipeline_state(&self, overrides: &[ExecutionFn]) -> Result<u8, StateError> {
+        let address = |f: ExecutionFn| f as *const () as usize;
+        let decoded_fn = address(self.decoded_fn);
+
+        if decoded_fn == address(Self::step_nop) {
+            return Ok(PIPELINE_NOP);
+        } else if decoded_fn == address(Self::step_exception) {
+            return Ok(PIPELINE_EXCEPTION);
+        } else if decoded_fn == address(Self::step_idle) {
+            return Ok(PIPELINE_IDLE);
+        }
+
+        if let Some(index) = overrides.iter().position(|&f| address(f) == decoded_fn) {
+            return Ok(PIPELINE_OVERRIDE + index as u8);
+        }
+
+        let running = if self.registers.getf_t() {
+            decoded_fn == address(Self::decode_thumb(self.decoded_op))
+        } else {
+            decoded_fn == address(Self::step_arm) || decoded_fn == address(Self::step_arm_cached)
+        };
+
+        if running {
+            return Ok(PIPELINE_RUNNING);
+        } else {
+            return Err(StateError::InvalidData(String::from(
+                "the CPU's execution is overridden by a function that can't be saved",
+            )));
+        }
+    }
+
     /// Returns the address of the instruction that will be executed on the next call to `step`.
     #[inline]
     pub fn next_exec_address(&self) -> u32 {
@@ -328,6 +1048,11 @@ impl ArmCpu {
         memory: &mut dyn ArmMemory,
         next_instr_address: u32,
     ) -> (bool, u32) {
+        #[cfg(feature = "jit")]
+        {
+            self.exception_taken = true;
+        }
+
         let exception_addr =
             next_instr_address.wrapping_sub(if self.registers.getf_t() { 2 } else { 4 });
 
@@ -424,6 +1149,34 @@ impl CpuException {
         }
     }
 
+    /// A number that identifies the exception in save states.
+    pub fn index(self) -> u8 {
+        match self {
+            CpuException::Reset => 0,
+            CpuException::Undefined => 1,
+            CpuException::SWI => 2,
+            CpuException::PrefetchAbort => 3,
+            CpuException::DataAbort => 4,
+            CpuException::IRQ => 5,
+            CpuException::FIQ => 6,
+            CpuException::AddressExceeds26Bit => 7,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<CpuException> {
+        match index {
+            0 => Some(CpuException::Reset),
+            1 => Some(CpuException::Undefined),
+            2 => Some(CpuException::SWI),
+            3 => Some(CpuException::PrefetchAbort),
+            4 => Some(CpuException::DataAbort),
+            5 => Some(CpuException::IRQ),
+            6 => Some(CpuException::FIQ),
+            7 => Some(CpuException::AddressExceeds26Bit),
+            _ => None,
+        }
+    }
+
     pub fn info(self) -> CpuExceptionInfo {
         match self {
             CpuException::Reset => EXCEPTION_RESET,
@@ -495,5 +1248,9 @@ pub const EXCEPTION_FIQ: CpuExceptionInfo =
 pub const EXCEPTION_ADDRESS_EXCEEDS_26BIT: CpuExceptionInfo =
     CpuExceptionInfo::new(8, CpuMode::Supervisor, None, 4, 0x14);
 
+/// The type of the functions that `ArmCpu::step` calls to run an instruction. Used for
+/// `ArmCpu::override_execution`.
+pub type ExecutionFn = fn(&mut ArmCpu, memory: &mut dyn ArmMemory, u32) -> u32;
+
 pub type ExceptionHandler =
     Box<dyn FnMut(&mut ArmCpu, &mut dyn ArmMemory, CpuException, u32) -> bool>;
@@ -97,6 +97,12 @@ pub enum THUMBInstrType {
 
 pub fn disassemble_arm(dest: &mut String, address: u32, memory: &dyn ArmMemory) {
     let opcode = memory.view_word(address);
+    #[cfg(feature = "armv5te")]
+    {
+        if arm_disasm_v5te(opcode, dest, address) {
+            return;
+        }
+    }
     for (select_bits, diff, instr_type) in ARM_OPCODE_TABLE.iter() {
         if ((opcode & select_bits) ^ diff) == 0 {
             write!(
@@ -114,6 +120,12 @@ pub fn disassemble_arm(dest: &mut String, address: u32, memory: &dyn ArmMemory)
 
 pub fn disassemble_thumb(dest: &mut String, address: u32, memory: &dyn ArmMemory) {
     let opcode = memory.view_halfword(address) as u32;
+    #[cfg(feature = "armv5te")]
+    {
+        if thumb_disasm_v5te(opcode, dest, address, memory) {
+            return;
+        }
+    }
     for (select_bits, diff, instr_type) in THUMB_OPCODE_TABLE.iter() {
         if ((opcode & *select_bits) ^ *diff) == 0 {
             match instr_type {
@@ -163,6 +175,182 @@ pub fn disassemble_thumb(dest: &mut String, address: u32, memory: &dyn ArmMemory
     dest.push_str("undefined");
 }
 
+/// Disassembles ARMv5TE ARM instructions. Returns false if the opcode is not one of them.
+#[cfg(feature = "armv5te")]
+fn arm_disasm_v5te(opcode: u32, buffer: &mut String, address: u32) -> bool {
+    let cond = arm_condition_suffix(bits!(opcode, 28, 31));
+    let rm = bits!(opcode, 0, 3);
+    let rs = bits!(opcode, 8, 11);
+    let rd = bits!(opcode, 12, 15);
+    let rn = bits!(opcode, 16, 19);
+
+    if (opcode & 0xFE000000) == 0xFA000000 {
+        let pc = address.wrapping_add(8); // PC is 8 ahead in ARM mode.
+        let offset =
+            sign_extend_32!(opcode & 0xFFFFFF, 24).wrapping_shl(2) | (bits!(opcode, 24) << 1);
+        write!(buffer, "blx 0x{:08X}", pc.wrapping_add(offset)).unwrap();
+    } else if (opcode & 0xFF70F000) == 0xF550F000 {
+        let sign = if bits_b!(opcode, 23) { "" } else { "-" };
+        write!(
+            buffer,
+            "pld [{}, #{}0x{:X}]",
+            reg_str(rn),
+            sign,
+            opcode & 0xFFF
+        )
+        .unwrap();
+    } else if (opcode & 0x0FFFFFF0) == 0x012FFF30 {
+        write!(buffer, "blx{} {}", cond, reg_str(rm)).unwrap();
+    } else if (opcode & 0xFFF000F0) == 0xE1200070 {
+        let comment = (bits!(opcode, 8, 19) << 4) | bits!(opcode, 0, 3);
+        write!(buffer, "bkpt 0x{:04X}", comment).unwrap();
+    } else if (opcode & 0x0FFF0FF0) == 0x016F0F10 {
+        write!(buffer, "clz{} {}, {}", cond, reg_str(rd), reg_str(rm)).unwrap();
+    } else if (opcode & 0x0F900FF0) == 0x01000050 {
+        let op = match bits!(opcode, 21, 22) {
+            0 => "qadd",
+            1 => "qsub",
+            2 => "qdadd",
+            _ => "qdsub",
+        };
+        write!(
+            buffer,
+            "{}{} {}, {}, {}",
+            op,
+            cond,
+            reg_str(rd),
+            reg_str(rm),
+            reg_str(rn)
+        )
+        .unwrap();
+    } else if (opcode & 0x0F900090) == 0x01000080 {
+        let x = if bits_b!(opcode, 5) { "t" } else { "b" };
+        let y = if bits_b!(opcode, 6) { "t" } else { "b" };
+        // for these instructions Rd is in bits 16-19 and Rn (or RdLo) is in bits 12-15
+        let (rd, rn) = (bits!(opcode, 16, 19), rd);
+        match bits!(opcode, 21, 22) {
+            0 => write!(
+                buffer,
+                "smla{}{}{} {}, {}, {}, {}",
+                x,
+                y,
+                cond,
+                reg_str(rd),
+                reg_str(rm),
+                reg_str(rs),
+                reg_str(rn)
+            ),
+            1 if bits_b!(opcode, 5) => write!(
+                buffer,
+                "smulw{}{} {}, {}, {}",
+                y,
+                cond,
+                reg_str(rd),
+                reg_str(rm),
+                reg_str(rs)
+            ),
+            1 => write!(
+                buffer,
+                "smlaw{}{} {}, {}, {}, {}",
+                y,
+                cond,
+                reg_str(rd),
+                reg_str(rm),
+                reg_str(rs),
+                reg_str(rn)
+            ),
+            2 => write!(
+                buffer,
+                "smlal{}{}{} {}, {}, {}, {}",
+                x,
+                y,
+                cond,
+                reg_str(rn),
+                reg_str(rd),
+                reg_str(rm),
+                reg_str(rs)
+            ),
+            _ => write!(
+                buffer,
+                "smul{}{}{} {}, {}, {}",
+                x,
+                y,
+                cond,
+                reg_str(rd),
+                reg_str(rm),
+                reg_str(rs)
+            ),
+        }
+        .unwrap();
+    } else if (opcode & 0x0E1000D0) == 0x000000D0 {
+        let op = if bits_b!(opcode, 5) { "strd" } else { "ldrd" };
+        let sign = if bits_b!(opcode, 23) { "" } else { "-" };
+        let offset = if bits_b!(opcode, 22) {
+            format!(
+                "#{}0x{:X}",
+                sign,
+                bits!(opcode, 0, 3) | (bits!(opcode, 8, 11) << 4)
+            )
+        } else {
+            format!("{}{}", sign, reg_str(rm))
+        };
+        if bits_b!(opcode, 24) {
+            let writeback = if bits_b!(opcode, 21) { "!" } else { "" };
+            write!(
+                buffer,
+                "{}{} {}, [{}, {}]{}",
+                op,
+                cond,
+                reg_str(rd),
+                reg_str(rn),
+                offset,
+                writeback
+            )
+            .unwrap();
+        } else {
+            write!(
+                buffer,
+                "{}{} {}, [{}], {}",
+                op,
+                cond,
+                reg_str(rd),
+                reg_str(rn),
+                offset
+            )
+            .unwrap();
+        }
+    } else {
+        return false;
+    }
+    return true;
+}
+
+/// Disassembles ARMv5TE THUMB instructions. Returns false if the opcode is not one of them.
+#[cfg(feature = "armv5te")]
+fn thumb_disasm_v5te(
+    opcode: u32,
+    buffer: &mut String,
+    address: u32,
+    memory: &dyn ArmMemory,
+) -> bool {
+    if (opcode & 0xFF80) == 0x4780 {
+        let rm = bits!(opcode, 3, 6);
+        write!(buffer, "blx {}", reg_str(rm)).unwrap();
+    } else if (opcode & 0xFF00) == 0xBE00 {
+        write!(buffer, "bkpt 0x{:02X}", opcode & 0xFF).unwrap();
+    } else if (opcode & 0xF800) == 0xE800 {
+        let previous_address = address.wrapping_sub(2);
+        let previous_opcode = memory.view_halfword(previous_address);
+        let previous_pc = previous_address.wrapping_add(4);
+        let setup = previous_pc.wrapping_add(sign_extend_32!((previous_opcode & 0x7FF) << 12, 23));
+        let dest = setup.wrapping_add((opcode & 0x7FF) << 1) & 0xFFFFFFFC;
+        write!(buffer, "blx 0x{:08X}", dest).unwrap();
+    } else {
+        return false;
+    }
+    return true;
+}
+
 fn thumb_disasm_conditional_branch(opcode: u32, buffer: &mut String, address: u32) {
     let pc = address.wrapping_add(4); // PC is 4 ahead in THUMB mode.
     let condition = condition_code_str(bits!(opcode, 8, 11));
@@ -570,6 +758,15 @@ fn condition_code_str(code: u32) -> &'static str {
     CONDITION_CODES[code as usize]
 }
 
+/// Like `condition_code_str` but returns an empty string for AL.
+#[cfg(feature = "armv5te")]
+fn arm_condition_suffix(code: u32) -> &'static str {
+    if code == 0xE {
+        return "";
+    }
+    condition_code_str(code)
+}
+
 // AddOffsetToStackPointer,
 // SoftwareInterrupt,
 // ALUOperations,
@@ -0,0 +1,603 @@
+//! Translates pre-decoded blocks into x86-64 machine code.
+//!
+//! Generated blocks are called as `extern "C" fn(ctx: *mut JitContext, registers: *mut u32) -> u32`
+//! and use these registers:
+//! - RBX: the `JitContext`
+//! - RBP: the CPU's general purpose registers (r0-r15), the CPSR is at `cpsr_offset` from these.
+//! - R14: the number of native instructions left before the block has to exit.
+//!
+//! The value returned by a block is either `PIPELINE_VALID` or the index of the entry that should
+//! be in the CPU's decode stage when the interpreter takes over again (see
+//! `ArmCpu::jit_sync_pipeline`).
+
+use super::x86_64::{Alu, Assembler, Cond, Label, Reg, Shift};
+use super::{jit_call, jit_native_run, JIT_EXIT, PIPELINE_VALID};
+use crate::block_cache::BlockEntry;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum AluOp {
+    And,
+    Eor,
+    Sub,
+    Rsb,
+    Add,
+    Adc,
+    Sbc,
+    Rsc,
+    Tst,
+    Teq,
+    Cmp,
+    Cmn,
+    Orr,
+    Mov,
+    Bic,
+    Mvn,
+}
+
+impl AluOp {
+    fn from_arm(op: u32) -> AluOp {
+        match op {
+            0x0 => AluOp::And,
+            0x1 => AluOp::Eor,
+            0x2 => AluOp::Sub,
+            0x3 => AluOp::Rsb,
+            0x4 => AluOp::Add,
+            0x5 => AluOp::Adc,
+            0x6 => AluOp::Sbc,
+            0x7 => AluOp::Rsc,
+            0x8 => AluOp::Tst,
+            0x9 => AluOp::Teq,
+            0xA => AluOp::Cmp,
+            0xB => AluOp::Cmn,
+            0xC => AluOp::Orr,
+            0xD => AluOp::Mov,
+            0xE => AluOp::Bic,
+            _ => AluOp::Mvn,
+        }
+    }
+
+    fn is_logical(self) -> bool {
+        matches!(
+            self,
+            AluOp::And
+                | AluOp::Eor
+                | AluOp::Tst
+                | AluOp::Teq
+                | AluOp::Orr
+                | AluOp::Mov
+                | AluOp::Bic
+                | AluOp::Mvn
+        )
+    }
+
+    fn uses_rn(self) -> bool {
+        self != AluOp::Mov && self != AluOp::Mvn
+    }
+
+    fn writes_rd(self) -> bool {
+        !matches!(self, AluOp::Tst | AluOp::Teq | AluOp::Cmp | AluOp::Cmn)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Operand {
+    Imm(u32),
+    Reg(u32),
+    /// A register shifted by an immediate in the range 1-31.
+    Shifted(u32, Shift, u8),
+}
+
+/// A data processing instruction that can be translated to native code.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct NativeOp {
+    cond: u32,
+    op: AluOp,
+    rd: u32,
+    rn: u32,
+    operand: Operand,
+    set_flags: bool,
+}
+
+const COND_AL: u32 = 0xE;
+
+/// Decodes the ARM data processing instructions that have a native translation. Anything that
+/// involves R15, the SPSR, shifts by a register or one of the interpreter's special cases is left
+/// to the interpreter.
+fn decode_arm(opcode: u32) -> Option<NativeOp> {
+    let cond = opcode >> 28;
+    if cond == 0xF || bits!(opcode, 26, 27) != 0 {
+        return None;
+    }
+
+    let op = AluOp::from_arm(bits!(opcode, 21, 24));
+    let set_flags = bits_b!(opcode, 20);
+    let rn = bits!(opcode, 16, 19);
+    let rd = bits!(opcode, 12, 15);
+
+    // TST, TEQ, CMP and CMN without S are MRS/MSR (and the ARMv5TE DSP instructions).
+    if !op.writes_rd() && !set_flags {
+        return None;
+    }
+
+    // ADCS, SBCS and RSCS are left to the interpreter so that its overflow calculation is kept
+    // exactly as it is.
+    if set_flags && (op == AluOp::Adc || op == AluOp::Sbc || op == AluOp::Rsc) {
+        return None;
+    }
+
+    let operand = if bits_b!(opcode, 25) {
+        // #NOTE The interpreter never changes the carry flag for rotated immediates.
+        let imm = bits!(opcode, 0, 7);
+        let rotate = bits!(opcode, 8, 11) * 2;
+        Operand::Imm(imm.rotate_right(rotate))
+    } else {
+        // Bit 4 set is a shift by register (or a multiply, swap, or halfword transfer).
+        if bits_b!(opcode, 4) {
+            return None;
+        }
+        let rm = bits!(opcode, 0, 3);
+        let amount = bits!(opcode, 7, 11) as u8;
+        if rm == 15 {
+            return None;
+        }
+        match (bits!(opcode, 5, 6), amount) {
+            (0, 0) => Operand::Reg(rm),
+            (0, _) => Operand::Shifted(rm, Shift::Shl, amount),
+            (1, 0) => return None, // LSR #32
+            (1, _) => Operand::Shifted(rm, Shift::Shr, amount),
+            (2, 0) => return None, // ASR #32
+            (2, _) => Operand::Shifted(rm, Shift::Sar, amount),
+            (_, 0) => return None, // RRX
+            (_, _) => Operand::Shifted(rm, Shift::Ror, amount),
+        }
+    };
+
+    // TSTP, TEQP, CMPP and CMNP use Rd=15 to copy the SPSR into the CPSR.
+    if rd == 15 || (op.uses_rn() && rn == 15) {
+        return None;
+    }
+
+    return Some(NativeOp {
+        cond,
+        op,
+        rd,
+        rn,
+        operand,
+        set_flags,
+    });
+}
+
+/// Decodes the THUMB ALU instructions that have a native translation.
+fn decode_thumb(opcode: u32) -> Option<NativeOp> {
+    let lo = |shift: u32| (opcode >> shift) & 0x7;
+    let native = |op, rd, rn, operand, set_flags| {
+        Some(NativeOp {
+            cond: COND_AL,
+            op,
+            rd,
+            rn,
+            operand,
+            set_flags,
+        })
+    };
+
+    match opcode >> 11 {
+        // Move shifted register. Shifts by #0 other than LSL are shifts by 32.
+        0b00000..=0b00010 => {
+            let amount = bits!(opcode, 6, 10) as u8;
+            let operand = match (opcode >> 11, amount) {
+                (0b00000, 0) => Operand::Reg(lo(3)),
+                (0b00000, _) => Operand::Shifted(lo(3), Shift::Shl, amount),
+                (_, 0) => return None,
+                (0b00001, _) => Operand::Shifted(lo(3), Shift::Shr, amount),
+                (_, _) => Operand::Shifted(lo(3), Shift::Sar, amount),
+            };
+            native(AluOp::Mov, lo(0), 0, operand, true)
+        }
+
+        // Add/subtract with a register or a 3-bit immediate.
+        0b00011 => {
+            let op = if bits_b!(opcode, 9) {
+                AluOp::Sub
+            } else {
+                AluOp::Add
+            };
+            let operand = if bits_b!(opcode, 10) {
+                Operand::Imm(lo(6))
+            } else {
+                Operand::Reg(lo(6))
+            };
+            native(op, lo(0), lo(3), operand, true)
+        }
+
+        // Move/compare/add/subtract with an 8-bit immediate.
+        0b00100..=0b00111 => {
+            let rd = lo(8);
+            let operand = Operand::Imm(opcode & 0xFF);
+            let op = match bits!(opcode, 11, 12) {
+                0 => AluOp::Mov,
+                1 => AluOp::Cmp,
+                2 => AluOp::Add,
+                _ => AluOp::Sub,
+            };
+            native(op, rd, rd, operand, true)
+        }
+
+        0b01000 => {
+            if !bits_b!(opcode, 10) {
+                // ALU operations (shifts by register, ADC, SBC and MUL are left to the
+                // interpreter).
+                let rd = lo(0);
+                let rs = lo(3);
+                match bits!(opcode, 6, 9) {
+                    0x0 => native(AluOp::And, rd, rd, Operand::Reg(rs), true),
+                    0x1 => native(AluOp::Eor, rd, rd, Operand::Reg(rs), true),
+                    0x8 => native(AluOp::Tst, rd, rd, Operand::Reg(rs), true),
+                    0x9 => native(AluOp::Rsb, rd, rs, Operand::Imm(0), true),
+                    0xA => native(AluOp::Cmp, rd, rd, Operand::Reg(rs), true),
+                    0xB => native(AluOp::Cmn, rd, rd, Operand::Reg(rs), true),
+                    0xC => native(AluOp::Orr, rd, rd, Operand::Reg(rs), true),
+                    0xE => native(AluOp::Bic, rd, rd, Operand::Reg(rs), true),
+                    0xF => native(AluOp::Mvn, rd, rd, Operand::Reg(rs), true),
+                    _ => None,
+                }
+            } else {
+                // Hi register operations (BX is left to the interpreter).
+                let rd = lo(0) + if bits_b!(opcode, 7) { 8 } else { 0 };
+                let rs = lo(3) + if bits_b!(opcode, 6) { 8 } else { 0 };
+                if rd == 15 || rs == 15 {
+                    return None;
+                }
+                match bits!(opcode, 8, 9) {
+                    0 => native(AluOp::Add, rd, rd, Operand::Reg(rs), false),
+                    1 => native(AluOp::Cmp, rd, rd, Operand::Reg(rs), true),
+                    2 => native(AluOp::Mov, rd, rd, Operand::Reg(rs), false),
+                    _ => None,
+                }
+            }
+        }
+
+        _ => None,
+    }
+}
+
+/// Returns the native translation of the entry at `index` if it has one. Native instructions
+/// don't fetch anything themselves so the two entries after them are needed to fill the CPU's
+/// pipeline when the block exits after them.
+fn native_op(entries: &[BlockEntry], index: usize, thumb: bool) -> Option<NativeOp> {
+    // Native code writes the registers directly, which would skip the write tracking.
+    if cfg!(feature = "track_register_writes") || index + 2 >= entries.len() {
+        return None;
+    }
+
+    if thumb {
+        decode_thumb(entries[index].opcode)
+    } else {
+        decode_arm(entries[index].opcode)
+    }
+}
+
+struct Compiler {
+    asm: Assembler,
+    cpsr_offset: i32,
+    /// The code for the block's first entry, used when the block loops back to its start.
+    start: Label,
+    /// Shared code that pops the saved registers and returns EAX.
+    epilogue: Label,
+    /// Returns `PIPELINE_VALID`.
+    exit_valid: Label,
+    /// Exits that return the index of an entry, emitted after the block's code.
+    exits: Vec<(Label, u32)>,
+}
+
+/// Compiles the entries of a block into machine code. Returns `None` if none of the block's
+/// instructions can be translated, in which case there's no point in compiling it.
+pub(crate) fn compile_block(
+    entries: &[BlockEntry],
+    thumb: bool,
+    cpsr_offset: i32,
+) -> Option<Vec<u8>> {
+    let ops: Vec<Option<NativeOp>> = (0..entries.len())
+        .map(|index| native_op(entries, index, thumb))
+        .collect();
+    if ops.iter().all(Option::is_none) {
+        return None;
+    }
+
+    let mut asm = Assembler::new();
+    let start = asm.new_label();
+    let epilogue = asm.new_label();
+    let exit_valid = asm.new_label();
+    let mut compiler = Compiler {
+        asm,
+        cpsr_offset,
+        start,
+        epilogue,
+        exit_valid,
+        exits: Vec::new(),
+    };
+    compiler.prologue();
+
+    let mut index = 0;
+    while index < ops.len() {
+        if ops[index].is_some() {
+            let run_end = (index..ops.len())
+                .find(|&idx| ops[idx].is_none())
+                .unwrap_or(ops.len());
+            compiler.native_run(index as u32, &ops[index..run_end]);
+            index = run_end;
+        } else {
+            let after_native = index > 0 && ops[index - 1].is_some();
+            compiler.call_interpreter(index as u32, after_native);
+            index += 1;
+        }
+    }
+
+    compiler.finish();
+    return Some(compiler.asm.finish());
+}
+
+impl Compiler {
+    fn prologue(&mut self) {
+        // Three pushes + the return address keeps the stack 16 byte aligned for calls.
+        self.asm.push(Reg::Rbx);
+        self.asm.push(Reg::Rbp);
+        self.asm.push(Reg::R14);
+        self.asm.mov_rr64(Reg::Rbx, Reg::Rdi);
+        self.asm.mov_rr64(Reg::Rbp, Reg::Rsi);
+        self.asm.bind(self.start);
+    }
+
+    fn finish(&mut self) {
+        // Falling off the end of the block means that the last instruction was run by the
+        // interpreter, which has already filled the pipeline.
+        self.asm.bind(self.exit_valid);
+        self.asm.mov_ri(Reg::Rax, PIPELINE_VALID);
+        self.asm.bind(self.epilogue);
+        self.asm.pop(Reg::R14);
+        self.asm.pop(Reg::Rbp);
+        self.asm.pop(Reg::Rbx);
+        self.asm.ret();
+
+        for (label, index) in std::mem::take(&mut self.exits) {
+            self.asm.bind(label);
+            self.asm.mov_ri(Reg::Rax, index);
+            self.asm.jmp(self.epilogue);
+        }
+    }
+
+    fn call(&mut self, function: usize, arg1: u32, arg2: u32) {
+        self.asm.mov_rr64(Reg::Rdi, Reg::Rbx);
+        self.asm.mov_ri(Reg::Rsi, arg1);
+        self.asm.mov_ri(Reg::Rdx, arg2);
+        self.asm.mov_ri64(Reg::Rax, function as u64);
+        self.asm.call_r(Reg::Rax);
+    }
+
+    /// Runs the entry at `index` using the interpreter and exits the block if it left the block or
+    /// if anything else needs the interpreter to take over (see `jit_call`).
+    fn call_interpreter(&mut self, index: u32, after_native: bool) {
+        self.call(jit_call as *const () as usize, index, after_native as u32);
+        self.asm.alu_ri(Alu::Cmp, Reg::Rax, JIT_EXIT);
+        self.asm.jcc(Cond::Zero, self.exit_valid);
+        self.asm.jcc(Cond::Above, self.start);
+    }
+
+    /// Emits a run of native instructions starting at `first`. The fetch cycles for the whole run
+    /// are added up front by `jit_native_run`, which also returns how many of the instructions can
+    /// run before the block has to exit (0 for all of them).
+    fn native_run(&mut self, first: u32, ops: &[Option<NativeOp>]) {
+        self.call(
+            jit_native_run as *const () as usize,
+            first,
+            ops.len() as u32,
+        );
+        self.asm.mov_rr(Reg::R14, Reg::Rax);
+
+        for (offset, op) in ops.iter().enumerate() {
+            let op = op.expect("native run with an interpreted instruction");
+            self.native_op(&op);
+
+            let exit = self.asm.new_label();
+            self.exits.push((exit, first + offset as u32 + 1));
+            self.asm.alu_ri(Alu::Sub, Reg::R14, 1);
+            self.asm.jcc(Cond::Zero, exit);
+        }
+    }
+
+    #[inline]
+    fn reg_disp(register: u32) -> i32 {
+        (register * 4) as i32
+    }
+
+    /// Jumps to `skip` if the condition `cond` fails.
+    fn condition_check(&mut self, cond: u32, skip: Label) {
+        const N: u8 = 31;
+        const Z: u8 = 30;
+        const C: u8 = 29;
+        const V: u8 = 28;
+
+        let asm = &mut self.asm;
+        asm.mov_load(Reg::Rdx, Reg::Rbp, self.cpsr_offset);
+        match cond {
+            0x0..=0x7 => {
+                let bit = [Z, C, N, V][(cond >> 1) as usize];
+                asm.bt_ri(Reg::Rdx, bit);
+                // Even conditions need the flag set, odd ones need it clear.
+                let fail = if (cond & 1) == 0 {
+                    Cond::NoCarry
+                } else {
+                    Cond::Carry
+                };
+                asm.jcc(fail, skip);
+            }
+
+            // HI: C=1 and Z=0, LS: C=0 or Z=1
+            0x8 | 0x9 => {
+                asm.mov_rr(Reg::Rax, Reg::Rdx);
+                asm.alu_ri(Alu::And, Reg::Rax, (1 << Z) | (1 << C));
+                asm.alu_ri(Alu::Cmp, Reg::Rax, 1 << C);
+                let fail = if cond == 0x8 {
+                    Cond::NotZero
+                } else {
+                    Cond::Zero
+                };
+                asm.jcc(fail, skip);
+            }
+
+            // GE: N=V, LT: N!=V
+            0xA | 0xB => {
+                asm.mov_rr(Reg::Rax, Reg::Rdx);
+                asm.shift_ri(Shift::Shr, Reg::Rax, N - V);
+                asm.alu_rr(Alu::Xor, Reg::Rax, Reg::Rdx);
+                asm.bt_ri(Reg::Rax, V);
+                let fail = if cond == 0xA {
+                    Cond::Carry
+                } else {
+                    Cond::NoCarry
+                };
+                asm.jcc(fail, skip);
+            }
+
+            // GT: Z=0 and N=V, LE: Z=1 or N!=V
+            0xC | 0xD => {
+                asm.mov_rr(Reg::Rax, Reg::Rdx);
+                asm.shift_ri(Shift::Shr, Reg::Rax, N - V);
+                asm.alu_rr(Alu::Xor, Reg::Rax, Reg::Rdx);
+                asm.alu_ri(Alu::And, Reg::Rax, 1 << V);
+                asm.alu_ri(Alu::And, Reg::Rdx, 1 << Z);
+                asm.alu_rr(Alu::Or, Reg::Rax, Reg::Rdx);
+                let fail = if cond == 0xC {
+                    Cond::NotZero
+                } else {
+                    Cond::Zero
+                };
+                asm.jcc(fail, skip);
+            }
+
+            _ => unreachable!("condition code {:X} has no check", cond),
+        }
+    }
+
+    /// Emits a data processing instruction. The second operand goes into ECX and the first into
+    /// EAX, which also holds the result.
+    fn native_op(&mut self, op: &NativeOp) {
+        let skip = self.asm.new_label();
+        if op.cond != COND_AL {
+            self.condition_check(op.cond, skip);
+        }
+
+        let logical = op.op.is_logical();
+        let mut shifter_carry = false;
+        match op.operand {
+            Operand::Imm(value) => self.asm.mov_ri(Reg::Rcx, value),
+            Operand::Reg(rm) => self.asm.mov_load(Reg::Rcx, Reg::Rbp, Self::reg_disp(rm)),
+            Operand::Shifted(rm, shift, amount) => {
+                self.asm.mov_load(Reg::Rcx, Reg::Rbp, Self::reg_disp(rm));
+                // The last bit shifted out by x86 is the ARM shifter carry.
+                self.asm.shift_ri(shift, Reg::Rcx, amount);
+                if logical && op.set_flags {
+                    self.asm.setcc(Cond::Carry, Reg::R10);
+                    shifter_carry = true;
+                }
+            }
+        }
+
+        if op.op.uses_rn() {
+            self.asm.mov_load(Reg::Rax, Reg::Rbp, Self::reg_disp(op.rn));
+        }
+
+        let asm = &mut self.asm;
+        match op.op {
+            AluOp::And | AluOp::Tst => asm.alu_rr(Alu::And, Reg::Rax, Reg::Rcx),
+            AluOp::Eor | AluOp::Teq => asm.alu_rr(Alu::Xor, Reg::Rax, Reg::Rcx),
+            AluOp::Orr => asm.alu_rr(Alu::Or, Reg::Rax, Reg::Rcx),
+            AluOp::Bic => {
+                asm.not(Reg::Rcx);
+                asm.alu_rr(Alu::And, Reg::Rax, Reg::Rcx);
+            }
+            AluOp::Mov => asm.mov_rr(Reg::Rax, Reg::Rcx),
+            AluOp::Mvn => {
+                asm.not(Reg::Rcx);
+                asm.mov_rr(Reg::Rax, Reg::Rcx);
+            }
+            AluOp::Add | AluOp::Cmn => asm.alu_rr(Alu::Add, Reg::Rax, Reg::Rcx),
+            AluOp::Sub | AluOp::Cmp => asm.alu_rr(Alu::Sub, Reg::Rax, Reg::Rcx),
+            AluOp::Rsb => {
+                asm.alu_rr(Alu::Sub, Reg::Rcx, Reg::Rax);
+                asm.mov_rr(Reg::Rax, Reg::Rcx);
+            }
+            // x86 has the same carry in for ADC, but SBB subtracts the carry instead of NOT carry.
+            AluOp::Adc => {
+                asm.bt_mi(Reg::Rbp, self.cpsr_offset, 29);
+                asm.alu_rr(Alu::Adc, Reg::Rax, Reg::Rcx);
+            }
+            AluOp::Sbc => {
+                asm.bt_mi(Reg::Rbp, self.cpsr_offset, 29);
+                asm.cmc();
+                asm.alu_rr(Alu::Sbb, Reg::Rax, Reg::Rcx);
+            }
+            AluOp::Rsc => {
+                asm.bt_mi(Reg::Rbp, self.cpsr_offset, 29);
+                asm.cmc();
+                asm.alu_rr(Alu::Sbb, Reg::Rcx, Reg::Rax);
+                asm.mov_rr(Reg::Rax, Reg::Rcx);
+            }
+        }
+
+        if op.set_flags {
+            if logical {
+                self.asm.test_rr(Reg::Rax, Reg::Rax);
+                self.asm.setcc(Cond::Sign, Reg::R8);
+                self.asm.setcc(Cond::Zero, Reg::R9);
+                self.store_flags(shifter_carry, false);
+            } else {
+                // #NOTE x86 sets the carry flag on borrow, ARM clears it.
+                let subtract = op.op == AluOp::Sub || op.op == AluOp::Cmp || op.op == AluOp::Rsb;
+                self.asm.setcc(Cond::Sign, Reg::R8);
+                self.asm.setcc(Cond::Zero, Reg::R9);
+                self.asm
+                    .setcc(if subtract { Cond::NoCarry } else { Cond::Carry }, Reg::R10);
+                self.asm.setcc(Cond::Overflow, Reg::R11);
+                self.store_flags(true, true);
+            }
+        }
+
+        if op.op.writes_rd() {
+            self.asm
+                .mov_store(Reg::Rbp, Self::reg_disp(op.rd), Reg::Rax);
+        }
+
+        self.asm.bind(skip);
+    }
+
+    /// Copies the flags from R8B (N), R9B (Z), R10B (C) and R11B (V) into the CPSR. C and V are
+    /// only changed if `carry` and `overflow` are true.
+    fn store_flags(&mut self, carry: bool, overflow: bool) {
+        let asm = &mut self.asm;
+        let mut mask = 0xC0000000u32;
+
+        asm.movzx_r8(Reg::R8, Reg::R8);
+        asm.shift_ri(Shift::Shl, Reg::R8, 31);
+        asm.movzx_r8(Reg::R9, Reg::R9);
+        asm.shift_ri(Shift::Shl, Reg::R9, 30);
+        asm.alu_rr(Alu::Or, Reg::R8, Reg::R9);
+        if carry {
+            mask |= 1 << 29;
+            asm.movzx_r8(Reg::R10, Reg::R10);
+            asm.shift_ri(Shift::Shl, Reg::R10, 29);
+            asm.alu_rr(Alu::Or, Reg::R8, Reg::R10);
+        }
+        if overflow {
+            mask |= 1 << 28;
+            asm.movzx_r8(Reg::R11, Reg::R11);
+            asm.shift_ri(Shift::Shl, Reg::R11, 28);
+            asm.alu_rr(Alu::Or, Reg::R8, Reg::R11);
+        }
+
+        asm.mov_load(Reg::Rdx, Reg::Rbp, self.cpsr_offset);
+        asm.alu_ri(Alu::And, Reg::Rdx, !mask);
+        asm.alu_rr(Alu::Or, Reg::Rdx, Reg::R8);
+        asm.mov_store(Reg::Rbp, self.cpsr_offset, Reg::Rdx);
+    }
+}
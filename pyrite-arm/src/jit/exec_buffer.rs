@@ -0,0 +1,87 @@
+//! Executable memory for the code generated by the JIT.
+
+use std::ptr;
+
+pub(crate) struct ExecBuffer {
+    ptr: *mut u8,
+    size: usize,
+    used: usize,
+    page_size: usize,
+}
+
+impl ExecBuffer {
+    /// Maps `size` bytes of memory for code. Returns `None` if the memory couldn't be mapped.
+    pub(crate) fn new(size: usize) -> Option<ExecBuffer> {
+        // SAFETY: this is an anonymous mapping that doesn't alias anything.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        return Some(ExecBuffer {
+            ptr: ptr as *mut u8,
+            size,
+            used: 0,
+            page_size,
+        });
+    }
+
+    /// Copies the code into the buffer and returns a pointer to the start of it. Returns `None`
+    /// if there is not enough space left in the buffer.
+    ///
+    /// The buffer's memory is only made writable while the code is being copied.
+    pub(crate) fn push(&mut self, code: &[u8]) -> Option<*const u8> {
+        let start = (self.used + 15) & !15;
+        let end = start + code.len();
+        if end > self.size {
+            return None;
+        }
+
+        let protect_start = start & !(self.page_size - 1);
+        let protect_len = end - protect_start;
+
+        // SAFETY: the range is inside of the mapping. Blocks are only compiled while no generated
+        // code is running so it is fine for these pages to be briefly non-executable.
+        unsafe {
+            let page = self.ptr.add(protect_start) as *mut libc::c_void;
+            if libc::mprotect(page, protect_len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+                return None;
+            }
+            ptr::copy_nonoverlapping(code.as_ptr(), self.ptr.add(start), code.len());
+            if libc::mprotect(page, protect_len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                panic!("failed to make JIT code executable");
+            }
+        }
+
+        self.used = end;
+        // SAFETY: `start` is inside of the mapping.
+        return Some(unsafe { self.ptr.add(start) as *const u8 });
+    }
+
+    /// Makes the whole buffer available again. Code that was pushed before this must not be
+    /// run afterwards.
+    pub(crate) fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `size` are the same as the ones that were used to map the buffer.
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Compiles blocks from the block cache into x86-64 machine code.
+//!
+//! Only data processing instructions that don't touch memory, R15 or the mode bits are translated
+//! into native code. Everything else in a compiled block is run by calling back into the
+//! interpreter, one instruction at a time, so that timing and side effects stay exactly the same
+//! as the cached interpreter. A compiled block returns to the interpreter as soon as any of these
+//! happens:
+//! - an instruction branches anywhere but the start of the block or takes an exception
+//! - memory containing cached code is written to
+//! - the cycle budget from `ArmMemory::jit_budget` runs out (e.g. an event has to be processed)
+
+mod compiler;
+mod exec_buffer;
+mod x86_64;
+
+use crate::block_cache::BlockEntry;
+use crate::cpu::ArmCpu;
+use crate::memory::ArmMemory;
+use exec_buffer::ExecBuffer;
+
+/// Size of the buffer used for generated code. Everything is thrown away and compiled again
+/// when this fills up.
+const CODE_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// The number of times that a block has to be entered before it is compiled.
+const COMPILE_THRESHOLD: u32 = 16;
+
+/// Returned by compiled code when the CPU's pipeline was left in a valid state by the
+/// interpreter. Any other value is the index of the entry that should be decoded next (see
+/// `ArmCpu::jit_sync_pipeline`).
+pub(crate) const PIPELINE_VALID: u32 = 0xFFFFFFFF;
+
+pub(crate) type BlockFn = unsafe extern "C" fn(*mut JitContext, *mut u32) -> u32;
+
+#[derive(Clone, Copy)]
+pub(crate) enum BlockJitState {
+    /// Not compiled yet. Contains the number of times that the block was entered.
+    Cold(u32),
+    Compiled(BlockFn),
+    /// The block doesn't have any instructions with a native translation.
+    Interpreted,
+}
+
+impl Default for BlockJitState {
+    fn default() -> BlockJitState {
+        BlockJitState::Cold(0)
+    }
+}
+
+/// State shared between `ArmCpu::step_jit` and the functions called by compiled code.
+#[repr(C)]
+pub(crate) struct JitContext<'a> {
+    pub(crate) cpu: *mut ArmCpu,
+    pub(crate) memory: *mut (dyn ArmMemory + 'a),
+    pub(crate) entries: *const BlockEntry,
+    pub(crate) entry_count: u32,
+    /// Address of the block's first instruction.
+    pub(crate) start: u32,
+    pub(crate) thumb: bool,
+    pub(crate) slot: u32,
+    /// The block cache's generation when the block was entered.
+    pub(crate) generation: u32,
+    /// Cycles that have passed but haven't been reported to the memory yet.
+    pub(crate) pending: u32,
+    /// The memory's last `jit_budget`. `pending` is always less than this while the block runs.
+    pub(crate) budget: u32,
+}
+
+impl JitContext<'_> {
+    #[inline]
+    pub(crate) fn instr_size(&self) -> u32 {
+        if self.thumb {
+            2
+        } else {
+            4
+        }
+    }
+
+    #[inline]
+    pub(crate) fn entry(&self, index: u32) -> BlockEntry {
+        debug_assert!(index < self.entry_count);
+        // SAFETY: the block's entries are not freed while compiled code is running from it
+        // without the generation changing, and the generation is checked after every call into
+        // the interpreter.
+        unsafe { *self.entries.add(index as usize) }
+    }
+
+    #[inline]
+    pub(crate) fn address(&self, index: u32) -> u32 {
+        self.start.wrapping_add(index * self.instr_size())
+    }
+}
+
+pub(crate) struct Jit {
+    buffer: ExecBuffer,
+}
+
+impl Jit {
+    pub(crate) fn new() -> Jit {
+        Jit {
+            buffer: ExecBuffer::new(CODE_BUFFER_SIZE).expect("failed to map memory for the JIT"),
+        }
+    }
+
+    /// Counts an entry into a cold block and returns true if it should be compiled now.
+    pub(crate) fn should_compile(state: &mut BlockJitState) -> bool {
+        if let BlockJitState::Cold(ref mut hits) = state {
+            *hits += 1;
+            return *hits >= COMPILE_THRESHOLD;
+        }
+        return false;
+    }
+
+    /// Compiles a block. Returns false if the code buffer is full, in which case the caller
+    /// should throw away all compiled code and try again.
+    pub(crate) fn compile(
+        &mut self,
+        entries: &[BlockEntry],
+        thumb: bool,
+        cpsr_offset: i32,
+        state: &mut BlockJitState,
+    ) -> bool {
+        let code = match compiler::compile_block(entries, thumb, cpsr_offset) {
+            Some(code) => code,
+            None => {
+                *state = BlockJitState::Interpreted;
+                return true;
+            }
+        };
+
+        match self.buffer.push(&code) {
+            Some(ptr) => {
+                // SAFETY: the buffer contains a function generated with the `BlockFn` ABI.
+                *state = BlockJitState::Compiled(unsafe {
+                    std::mem::transmute::<*const u8, BlockFn>(ptr)
+                });
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    /// Throws away all compiled code. Blocks that were compiled must be reset to `Cold` first.
+    pub(crate) fn clear(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+/// Returned by `jit_call` to continue with the next entry.
+pub(crate) const JIT_CONTINUE: u32 = 0;
+/// Returned by `jit_call` when the block has to exit.
+pub(crate) const JIT_EXIT: u32 = 1;
+/// Returned by `jit_call` when the entry branched back to the start of the block.
+pub(crate) const JIT_LOOP: u32 = 2;
+
+/// Called by compiled code to run the entry at `index` with the interpreter. If `sync` is not 0
+/// the entry before it was run natively and the CPU's pipeline has to be filled first.
+pub(crate) unsafe extern "C" fn jit_call(ctx: *mut JitContext, index: u32, sync: u32) -> u32 {
+    let ctx = &mut *ctx;
+    let cpu = &mut *ctx.cpu;
+    let memory = &mut *ctx.memory;
+
+    if ctx.pending > 0 {
+        memory.jit_advance(ctx.pending);
+        ctx.pending = 0;
+    }
+
+    if sync != 0 {
+        cpu.jit_sync_pipeline(ctx, index);
+    }
+
+    ctx.pending = cpu.jit_step_interpreter(memory);
+    let next = match cpu.jit_next_entry(ctx, index) {
+        Some(next) => next,
+        None => return JIT_EXIT,
+    };
+
+    ctx.budget = memory.jit_budget();
+    if ctx.pending >= ctx.budget {
+        return JIT_EXIT;
+    } else if next == 0 {
+        return JIT_LOOP;
+    } else {
+        return JIT_CONTINUE;
+    }
+}
+
+/// Called by compiled code before running `count` entries starting at `index` natively. Native
+/// instructions only take the cycles for their prefetch, which are added to the pending cycles
+/// here. Returns the number of instructions that can run before the cycle budget runs out or 0 if
+/// all of them can run.
+pub(crate) unsafe extern "C" fn jit_native_run(
+    ctx: *mut JitContext,
+    index: u32,
+    count: u32,
+) -> u32 {
+    let ctx = &mut *ctx;
+    let memory = &mut *ctx.memory;
+
+    for offset in 0..count {
+        let fetch_index = index + offset + 2;
+        let address = ctx.address(fetch_index);
+        let word = ctx.entry(fetch_index).word;
+        ctx.pending += if ctx.thumb {
+            memory.cached_code_cycles_halfword(address, word, true)
+        } else {
+            memory.cached_code_cycles_word(address, word, true)
+        };
+
+        if ctx.pending >= ctx.budget {
+            return offset + 1;
+        }
+    }
+    return 0;
+}
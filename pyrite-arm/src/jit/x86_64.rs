@@ -0,0 +1,364 @@
+//! A tiny x86-64 assembler with only the instructions that the JIT needs. All 32-bit memory
+//! operands use the `[base + disp32]` addressing mode.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub(crate) enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+impl Reg {
+    #[inline]
+    fn low(self) -> u8 {
+        (self as u8) & 7
+    }
+}
+
+/// Condition codes used by `jcc` and `setcc`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub(crate) enum Cond {
+    Overflow = 0x0,
+    NoOverflow = 0x1,
+    Carry = 0x2,
+    NoCarry = 0x3,
+    Zero = 0x4,
+    NotZero = 0x5,
+    BelowEqual = 0x6,
+    Above = 0x7,
+    Sign = 0x8,
+    NoSign = 0x9,
+    Less = 0xC,
+    GreaterEqual = 0xD,
+    LessEqual = 0xE,
+    Greater = 0xF,
+}
+
+/// Two operand ALU instructions. The value is the opcode extension used by the immediate forms.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub(crate) enum Alu {
+    Add = 0,
+    Or = 1,
+    Adc = 2,
+    Sbb = 3,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// Shifts and rotates by an immediate. The value is the opcode extension.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub(crate) enum Shift {
+    Rol = 0,
+    Ror = 1,
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Label(usize);
+
+pub(crate) struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// (offset of a rel32 operand, label that it should point to)
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub(crate) fn new() -> Assembler {
+        Assembler {
+            code: Vec::with_capacity(1024),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    /// Resolves all jumps and returns the machine code.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        for &(offset, label) in self.fixups.iter() {
+            let target = self.labels[label.0].expect("jump to unbound label");
+            let rel = (target as isize - (offset as isize + 4)) as i32;
+            self.code[offset..(offset + 4)].copy_from_slice(&rel.to_le_bytes());
+        }
+        return self.code;
+    }
+
+    pub(crate) fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub(crate) fn bind(&mut self, label: Label) {
+        debug_assert!(self.labels[label.0].is_none(), "label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    #[inline]
+    fn byte(&mut self, value: u8) {
+        self.code.push(value);
+    }
+
+    #[inline]
+    fn dword(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Emits a REX prefix if one is required. `force` is used for byte registers so that
+    /// encodings 4-7 refer to SPL-DIL instead of AH-BH.
+    fn rex(&mut self, w: bool, reg: u8, base: u8, force: bool) {
+        let rex = 0x40 | ((w as u8) << 3) | ((reg >> 3) << 2) | (base >> 3);
+        if rex != 0x40 || force {
+            self.byte(rex);
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: Reg) {
+        self.byte(0xC0 | ((reg & 7) << 3) | rm.low());
+    }
+
+    fn modrm_mem(&mut self, reg: u8, base: Reg, disp: i32) {
+        self.byte(0x80 | ((reg & 7) << 3) | base.low());
+        // RSP and R12 as a base can only be encoded with a SIB byte.
+        if base.low() == 4 {
+            self.byte(0x24);
+        }
+        self.dword(disp as u32);
+    }
+
+    /// mov dst32, [base + disp]
+    pub(crate) fn mov_load(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.rex(false, dst as u8, base as u8, false);
+        self.byte(0x8B);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    /// mov [base + disp], src32
+    pub(crate) fn mov_store(&mut self, base: Reg, disp: i32, src: Reg) {
+        self.rex(false, src as u8, base as u8, false);
+        self.byte(0x89);
+        self.modrm_mem(src as u8, base, disp);
+    }
+
+    /// mov dst32, src32
+    pub(crate) fn mov_rr(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, src as u8, dst as u8, false);
+        self.byte(0x89);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// mov dst64, src64
+    pub(crate) fn mov_rr64(&mut self, dst: Reg, src: Reg) {
+        self.rex(true, src as u8, dst as u8, false);
+        self.byte(0x89);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// mov dst32, imm32
+    pub(crate) fn mov_ri(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst as u8, false);
+        self.byte(0xB8 + dst.low());
+        self.dword(imm);
+    }
+
+    /// mov dst64, imm64
+    pub(crate) fn mov_ri64(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, 0, dst as u8, false);
+        self.byte(0xB8 + dst.low());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// op dst32, src32
+    pub(crate) fn alu_rr(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.rex(false, src as u8, dst as u8, false);
+        self.byte(((op as u8) << 3) | 0x01);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// op dst32, imm32
+    pub(crate) fn alu_ri(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst as u8, false);
+        self.byte(0x81);
+        self.modrm_reg(op as u8, dst);
+        self.dword(imm);
+    }
+
+    /// test lhs32, rhs32
+    pub(crate) fn test_rr(&mut self, lhs: Reg, rhs: Reg) {
+        self.rex(false, rhs as u8, lhs as u8, false);
+        self.byte(0x85);
+        self.modrm_reg(rhs as u8, lhs);
+    }
+
+    /// not reg32
+    pub(crate) fn not(&mut self, reg: Reg) {
+        self.rex(false, 0, reg as u8, false);
+        self.byte(0xF7);
+        self.modrm_reg(2, reg);
+    }
+
+    /// shift reg32, imm8
+    pub(crate) fn shift_ri(&mut self, op: Shift, reg: Reg, amount: u8) {
+        self.rex(false, 0, reg as u8, false);
+        self.byte(0xC1);
+        self.modrm_reg(op as u8, reg);
+        self.byte(amount);
+    }
+
+    /// setcc reg8
+    pub(crate) fn setcc(&mut self, cond: Cond, reg: Reg) {
+        self.rex(false, 0, reg as u8, true);
+        self.byte(0x0F);
+        self.byte(0x90 | (cond as u8));
+        self.modrm_reg(0, reg);
+    }
+
+    /// movzx dst32, src8
+    pub(crate) fn movzx_r8(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, dst as u8, src as u8, true);
+        self.byte(0x0F);
+        self.byte(0xB6);
+        self.modrm_reg(dst as u8, src);
+    }
+
+    /// bt reg32, imm8
+    pub(crate) fn bt_ri(&mut self, reg: Reg, bit: u8) {
+        self.rex(false, 0, reg as u8, false);
+        self.byte(0x0F);
+        self.byte(0xBA);
+        self.modrm_reg(4, reg);
+        self.byte(bit);
+    }
+
+    /// bt dword [base + disp], imm8
+    pub(crate) fn bt_mi(&mut self, base: Reg, disp: i32, bit: u8) {
+        self.rex(false, 0, base as u8, false);
+        self.byte(0x0F);
+        self.byte(0xBA);
+        self.modrm_mem(4, base, disp);
+        self.byte(bit);
+    }
+
+    /// cmc (complement the carry flag)
+    pub(crate) fn cmc(&mut self) {
+        self.byte(0xF5);
+    }
+
+    pub(crate) fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, reg as u8, false);
+        self.byte(0x50 + reg.low());
+    }
+
+    pub(crate) fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, reg as u8, false);
+        self.byte(0x58 + reg.low());
+    }
+
+    /// call reg64
+    pub(crate) fn call_r(&mut self, reg: Reg) {
+        self.rex(false, 0, reg as u8, false);
+        self.byte(0xFF);
+        self.modrm_reg(2, reg);
+    }
+
+    pub(crate) fn ret(&mut self) {
+        self.byte(0xC3);
+    }
+
+    pub(crate) fn jcc(&mut self, cond: Cond, label: Label) {
+        self.byte(0x0F);
+        self.byte(0x80 | (cond as u8));
+        self.fixups.push((self.code.len(), label));
+        self.dword(0);
+    }
+
+    pub(crate) fn jmp(&mut self, label: Label) {
+        self.byte(0xE9);
+        self.fixups.push((self.code.len(), label));
+        self.dword(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        f(&mut asm);
+        asm.finish()
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(
+            assemble(|a| a.mov_load(Reg::Rax, Reg::Rbp, 8)),
+            [0x8B, 0x85, 0x08, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            assemble(|a| a.mov_store(Reg::R12, -4, Reg::R9)),
+            [0x45, 0x89, 0x8C, 0x24, 0xFC, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            assemble(|a| a.mov_rr64(Reg::Rbx, Reg::Rdi)),
+            [0x48, 0x89, 0xFB]
+        );
+        assert_eq!(
+            assemble(|a| a.alu_rr(Alu::Sub, Reg::Rax, Reg::Rcx)),
+            [0x29, 0xC8]
+        );
+        assert_eq!(
+            assemble(|a| a.alu_ri(Alu::And, Reg::Rdx, 0x0FFFFFFF)),
+            [0x81, 0xE2, 0xFF, 0xFF, 0xFF, 0x0F]
+        );
+        assert_eq!(
+            assemble(|a| a.setcc(Cond::Sign, Reg::R8)),
+            [0x41, 0x0F, 0x98, 0xC0]
+        );
+        assert_eq!(
+            assemble(|a| a.movzx_r8(Reg::R9, Reg::R9)),
+            [0x45, 0x0F, 0xB6, 0xC9]
+        );
+        assert_eq!(
+            assemble(|a| a.shift_ri(Shift::Ror, Reg::Rcx, 3)),
+            [0xC1, 0xC9, 0x03]
+        );
+        assert_eq!(
+            assemble(|a| a.bt_ri(Reg::Rdx, 30)),
+            [0x0F, 0xBA, 0xE2, 0x1E]
+        );
+        assert_eq!(assemble(|a| a.push(Reg::R14)), [0x41, 0x56]);
+        assert_eq!(assemble(|a| a.call_r(Reg::Rax)), [0xFF, 0xD0]);
+        assert_eq!(
+            assemble(|a| {
+                let label = a.new_label();
+                a.jcc(Cond::Zero, label);
+                a.bind(label);
+            }),
+            [0x0F, 0x84, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+}
@@ -3,13 +3,22 @@
 #[macro_use]
 extern crate pyrite_common;
 
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+compile_error!("the `jit` feature is only supported on x86-64 Linux");
+
 pub mod alu;
 pub mod arm;
+pub mod block_cache;
+pub mod coprocessor;
 pub mod cpu;
 pub mod disasm;
+#[cfg(feature = "jit")]
+mod jit;
 pub mod memory;
 pub mod registers;
 pub mod thumb;
 
+pub use block_cache::BlockInvalidator;
+pub use coprocessor::Coprocessor;
 pub use cpu::ArmCpu;
 pub use memory::ArmMemory;
@@ -33,6 +33,42 @@ pub trait ArmMemory {
         return cycles;
     }
 
+    /// Returns true if the CPU's block cache may decode the code at `addr` ahead of time using
+    /// `view_word`. Memory that can change without being written to, memory whose writes aren't
+    /// reported to the block cache, and memory where `view_word` doesn't return what a code
+    /// fetch would should return false.
+    fn code_cacheable(&self, _addr: u32) -> bool {
+        true
+    }
+
+    /// Used instead of `read_code_word` when the CPU takes an opcode from its block cache.
+    /// `value` is the word that was cached for the address. This should have the same side effects
+    /// as actually reading the opcode. The default implementation uses `code_cycles_word`.
+    fn cached_code_cycles_word(&mut self, addr: u32, _value: u32, seq: bool) -> u32 {
+        self.code_cycles_word(addr, seq)
+    }
+
+    /// Used instead of `read_code_halfword` when the CPU takes an opcode from its block cache.
+    /// `value` is the cached word that contains the halfword. The default implementation uses
+    /// `code_cycles_halfword`.
+    fn cached_code_cycles_halfword(&mut self, addr: u32, _value: u32, seq: bool) -> u32 {
+        self.code_cycles_halfword(addr, seq)
+    }
+
+    /// The number of cycles that code compiled by the JIT may run for before it has to return to
+    /// the caller of `ArmCpu::step` (e.g. because an event has to be processed after that many
+    /// cycles). Compiled code stops at the first instruction that reaches the budget.
+    #[cfg(feature = "jit")]
+    fn jit_budget(&self) -> u32 {
+        u32::MAX
+    }
+
+    /// Called by code compiled by the JIT for cycles that passed without returning from
+    /// `ArmCpu::step`. The cycles passed to this are always less than the last `jit_budget`. The
+    /// cycles of the last instruction run by the JIT are returned from `step` as usual.
+    #[cfg(feature = "jit")]
+    fn jit_advance(&mut self, _cycles: u32) {}
+
     fn as_any(&self) -> &dyn std::any::Any;
     fn as_mut_any(&mut self) -> &mut dyn std::any::Any;
 }
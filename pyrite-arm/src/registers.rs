@@ -1,3 +1,5 @@
+use pyrite_common::{StateError, StateReader, StateWriter};
+
 macro_rules! set_bit {
     ($v:expr, $b:expr) => {
         $v |= 1 << $b
@@ -82,6 +84,9 @@ fn check_cpu_mode(mode_bits: u32) -> CpuMode {
     return mode;
 }
 
+/// The version of the state written by `ArmRegisters::save_state`.
+const REGISTERS_STATE_VERSION: u32 = 1;
+
 pub struct ArmRegisters {
     /// The currently in use general purpose registers (r0-r15).
     gp_registers: [u32; 16],
@@ -159,6 +164,21 @@ impl ArmRegisters {
         0
     }
 
+    /// Pointer to r0 of the current general purpose registers, used by code generated by the JIT.
+    #[cfg(feature = "jit")]
+    #[inline]
+    pub(crate) fn gp_registers_ptr(&mut self) -> *mut u32 {
+        self.gp_registers.as_mut_ptr()
+    }
+
+    /// The offset in bytes from r0 (see `gp_registers_ptr`) to the CPSR.
+    #[cfg(feature = "jit")]
+    pub(crate) fn cpsr_offset(&self) -> i32 {
+        let base = self.gp_registers.as_ptr() as isize;
+        let cpsr = &self.cpsr as *const u32 as isize;
+        (cpsr - base) as i32
+    }
+
     pub fn write_with_mode(&mut self, tmp_mode: CpuMode, register: u32, value: u32) {
         let old_mode = self.read_mode();
         self.write_mode(tmp_mode);
@@ -186,6 +206,12 @@ impl ArmRegisters {
     pub fn getf_v(&self) -> bool {
         (self.cpsr & (1 << 28)) != 0
     }
+    /// Returns the current value of the Q (Sticky Overflow) flag in the CPSR. (ARMv5TE only)
+    #[cfg(feature = "armv5te")]
+    #[inline(always)]
+    pub fn getf_q(&self) -> bool {
+        (self.cpsr & (1 << 27)) != 0
+    }
     /// Returns the current value of the I (IRQ Disable) flag in the CPSR.
     #[inline(always)]
     pub fn getf_i(&self) -> bool {
@@ -222,6 +248,12 @@ impl ArmRegisters {
     pub fn setf_v(&mut self) {
         set_bit!(self.cpsr, 28);
     }
+    /// Sets the Q (Sticky Overflow) flag in the CPSR. (ARMv5TE only)
+    #[cfg(feature = "armv5te")]
+    #[inline(always)]
+    pub fn setf_q(&mut self) {
+        set_bit!(self.cpsr, 27);
+    }
     /// Sets the I (IRQ Disable) flag in the CPSR.
     #[inline(always)]
     pub fn setf_i(&mut self) {
@@ -415,6 +447,34 @@ impl ArmRegisters {
         self.spsr = value;
     }
 
+    /// Writes all registers, including the banked registers and SPSRs of every mode.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(REGISTERS_STATE_VERSION);
+        state.write_u32_slice(&self.gp_registers);
+        state.write_u32_slice(&self.bk_registers);
+        state.write_u32_slice(&self.bk_spsr);
+        state.write_u32(self.cpsr);
+        state.write_u32(self.spsr);
+    }
+
+    /// Reads registers that were written by `save_state`.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_version("ARM registers", REGISTERS_STATE_VERSION)?;
+        state.read_u32_slice(&mut self.gp_registers)?;
+        state.read_u32_slice(&mut self.bk_registers)?;
+        state.read_u32_slice(&mut self.bk_spsr)?;
+        self.cpsr = state.read_u32()?;
+        self.spsr = state.read_u32()?;
+
+        #[cfg(feature = "track_register_writes")]
+        {
+            self.gp_registers_record = [0; 16];
+            self.bk_registers_record = [0; 15];
+        }
+
+        return Ok(());
+    }
+
     /// Called during a mode switch to switch the general purpose registers
     /// and the spsr to their proper banked versions.
     fn on_mode_switch(&mut self, old_mode: CpuMode, new_mode: CpuMode) {
@@ -1002,8 +1002,7 @@ pub fn thumb_pop_pc(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, opcode: u32) -
     // transfer PC
     addr = addr.wrapping_add(4);
     let value = memory.read_data_word(addr, seq, &mut cycles);
-    let dest = value & 0xFFFFFFFE;
-    cycles += cpu.thumb_branch_to(dest, memory);
+    cycles += cpu.load_branch_to(value, memory);
 
     return cycles;
 }
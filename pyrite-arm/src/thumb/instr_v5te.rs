@@ -0,0 +1,74 @@
+//! ARMv5TE additions to the THUMB instruction set. These are only available when the `armv5te`
+//! feature is enabled and are patched into the ARMv4T opcode table by `extend_opcode_table`.
+
+use super::super::cpu::{CpuException, ExecutionFn};
+use super::super::{ArmCpu, ArmMemory};
+
+/// Replaces entries of the ARMv4T THUMB opcode table with the ARMv5TE instructions that occupy
+/// them.
+pub const fn extend_opcode_table(mut table: [ExecutionFn; 256]) -> [ExecutionFn; 256] {
+    table[0x47] = thumb_bx_blx_reg;
+    table[0xBE] = thumb_bkpt;
+
+    let mut row = 0xE8;
+    while row <= 0xEF {
+        table[row] = thumb_blx_off;
+        row += 1;
+    }
+
+    table
+}
+
+/// BX Rs and BLX Rm (when bit 7 is set)
+pub fn thumb_bx_blx_reg(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, opcode: u32) -> u32 {
+    let mut cycles = cpu.thumb_prefetch(memory);
+
+    let rs_hi = bits_b!(opcode, 6);
+    let rs = bits!(opcode, 3, 5) + (if rs_hi { 8 } else { 0 });
+    let dest = cpu.registers.read(rs);
+
+    if bits_b!(opcode, 7) {
+        // PC is the address of the BLX instruction + 4 here.
+        let pc = cpu.registers.read(15);
+        cpu.registers.write(14, pc.wrapping_sub(2) | 1);
+    }
+
+    if (dest & 1) == 0 {
+        cpu.registers.clearf_t();
+        cycles += cpu.arm_branch_to(dest & 0xFFFFFFFC, memory);
+    } else {
+        cycles += cpu.thumb_branch_to(dest & 0xFFFFFFFE, memory);
+    }
+
+    return cycles;
+}
+
+/// The second half of a long branch with link that switches to ARM state. This is used in place
+/// of `thumb_bl_off` when bit 12 of the second instruction is clear.
+pub fn thumb_blx_off(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, opcode: u32) -> u32 {
+    let mut cycles = cpu.thumb_prefetch_cycles(memory);
+
+    let pc = cpu.registers.read(15);
+    let lr = cpu.registers.read(14);
+    let off = (opcode & 0x7FF) << 1;
+    let dest = lr.wrapping_add(off) & 0xFFFFFFFC;
+    cpu.registers.write(14, (pc.wrapping_sub(2)) | 1);
+    cpu.registers.clearf_t();
+    cycles += cpu.arm_branch_to(dest, memory);
+
+    return cycles;
+}
+
+/// BKPT <#immediate>
+pub fn thumb_bkpt(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory, _opcode: u32) -> u32 {
+    let mut cycles = cpu.thumb_prefetch(memory);
+    // The breakpoint is treated as a prefetch abort of the BKPT instruction itself.
+    cycles += cpu
+        .handle_exception(
+            CpuException::PrefetchAbort,
+            memory,
+            cpu.registers.read(15).wrapping_sub(4),
+        )
+        .1;
+    return cycles;
+}
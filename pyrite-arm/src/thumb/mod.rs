@@ -1,9 +1,18 @@
 mod instr;
+#[cfg(feature = "armv5te")]
+mod instr_v5te;
 
 use self::instr::*;
-use super::{ArmCpu, ArmMemory};
+use super::cpu::ExecutionFn;
 
-pub const THUMB_OPCODE_TABLE: [fn(&mut ArmCpu, &mut dyn ArmMemory, u32) -> u32; 256] = [
+#[cfg(not(feature = "armv5te"))]
+pub const THUMB_OPCODE_TABLE: [ExecutionFn; 256] = THUMBV4T_OPCODE_TABLE;
+
+#[cfg(feature = "armv5te")]
+pub const THUMB_OPCODE_TABLE: [ExecutionFn; 256] =
+    instr_v5te::extend_opcode_table(THUMBV4T_OPCODE_TABLE);
+
+pub const THUMBV4T_OPCODE_TABLE: [ExecutionFn; 256] = [
     /* Bits 15-12 */
     /* 0x0 */
     thumb_lsl_imm,
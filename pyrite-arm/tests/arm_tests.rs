@@ -1,5 +1,9 @@
 mod util;
-use pyrite_arm::{ArmCpu, ArmMemory};
+use pyrite_arm::coprocessor::Handshake;
+use pyrite_arm::cpu::CpuException;
+use pyrite_arm::registers::CpuMode;
+use pyrite_arm::{ArmCpu, ArmMemory, BlockInvalidator, Coprocessor};
+use pyrite_common::{Shared, StateError, StateReader, StateWriter};
 use util::run_cpu;
 
 pub const MAX_PROGRAM_SIZE: u32 = 0x1000;
@@ -52,6 +56,38 @@ pub fn test_division() {
     assert_eq!(cpu.registers.read(4), 0xBEEF);
 }
 
+#[test]
+pub fn test_ror_by_register() {
+    // (rotation, result, carry)
+    let cases = [
+        (0, 0x80000001, false),
+        (1, 0xC0000000, true),
+        (4, 0x18000000, false),
+        (32, 0x80000001, true),
+        (33, 0xC0000000, true),
+        (64, 0x80000001, true),
+        (96, 0x80000001, true),
+        (256, 0x80000001, false),
+    ];
+
+    for &(rotation, result, carry) in cases.iter() {
+        let mut cpu = ArmCpu::new();
+        let mut mem = vec![0u8; 0x100];
+        mem.write_data_word(0x00, 0xE1B00271, false, &mut 0); // movs r0, r1, ror r2
+        mem.write_data_word(0x04, 0xEF000010, false, &mut 0); // swi 0x10
+        cpu.registers.write(1, 0x80000001);
+        cpu.registers.write(2, rotation);
+        let _ = cpu.set_pc(0, &mut mem);
+        run_cpu(&mut cpu, &mut mem);
+        assert_eq!(
+            (cpu.registers.read(0), cpu.registers.getf_c()),
+            (result, carry),
+            "ROR by {}",
+            rotation
+        );
+    }
+}
+
 #[test]
 pub fn test_chacha20() {
     let expected = [
@@ -74,13 +110,20 @@ pub fn test_chacha20() {
 
     let mut dest = [0u8; 64];
 
-    chacha20(1, &test_key, &test_nonce, &mut dest);
+    chacha20(1, &test_key, &test_nonce, &mut dest, false);
+    assert_eq!(&expected[0..], &dest[0..]);
+
+    let mut dest = [0u8; 64];
+    chacha20(1, &test_key, &test_nonce, &mut dest, true);
     assert_eq!(&expected[0..], &dest[0..]);
 
-    fn chacha20(iterations: u32, key: &[u8], nonce: &[u8], dest: &mut [u8]) {
+    fn chacha20(iterations: u32, key: &[u8], nonce: &[u8], dest: &mut [u8], block_cache: bool) {
         static CHACHA20_BIN: &[u8] = include_bytes!("../data/bin/chacha20.bin");
 
         let mut cpu = ArmCpu::new();
+        if block_cache {
+            let _ = cpu.enable_block_cache();
+        }
         let mut mem = CHACHA20_BIN.to_vec();
         mem.resize(0x1000, 0xCE);
         let _ = cpu.set_pc(0, &mut mem); // reset the program counter
@@ -133,13 +176,20 @@ pub fn test_chacha20_thumb() {
 
     let mut dest = [0u8; 64];
 
-    chacha20(1, &test_key, &test_nonce, &mut dest);
+    chacha20(1, &test_key, &test_nonce, &mut dest, false);
+    assert_eq!(&expected[0..], &dest[0..]);
+
+    let mut dest = [0u8; 64];
+    chacha20(1, &test_key, &test_nonce, &mut dest, true);
     assert_eq!(&expected[0..], &dest[0..]);
 
-    fn chacha20(iterations: u32, key: &[u8], nonce: &[u8], dest: &mut [u8]) {
+    fn chacha20(iterations: u32, key: &[u8], nonce: &[u8], dest: &mut [u8], block_cache: bool) {
         static CHACHA20_BIN_THUMB: &[u8] = include_bytes!("../data/bin/chacha20_thumb.bin");
 
         let mut cpu = ArmCpu::new();
+        if block_cache {
+            let _ = cpu.enable_block_cache();
+        }
         let mut mem = CHACHA20_BIN_THUMB.to_vec();
         mem.resize(0x1000, 0xCE);
         let _ = cpu.set_pc(0, &mut mem); // reset the program counter
@@ -171,6 +221,346 @@ pub fn test_chacha20_thumb() {
     }
 }
 
+#[test]
+pub fn test_coprocessor_instructions() {
+    struct TestCoprocessor {
+        registers: Shared<[u32; 16]>,
+    }
+
+    impl Coprocessor for TestCoprocessor {
+        fn data_operation(
+            &mut self,
+            opcode1: u32,
+            crd: u32,
+            crn: u32,
+            crm: u32,
+            opcode2: u32,
+        ) -> Handshake {
+            let mut registers = self.registers.borrow_mut();
+            registers[crd as usize] = (opcode1 << 12) | (crn << 8) | (crm << 4) | opcode2;
+            Handshake::accept_after(3, ())
+        }
+
+        fn write_register(
+            &mut self,
+            _opcode1: u32,
+            crn: u32,
+            _crm: u32,
+            _opcode2: u32,
+            value: u32,
+        ) -> Handshake {
+            self.registers.borrow_mut()[crn as usize] = value;
+            Handshake::accept(())
+        }
+
+        fn read_register(
+            &mut self,
+            _opcode1: u32,
+            crn: u32,
+            _crm: u32,
+            _opcode2: u32,
+        ) -> Handshake<u32> {
+            Handshake::accept(self.registers.borrow()[crn as usize])
+        }
+
+        fn begin_load(&mut self, _crd: u32, _long: bool, _option: u32) -> Handshake<u32> {
+            Handshake::accept(2)
+        }
+
+        fn load_word(&mut self, crd: u32, index: u32, value: u32) {
+            self.registers.borrow_mut()[(crd + index) as usize] = value;
+        }
+
+        fn begin_store(&mut self, _crd: u32, _long: bool, _option: u32) -> Handshake<u32> {
+            Handshake::accept(2)
+        }
+
+        fn store_word(&mut self, crd: u32, index: u32) -> u32 {
+            self.registers.borrow()[(crd + index) as usize]
+        }
+    }
+
+    let program: [(u32, u32); 11] = [
+        (0x00, 0xEA000006), // b 0x20
+        (0x04, 0xEF000010), // swi 0x10 (undefined instruction vector)
+        (0x20, 0xE3A00055), // mov r0, #0x55
+        (0x24, 0xEE010E72), // mcr p14, 0, r0, c1, c2, 3
+        (0x28, 0xEE111E72), // mrc p14, 0, r1, c1, c2, 3
+        (0x2C, 0xEE143E45), // cdp p14, 1, c3, c4, c5, 2
+        (0x30, 0xEE020E72), // mcr p14, 0, r0, c2, c2, 3
+        (0x34, 0xE3A02C01), // mov r2, #0x100
+        (0x38, 0xEDA21E01), // stc p14, c1, [r2, #4]!
+        (0x3C, 0xEC325E01), // ldc p14, c5, [r2], #-4
+        (0x40, 0xEE000F10), // mcr p15, 0, r0, c0, c0, 0 (no coprocessor 15)
+    ];
+
+    let mut mem = vec![0u8; 0x200];
+    for (addr, opcode) in program.iter() {
+        mem.write_data_word(*addr, *opcode, false, &mut 0);
+    }
+
+    let registers = Shared::new([0u32; 16]);
+    let mut cpu = ArmCpu::new();
+    cpu.attach_coprocessor(
+        14,
+        Box::new(TestCoprocessor {
+            registers: Shared::share(&registers),
+        }),
+    );
+    let _ = cpu.set_pc(0, &mut mem);
+    run_cpu(&mut cpu, &mut mem);
+
+    // MCR/MRC
+    assert_eq!(cpu.registers.read(1), 0x55);
+    // CDP
+    assert_eq!(registers.borrow()[3], 0x1452);
+    // STC
+    assert_eq!(mem.view_word(0x104), 0x55);
+    assert_eq!(mem.view_word(0x108), 0x55);
+    // LDC
+    assert_eq!(registers.borrow()[5], 0x55);
+    assert_eq!(registers.borrow()[6], 0x55);
+    assert_eq!(cpu.registers.read(2), 0x100);
+
+    // The instruction for the missing coprocessor should take the undefined instruction trap.
+    assert_eq!(cpu.registers.read_mode(), CpuMode::Undefined);
+    assert_eq!(cpu.registers.read(14), 0x44);
+}
+
+#[test]
+pub fn test_block_cache_self_modifying_code() {
+    /// Memory that reports all writes to the CPU's block cache.
+    struct InvalidatingMemory {
+        mem: Vec<u8>,
+        invalidator: BlockInvalidator,
+    }
+
+    impl ArmMemory for InvalidatingMemory {
+        fn on_internal_cycles(&mut self, icycles: u32) {
+            self.mem.on_internal_cycles(icycles);
+        }
+
+        fn read_code_word(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u32 {
+            self.mem.read_code_word(addr, seq, cycles)
+        }
+
+        fn read_code_halfword(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u16 {
+            self.mem.read_code_halfword(addr, seq, cycles)
+        }
+
+        fn read_data_word(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u32 {
+            self.mem.read_data_word(addr, seq, cycles)
+        }
+
+        fn read_data_halfword(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u16 {
+            self.mem.read_data_halfword(addr, seq, cycles)
+        }
+
+        fn read_data_byte(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u8 {
+            self.mem.read_data_byte(addr, seq, cycles)
+        }
+
+        fn write_data_word(&mut self, addr: u32, data: u32, seq: bool, cycles: &mut u32) {
+            self.invalidator.on_write(addr);
+            self.mem.write_data_word(addr, data, seq, cycles)
+        }
+
+        fn write_data_halfword(&mut self, addr: u32, data: u16, seq: bool, cycles: &mut u32) {
+            self.invalidator.on_write(addr);
+            self.mem.write_data_halfword(addr, data, seq, cycles)
+        }
+
+        fn write_data_byte(&mut self, addr: u32, data: u8, seq: bool, cycles: &mut u32) {
+            self.invalidator.on_write(addr);
+            self.mem.write_data_byte(addr, data, seq, cycles)
+        }
+
+        fn view_word(&self, addr: u32) -> u32 {
+            self.mem.view_word(addr)
+        }
+
+        fn view_halfword(&self, addr: u32) -> u16 {
+            self.mem.view_halfword(addr)
+        }
+
+        fn view_byte(&self, addr: u32) -> u8 {
+            self.mem.view_byte(addr)
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+
+        fn as_mut_any(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+    }
+
+    let program: [(u32, u32); 9] = [
+        (0x00, 0xE3A00000), // mov r0, #0
+        (0x04, 0xE59F1074), // ldr r1, [pc, #0x74] (0x80)
+        (0x08, 0xEB00000C), // bl 0x40
+        (0x0C, 0xE58F102C), // str r1, [pc, #0x2C] (0x40)
+        (0x10, 0xEB00000A), // bl 0x40
+        (0x14, 0xEF000010), // swi 0x10 (halt)
+        (0x40, 0xE2800001), // add r0, r0, #1
+        (0x44, 0xE12FFF1E), // bx lr
+        (0x80, 0xE2800002), // add r0, r0, #2 (copied to 0x40)
+    ];
+
+    let mut cpu = ArmCpu::new();
+    let mut mem = InvalidatingMemory {
+        mem: vec![0u8; 0x100],
+        invalidator: cpu.enable_block_cache(),
+    };
+    for (addr, opcode) in program.iter() {
+        mem.write_data_word(*addr, *opcode, false, &mut 0);
+    }
+
+    let _ = cpu.set_pc(0, &mut mem);
+    run_cpu(&mut cpu, &mut mem);
+
+    // The second call must run the new instruction instead of the cached one.
+    assert_eq!(cpu.registers.read(0), 3);
+}
+
+#[test]
+pub fn test_save_state_chacha20() {
+    static CHACHA20_BIN: &[u8] = include_bytes!("../data/bin/chacha20.bin");
+
+    let key: Vec<u8> = (0..32).collect();
+    let nonce = [
+        0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x4A, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// Runs the program until it asks for the iteration count and returns the saved state.
+    fn run_to_state(cpu: &mut ArmCpu, mem: &mut Vec<u8>, key: &[u8], nonce: &[u8]) -> Vec<u8> {
+        let _ = cpu.set_pc(0, mem);
+        while let Some((signal_type, signal_value)) = run_cpu(cpu, mem) {
+            match signal_type {
+                0 => set_memory_bytes(mem, signal_value, key),
+                1 => set_memory_bytes(mem, signal_value, nonce),
+                2 => {
+                    cpu.registers.write(0, 4);
+                    let mut state = StateWriter::new();
+                    cpu.save_state(&mut state, &[])
+                        .expect("failed to save state");
+                    return state.into_inner();
+                }
+                _ => panic!("unexpected signal: {}", signal_type),
+            }
+        }
+        panic!("program ended before the iteration count was requested");
+    }
+
+    fn run_to_end(cpu: &mut ArmCpu, mem: &mut Vec<u8>) -> [u8; 64] {
+        let mut dest = [0u8; 64];
+        while let Some((signal_type, signal_value)) = run_cpu(cpu, mem) {
+            assert_eq!(signal_type, 3);
+            get_memory_bytes(mem, signal_value, &mut dest);
+        }
+        dest
+    }
+
+    let mut cpu = ArmCpu::new();
+    let mut mem = CHACHA20_BIN.to_vec();
+    mem.resize(0x1000, 0xCE);
+    let state = run_to_state(&mut cpu, &mut mem, &key, &nonce);
+    let saved_mem = mem.clone();
+    let expected = run_to_end(&mut cpu, &mut mem);
+
+    for &block_cache in [false, true].iter() {
+        let mut loaded_cpu = ArmCpu::new();
+        if block_cache {
+            let _ = loaded_cpu.enable_block_cache();
+        }
+        let mut loaded_mem = saved_mem.clone();
+        loaded_cpu
+            .load_state(&mut StateReader::new(&state), &[])
+            .expect("failed to load state");
+        assert_eq!(run_to_end(&mut loaded_cpu, &mut loaded_mem), expected);
+        for register in 0..16 {
+            assert_eq!(
+                loaded_cpu.registers.read(register),
+                cpu.registers.read(register)
+            );
+        }
+        assert_eq!(loaded_cpu.registers.read_cpsr(), cpu.registers.read_cpsr());
+    }
+}
+
+#[test]
+pub fn test_save_state_pipeline() {
+    fn step_override(cpu: &mut ArmCpu, _memory: &mut dyn ArmMemory, _opcode: u32) -> u32 {
+        cpu.resume_execution();
+        77
+    }
+
+    let mut mem = vec![0u8; 0x100];
+    mem.write_data_word(0x00, 0xE3A00001, false, &mut 0); // mov r0, #1
+    mem.write_data_word(0x04, 0xE3A01002, false, &mut 0); // mov r1, #2
+
+    let mut cpu = ArmCpu::new();
+    let _ = cpu.set_pc(0, &mut mem);
+    cpu.override_execution(step_override);
+
+    // Overrides that are not in the list can't be saved.
+    assert!(cpu.save_state(&mut StateWriter::new(), &[]).is_err());
+
+    let mut state = StateWriter::new();
+    cpu.save_state(&mut state, &[step_override])
+        .expect("failed to save state");
+    let state = state.into_inner();
+
+    let mut loaded_cpu = ArmCpu::new();
+    loaded_cpu
+        .load_state(&mut StateReader::new(&state), &[step_override])
+        .expect("failed to load state");
+    assert_eq!(loaded_cpu.step(&mut mem), 77);
+    let _ = loaded_cpu.step(&mut mem);
+    assert_eq!(loaded_cpu.registers.read(0), 1);
+
+    // Pending exceptions are restored.
+    let mut cpu = ArmCpu::new();
+    cpu.registers.clearf_i();
+    let _ = cpu.set_pc(0, &mut mem);
+    cpu.set_pending_exception(CpuException::IRQ);
+    let mut state = StateWriter::new();
+    cpu.save_state(&mut state, &[])
+        .expect("failed to save state");
+    let state = state.into_inner();
+
+    let mut loaded_cpu = ArmCpu::new();
+    loaded_cpu
+        .load_state(&mut StateReader::new(&state), &[])
+        .expect("failed to load state");
+    let _ = loaded_cpu.step(&mut mem);
+    assert_eq!(loaded_cpu.registers.read_mode(), CpuMode::IRQ);
+    assert_eq!(loaded_cpu.next_exec_address(), 0x18);
+
+    // Banked registers are restored.
+    let mut cpu = ArmCpu::new();
+    cpu.registers.write_mode(CpuMode::Supervisor);
+    cpu.registers.write(13, 0x1234);
+    cpu.registers.write_mode(CpuMode::System);
+    let mut state = StateWriter::new();
+    cpu.save_state(&mut state, &[])
+        .expect("failed to save state");
+    let state = state.into_inner();
+    let mut loaded_cpu = ArmCpu::new();
+    loaded_cpu
+        .load_state(&mut StateReader::new(&state), &[])
+        .expect("failed to load state");
+    loaded_cpu.registers.write_mode(CpuMode::Supervisor);
+    assert_eq!(loaded_cpu.registers.read(13), 0x1234);
+
+    // Truncated states are rejected.
+    assert_eq!(
+        ArmCpu::new().load_state(&mut StateReader::new(&state[..20]), &[]),
+        Err(StateError::UnexpectedEnd)
+    );
+}
+
 // @ NOTE I uncomment these two VERY inefficient functions
 //        and use them while I am debugging and for nothing else.
 // fn to_hex(data: &[u8]) -> String {
@@ -195,3 +585,69 @@ pub fn test_chacha20_thumb() {
 //     }
 //     return out;
 // }
+
+#[test]
+pub fn test_irq_line() {
+    let program: [(u32, u32); 8] = [
+        (0x18, 0xE3A02018), // mov r2, #0x18 (IRQ vector)
+        (0x1C, 0xEF000010), // swi 0x10 (halt)
+        (0x40, 0xE10F0000), // mrs r0, cpsr
+        (0x44, 0xE3C00080), // bic r0, r0, #0x80
+        (0x48, 0xE3A01001), // mov r1, #1
+        (0x4C, 0xE129F000), // msr cpsr_fc, r0
+        (0x50, 0xE3A01002), // mov r1, #2
+        (0x54, 0xEF000010), // swi 0x10 (halt)
+    ];
+
+    for &asserted in [false, true].iter() {
+        let mut cpu = ArmCpu::new();
+        let mut mem = vec![0u8; 0x100];
+        for (addr, opcode) in program.iter() {
+            mem.write_data_word(*addr, *opcode, false, &mut 0);
+        }
+
+        // The line stays asserted while IRQs are disabled and the IRQ is taken right after the
+        // instruction that enables them.
+        cpu.registers.setf_i();
+        cpu.set_irq_line(asserted);
+        let _ = cpu.set_pc(0x40, &mut mem);
+        run_cpu(&mut cpu, &mut mem);
+
+        if asserted {
+            assert_eq!(cpu.registers.read_mode(), CpuMode::IRQ);
+            assert_eq!(cpu.registers.read(1), 1);
+            assert_eq!(cpu.registers.read(2), 0x18);
+            assert_eq!(cpu.registers.read(14), 0x54);
+        } else {
+            assert_eq!(cpu.registers.read_mode(), CpuMode::System);
+            assert_eq!(cpu.registers.read(1), 2);
+        }
+    }
+}
+
+#[test]
+pub fn test_irq_line_retracted() {
+    let program: [(u32, u32); 4] = [
+        (0x00, 0xE3A01001), // mov r1, #1
+        (0x04, 0xEF000010), // swi 0x10 (halt)
+        (0x18, 0xE3A02018), // mov r2, #0x18 (IRQ vector)
+        (0x1C, 0xEF000010), // swi 0x10 (halt)
+    ];
+
+    let mut cpu = ArmCpu::new();
+    let mut mem = vec![0u8; 0x100];
+    for (addr, opcode) in program.iter() {
+        mem.write_data_word(*addr, *opcode, false, &mut 0);
+    }
+
+    // The IRQ is waiting to be taken but the line goes low before the next instruction.
+    cpu.registers.clearf_i();
+    let _ = cpu.set_pc(0x00, &mut mem);
+    cpu.set_irq_line(true);
+    cpu.set_irq_line(false);
+    run_cpu(&mut cpu, &mut mem);
+
+    assert_eq!(cpu.registers.read_mode(), CpuMode::System);
+    assert_eq!(cpu.registers.read(1), 1);
+    assert_eq!(cpu.registers.read(2), 0);
+}
@@ -0,0 +1,224 @@
+#![cfg(feature = "armv5te")]
+
+mod util;
+use pyrite_arm::disasm::disassemble_arm;
+use pyrite_arm::registers::CpuMode;
+use pyrite_arm::{ArmCpu, ArmMemory};
+use util::run_cpu;
+
+#[test]
+pub fn test_armv5te_instructions() {
+    let program: [(u32, u32); 16] = [
+        (0x00, 0xE3A00801), // mov r0, #0x10000
+        (0x04, 0xE16F1F10), // clz r1, r0
+        (0x08, 0xE3E02102), // mvn r2, #0x80000000
+        (0x0C, 0xE3A03001), // mov r3, #1
+        (0x10, 0xE1034052), // qadd r4, r2, r3
+        (0x14, 0xE3A05C01), // mov r5, #0x100
+        (0x18, 0xE1C520F0), // strd r2, r3, [r5]
+        (0x1C, 0xE1C560D0), // ldrd r6, r7, [r5]
+        (0x20, 0xE3E09002), // mvn r9, #2
+        (0x24, 0xE3A0A007), // mov r10, #7
+        (0x28, 0xE16B0A89), // smulbb r11, r9, r10
+        (0x2C, 0xFA000000), // blx 0x34
+        (0x30, 0xEF000010), // swi 0x10
+        (0x34, 0x202AB500), // push {lr}; movs r0, #42
+        (0x38, 0x0000BD00), // pop {pc}
+        (0x3C, 0x00000000),
+    ];
+
+    let mut mem = vec![0u8; 0x400];
+    for (addr, opcode) in program.iter() {
+        mem.write_data_word(*addr, *opcode, false, &mut 0);
+    }
+
+    let mut cpu = ArmCpu::new();
+    cpu.registers.write(13, 0x400);
+    let _ = cpu.set_pc(0, &mut mem);
+    run_cpu(&mut cpu, &mut mem);
+
+    // CLZ
+    assert_eq!(cpu.registers.read(1), 15);
+    // QADD
+    assert_eq!(cpu.registers.read(4), 0x7FFFFFFF);
+    assert!(cpu.registers.getf_q());
+    // STRD/LDRD
+    assert_eq!(mem.view_word(0x100), 0x7FFFFFFF);
+    assert_eq!(mem.view_word(0x104), 1);
+    assert_eq!(cpu.registers.read(6), 0x7FFFFFFF);
+    assert_eq!(cpu.registers.read(7), 1);
+    // SMULBB
+    assert_eq!(cpu.registers.read(11), (-21i32) as u32);
+    // BLX into THUMB and POP {pc} back into ARM
+    assert_eq!(cpu.registers.read(0), 42);
+    assert!(!cpu.registers.getf_t());
+    assert_eq!(cpu.registers.read(13), 0x400);
+
+    let mut disasm = String::new();
+    disassemble_arm(&mut disasm, 0x04, &mem);
+    assert_eq!(disasm, "clz r1, r0");
+}
+
+/// Runs ARM `program` from address 0 with the registers in `regs` set and returns the CPU once it
+/// reaches `swi 0x10`.
+fn run_arm(program: &[u32], regs: &[(u32, u32)]) -> ArmCpu {
+    let mut mem = vec![0u8; 0x400];
+    for (index, opcode) in program.iter().enumerate() {
+        mem.write_data_word(index as u32 * 4, *opcode, false, &mut 0);
+    }
+    mem.write_data_word(program.len() as u32 * 4, 0xEF000010, false, &mut 0); // swi 0x10
+
+    let mut cpu = ArmCpu::new();
+    for &(register, value) in regs {
+        cpu.registers.write(register, value);
+    }
+    let _ = cpu.set_pc(0, &mut mem);
+    run_cpu(&mut cpu, &mut mem);
+    cpu
+}
+
+/// Runs THUMB code starting at 0x100 in `mem` and returns the CPU once it reaches `swi 0x10`.
+fn run_thumb(mem: &mut Vec<u8>, regs: &[(u32, u32)]) -> ArmCpu {
+    let mut cpu = ArmCpu::new();
+    for &(register, value) in regs {
+        cpu.registers.write(register, value);
+    }
+    cpu.registers.setf_t();
+    let _ = cpu.set_pc(0x100, mem);
+    run_cpu(&mut cpu, mem);
+    cpu
+}
+
+fn write_thumb(mem: &mut Vec<u8>, addr: u32, opcodes: &[u16]) {
+    for (index, opcode) in opcodes.iter().enumerate() {
+        mem.write_data_halfword(addr + index as u32 * 2, *opcode, false, &mut 0);
+    }
+}
+
+#[test]
+pub fn test_armv5te_halfword_multiplies() {
+    // r2 = (-1, 3) and r3 = (2, -2) as (top, bottom) halfwords, r4 = 3.0 in 16.16 fixed point.
+    let regs = [(2, 0xFFFF0003), (3, 0x0002FFFE), (4, 0x00030000), (5, 100)];
+    let cpu = run_arm(
+        &[
+            0xE1005382, // smlabb r0, r2, r3, r5
+            0xE10153E2, // smlatt r1, r2, r3, r5
+            0xE1265384, // smlawb r6, r4, r3, r5
+            0xE12753C4, // smlawt r7, r4, r3, r5
+            0xE12803A4, // smulwb r8, r4, r3
+            0xE12903E4, // smulwt r9, r4, r3
+        ],
+        &regs,
+    );
+    assert_eq!(cpu.registers.read(0), 94);
+    assert_eq!(cpu.registers.read(1), 98);
+    assert_eq!(cpu.registers.read(6), 94);
+    assert_eq!(cpu.registers.read(7), 106);
+    assert_eq!(cpu.registers.read(8), (-6i32) as u32);
+    assert_eq!(cpu.registers.read(9), 6);
+    assert!(!cpu.registers.getf_q());
+
+    // The accumulate overflows and sets Q without saturating.
+    let cpu = run_arm(&[0xE1005382], &[(2, 3), (3, 1), (5, 0x7FFFFFFF)]);
+    assert_eq!(cpu.registers.read(0), 0x80000002);
+    assert!(cpu.registers.getf_q());
+    let cpu = run_arm(&[0xE1265384], &[(3, 1), (4, 0x00010000), (5, 0x7FFFFFFF)]);
+    assert_eq!(cpu.registers.read(6), 0x80000000);
+    assert!(cpu.registers.getf_q());
+}
+
+#[test]
+pub fn test_armv5te_saturating_arithmetic() {
+    let qsub = 0xE1231052; // qsub r1, r2, r3
+    let qdadd = 0xE1431052; // qdadd r1, r2, r3
+    let qdsub = 0xE1631052; // qdsub r1, r2, r3
+
+    // (opcode, r2, r3, result, saturated)
+    let cases = [
+        (qsub, 5, 7, (-2i32) as u32, false),
+        (qsub, 0x80000000, 1, 0x80000000, true),
+        (qsub, 0x7FFFFFFF, 0xFFFFFFFF, 0x7FFFFFFF, true),
+        (qdadd, 10, 3, 16, false),
+        // Doubling r3 saturates even though the sum would fit.
+        (qdadd, 0xFFFFFFFF, 0x40000000, 0x7FFFFFFE, true),
+        (qdadd, 1, 0x40000000, 0x7FFFFFFF, true),
+        (qdsub, 10, 3, 4, false),
+        (qdsub, 0, 0xC0000000, 0x7FFFFFFF, true),
+        (qdsub, 0x80000000, 0x20000000, 0x80000000, true),
+    ];
+    for &(opcode, rm, rn, result, saturated) in cases.iter() {
+        let cpu = run_arm(&[opcode], &[(2, rm), (3, rn)]);
+        assert_eq!(
+            (cpu.registers.read(1), cpu.registers.getf_q()),
+            (result, saturated),
+            "{:08X} with {:08X} and {:08X}",
+            opcode,
+            rm,
+            rn
+        );
+    }
+
+    // Q is sticky and isn't cleared by an instruction that doesn't saturate.
+    let cpu = run_arm(&[qsub, 0xE1034052], &[(2, 0x80000000), (3, 1)]); // qadd r4, r2, r3
+    assert_eq!(cpu.registers.read(4), 0x80000001);
+    assert!(cpu.registers.getf_q());
+}
+
+#[test]
+pub fn test_armv5te_bkpt() {
+    // The prefetch abort vector halts the test.
+    let mut program = [0u32; 9];
+    program[3] = 0xEF000010; // swi 0x10
+    program[8] = 0xE1200172; // bkpt #0x12
+    let mut mem = vec![0u8; 0x400];
+    for (index, opcode) in program.iter().enumerate() {
+        mem.write_data_word(index as u32 * 4, *opcode, false, &mut 0);
+    }
+    let mut cpu = ArmCpu::new();
+    let _ = cpu.set_pc(0x20, &mut mem);
+    run_cpu(&mut cpu, &mut mem);
+    assert_eq!(cpu.registers.read_mode(), CpuMode::Abort);
+    assert_eq!(cpu.registers.read(14), 0x24);
+    assert!(!cpu.registers.getf_t());
+
+    let mut mem = vec![0u8; 0x400];
+    mem.write_data_word(0x0C, 0xEF000010, false, &mut 0); // swi 0x10
+    write_thumb(&mut mem, 0x100, &[0x46C0, 0xBE01]); // nop; bkpt #1
+    let cpu = run_thumb(&mut mem, &[]);
+    assert_eq!(cpu.registers.read_mode(), CpuMode::Abort);
+    assert_eq!(cpu.registers.read(14), 0x106);
+    assert!(!cpu.registers.getf_t());
+}
+
+#[test]
+pub fn test_armv5te_thumb_blx() {
+    // BLX (immediate) to ARM code at 0x200.
+    let mut mem = vec![0u8; 0x400];
+    write_thumb(&mut mem, 0x100, &[0xF000, 0xE87E]); // blx 0x200
+    mem.write_data_word(0x200, 0xE3A0002A, false, &mut 0); // mov r0, #42
+    mem.write_data_word(0x204, 0xEF000010, false, &mut 0); // swi 0x10
+    let cpu = run_thumb(&mut mem, &[]);
+    assert_eq!(cpu.registers.read(0), 42);
+    assert_eq!(cpu.registers.read(14), 0x105);
+    assert!(!cpu.registers.getf_t());
+
+    // BLX (register) to ARM code that returns, then to THUMB code that returns.
+    let mut mem = vec![0u8; 0x400];
+    write_thumb(
+        &mut mem,
+        0x100,
+        &[
+            0x4798, // blx r3
+            0x47A8, // blx r5
+            0xDF10, // swi 0x10
+        ],
+    );
+    mem.write_data_word(0x200, 0xE3A01001, false, &mut 0); // mov r1, #1
+    mem.write_data_word(0x204, 0xE12FFF1E, false, &mut 0); // bx lr
+    write_thumb(&mut mem, 0x180, &[0x2007, 0x4770]); // movs r0, #7; bx lr
+    let cpu = run_thumb(&mut mem, &[(3, 0x200), (5, 0x181)]);
+    assert_eq!(cpu.registers.read(1), 1);
+    assert_eq!(cpu.registers.read(0), 7);
+    assert_eq!(cpu.registers.read(14), 0x105);
+    assert!(cpu.registers.getf_t());
+}
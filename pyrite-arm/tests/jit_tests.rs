@@ -0,0 +1,369 @@
+#![cfg(feature = "jit")]
+
+use pyrite_arm::cpu::CpuException;
+use pyrite_arm::{ArmCpu, ArmMemory, BlockInvalidator};
+use pyrite_common::Shared;
+
+/// Memory that reports writes to the CPU's block cache and keeps track of the cycles that were
+/// passed to `jit_advance`.
+struct TestMemory {
+    mem: Vec<u8>,
+    invalidator: Option<BlockInvalidator>,
+    advanced_cycles: u64,
+}
+
+impl TestMemory {
+    fn new(mem: Vec<u8>) -> TestMemory {
+        TestMemory {
+            mem,
+            invalidator: None,
+            advanced_cycles: 0,
+        }
+    }
+
+    fn on_write(&self, addr: u32) {
+        if let Some(ref invalidator) = self.invalidator {
+            invalidator.on_write(addr);
+        }
+    }
+}
+
+impl ArmMemory for TestMemory {
+    fn on_internal_cycles(&mut self, icycles: u32) {
+        self.mem.on_internal_cycles(icycles);
+    }
+
+    fn read_code_word(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u32 {
+        self.mem.read_code_word(addr, seq, cycles)
+    }
+
+    fn read_code_halfword(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u16 {
+        self.mem.read_code_halfword(addr, seq, cycles)
+    }
+
+    fn read_data_word(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u32 {
+        self.mem.read_data_word(addr, seq, cycles)
+    }
+
+    fn read_data_halfword(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u16 {
+        self.mem.read_data_halfword(addr, seq, cycles)
+    }
+
+    fn read_data_byte(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u8 {
+        self.mem.read_data_byte(addr, seq, cycles)
+    }
+
+    fn write_data_word(&mut self, addr: u32, data: u32, seq: bool, cycles: &mut u32) {
+        self.on_write(addr);
+        self.mem.write_data_word(addr, data, seq, cycles)
+    }
+
+    fn write_data_halfword(&mut self, addr: u32, data: u16, seq: bool, cycles: &mut u32) {
+        self.on_write(addr);
+        self.mem.write_data_halfword(addr, data, seq, cycles)
+    }
+
+    fn write_data_byte(&mut self, addr: u32, data: u8, seq: bool, cycles: &mut u32) {
+        self.on_write(addr);
+        self.mem.write_data_byte(addr, data, seq, cycles)
+    }
+
+    fn view_word(&self, addr: u32) -> u32 {
+        self.mem.view_word(addr)
+    }
+
+    fn view_halfword(&self, addr: u32) -> u16 {
+        self.mem.view_halfword(addr)
+    }
+
+    fn view_byte(&self, addr: u32) -> u8 {
+        self.mem.view_byte(addr)
+    }
+
+    fn jit_advance(&mut self, cycles: u32) {
+        self.advanced_cycles += cycles as u64;
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_mut_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+/// Runs the CPU until it hits an SWI and returns the number of cycles that it took.
+fn run_until_swi(cpu: &mut ArmCpu, memory: &mut TestMemory) -> u64 {
+    const MAX_STEPS: u32 = 1000000;
+
+    let halted = Shared::new(false);
+    let handler_halted = Shared::share(&halted);
+    cpu.set_exception_handler(Box::new(move |_cpu, _memory, exception, _addr| {
+        if exception == CpuException::SWI {
+            *handler_halted.borrow_mut() = true;
+            return true;
+        }
+        false
+    }));
+
+    let mut cycles = 0u64;
+    let advanced_cycles = memory.advanced_cycles;
+    let mut steps = 0;
+    while !*halted.borrow() {
+        cycles += cpu.step(memory) as u64;
+        steps += 1;
+        if steps > MAX_STEPS {
+            panic!("HIT TEST STEP LIMIT");
+        }
+    }
+    drop(cpu.remove_exception_handler());
+    cycles + (memory.advanced_cycles - advanced_cycles)
+}
+
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, max: u32) -> u32 {
+        self.next() % max
+    }
+}
+
+/// A random ARM data processing instruction using r0-r7. This includes forms that the JIT leaves
+/// to the interpreter (shifts by register, RRX, ADCS, ...).
+fn random_arm_alu(rng: &mut XorShift) -> u32 {
+    let cond = if rng.below(4) == 0 {
+        rng.below(15)
+    } else {
+        0xE
+    };
+    let op = rng.below(16);
+    let set_flags = if (8..=11).contains(&op) {
+        1
+    } else {
+        rng.below(2)
+    };
+    let rn = rng.below(8);
+    let rd = rng.below(8);
+    let operand = match rng.below(4) {
+        0 => (1 << 25) | (rng.below(16) << 8) | rng.below(256),
+        1 | 2 => (rng.below(32) << 7) | (rng.below(4) << 5) | rng.below(8),
+        _ => (rng.below(8) << 8) | (rng.below(4) << 5) | (1 << 4) | rng.below(8),
+    };
+    (cond << 28) | (op << 21) | (set_flags << 20) | (rn << 16) | (rd << 12) | operand
+}
+
+/// A random THUMB ALU instruction using r0-r6 and r9-r12.
+fn random_thumb_alu(rng: &mut XorShift) -> u32 {
+    let lo = |rng: &mut XorShift| rng.below(7);
+    match rng.below(5) {
+        0 => (rng.below(3) << 11) | (rng.below(32) << 6) | (lo(rng) << 3) | lo(rng),
+        1 => 0x1800 | (rng.below(4) << 9) | (rng.below(7) << 6) | (lo(rng) << 3) | lo(rng),
+        2 => 0x2000 | (rng.below(4) << 11) | (lo(rng) << 8) | rng.below(256),
+        3 => 0x4000 | (rng.below(16) << 6) | (lo(rng) << 3) | lo(rng),
+        _ => {
+            // Hi register ADD, CMP and MOV with at least one register in r9-r12.
+            let (hd, hs) = match rng.below(3) {
+                0 => (1, 0),
+                1 => (0, 1),
+                _ => (1, 1),
+            };
+            let rd = if hd == 1 { 1 + rng.below(4) } else { lo(rng) };
+            let rs = if hs == 1 { 1 + rng.below(4) } else { lo(rng) };
+            0x4400 | (rng.below(3) << 8) | (hd << 7) | (hs << 6) | (rs << 3) | rd
+        }
+    }
+}
+
+/// Runs a loop of random instructions 40 times, which is enough for the JIT to compile it.
+fn run_random_program(seed: u32, thumb: bool, jit: bool) -> (ArmCpu, u64) {
+    const LOOP_LENGTH: u32 = 40;
+
+    let mut rng = XorShift(seed);
+    let mut mem = TestMemory::new(vec![0u8; 0x200]);
+    let mut addr = 0;
+    if thumb {
+        for _ in 0..LOOP_LENGTH {
+            mem.write_data_halfword(addr, random_thumb_alu(&mut rng) as u16, false, &mut 0);
+            addr += 2;
+        }
+        let tail: [u16; 5] = [
+            0x4647,                                                           // mov r7, r8
+            0x3F01,                                                           // sub r7, #1
+            0x46B8,                                                           // mov r8, r7
+            0xD100 | ((((0i32 - (addr as i32 + 6 + 4)) >> 1) as u16) & 0xFF), // bne 0
+            0xDF10,                                                           // swi 0x10
+        ];
+        for opcode in tail.iter() {
+            mem.write_data_halfword(addr, *opcode, false, &mut 0);
+            addr += 2;
+        }
+    } else {
+        for _ in 0..LOOP_LENGTH {
+            mem.write_data_word(addr, random_arm_alu(&mut rng), false, &mut 0);
+            addr += 4;
+        }
+        let tail: [u32; 3] = [
+            0xE2588001, // subs r8, r8, #1
+            0x1A000000 | ((((0i32 - (addr as i32 + 4 + 8)) >> 2) as u32) & 0xFFFFFF), // bne 0
+            0xEF000010, // swi 0x10
+        ];
+        for opcode in tail.iter() {
+            mem.write_data_word(addr, *opcode, false, &mut 0);
+            addr += 4;
+        }
+    }
+
+    let mut cpu = ArmCpu::new();
+    if jit {
+        let _ = cpu.enable_jit();
+    }
+    for register in 0..13 {
+        cpu.registers.write(register, rng.next());
+    }
+    cpu.registers.write(8, 40);
+    cpu.registers.putf_n(rng.below(2) == 1);
+    cpu.registers.putf_z(rng.below(2) == 1);
+    cpu.registers.putf_c(rng.below(2) == 1);
+    cpu.registers.putf_v(rng.below(2) == 1);
+    cpu.registers.putf_t(thumb);
+
+    let mut cycles = cpu.set_pc(0, &mut mem) as u64;
+    cycles += run_until_swi(&mut cpu, &mut mem);
+    (cpu, cycles)
+}
+
+fn compare_random_programs(thumb: bool) {
+    for seed in 1..=200 {
+        let (interpreted, interpreted_cycles) = run_random_program(seed, thumb, false);
+        let (jit, jit_cycles) = run_random_program(seed, thumb, true);
+        for register in 0..16 {
+            assert_eq!(
+                interpreted.registers.read(register),
+                jit.registers.read(register),
+                "r{} mismatch with seed {}",
+                register,
+                seed
+            );
+        }
+        assert_eq!(
+            interpreted.registers.read_cpsr(),
+            jit.registers.read_cpsr(),
+            "CPSR mismatch with seed {}",
+            seed
+        );
+        assert_eq!(
+            interpreted_cycles, jit_cycles,
+            "cycle mismatch with seed {}",
+            seed
+        );
+    }
+}
+
+#[test]
+pub fn test_jit_random_arm() {
+    compare_random_programs(false);
+}
+
+#[test]
+pub fn test_jit_random_thumb() {
+    compare_random_programs(true);
+}
+
+#[test]
+pub fn test_jit_chacha20() {
+    fn chacha20(binary: &[u8], thumb: bool, jit: bool) -> ([u8; 64], u64) {
+        let mut cpu = ArmCpu::new();
+        if jit {
+            let _ = cpu.enable_jit();
+        }
+        let mut mem = binary.to_vec();
+        mem.resize(0x1000, 0xCE);
+        let mut mem = TestMemory::new(mem);
+        let mut cycles = cpu.set_pc(0, &mut mem) as u64;
+
+        let mut dest = [0u8; 64];
+        loop {
+            cycles += run_until_swi(&mut cpu, &mut mem);
+            let swi_addr = cpu
+                .next_exec_address()
+                .wrapping_sub(if thumb { 2 } else { 4 });
+            let comment = if thumb {
+                mem.view_halfword(swi_addr) as u32 & 0xFF
+            } else {
+                mem.view_word(swi_addr) & 0xFFFFFF
+            };
+
+            match (comment, cpu.registers.read(0)) {
+                (4, signal) if signal < 2 => {
+                    // The key (32 bytes) and the nonce (12 bytes).
+                    let addr = cpu.registers.read(1);
+                    let len = if signal == 0 { 32 } else { 12 };
+                    for offset in 0..len {
+                        mem.write_data_byte(addr + offset, (offset * 7) as u8, false, &mut 0);
+                    }
+                }
+                (4, 2) => cpu.registers.write(0, 8),
+                (4, 3) => {
+                    let addr = cpu.registers.read(1);
+                    for (offset, byte) in dest.iter_mut().enumerate() {
+                        *byte = mem.view_byte(addr + offset as u32);
+                    }
+                }
+                (16, _) => break,
+                (comment, value) => panic!("unexpected SWI {} ({})", comment, value),
+            }
+        }
+        (dest, cycles)
+    }
+
+    static CHACHA20_BIN: &[u8] = include_bytes!("../data/bin/chacha20.bin");
+    static CHACHA20_BIN_THUMB: &[u8] = include_bytes!("../data/bin/chacha20_thumb.bin");
+
+    assert_eq!(
+        chacha20(CHACHA20_BIN, false, false),
+        chacha20(CHACHA20_BIN, false, true)
+    );
+    assert_eq!(
+        chacha20(CHACHA20_BIN_THUMB, true, false),
+        chacha20(CHACHA20_BIN_THUMB, true, true)
+    );
+}
+
+#[test]
+pub fn test_jit_self_modifying_code() {
+    let program: [(u32, u32); 11] = [
+        (0x00, 0xE3A00000), // mov r0, #0
+        (0x04, 0xE3A08028), // mov r8, #40
+        (0x08, 0xE59F1070), // ldr r1, [pc, #0x70] (0x80)
+        (0x0C, 0xE2800001), // add r0, r0, #1
+        (0x10, 0xE2822003), // add r2, r2, #3
+        (0x14, 0xE3580014), // cmp r8, #20
+        (0x18, 0x050F1014), // streq r1, [pc, #-0x14] (0x0C)
+        (0x1C, 0xE2588001), // subs r8, r8, #1
+        (0x20, 0x1AFFFFF9), // bne 0x0C
+        (0x24, 0xEF000010), // swi 0x10
+        (0x80, 0xE2800002), // add r0, r0, #2 (copied to 0x0C)
+    ];
+
+    let mut cpu = ArmCpu::new();
+    let mut mem = TestMemory::new(vec![0u8; 0x100]);
+    mem.invalidator = Some(cpu.enable_jit());
+    for (addr, opcode) in program.iter() {
+        mem.write_data_word(*addr, *opcode, false, &mut 0);
+    }
+
+    let _ = cpu.set_pc(0, &mut mem);
+    run_until_swi(&mut cpu, &mut mem);
+
+    // The first 21 iterations add 1 and the last 19 add 2 after the instruction is replaced.
+    assert_eq!(cpu.registers.read(0), 59);
+    assert_eq!(cpu.registers.read(2), 120);
+}
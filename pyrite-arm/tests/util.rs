@@ -4,10 +4,10 @@ use pyrite_common::Shared;
 pub fn read_swi_comment(memory: &mut dyn ArmMemory, addr: u32, thumb: bool) -> u32 {
     if thumb {
         let opcode = memory.read_data_halfword(addr, false, &mut 0) as u32;
-        return opcode & 0xFF;
+        opcode & 0xFF
     } else {
         let opcode = memory.read_data_word(addr, false, &mut 0);
-        return opcode & 0xFFFFFF;
+        opcode & 0xFFFFFF
     }
 }
 
@@ -71,5 +71,5 @@ pub fn run_cpu(cpu: &mut ArmCpu, memory: &mut dyn ArmMemory) -> Option<(u32, u32
         cpu.set_exception_handler(old_handler);
     }
 
-    return Shared::unwrap(signal);
+    Shared::unwrap(signal)
 }
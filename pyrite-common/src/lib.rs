@@ -1,4 +1,7 @@
+#![allow(clippy::needless_return)]
 pub mod macros;
 pub mod shared;
+pub mod state;
 
 pub use shared::Shared;
+pub use state::{StateError, StateReader, StateWriter};
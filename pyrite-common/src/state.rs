@@ -0,0 +1,351 @@
+//! Helpers for reading and writing save states.
+//!
+//! States are a flat little-endian byte stream. Every component writes its own version number
+//! before its fields so that it can reject (or migrate) states written by older versions.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The state ended before everything was read.
+    UnexpectedEnd,
+
+    /// A component's state was written by a version that can't be loaded.
+    UnsupportedVersion {
+        component: &'static str,
+        version: u32,
+    },
+
+    /// The state contains a value that isn't valid for the component.
+    InvalidData(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::UnexpectedEnd => write!(f, "unexpected end of state"),
+            StateError::UnsupportedVersion { component, version } => {
+                write!(f, "unsupported {} state version: {}", component, version)
+            }
+            StateError::InvalidData(message) => write!(f, "invalid state: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+
+    /// Set for writers that only hash what is written to them (see `StateWriter::hasher`).
+    hasher: Option<StateHasher>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+            hasher: None,
+        }
+    }
+
+    /// A writer that hashes everything that is written to it instead of keeping it. The hash is
+    /// the same as `hash` of the bytes that a normal writer would have collected.
+    pub fn hasher() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+            hasher: Some(StateHasher::new()),
+        }
+    }
+
+    /// True for writers that were created with `StateWriter::hasher`.
+    pub fn is_hasher(&self) -> bool {
+        self.hasher.is_some()
+    }
+
+    #[inline]
+    fn put(&mut self, bytes: &[u8]) {
+        match self.hasher {
+            Some(ref mut hasher) => hasher.update(bytes),
+            None => self.data.extend_from_slice(bytes),
+        }
+    }
+
+    #[inline]
+    pub fn write_u8(&mut self, value: u8) {
+        self.put(&[value]);
+    }
+
+    #[inline]
+    pub fn write_bool(&mut self, value: bool) {
+        self.put(&[value as u8]);
+    }
+
+    #[inline]
+    pub fn write_u16(&mut self, value: u16) {
+        self.put(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_u32(&mut self, value: u32) {
+        self.put(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_u64(&mut self, value: u64) {
+        self.put(&value.to_le_bytes());
+    }
+
+    pub fn write_u32_slice(&mut self, values: &[u32]) {
+        for &value in values {
+            self.write_u32(value);
+        }
+    }
+
+    /// Writes bytes without a length. The reader must know how many bytes to expect.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.put(bytes);
+    }
+
+    /// The number of bytes that were written.
+    pub fn len(&self) -> usize {
+        match self.hasher {
+            Some(ref hasher) => hasher.len as usize,
+            None => self.data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The hash of everything that was written to a writer that was created with
+    /// `StateWriter::hasher`.
+    pub fn finish_hash(self) -> Option<u64> {
+        self.hasher.map(|hasher| hasher.finish())
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    /// Reads `len` bytes.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < len {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let bytes = &self.data[self.position..(self.position + len)];
+        self.position += len;
+        return Ok(bytes);
+    }
+
+    /// Fills `dest` with the next `dest.len()` bytes.
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        dest.copy_from_slice(self.read_bytes(dest.len())?);
+        return Ok(());
+    }
+
+    #[inline]
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(StateError::InvalidData(format!("bad boolean: {}", value))),
+        }
+    }
+
+    #[inline]
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        self.read_into(&mut bytes)?;
+        return Ok(u16::from_le_bytes(bytes));
+    }
+
+    #[inline]
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        self.read_into(&mut bytes)?;
+        return Ok(u32::from_le_bytes(bytes));
+    }
+
+    #[inline]
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        self.read_into(&mut bytes)?;
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn read_u32_slice(&mut self, dest: &mut [u32]) -> Result<(), StateError> {
+        for value in dest.iter_mut() {
+            *value = self.read_u32()?;
+        }
+        return Ok(());
+    }
+
+    /// Reads a component's version number and returns it if it is between 1 and `current`.
+    pub fn read_version(
+        &mut self,
+        component: &'static str,
+        current: u32,
+    ) -> Result<u32, StateError> {
+        let version = self.read_u32()?;
+        if version == 0 || version > current {
+            return Err(StateError::UnsupportedVersion { component, version });
+        }
+        return Ok(version);
+    }
+
+    /// The number of bytes that haven't been read yet.
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+}
+
+/// Compresses a state with a simple run-length encoding (PackBits). States are mostly made up of
+/// memory that is zeroed or filled with the same byte so this is good enough and very fast.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    let mut index = 0;
+
+    while index < data.len() {
+        let run = data[index..]
+            .iter()
+            .take(129)
+            .take_while(|&&b| b == data[index])
+            .count();
+
+        if run >= 2 {
+            // 2-129 repeated bytes.
+            out.push((run + 126) as u8);
+            out.push(data[index]);
+            index += run;
+        } else {
+            // 1-128 literal bytes that end before the next run.
+            let start = index;
+            index += 1;
+            while index < data.len()
+                && (index - start) < 128
+                && !(index + 1 < data.len() && data[index] == data[index + 1])
+            {
+                index += 1;
+            }
+            out.push((index - start - 1) as u8);
+            out.extend_from_slice(&data[start..index]);
+        }
+    }
+
+    return out;
+}
+
+/// Decompresses data that was compressed with `compress`. Fails if the decompressed data would
+/// be larger than `max_len`.
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, StateError> {
+    let mut out = Vec::with_capacity(max_len.min(data.len() * 4));
+    let mut reader = StateReader::new(data);
+
+    while reader.remaining() > 0 {
+        let control = reader.read_u8()? as usize;
+        if control < 128 {
+            out.extend_from_slice(reader.read_bytes(control + 1)?);
+        } else {
+            let value = reader.read_u8()?;
+            out.resize(out.len() + (control - 126), value);
+        }
+
+        if out.len() > max_len {
+            return Err(StateError::InvalidData(String::from(
+                "compressed state is too large",
+            )));
+        }
+    }
+
+    return Ok(out);
+}
+
+const HASH_MUL: u64 = 0x9E3779B97F4A7C15;
+
+/// A fast (not cryptographic) 64-bit hash used to compare states.
+pub fn hash(data: &[u8]) -> u64 {
+    let mut hasher = StateHasher::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// Computes `hash` over bytes that arrive a few at a time.
+#[derive(Default)]
+struct StateHasher {
+    hash: u64,
+    len: u64,
+
+    /// Bytes that don't make up a whole word yet.
+    pending: [u8; 8],
+    pending_len: usize,
+}
+
+impl StateHasher {
+    fn new() -> StateHasher {
+        StateHasher::default()
+    }
+
+    #[inline]
+    fn mix(hash: u64, value: u64) -> u64 {
+        (hash ^ value).wrapping_mul(HASH_MUL).rotate_left(29)
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+
+        if self.pending_len > 0 {
+            let take = std::cmp::min(8 - self.pending_len, data.len());
+            self.pending[self.pending_len..(self.pending_len + take)]
+                .copy_from_slice(&data[..take]);
+            self.pending_len += take;
+            data = &data[take..];
+            if self.pending_len < 8 {
+                return;
+            }
+            self.hash = Self::mix(self.hash, u64::from_le_bytes(self.pending));
+            self.pending_len = 0;
+        }
+
+        let mut words = data.chunks_exact(8);
+        for word in &mut words {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(word);
+            self.hash = Self::mix(self.hash, u64::from_le_bytes(bytes));
+        }
+        let remainder = words.remainder();
+        self.pending[..remainder.len()].copy_from_slice(remainder);
+        self.pending_len = remainder.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut hash = self.hash;
+        for &byte in self.pending[..self.pending_len].iter() {
+            hash = Self::mix(hash, byte as u64);
+        }
+        hash = Self::mix(hash, self.len);
+
+        // Mix the bits one last time so that similar states don't have similar hashes.
+        hash ^= hash >> 32;
+        hash = hash.wrapping_mul(HASH_MUL);
+        hash ^= hash >> 29;
+        hash
+    }
+}
@@ -22,7 +22,16 @@ fn draw_single_frame(gba: &mut Gba) {
     gba.video_frame(&mut no_video, &mut no_audio);
 }
 
-fn setup_gba(rom_file: &str) -> Box<Gba> {
+/// The CPU implementation used by a group of benchmarks.
+#[derive(Clone, Copy)]
+enum CpuBackend {
+    Interpreter,
+    BlockCache,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+fn setup_gba(rom_file: &str, backend: CpuBackend) -> Box<Gba> {
     use std::fs::File;
     use std::io::prelude::*;
 
@@ -35,13 +44,26 @@ fn setup_gba(rom_file: &str) -> Box<Gba> {
             .read_to_end(&mut rom)
             .expect("failed to read rom file");
     }
-    gba.set_rom(rom);
+    gba.set_rom(rom).expect("failed to set rom");
 
     gba.reset(true);
+    match backend {
+        CpuBackend::Interpreter => {}
+        CpuBackend::BlockCache => gba.set_block_cache_enabled(true),
+        #[cfg(feature = "jit")]
+        CpuBackend::Jit => gba.set_jit_enabled(true),
+    }
     return gba;
 }
 
 fn tonc_benchmarks(c: &mut Criterion) {
+    run_tonc_benchmarks(c, "tonc", CpuBackend::Interpreter);
+    run_tonc_benchmarks(c, "tonc-block-cache", CpuBackend::BlockCache);
+    #[cfg(feature = "jit")]
+    run_tonc_benchmarks(c, "tonc-jit", CpuBackend::Jit);
+}
+
+fn run_tonc_benchmarks(c: &mut Criterion, group_name: &str, backend: CpuBackend) {
     let tonc_benchmarks: &[(usize, &'static str, &'static str)] = &[
         (60, "m3_demo", "../roms/tonc/m3_demo.gba"),
         (50, "brin_demo", "../roms/tonc/brin_demo.gba"),
@@ -55,7 +77,7 @@ fn tonc_benchmarks(c: &mut Criterion) {
         (50, "tmr_demo", "../roms/tonc/tmr_demo.gba"),
     ];
 
-    let mut group = c.benchmark_group("tonc");
+    let mut group = c.benchmark_group(group_name);
     for (sample_count, name, filepath) in tonc_benchmarks.iter() {
         if *sample_count == 0 {
             group.sample_size(100);
@@ -63,7 +85,7 @@ fn tonc_benchmarks(c: &mut Criterion) {
             group.sample_size(*sample_count);
         }
 
-        let mut gba = setup_gba(*filepath);
+        let mut gba = setup_gba(*filepath, backend);
         draw_frames(&mut gba, 256); // used to get into the correct mode
 
         group.bench_function(*name, |b| b.iter(|| draw_single_frame(&mut gba)));
@@ -0,0 +1,109 @@
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+/// Each FIFO holds 32 8-bit samples.
+const SOUND_FIFO_SIZE: usize = 32;
+
+/// A FIFO asks for more samples once it is half empty.
+const SOUND_FIFO_REQUEST_THRESHOLD: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoChannel {
+    A,
+    B,
+}
+
+impl FifoChannel {
+    /// The address of the FIFO's register. DMA1 and DMA2 use this to decide which FIFO they are
+    /// refilling.
+    pub fn address(self) -> u32 {
+        match self {
+            FifoChannel::A => 0x040000A0,
+            FifoChannel::B => 0x040000A4,
+        }
+    }
+}
+
+/// One of the two direct sound FIFOs. Samples are written by the CPU or DMA and taken out
+/// whenever the timer selected in SOUNDCNT_H overflows.
+pub struct SoundFifo {
+    samples: VecDeque<i8>,
+    /// The sample that is currently being played.
+    current: i8,
+}
+
+impl Default for SoundFifo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoundFifo {
+    pub fn new() -> SoundFifo {
+        SoundFifo {
+            samples: VecDeque::with_capacity(SOUND_FIFO_SIZE),
+            current: 0,
+        }
+    }
+
+    pub fn write_halfword(&mut self, data: u16) {
+        self.push(data as u8);
+        self.push((data >> 8) as u8);
+    }
+
+    fn push(&mut self, sample: u8) {
+        if self.samples.len() < SOUND_FIFO_SIZE {
+            self.samples.push_back(sample as i8);
+        } else {
+            log::debug!("sound FIFO overflow");
+        }
+    }
+
+    /// Moves on to the next sample. Returns true if the FIFO wants to be refilled.
+    pub fn next_sample(&mut self) -> bool {
+        if let Some(sample) = self.samples.pop_front() {
+            self.current = sample;
+        }
+        self.samples.len() <= SOUND_FIFO_REQUEST_THRESHOLD
+    }
+
+    pub fn current(&self) -> i8 {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.current as u8);
+        state.write_u8(self.samples.len() as u8);
+        for &sample in self.samples.iter() {
+            state.write_u8(sample as u8);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.current = state.read_u8()? as i8;
+        let len = state.read_u8()? as usize;
+        if len > SOUND_FIFO_SIZE {
+            return Err(StateError::InvalidData(format!(
+                "bad sound FIFO size: {}",
+                len
+            )));
+        }
+        self.samples.clear();
+        for _ in 0..len {
+            self.samples.push_back(state.read_u8()? as i8);
+        }
+        Ok(())
+    }
+}
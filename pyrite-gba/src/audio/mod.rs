@@ -1,11 +1,16 @@
-// use crate::dma::GbaDMA;
-// use crate::irq::Interrupt;
+pub mod fifo;
+
+use self::fifo::{FifoChannel, SoundFifo};
+use crate::dma::GbaDMA;
 use crate::scheduler::{GbaEvent, SharedGbaScheduler};
 use crate::GbaAudioOutput;
 use pyrite_common::bits_set;
+use pyrite_common::{StateError, StateReader, StateWriter};
 
 const CYCLES_PER_SECOND: u32 = 16 * 1024 * 1024;
 
+const AUDIO_STATE_VERSION: u32 = 2;
+
 pub struct GbaAudio {
     scheduler: SharedGbaScheduler,
     pub registers: GbaAudioRegisters,
@@ -13,6 +18,8 @@ pub struct GbaAudio {
     dirty: u8,
     channel1: SquareWave,
     channel2: SquareWave,
+    fifo_a: SoundFifo,
+    fifo_b: SoundFifo,
 }
 
 impl GbaAudio {
@@ -23,9 +30,43 @@ impl GbaAudio {
             dirty: 0,
             channel1: SquareWave::new(),
             channel2: SquareWave::new(),
+            fifo_a: SoundFifo::new(),
+            fifo_b: SoundFifo::new(),
         }
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(AUDIO_STATE_VERSION);
+        for &value in self.registers.values().iter() {
+            state.write_u16(value);
+        }
+        state.write_u8(self.dirty);
+        self.channel1.save_state(state);
+        self.channel2.save_state(state);
+        self.fifo_a.save_state(state);
+        self.fifo_b.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let version = state.read_version("audio", AUDIO_STATE_VERSION)?;
+        let mut values = [0u16; AUDIO_REGISTER_COUNT];
+        for value in values.iter_mut() {
+            *value = state.read_u16()?;
+        }
+        self.registers.set_values(&values);
+        self.dirty = state.read_u8()?;
+        self.channel1.load_state(state)?;
+        self.channel2.load_state(state)?;
+        if version >= 2 {
+            self.fifo_a.load_state(state)?;
+            self.fifo_b.load_state(state)?;
+        } else {
+            self.fifo_a = SoundFifo::new();
+            self.fifo_b = SoundFifo::new();
+        }
+        Ok(())
+    }
+
     pub fn update(&mut self, audio: &mut dyn GbaAudioOutput) {
         if self.psg_channel_dirty(PSGChannel::ToneSweep) {
             audio.set_tone_sweep_state(self.channel1.state());
@@ -399,7 +440,57 @@ impl GbaAudio {
     }
 
     pub(crate) fn set_soundcnt_h(&mut self, value: u16) {
-        self.registers.soundcnt_h.value = value;
+        let control = DMASoundControl::wrap(value);
+        if control.reset_a() {
+            self.fifo_a.clear();
+        }
+        if control.reset_b() {
+            self.fifo_b.clear();
+        }
+        // the reset bits always read as zero
+        self.registers.soundcnt_h.value = value & 0x770F;
+    }
+
+    pub fn fifo(&self, channel: FifoChannel) -> &SoundFifo {
+        match channel {
+            FifoChannel::A => &self.fifo_a,
+            FifoChannel::B => &self.fifo_b,
+        }
+    }
+
+    fn fifo_mut(&mut self, channel: FifoChannel) -> &mut SoundFifo {
+        match channel {
+            FifoChannel::A => &mut self.fifo_a,
+            FifoChannel::B => &mut self.fifo_b,
+        }
+    }
+
+    pub(crate) fn write_fifo(&mut self, channel: FifoChannel, data: u16) {
+        self.fifo_mut(channel).write_halfword(data);
+    }
+
+    /// Called with the number of times that timer 0 and timer 1 overflowed. Each FIFO plays the
+    /// next sample every time its timer overflows and asks DMA1 or DMA2 to refill it once it is half
+    /// empty.
+    pub(crate) fn timer_overflows(&mut self, overflows: [u32; 2], dma: &mut GbaDMA) {
+        if !self.registers.soundcnt_x.master_enable() {
+            return;
+        }
+
+        let control = self.registers.soundcnt_h;
+        let timers = [
+            (FifoChannel::A, control.timer_a()),
+            (FifoChannel::B, control.timer_b()),
+        ];
+        for &(channel, timer) in timers.iter() {
+            let mut request = false;
+            for _ in 0..overflows[timer as usize] {
+                request |= self.fifo_mut(channel).next_sample();
+            }
+            if request {
+                dma.start_sound_fifo(channel.address());
+            }
+        }
     }
 
     pub(crate) fn set_sound_bias(&mut self, value: u16) {
@@ -424,6 +515,21 @@ impl SquareWave {
         }
     }
 
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.freq_setting.0);
+        state.write_u8(self.duty_cycle.0);
+        state.write_u8(self.volume.0);
+        state.write_bool(self.playing);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.freq_setting = SquareFreqSetting(state.read_u16()?);
+        self.duty_cycle = SquareWaveDutyCycle(state.read_u8()?);
+        self.volume = PSGVolume(state.read_u8()?);
+        self.playing = state.read_bool()?;
+        Ok(())
+    }
+
     pub fn state(&self) -> SquareWaveState {
         let mut state = SquareWaveState::default();
         if self.playing {
@@ -460,7 +566,46 @@ pub struct GbaAudioRegisters {
     pub sound4cnt_h: UnimplementedSound,
 }
 
+/// The number of registers returned by `GbaAudioRegisters::values`.
+const AUDIO_REGISTER_COUNT: usize = 14;
+
 impl GbaAudioRegisters {
+    fn values(&self) -> [u16; AUDIO_REGISTER_COUNT] {
+        [
+            self.bias.value,
+            self.soundcnt_l.value,
+            self.soundcnt_h.value,
+            self.soundcnt_x.value,
+            self.sound1cnt_l.value,
+            self.sound1cnt_h.value,
+            self.sound1cnt_x.value,
+            self.sound2cnt_l.value,
+            self.sound2cnt_h.value,
+            self.sound3cnt_l.value,
+            self.sound3cnt_h.value,
+            self.sound3cnt_x.value,
+            self.sound4cnt_l.value,
+            self.sound4cnt_h.value,
+        ]
+    }
+
+    fn set_values(&mut self, values: &[u16; AUDIO_REGISTER_COUNT]) {
+        self.bias.value = values[0];
+        self.soundcnt_l.value = values[1];
+        self.soundcnt_h.value = values[2];
+        self.soundcnt_x.value = values[3];
+        self.sound1cnt_l.value = values[4];
+        self.sound1cnt_h.value = values[5];
+        self.sound1cnt_x.value = values[6];
+        self.sound2cnt_l.value = values[7];
+        self.sound2cnt_h.value = values[8];
+        self.sound3cnt_l.value = values[9];
+        self.sound3cnt_h.value = values[10];
+        self.sound3cnt_x.value = values[11];
+        self.sound4cnt_l.value = values[12];
+        self.sound4cnt_h.value = values[13];
+    }
+
     /// Called when master enable is set to zero.
     /// All sound registers are zeroed and must be reinitialized.
     pub fn zero_sound_registers(&mut self) {
@@ -531,6 +676,16 @@ impl PSGSoundControl {
 }
 
 bitfields! (DMASoundControl: u16 {
+    volume_a, set_volume_a: bool = [2, 2],
+    volume_b, set_volume_b: bool = [3, 3],
+    enable_right_a, set_enable_right_a: bool = [8, 8],
+    enable_left_a, set_enable_left_a: bool = [9, 9],
+    timer_a, set_timer_a: u16 = [10, 10],
+    reset_a, set_reset_a: bool = [11, 11],
+    enable_right_b, set_enable_right_b: bool = [12, 12],
+    enable_left_b, set_enable_left_b: bool = [13, 13],
+    timer_b, set_timer_b: u16 = [14, 14],
+    reset_b, set_reset_b: bool = [15, 15],
 });
 
 bitfields! (SoundEnable: u16 {
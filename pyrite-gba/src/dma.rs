@@ -1,6 +1,25 @@
 use crate::hardware::GbaHardware;
 use crate::scheduler::{GbaEvent, SharedGbaScheduler};
 use pyrite_arm::{ArmCpu, ArmMemory};
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+const DMA_STATE_VERSION: u32 = 2;
+
+const ALL_CHANNELS: [DMAChannelIndex; 4] = [
+    DMAChannelIndex::DMA0,
+    DMAChannelIndex::DMA1,
+    DMAChannelIndex::DMA2,
+    DMAChannelIndex::DMA3,
+];
+
+/// The sound FIFO special timing always transfers 4 words.
+const SOUND_FIFO_TRANSFER_COUNT: u32 = 4;
+
+/// Internal cycles that a DMA transfer takes before its first unit is transferred.
+const STARTUP_CYCLES: u32 = 2;
+
+/// Transfers from the GamePak to the GamePak take twice as long to start.
+const GAMEPAK_STARTUP_CYCLES: u32 = 4;
 
 pub struct GbaDMA {
     channels: [DMAChannel; 4],
@@ -9,6 +28,10 @@ pub struct GbaDMA {
 
     /// The last data that was transferred. This is used when the source address is invalid.
     dma_bus: u32,
+
+    /// The channel that transferred the last unit. A channel that was interrupted by one with a
+    /// higher priority has to start again with non-sequential accesses when it resumes.
+    last_channel: Option<DMAChannelIndex>,
 }
 
 impl GbaDMA {
@@ -22,67 +45,54 @@ impl GbaDMA {
             ],
             active_channels: 0,
             dma_bus: 0,
+            last_channel: None,
             scheduler: scheduler,
         }
     }
 
     pub fn start_hblank(&mut self) {
-        if !self.channel_active(DMAChannelIndex::DMA0)
-            && self.channel(DMAChannelIndex::DMA0).control.start_timing() == DMAStartTiming::HBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA0), 0);
-        }
-
-        if !self.channel_active(DMAChannelIndex::DMA1)
-            && self.channel(DMAChannelIndex::DMA1).control.start_timing() == DMAStartTiming::HBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA1), 0);
-        }
-
-        if !self.channel_active(DMAChannelIndex::DMA2)
-            && self.channel(DMAChannelIndex::DMA2).control.start_timing() == DMAStartTiming::HBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA2), 0);
-        }
-
-        if !self.channel_active(DMAChannelIndex::DMA3)
-            && self.channel(DMAChannelIndex::DMA3).control.start_timing() == DMAStartTiming::HBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA3), 0);
-        }
+        self.start_channels(DMAStartTiming::HBlank, &ALL_CHANNELS);
     }
 
     pub fn start_vblank(&mut self) {
-        if !self.channel_active(DMAChannelIndex::DMA0)
-            && self.channel(DMAChannelIndex::DMA0).control.start_timing() == DMAStartTiming::VBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA0), 0);
-        }
+        self.start_channels(DMAStartTiming::VBlank, &ALL_CHANNELS);
+    }
 
-        if !self.channel_active(DMAChannelIndex::DMA1)
-            && self.channel(DMAChannelIndex::DMA1).control.start_timing() == DMAStartTiming::VBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA1), 0);
+    /// DMA3's special start timing is video capture. It starts at the beginning of HBlank from
+    /// line 2 through line 161 and is disabled by the hardware afterwards (at line 162).
+    pub fn video_capture(&mut self, line: u16) {
+        match line {
+            2..=161 => self.start_channels(DMAStartTiming::Special, &[DMAChannelIndex::DMA3]),
+            162 => {
+                let channel = self.channel_mut(DMAChannelIndex::DMA3);
+                if channel.control.start_timing() == DMAStartTiming::Special {
+                    channel.control.set_enabled(false);
+                }
+            }
+            _ => { /* NOP */ }
         }
+    }
 
-        if !self.channel_active(DMAChannelIndex::DMA2)
-            && self.channel(DMAChannelIndex::DMA2).control.start_timing() == DMAStartTiming::VBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA2), 0);
+    /// DMA1 and DMA2's special start timing is used to refill the sound FIFOs. They start when the
+    /// FIFO that they are writing to requests more data.
+    pub fn start_sound_fifo(&mut self, fifo_address: u32) {
+        let fifo_channels = [DMAChannelIndex::DMA1, DMAChannelIndex::DMA2];
+        for &channel_index in fifo_channels.iter() {
+            if self.channel(channel_index).original_destination == fifo_address {
+                self.start_channels(DMAStartTiming::Special, &[channel_index]);
+            }
         }
+    }
 
-        if !self.channel_active(DMAChannelIndex::DMA3)
-            && self.channel(DMAChannelIndex::DMA3).control.start_timing() == DMAStartTiming::VBlank
-        {
-            self.scheduler
-                .schedule(GbaEvent::DMA(DMAChannelIndex::DMA3), 0);
+    fn start_channels(&mut self, timing: DMAStartTiming, channels: &[DMAChannelIndex]) {
+        for &channel_index in channels.iter() {
+            let control = self.channel(channel_index).control;
+            if control.enabled()
+                && control.start_timing() == timing
+                && !self.channel_active(channel_index)
+            {
+                self.scheduler.schedule(GbaEvent::DMA(channel_index), 0);
+            }
         }
     }
 
@@ -91,13 +101,46 @@ impl GbaDMA {
         self.active_channels != 0
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(DMA_STATE_VERSION);
+        state.write_u8(self.active_channels);
+        state.write_u32(self.dma_bus);
+        state.write_u8(self.last_channel.map(u8::from).unwrap_or(0xFF));
+        for channel in self.channels.iter() {
+            channel.save_state(state);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let version = state.read_version("DMA", DMA_STATE_VERSION)?;
+        self.active_channels = state.read_u8()? & 0xF;
+        self.dma_bus = state.read_u32()?;
+        self.last_channel = if version >= 2 {
+            DMAChannelIndex::from_index(state.read_u8()?)
+        } else {
+            None
+        };
+        for channel in self.channels.iter_mut() {
+            channel.load_state(state)?;
+        }
+        Ok(())
+    }
+
     fn channel_active(&self, channel_index: DMAChannelIndex) -> bool {
         (self.active_channels & (1 << u8::from(channel_index))) != 0
     }
 
     pub fn begin_transfer(&mut self, channel_index: DMAChannelIndex, cpu: &mut ArmCpu) {
+        // The channel might have been disabled between being scheduled and starting.
+        if !self.channel(channel_index).control.enabled() || self.channel_active(channel_index) {
+            return;
+        }
+
         self.active_channels |= 1 << u8::from(channel_index);
         self.channel_mut(channel_index).first_transfer = true;
+        if self.channel(channel_index).sound_fifo() {
+            self.channel_mut(channel_index).count = SOUND_FIFO_TRANSFER_COUNT;
+        }
         cpu.override_execution(Self::cpu_step_override);
     }
 
@@ -114,8 +157,11 @@ impl GbaDMA {
         self.channel_mut(channel_index)
             .control
             .set_enabled(remain_enabled);
-        if self.channel(channel_index).control.dst_control() == DMAAddressControl::IncReload {
-            self.channel_mut(channel_index).reload(false);
+        if remain_enabled {
+            let reload_destination =
+                self.channel(channel_index).control.dst_control() == DMAAddressControl::IncReload;
+            self.channel_mut(channel_index)
+                .reload_repeat(reload_destination);
         }
         self.channel_mut(channel_index).first_transfer = true;
         if self.channel(channel_index).control.irq() {
@@ -133,67 +179,76 @@ impl GbaDMA {
     fn transfer(hw: &mut GbaHardware, channel_index: DMAChannelIndex, cpu: &mut ArmCpu) -> u32 {
         let mut cycles = 0;
 
-        let transfer_size;
-        if hw.dma.channel(channel_index).valid_destination {
-            let seq = !hw.dma.channel(channel_index).first_transfer;
-            if hw.dma.channel(channel_index).control.transfer_type() == DMATransferType::Halfword {
-                if hw.dma.channel(channel_index).valid_source {
-                    let source_address = hw.dma.channel(channel_index).source;
-                    hw.dma.dma_bus = hw.read_data_halfword(source_address, seq, &mut cycles) as u32;
-                }
-                let destination_address = hw.dma.channel(channel_index).destination;
-                hw.write_data_halfword(
-                    destination_address,
-                    hw.dma.dma_bus as u16,
-                    seq,
-                    &mut cycles,
-                );
-                transfer_size = 2;
+        let channel = hw.dma.channel(channel_index);
+        let transfer_type = channel.transfer_type();
+        let transfer_size = if transfer_type == DMATransferType::Halfword {
+            2
+        } else {
+            4
+        };
+        let source = channel.source;
+        let destination = channel.destination;
+        let valid_source = channel.valid_source;
+        let first_transfer = channel.first_transfer;
+
+        // The first unit of a transfer and the first unit after being interrupted by a higher
+        // priority channel use non-sequential accesses. Everything else is sequential.
+        let mut seq = !first_transfer && hw.dma.last_channel == Some(channel_index);
+        if first_transfer {
+            cycles += if is_gamepak(source) && is_gamepak(destination) {
+                GAMEPAK_STARTUP_CYCLES
             } else {
-                if hw.dma.channel(channel_index).valid_source {
-                    let source_address = hw.dma.channel(channel_index).source;
-                    hw.dma.dma_bus = hw.read_data_word(source_address, seq, &mut cycles);
-                }
-                let destination_address = hw.dma.channel(channel_index).destination;
-                hw.write_data_word(destination_address, hw.dma.dma_bus, seq, &mut cycles);
-                transfer_size = 4;
+                STARTUP_CYCLES
+            };
+        }
+        hw.dma.last_channel = Some(channel_index);
+
+        if valid_source {
+            // #NOTE The GamePak's address counter only covers 128KB so it has to be given a new
+            // address (a non-sequential access) at the start of every 128KB block.
+            let source_seq = seq && !is_gamepak_block_start(source);
+            if transfer_type == DMATransferType::Halfword {
+                let value = hw.read_data_halfword(source, source_seq, &mut cycles) as u32;
+                hw.dma.dma_bus = value | (value << 16);
+            } else {
+                hw.dma.dma_bus = hw.read_data_word(source, source_seq, &mut cycles);
             }
         } else {
-            if hw.dma.channel(channel_index).control.transfer_type() == DMATransferType::Halfword {
-                transfer_size = 2;
+            // Nothing is read so the destination gets whatever was last transferred.
+            cycles += 1;
+        }
+
+        if hw.dma.channel(channel_index).valid_destination {
+            seq = seq && !is_gamepak_block_start(destination);
+            if transfer_type == DMATransferType::Halfword {
+                hw.write_data_halfword(destination, hw.dma.dma_bus as u16, seq, &mut cycles);
             } else {
-                transfer_size = 4;
+                hw.write_data_word(destination, hw.dma.dma_bus, seq, &mut cycles);
             }
-            cycles = 1;
+        } else {
+            cycles += 1;
         }
 
-        hw.dma.channel_mut(channel_index).first_transfer = false;
-        hw.dma.channel_mut(channel_index).count -= 1;
+        let channel = hw.dma.channel_mut(channel_index);
+        channel.first_transfer = false;
+        channel.count -= 1;
 
-        match hw.dma.channel(channel_index).control.dst_control() {
-            DMAAddressControl::Increment => {
-                hw.dma.channel_mut(channel_index).destination += transfer_size;
-                hw.dma.channel_mut(channel_index).validate_destination();
+        match channel.dst_control() {
+            DMAAddressControl::Increment | DMAAddressControl::IncReload => {
+                channel.set_internal_destination(channel.destination.wrapping_add(transfer_size));
             }
             DMAAddressControl::Decrement => {
-                hw.dma.channel_mut(channel_index).destination -= transfer_size;
-                hw.dma.channel_mut(channel_index).validate_destination();
+                channel.set_internal_destination(channel.destination.wrapping_sub(transfer_size));
             }
             DMAAddressControl::Fixed => { /* NOP */ }
-            DMAAddressControl::IncReload => {
-                hw.dma.channel_mut(channel_index).destination += transfer_size;
-                hw.dma.channel_mut(channel_index).validate_destination();
-            }
         }
 
-        match hw.dma.channel(channel_index).control.src_control() {
+        match channel.src_control() {
             DMAAddressControl::Increment => {
-                hw.dma.channel_mut(channel_index).source += transfer_size;
-                hw.dma.channel_mut(channel_index).validate_source();
+                channel.set_internal_source(channel.source.wrapping_add(transfer_size));
             }
             DMAAddressControl::Decrement => {
-                hw.dma.channel_mut(channel_index).source -= transfer_size;
-                hw.dma.channel_mut(channel_index).validate_source();
+                channel.set_internal_source(channel.source.wrapping_sub(transfer_size));
             }
             DMAAddressControl::Fixed => { /* NOP */ }
             DMAAddressControl::IncReload => { /* NOP */ }
@@ -278,33 +333,96 @@ impl DMAChannel {
         }
     }
 
+    /// DMA0 can only access internal memory (27-bit addresses). The other channels can read from
+    /// the GamePak (28-bit addresses) but only DMA3 can write to it.
+    fn source_mask(&self) -> u32 {
+        if self.index == DMAChannelIndex::DMA0 {
+            0x07FFFFFF
+        } else {
+            0x0FFFFFFF
+        }
+    }
+
+    fn destination_mask(&self) -> u32 {
+        if self.index == DMAChannelIndex::DMA3 {
+            0x0FFFFFFF
+        } else {
+            0x07FFFFFF
+        }
+    }
+
+    /// DMA3 has a 16-bit word count and the others only have 14 bits.
+    fn count_mask(&self) -> u16 {
+        if self.index == DMAChannelIndex::DMA3 {
+            0xFFFF
+        } else {
+            0x3FFF
+        }
+    }
+
     pub fn set_source(&mut self, new_source: u32) {
-        self.original_source = new_source;
+        self.original_source = new_source & self.source_mask();
     }
 
     pub fn set_destination(&mut self, new_destination: u32) {
-        self.original_destination = new_destination;
+        self.original_destination = new_destination & self.destination_mask();
+    }
+
+    fn set_internal_source(&mut self, source: u32) {
+        self.source = source & self.source_mask();
+        self.validate_source();
     }
 
+    fn set_internal_destination(&mut self, destination: u32) {
+        self.destination = destination & self.destination_mask();
+        self.validate_destination();
+    }
+
+    // #NOTE The address masks already keep channels out of memory that they can't access so the
+    // only invalid addresses left are GamePak SRAM, which isn't connected to the DMA controller,
+    // and the BIOS and unused memory below EWRAM for sources. Reads from those return the open
+    // bus value of the DMA controller instead, which is the last value that it transferred.
+
     pub fn validate_destination(&mut self) {
-        self.valid_destination = if self.index == DMAChannelIndex::DMA3 {
-            // Only DMA3 is allowed to access GamePak ROM/Flash ROM.
-            // SRAM is not allowed.
-            self.destination <= 0x0FFFFFFF
-                && (self.destination < 0x0E000000 || self.destination > 0x0E00FFFF)
-        } else {
-            self.destination <= 0x07FFFFFF
-        };
+        self.valid_destination = !is_sram(self.destination);
     }
 
     pub fn validate_source(&mut self) {
-        self.valid_source = if self.index == DMAChannelIndex::DMA3 {
-            // Only DMA3 is allowed to access GamePak ROM/Flash ROM.
-            // SRAM is not allowed.
-            self.source <= 0x0FFFFFFF && (self.source < 0x0E000000 || self.source > 0x0E00FFFF)
+        self.valid_source = self.source >= 0x02000000 && !is_sram(self.source);
+    }
+
+    /// True if this channel is refilling a sound FIFO instead of doing a normal transfer.
+    fn sound_fifo(&self) -> bool {
+        (self.index == DMAChannelIndex::DMA1 || self.index == DMAChannelIndex::DMA2)
+            && self.control.start_timing() == DMAStartTiming::Special
+    }
+
+    /// Sound FIFO transfers are always 32-bit.
+    fn transfer_type(&self) -> DMATransferType {
+        if self.sound_fifo() {
+            DMATransferType::Word
         } else {
-            self.source <= 0x07FFFFFF
-        };
+            self.control.transfer_type()
+        }
+    }
+
+    /// The GamePak can only be read from in order so a GamePak source address is always
+    /// incremented.
+    fn src_control(&self) -> DMAAddressControl {
+        if is_gamepak(self.source) {
+            DMAAddressControl::Increment
+        } else {
+            self.control.src_control()
+        }
+    }
+
+    /// Sound FIFO transfers always write to the same address.
+    fn dst_control(&self) -> DMAAddressControl {
+        if self.sound_fifo() {
+            DMAAddressControl::Fixed
+        } else {
+            self.control.dst_control()
+        }
     }
 
     pub fn set_source_lo(&mut self, new_source_lo: u16) {
@@ -329,7 +447,7 @@ impl DMAChannel {
     }
 
     pub fn set_count(&mut self, new_count: u16) {
-        self.original_count = new_count;
+        self.original_count = new_count & self.count_mask();
     }
 
     pub fn control(&self) -> u16 {
@@ -347,6 +465,13 @@ impl DMAChannel {
             );
         }
 
+        if self.index == DMAChannelIndex::DMA0
+            && self.control.start_timing() == DMAStartTiming::Special
+        {
+            // DMA0 has no special start timing so it just never starts.
+            log::debug!("prohibited special start timing for DMA0");
+        }
+
         if self.control.enabled() && old_enabled != self.control.enabled() {
             self.reload(true);
             if self.control.start_timing() == DMAStartTiming::Immediate {
@@ -371,25 +496,72 @@ impl DMAChannel {
         }
     }
 
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.source);
+        state.write_u32(self.original_source);
+        state.write_bool(self.valid_source);
+        state.write_u32(self.destination);
+        state.write_u32(self.original_destination);
+        state.write_bool(self.valid_destination);
+        state.write_u32(self.count);
+        state.write_u16(self.original_count);
+        state.write_u16(self.control.value);
+        state.write_bool(self.first_transfer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u32()?;
+        self.original_source = state.read_u32()?;
+        self.valid_source = state.read_bool()?;
+        self.destination = state.read_u32()?;
+        self.original_destination = state.read_u32()?;
+        self.valid_destination = state.read_bool()?;
+        self.count = state.read_u32()?;
+        self.original_count = state.read_u16()?;
+        self.control.value = state.read_u16()?;
+        self.first_transfer = state.read_bool()?;
+        Ok(())
+    }
+
     pub fn reload(&mut self, reload_source: bool) {
         if reload_source {
-            self.source = self.original_source;
-            self.validate_source();
+            self.set_internal_source(self.original_source);
         }
-        self.destination = self.original_destination;
-        self.validate_destination();
+        self.set_internal_destination(self.original_destination);
+        self.reload_count();
+    }
+
+    /// Repeating transfers start over with the original word count and only go back to the
+    /// original destination address if the destination address control is Increment/Reload.
+    fn reload_repeat(&mut self, reload_destination: bool) {
+        if reload_destination {
+            self.set_internal_destination(self.original_destination);
+        }
+        self.reload_count();
+    }
+
+    fn reload_count(&mut self) {
         self.count = if self.original_count == 0 {
-            if self.index == DMAChannelIndex::DMA3 {
-                0x10000
-            } else {
-                0x4000
-            }
+            self.count_mask() as u32 + 1
         } else {
             self.original_count as u32
         };
     }
 }
 
+fn is_sram(address: u32) -> bool {
+    (0x0E000000..=0x0E00FFFF).contains(&address)
+}
+
+/// True for addresses in one of the three GamePak ROM wait state regions.
+fn is_gamepak(address: u32) -> bool {
+    (0x08000000..=0x0DFFFFFF).contains(&address)
+}
+
+fn is_gamepak_block_start(address: u32) -> bool {
+    is_gamepak(address) && (address & 0x1FFFF) == 0
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum DMAChannelIndex {
@@ -399,6 +571,18 @@ pub enum DMAChannelIndex {
     DMA3 = 3,
 }
 
+impl DMAChannelIndex {
+    pub fn from_index(index: u8) -> Option<DMAChannelIndex> {
+        match index {
+            0 => Some(DMAChannelIndex::DMA0),
+            1 => Some(DMAChannelIndex::DMA1),
+            2 => Some(DMAChannelIndex::DMA2),
+            3 => Some(DMAChannelIndex::DMA3),
+            _ => None,
+        }
+    }
+}
+
 impl From<DMAChannelIndex> for u8 {
     fn from(channel_index: DMAChannelIndex) -> u8 {
         match channel_index {
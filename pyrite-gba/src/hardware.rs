@@ -1,19 +1,23 @@
+use crate::audio::fifo::FifoChannel;
 use crate::audio::GbaAudio;
 use crate::dma::{DMAChannelIndex, GbaDMA};
 use crate::ioregs;
-use crate::irq::GbaInterruptControl;
+use crate::irq::{self, GbaInterruptControl, Interrupt};
 use crate::keypad::GbaKeypad;
 use crate::lcd::palette::GbaPalette;
 use crate::lcd::GbaLCD;
 use crate::scheduler::{GbaEvent, SharedGbaScheduler};
+use crate::sio::GbaSerial;
 use crate::sysctl::GbaSystemControl;
 use crate::timers::{GbaTimers, TimerIndex};
 use crate::util::memory::*;
 use pyrite_arm::memory::ArmMemory;
+use pyrite_arm::BlockInvalidator;
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::fmt;
 
 // @TODO remove these when they are implemented. These values are just here to make the emulator
 // less noisy.
-static mut DEBUG_SERIAL1_REG_ACCESS: bool = false;
 static mut DEBUG_SERIAL2_REG_ACCESS: bool = false;
 static mut DEBUG_SRAM_MEM_ACCESS: bool = false;
 static mut DEBUG_GAMEPAK_GPIO_WRITE: bool = false;
@@ -33,6 +37,34 @@ pub type IWRAM = [u8; 32 * 1024];
 pub type VRAM = [u8; 96 * 1024];
 pub type OAM = [u8; 1 * 1024];
 
+const MEMORY_STATE_VERSION: u32 = 2;
+
+/// The size of the GamePak ROM space. It is repeated in each of the three wait state regions.
+const GAMEPAK_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+/// The last opcode that the BIOS fetches before jumping to the ROM.
+pub(crate) const BIOS_OPEN_BUS_AFTER_STARTUP: u32 = 0xE129F000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    /// The ROM doesn't fit into the 32MB GamePak ROM space. This has the length of the ROM.
+    TooLarge(usize),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TooLarge(length) => write!(
+                f,
+                "GamePak ROM is {} bytes long but cannot be greater than 32MB",
+                length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
 pub struct GbaHardware {
     // garden variety memory:
     pub(crate) bios: Box<BIOS>,
@@ -42,7 +74,7 @@ pub struct GbaHardware {
     pub(crate) oam: Box<OAM>,
     pub(crate) pal: Box<GbaPalette>,
     pub(crate) gamepak: Box<[u8]>,
-    pub(crate) gamepak_mask: usize,
+    pub(crate) gamepak_hash: u64,
 
     pub(crate) sysctl: GbaSystemControl,
     pub lcd: GbaLCD,
@@ -51,18 +83,26 @@ pub struct GbaHardware {
     pub irq: GbaInterruptControl,
     pub dma: GbaDMA,
     pub timers: GbaTimers,
+    pub sio: GbaSerial,
     pub scheduler: SharedGbaScheduler,
 
     /// This singular purpose of this is to make 8bit writes to larger IO registers consistent by
     /// storing the values that were last written to them.
     ioreg_bytes: [u8; 0x20C],
 
-    /// Last code read with read_code_halfword or read_code_word.
-    last_code_read: u32,
+    /// The value that reads from unused memory return. This is whatever was left on the bus by
+    /// the last opcode fetch (see `latch_opcode`).
+    open_bus: u32,
+
+    /// The last opcode that was fetched from the BIOS.
+    pub(crate) bios_open_bus: u32,
 
-    /// If this is set to true, reading from the BIOS will be allowed.
-    /// If this is false, all reads from the BIOS will return the last read opcode.
+    /// The BIOS can only be read while the CPU is running code inside of it. Reads from the BIOS
+    /// return `bios_open_bus` otherwise.
     allow_bios_access: bool,
+
+    /// Told about writes to RAM while the CPU's block cache is enabled.
+    pub(crate) code_invalidator: Option<BlockInvalidator>,
 }
 
 impl GbaHardware {
@@ -76,7 +116,7 @@ impl GbaHardware {
             oam: Box::new([2u8; 1 * 1024]),
             pal: Box::new(GbaPalette::new()),
             gamepak: Box::new([0u8; 0]),
-            gamepak_mask: 0,
+            gamepak_hash: Self::hash_gamepak(&[]),
 
             sysctl: GbaSystemControl::new(),
             lcd: GbaLCD::new(scheduler.clone()),
@@ -85,12 +125,13 @@ impl GbaHardware {
             irq: GbaInterruptControl::new(),
             dma: GbaDMA::new(scheduler.clone()),
             timers: GbaTimers::new(scheduler.clone()),
+            sio: GbaSerial::new(scheduler.clone()),
 
             ioreg_bytes: [0u8; 0x20C],
-            last_code_read: 0,
-
-            // @TODO implement
+            open_bus: 0,
+            bios_open_bus: 0,
             allow_bios_access: true,
+            code_invalidator: None,
 
             scheduler: scheduler,
         }
@@ -104,33 +145,102 @@ impl GbaHardware {
         (&mut self.bios[0..data.len()]).copy_from_slice(data);
     }
 
-    pub fn set_gamepak_rom(&mut self, mut data: Vec<u8>) {
-        let next_power_of_two = data
-            .len()
-            .checked_next_power_of_two()
-            .expect("GamePak ROM 'next power of 2' extension overflowed");
-        data.resize(next_power_of_two, 0);
+    pub fn set_gamepak_rom(&mut self, mut data: Vec<u8>) -> Result<(), RomError> {
+        if data.len() > GAMEPAK_ROM_SIZE {
+            return Err(RomError::TooLarge(data.len()));
+        }
+        // Padded so that the last word can always be read whole.
+        let padded_len = (data.len() + 3) & !3;
+        data.resize(padded_len, 0);
+        self.gamepak_hash = Self::hash_gamepak(&data);
         self.gamepak = data.into_boxed_slice();
-        self.gamepak_mask = next_power_of_two - 1;
+        Ok(())
+    }
+
+    /// Writes the contents of all RAM, including the IO register bytes. The BIOS and GamePak ROM
+    /// are not part of the state.
+    pub(crate) fn save_memory_state(&self, state: &mut StateWriter) {
+        state.write_u32(MEMORY_STATE_VERSION);
+        state.write_bytes(&*self.ewram);
+        state.write_bytes(&*self.iwram);
+        state.write_bytes(&*self.vram);
+        state.write_bytes(&*self.oam);
+        self.pal.save_state(state);
+        state.write_bytes(&self.ioreg_bytes);
+        state.write_u32(self.open_bus);
+        state.write_u32(self.bios_open_bus);
+        state.write_bool(self.allow_bios_access);
+    }
+
+    pub(crate) fn load_memory_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let version = state.read_version("memory", MEMORY_STATE_VERSION)?;
+        state.read_into(&mut *self.ewram)?;
+        state.read_into(&mut *self.iwram)?;
+        state.read_into(&mut *self.vram)?;
+        state.read_into(&mut *self.oam)?;
+        self.pal.load_state(state)?;
+        state.read_into(&mut self.ioreg_bytes)?;
+        self.open_bus = state.read_u32()?;
+        self.bios_open_bus = if version >= 2 {
+            state.read_u32()?
+        } else {
+            BIOS_OPEN_BUS_AFTER_STARTUP
+        };
+        self.allow_bios_access = state.read_bool()?;
+        self.invalidate_code();
+        Ok(())
+    }
+
+    /// A hash (FNV-1a) of the GamePak ROM that is used to check that a save state belongs to it.
+    fn hash_gamepak(gamepak: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &byte in gamepak.iter() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    /// Reports a write to EWRAM or IWRAM to the CPU's block cache (if it is enabled).
+    /// `addr` should be the unmirrored address that was written to.
+    #[inline(always)]
+    fn on_ram_write(&self, addr: u32) {
+        if let Some(ref invalidator) = self.code_invalidator {
+            invalidator.on_write(addr);
+        }
+    }
+
+    /// Requests the keypad interrupt if the KEYCNT condition holds. The condition is a level so
+    /// this is checked again whenever the keys, KEYCNT or the IF flag might have changed.
+    pub(crate) fn check_keypad_irq(&self) {
+        if self.keypad.irq_condition() {
+            self.scheduler.schedule(GbaEvent::IRQ(Interrupt::Keypad), 0);
+        }
+    }
+
+    /// Called when the memory that is mapped to some addresses changes without being written to.
+    pub(crate) fn invalidate_code(&self) {
+        if let Some(ref invalidator) = self.code_invalidator {
+            invalidator.invalidate_all();
+        }
     }
 
     pub fn view32(&self, addr: u32) -> u32 {
-        const BAD_VALUE: u32 = 0xDEADBEEF;
+        let bad_value = self.open_bus;
 
         let addr = addr & 0xFFFFFFFC; // word align the address
 
         match Region::from_address(addr) {
             Region::BIOS => {
                 if addr < 0x4000 {
-                    self.bios_read32(addr)
+                    read_u32(&*self.bios, addr as usize)
                 } else {
-                    BAD_VALUE
+                    bad_value
                 }
             }
-            Region::Unused0x1 => BAD_VALUE,
+            Region::Unused0x1 => bad_value,
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
-                    BAD_VALUE
+                    bad_value
                 } else if self.sysctl.ram_external {
                     read_u32(&*self.ewram, addr as usize % (256 * 1024))
                 } else {
@@ -140,7 +250,7 @@ impl GbaHardware {
 
             Region::InternalRAM => {
                 if self.sysctl.ram_disabled {
-                    BAD_VALUE
+                    bad_value
                 } else {
                     read_u32(&*self.iwram, addr as usize % (32 * 1024))
                 }
@@ -156,28 +266,28 @@ impl GbaHardware {
             | Region::GamePak1Hi
             | Region::GamePak2Lo
             | Region::GamePak2Hi => self.gamepak_read32(addr, false),
-            Region::SRAM => BAD_VALUE,
-            Region::Unused0xF => BAD_VALUE,
+            Region::SRAM => self.sram_read32(addr),
+            Region::Unused0xF => bad_value,
         }
     }
 
     pub fn view16(&self, addr: u32) -> u16 {
-        pub const BAD_VALUE: u16 = 0xDEAD;
+        let bad_value = halfword_of_word(self.open_bus, addr);
 
         let addr = addr & 0xFFFFFFFE; // halfword align the address
 
         match Region::from_address(addr) {
             Region::BIOS => {
                 if addr < 0x4000 {
-                    self.bios_read16(addr)
+                    read_u16(&*self.bios, addr as usize)
                 } else {
-                    BAD_VALUE
+                    bad_value
                 }
             }
-            Region::Unused0x1 => BAD_VALUE,
+            Region::Unused0x1 => bad_value,
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
-                    BAD_VALUE
+                    bad_value
                 } else if self.sysctl.ram_external {
                     read_u16(&*self.ewram, addr as usize % (256 * 1024))
                 } else {
@@ -186,7 +296,7 @@ impl GbaHardware {
             }
             Region::InternalRAM => {
                 if self.sysctl.ram_disabled {
-                    BAD_VALUE
+                    bad_value
                 } else {
                     read_u16(&*self.iwram, addr as usize % (32 * 1024))
                 }
@@ -201,26 +311,26 @@ impl GbaHardware {
             | Region::GamePak1Hi
             | Region::GamePak2Lo
             | Region::GamePak2Hi => self.gamepak_read16(addr, false),
-            Region::SRAM => BAD_VALUE,
-            Region::Unused0xF => BAD_VALUE,
+            Region::SRAM => self.sram_read16(addr),
+            Region::Unused0xF => bad_value,
         }
     }
 
     pub fn view8(&self, addr: u32) -> u8 {
-        const BAD_VALUE: u8 = 0xDE;
+        let bad_value = byte_of_word(self.open_bus, addr);
 
         match Region::from_address(addr) {
             Region::BIOS => {
                 if addr < 0x4000 {
-                    self.bios_read8(addr)
+                    self.bios[addr as usize]
                 } else {
-                    BAD_VALUE
+                    bad_value
                 }
             }
-            Region::Unused0x1 => BAD_VALUE,
+            Region::Unused0x1 => bad_value,
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
-                    BAD_VALUE
+                    bad_value
                 } else if self.sysctl.ram_external {
                     self.ewram[addr as usize % (256 * 1024)]
                 } else {
@@ -229,7 +339,7 @@ impl GbaHardware {
             }
             Region::InternalRAM => {
                 if self.sysctl.ram_disabled {
-                    BAD_VALUE
+                    bad_value
                 } else {
                     self.iwram[addr as usize % (32 * 1024)]
                 }
@@ -244,55 +354,75 @@ impl GbaHardware {
             | Region::GamePak1Hi
             | Region::GamePak2Lo
             | Region::GamePak2Hi => self.gamepak_read8(addr, false),
-            Region::SRAM => BAD_VALUE,
-            Region::Unused0xF => BAD_VALUE,
+            Region::SRAM => self.sram_read8(addr),
+            Region::Unused0xF => bad_value,
         }
     }
 
     fn bios_read32(&self, addr: u32) -> u32 {
-        if self.allow_bios_access && addr <= 0x3FFC {
+        if addr > 0x3FFC {
+            self.bad_read(32, addr, "out of BIOS range");
+            self.open_bus
+        } else if self.allow_bios_access {
             read_u32(&*self.bios, addr as usize)
         } else {
-            self.bad_read(32, addr, "out of BIOS range or no permission");
-            self.last_code_read
+            self.bios_open_bus
         }
     }
 
     fn bios_read16(&self, addr: u32) -> u16 {
-        if self.allow_bios_access && addr <= 0x3FFE {
+        if addr > 0x3FFE {
+            self.bad_read(16, addr, "out of BIOS range");
+            halfword_of_word(self.open_bus, addr)
+        } else if self.allow_bios_access {
             read_u16(&*self.bios, addr as usize)
         } else {
-            self.bad_read(16, addr, "out of BIOS range or no permission");
-            halfword_of_word(self.last_code_read, addr)
+            halfword_of_word(self.bios_open_bus, addr)
         }
     }
 
     fn bios_read8(&self, addr: u32) -> u8 {
-        if self.allow_bios_access && addr < 0x4000 {
+        if addr > 0x3FFF {
+            self.bad_read(8, addr, "out of BIOS range");
+            byte_of_word(self.open_bus, addr)
+        } else if self.allow_bios_access {
             self.bios[addr as usize]
         } else {
-            self.bad_read(8, addr, "out of BIOS range or no permission");
-            byte_of_word(self.last_code_read, addr)
+            byte_of_word(self.bios_open_bus, addr)
         }
     }
 
     // #NOTE this function assumes that the address being passed to it is aligned to multiple of 4
     // bytes.
     fn gamepak_read32(&self, addr: u32, _display_error: bool) -> u32 {
-        let offset = addr as usize & self.gamepak_mask;
-        return unsafe { read_u32_unchecked(&*self.gamepak, offset) };
+        let offset = addr as usize & (GAMEPAK_ROM_SIZE - 1);
+        if offset < self.gamepak.len() {
+            unsafe { read_u32_unchecked(&*self.gamepak, offset) }
+        } else {
+            let lo = gamepak_open_bus(addr) as u32;
+            let hi = gamepak_open_bus(addr + 2) as u32;
+            lo | (hi << 16)
+        }
     }
 
     // #NOTE this function assumes that the address being passed to it is aligned to a multiple of
     // 2 bytes.
     fn gamepak_read16(&self, addr: u32, _display_error: bool) -> u16 {
-        let offset = addr as usize & self.gamepak_mask;
-        return unsafe { read_u16_unchecked(&*self.gamepak, offset) };
+        let offset = addr as usize & (GAMEPAK_ROM_SIZE - 1);
+        if offset < self.gamepak.len() {
+            unsafe { read_u16_unchecked(&*self.gamepak, offset) }
+        } else {
+            gamepak_open_bus(addr)
+        }
     }
 
     fn gamepak_read8(&self, addr: u32, _display_error: bool) -> u8 {
-        let offset = addr as usize & self.gamepak_mask;
-        return unsafe { read_u8_unchecked(&*self.gamepak, offset) };
+        let offset = addr as usize & (GAMEPAK_ROM_SIZE - 1);
+        if offset < self.gamepak.len() {
+            unsafe { read_u8_unchecked(&*self.gamepak, offset) }
+        } else {
+            (gamepak_open_bus(addr) >> ((addr & 1) << 3)) as u8
+        }
     }
 
     #[cold]
@@ -418,7 +548,7 @@ impl GbaHardware {
                 if display_error {
                     self.bad_read(32, addr, "invalid IO register");
                 }
-                return self.last_code_read;
+                self.open_bus
             }
         }
     }
@@ -430,7 +560,7 @@ impl GbaHardware {
             if display_error {
                 self.bad_read(16, addr, "invalid IO register");
             }
-            halfword_of_word(self.last_code_read, addr)
+            halfword_of_word(self.open_bus, addr)
         }
     }
 
@@ -456,7 +586,7 @@ impl GbaHardware {
                     if display_error {
                         self.bad_read(8, addr, "invalid IO register");
                     }
-                    byte_of_word(self.last_code_read, addr)
+                    byte_of_word(self.open_bus, addr)
                 }
             }
         }
@@ -465,27 +595,15 @@ impl GbaHardware {
     fn io_write32(&mut self, addr: u32, data: u32, display_error: bool) -> bool {
         // the address is 32-bit aligned by this point so adding 2 like this is safe.
         let offset_lo = Self::io_off(addr);
+        if offset_lo <= ioregs::BLDY {
+            self.lcd_register_write();
+        }
 
         match offset_lo {
-            ioregs::BG2X => {
-                self.lcd.registers.bg2_affine_params.set_x(data);
-                self.lcd.registers.bg2_affine_params.copy_reference_points();
-            }
-
-            ioregs::BG2Y => {
-                self.lcd.registers.bg2_affine_params.set_y(data);
-                self.lcd.registers.bg2_affine_params.copy_reference_points();
-            }
-
-            ioregs::BG3X => {
-                self.lcd.registers.bg3_affine_params.set_x(data);
-                self.lcd.registers.bg3_affine_params.copy_reference_points();
-            }
-
-            ioregs::BG3Y => {
-                self.lcd.registers.bg3_affine_params.set_y(data);
-                self.lcd.registers.bg3_affine_params.copy_reference_points();
-            }
+            ioregs::BG2X => self.lcd.registers.bg2_affine_params.set_x(data),
+            ioregs::BG2Y => self.lcd.registers.bg2_affine_params.set_y(data),
+            ioregs::BG3X => self.lcd.registers.bg3_affine_params.set_x(data),
+            ioregs::BG3Y => self.lcd.registers.bg3_affine_params.set_y(data),
 
             ioregs::DMA0SAD => {
                 self.dma.channel_mut(DMAChannelIndex::DMA0).set_source(data);
@@ -529,6 +647,7 @@ impl GbaHardware {
 
             ioregs::IMC => {
                 self.sysctl.set_imemctl(data);
+                self.invalidate_code();
             }
 
             _ => {
@@ -571,7 +690,8 @@ impl GbaHardware {
             }
 
             ioregs::HALTCNT => {
-                if (data & 1) == 0 {
+                // Bit 7 selects Stop mode instead of Halt mode.
+                if (data & 0x80) == 0 {
                     self.scheduler.schedule(GbaEvent::Halt, 0);
                 } else {
                     self.scheduler.schedule(GbaEvent::Stop, 0);
@@ -606,6 +726,15 @@ impl GbaHardware {
                 true
             }
 
+            // Writing 1s to IF acknowledges interrupts so the other byte can't be merged in like
+            // it is for the other registers.
+            0x202..=0x203 => {
+                self.irq.write_if((data as u16) << ((offset & 1) << 3));
+                self.check_keypad_irq();
+                irq::schedule_line_update(&self.scheduler);
+                true
+            }
+
             // @TODO make 8bit writes to internal memory control (0x800) possible as well
             0x000..=0x208 => {
                 let halfword_offset = offset & 0xFFFE;
@@ -653,9 +782,18 @@ impl GbaHardware {
             };
         }
 
+        if offset <= ioregs::BLDY {
+            self.lcd_register_write();
+        }
+
         match offset {
             // LCD
-            ioregs::DISPCNT => self.lcd.registers.dispcnt.value = data,
+            // bit 3 (CGB mode) can only be changed by the BIOS
+            ioregs::DISPCNT => {
+                self.lcd.registers.dispcnt.value =
+                    (self.lcd.registers.dispcnt.value & 0x0008) | (data & !0x0008)
+            }
+            ioregs::GREENSWAP => self.lcd.registers.greenswap = data & 1,
             ioregs::DISPSTAT => self.lcd.registers.set_dispstat(data),
             ioregs::BG0CNT => self.lcd.registers.bg_cnt[0].value = data,
             ioregs::BG1CNT => self.lcd.registers.bg_cnt[1].value = data,
@@ -669,44 +807,20 @@ impl GbaHardware {
             ioregs::BG1VOFS => self.lcd.registers.bg_ofs[1].y = data,
             ioregs::BG2VOFS => self.lcd.registers.bg_ofs[2].y = data,
             ioregs::BG3VOFS => self.lcd.registers.bg_ofs[3].y = data,
-            ioregs::BG2X => {
-                self.lcd.registers.bg2_affine_params.set_x_lo(data);
-                self.lcd.registers.bg2_affine_params.copy_reference_points();
-            }
-            ioregs::BG2X_HI => {
-                self.lcd.registers.bg2_affine_params.set_x_hi(data);
-                self.lcd.registers.bg2_affine_params.copy_reference_points();
-            }
-            ioregs::BG2Y => {
-                self.lcd.registers.bg2_affine_params.set_y_lo(data);
-                self.lcd.registers.bg2_affine_params.copy_reference_points();
-            }
-            ioregs::BG2Y_HI => {
-                self.lcd.registers.bg2_affine_params.set_y_hi(data);
-                self.lcd.registers.bg2_affine_params.copy_reference_points();
-            }
+            ioregs::BG2X => self.lcd.registers.bg2_affine_params.set_x_lo(data),
+            ioregs::BG2X_HI => self.lcd.registers.bg2_affine_params.set_x_hi(data),
+            ioregs::BG2Y => self.lcd.registers.bg2_affine_params.set_y_lo(data),
+            ioregs::BG2Y_HI => self.lcd.registers.bg2_affine_params.set_y_hi(data),
 
             ioregs::BG2PA => self.lcd.registers.bg2_affine_params.set_a(data),
             ioregs::BG2PB => self.lcd.registers.bg2_affine_params.set_b(data),
             ioregs::BG2PC => self.lcd.registers.bg2_affine_params.set_c(data),
             ioregs::BG2PD => self.lcd.registers.bg2_affine_params.set_d(data),
 
-            ioregs::BG3X => {
-                self.lcd.registers.bg3_affine_params.set_x_lo(data);
-                self.lcd.registers.bg3_affine_params.copy_reference_points();
-            }
-            ioregs::BG3X_HI => {
-                self.lcd.registers.bg3_affine_params.set_x_hi(data);
-                self.lcd.registers.bg3_affine_params.copy_reference_points();
-            }
-            ioregs::BG3Y => {
-                self.lcd.registers.bg3_affine_params.set_y_lo(data);
-                self.lcd.registers.bg3_affine_params.copy_reference_points();
-            }
-            ioregs::BG3Y_HI => {
-                self.lcd.registers.bg3_affine_params.set_y_hi(data);
-                self.lcd.registers.bg3_affine_params.copy_reference_points();
-            }
+            ioregs::BG3X => self.lcd.registers.bg3_affine_params.set_x_lo(data),
+            ioregs::BG3X_HI => self.lcd.registers.bg3_affine_params.set_x_hi(data),
+            ioregs::BG3Y => self.lcd.registers.bg3_affine_params.set_y_lo(data),
+            ioregs::BG3Y_HI => self.lcd.registers.bg3_affine_params.set_y_hi(data),
 
             ioregs::BG3PA => self.lcd.registers.bg3_affine_params.set_a(data),
             ioregs::BG3PB => self.lcd.registers.bg3_affine_params.set_b(data),
@@ -731,20 +845,34 @@ impl GbaHardware {
             ioregs::BLDY => self.lcd.registers.brightness = data,
 
             // Keypad Input
-            ioregs::KEYCNT => self.keypad.control = data,
+            ioregs::KEYCNT => {
+                self.keypad.control = data;
+                self.check_keypad_irq();
+            }
 
             // System Control
             ioregs::WAITCNT => self.sysctl.set_reg_waitcnt(data),
-            ioregs::IMC => self.sysctl.set_imemctl_lo(data),
+            ioregs::IMC => {
+                self.sysctl.set_imemctl_lo(data);
+                self.invalidate_code();
+            }
             ioregs::IMC_H => self.sysctl.set_imemctl_hi(data),
 
             // Interrupt Control
             ioregs::IME => {
                 self.irq.master_enable = (data & 1) != 0;
+                irq::schedule_line_update(&self.scheduler);
             }
             ioregs::IME_HI => { /* NOP */ }
-            ioregs::IE => self.irq.enabled = data,
-            ioregs::IF => self.irq.write_if(data),
+            ioregs::IE => {
+                self.irq.enabled = data;
+                irq::schedule_line_update(&self.scheduler);
+            }
+            ioregs::IF => {
+                self.irq.write_if(data);
+                self.check_keypad_irq();
+                irq::schedule_line_update(&self.scheduler);
+            }
 
             // DMA 0
             ioregs::DMA0SAD => self
@@ -870,6 +998,8 @@ impl GbaHardware {
             }
             ioregs::SOUNDBIAS => self.audio.set_sound_bias(data),
             ioregs::SOUNDBIAS_H => { /* NOT USED */ }
+            ioregs::FIFO_A | ioregs::FIFO_A_H => self.audio.write_fifo(FifoChannel::A, data),
+            ioregs::FIFO_B | ioregs::FIFO_B_H => self.audio.write_fifo(FifoChannel::B, data),
 
             // TODO figure this out some time:
             // Unused areas that are still written to I think (???):
@@ -895,15 +1025,22 @@ impl GbaHardware {
             0x20A => (),
             0x302 => (),
 
-            // TODO implement the serial comm (1) registers
-            0x120..=0x12C => {
-                warn_unimplemented!(
-                    DEBUG_SERIAL1_REG_ACCESS,
-                    "attempted to access unimplemented serial communication (1) I/O registers"
-                );
-            }
-
-            // TODO implement the serial comm (2) registers
+            // SERIAL
+            ioregs::SIOMULTI0 => self.sio.write_data(0, data),
+            ioregs::SIOMULTI1 => self.sio.write_data(1, data),
+            ioregs::SIOMULTI2 => self.sio.write_data(2, data),
+            ioregs::SIOMULTI3 => self.sio.write_data(3, data),
+            ioregs::SIOCNT => self.sio.write_control(data),
+            ioregs::SIOMLT_SEND => self.sio.write_send(data),
+            ioregs::RCNT => self.sio.write_rcnt(data),
+            ioregs::JOYCNT => self.sio.write_joycnt(data),
+            ioregs::JOY_RECV => self.sio.write_joy_receive(0, data),
+            ioregs::JOY_RECV_H => self.sio.write_joy_receive(1, data),
+            ioregs::JOY_TRANS => self.sio.write_joy_send(0, data),
+            ioregs::JOY_TRANS_H => self.sio.write_joy_send(1, data),
+            ioregs::JOYSTAT => self.sio.write_joystat(data),
+
+            // TODO implement the rest of the serial comm (2) registers
             0x134..=0x15A => {
                 warn_unimplemented!(
                     DEBUG_SERIAL2_REG_ACCESS,
@@ -932,6 +1069,7 @@ impl GbaHardware {
         match offset {
             // LCD
             ioregs::DISPCNT => Some(self.lcd.registers.dispcnt.value),
+            ioregs::GREENSWAP => Some(self.lcd.registers.greenswap),
             ioregs::DISPSTAT => Some(self.lcd.registers.dispstat.value),
             ioregs::VCOUNT => Some(self.lcd.registers.line),
             ioregs::BG0CNT => Some(self.lcd.registers.bg_cnt[0].value),
@@ -944,7 +1082,10 @@ impl GbaHardware {
             ioregs::BLDALPHA => Some(self.lcd.registers.alpha),
 
             // Keypad Input
-            ioregs::KEYINPUT => Some(self.keypad.input),
+            ioregs::KEYINPUT => {
+                self.keypad.polled.set(true);
+                Some(self.keypad.input)
+            }
             ioregs::KEYCNT => Some(self.keypad.control),
 
             // System Control
@@ -1026,16 +1167,22 @@ impl GbaHardware {
             0x20A => Some(0),
             0x302 => Some(0),
 
-            // TODO implement the serial comm (1) registers
-            0x120..=0x12C => {
-                warn_unimplemented!(
-                    DEBUG_SERIAL1_REG_ACCESS,
-                    "attempted to access unimplemented serial communication (1) I/O registers"
-                );
-                Some(0)
-            }
-
-            // TODO implement the serial comm (2) registers
+            // SERIAL
+            ioregs::SIOMULTI0 => Some(self.sio.read_data(0)),
+            ioregs::SIOMULTI1 => Some(self.sio.read_data(1)),
+            ioregs::SIOMULTI2 => Some(self.sio.read_data(2)),
+            ioregs::SIOMULTI3 => Some(self.sio.read_data(3)),
+            ioregs::SIOCNT => Some(self.sio.read_control()),
+            ioregs::SIOMLT_SEND => Some(self.sio.read_send()),
+            ioregs::RCNT => Some(self.sio.read_rcnt()),
+            ioregs::JOYCNT => Some(self.sio.read_joycnt()),
+            ioregs::JOY_RECV => Some(self.sio.read_joy_receive(0)),
+            ioregs::JOY_RECV_H => Some(self.sio.read_joy_receive(1)),
+            ioregs::JOY_TRANS => Some(self.sio.read_joy_send(0)),
+            ioregs::JOY_TRANS_H => Some(self.sio.read_joy_send(1)),
+            ioregs::JOYSTAT => Some(self.sio.read_joystat()),
+
+            // TODO implement the rest of the serial comm (2) registers
             0x134..=0x15A => {
                 warn_unimplemented!(
                     DEBUG_SERIAL2_REG_ACCESS,
@@ -1048,6 +1195,57 @@ impl GbaHardware {
         }
     }
 
+    /// Some registers change when they are read. This is only called for reads by the CPU and DMA
+    /// so that viewing the registers leaves them alone.
+    fn io_read_effects(&mut self, addr: u32, size: u16) {
+        let start = Self::io_off(addr);
+        let reads = |register: u16| start < register + 2 && register < start + size;
+
+        if reads(ioregs::SIOCNT) {
+            self.sio.uart_control_read();
+        }
+        if reads(ioregs::SIODATA8) {
+            self.sio.uart_data_read();
+        }
+        if reads(ioregs::JOY_RECV) || reads(ioregs::JOY_RECV_H) {
+            self.sio.joy_receive_read();
+        }
+    }
+
+    /// Called for every access to palette RAM, VRAM and OAM. In accurate LCD mode the CPU waits a
+    /// cycle if the LCD is using video memory and the pixels drawn so far are caught up before a
+    /// write changes them.
+    #[inline(always)]
+    fn video_memory_access(&mut self, region: Region, write: bool, cycles: &mut u32) {
+        if self.lcd.accurate() {
+            self.accurate_video_memory_access(region, write, cycles);
+        }
+    }
+
+    #[inline(never)]
+    fn accurate_video_memory_access(&mut self, region: Region, write: bool, cycles: &mut u32) {
+        if self.lcd.video_memory_busy(region) {
+            *cycles += 1;
+        }
+        if write {
+            self.catch_up_lcd();
+        }
+    }
+
+    /// Called before a write to one of the LCD registers.
+    #[inline(always)]
+    fn lcd_register_write(&mut self) {
+        if self.lcd.accurate() {
+            self.catch_up_lcd();
+        }
+    }
+
+    fn catch_up_lcd(&mut self) {
+        if let Some(dot) = self.lcd.drawing_dot() {
+            self.lcd.catch_up(dot, &self.vram, &self.oam, &self.pal);
+        }
+    }
+
     /// Converts an address into an offset into the IO registers (in the range 0x000 to 0x800)
     /// taking into account that address 0x04000800 is mirrored every 64K.
     fn io_off(addr: u32) -> u16 {
@@ -1093,30 +1291,171 @@ impl GbaHardware {
             message
         );
     }
+    /// Cycles for a word opcode fetch without the GamePak prefetch buffer.
+    #[inline]
+    fn bus_code_cycles_word(&self, addr: u32, seq: bool) -> u32 {
+        match Region::from_address(addr) {
+            Region::BIOS => 1,
+            Region::Unused0x1 => 1,
+            Region::ExternalRAM => {
+                if self.sysctl.ram_disabled || !self.sysctl.ram_external {
+                    1
+                } else {
+                    self.sysctl.ram_cycles.word.get(true) // sequential and non-sequential are the same
+                }
+            }
+            Region::InternalRAM => 1,
+            Region::IORegisters => 1,
+            Region::Palette => 2,
+            Region::VRAM => 2,
+            Region::OAM => 1,
+            Region::GamePak0Lo | Region::GamePak0Hi => self.sysctl.gamepak_cycles[0].word.get(seq),
+            Region::GamePak1Lo | Region::GamePak1Hi => self.sysctl.gamepak_cycles[1].word.get(seq),
+            Region::GamePak2Lo | Region::GamePak2Hi => self.sysctl.gamepak_cycles[2].word.get(seq),
+            Region::SRAM => self.sysctl.sram_cycles.word.get(true), // same for seq and nonseq
+            Region::Unused0xF => 1,
+        }
+    }
+
+    /// Cycles for a halfword opcode fetch without the GamePak prefetch buffer.
+    #[inline]
+    fn bus_code_cycles_halfword(&self, addr: u32, seq: bool) -> u32 {
+        match Region::from_address(addr) {
+            Region::BIOS => 1,
+            Region::Unused0x1 => 1,
+            Region::ExternalRAM => {
+                if self.sysctl.ram_disabled || !self.sysctl.ram_external {
+                    1
+                } else {
+                    self.sysctl.ram_cycles.halfword.get(true) // sequential and non-sequential are the same
+                }
+            }
+            Region::InternalRAM => 1,
+            Region::IORegisters => 1,
+            Region::Palette => 1,
+            Region::VRAM => 1,
+            Region::OAM => 1,
+            Region::GamePak0Lo | Region::GamePak0Hi => {
+                self.sysctl.gamepak_cycles[0].halfword.get(seq)
+            }
+            Region::GamePak1Lo | Region::GamePak1Hi => {
+                self.sysctl.gamepak_cycles[1].halfword.get(seq)
+            }
+            Region::GamePak2Lo | Region::GamePak2Hi => {
+                self.sysctl.gamepak_cycles[2].halfword.get(seq)
+            }
+            Region::SRAM => self.sysctl.sram_cycles.word.get(true), // same for seq and nonseq
+            Region::Unused0xF => 1,
+        }
+    }
+
+    /// Cycles for an opcode fetch of `halfwords` halfwords. Fetches from the GamePak ROM go through
+    /// the prefetch buffer if it is enabled. `bus_cycles` is what the fetch takes without it.
+    #[inline]
+    fn code_fetch_cycles(&mut self, addr: u32, seq: bool, halfwords: u32, bus_cycles: u32) -> u32 {
+        if !is_gamepak_rom(addr) {
+            self.sysctl.prefetch.stop();
+            return bus_cycles;
+        }
+
+        if self.sysctl.prefetch_enabled {
+            if seq {
+                if let Some(cycles) = self.sysctl.prefetch.fetch(addr, halfwords) {
+                    return cycles;
+                }
+            }
+            let seq_cycles = self.sysctl.gamepak_seq_cycles(addr);
+            self.sysctl
+                .prefetch
+                .restart(addr.wrapping_add(halfwords * 2), seq_cycles);
+        }
+        bus_cycles
+    }
+
+    /// Updates the open bus value after an opcode fetch from `addr`. `word` is the aligned word
+    /// that contains the opcode. In ARM state this is just the opcode but in THUMB state what is
+    /// left on the bus depends on the width of the memory that the opcode came from.
+    #[inline]
+    fn latch_opcode(&mut self, addr: u32, word: u32, thumb: bool) {
+        if self.allow_bios_access {
+            self.bios_open_bus = word;
+        }
+
+        if !thumb {
+            self.open_bus = word;
+            return;
+        }
+
+        self.open_bus = match Region::from_address(addr) {
+            // 32-bit buses get both halfwords of the word.
+            Region::BIOS | Region::OAM => word,
+
+            // IWRAM keeps the halfword from the previous fetch in the other half.
+            Region::InternalRAM if (addr & 2) == 0 => {
+                let previous = addr.wrapping_sub(2) as usize % (32 * 1024);
+                (word & 0xFFFF) | ((read_u16(&*self.iwram, previous) as u32) << 16)
+            }
+            Region::InternalRAM => word,
+
+            // 16-bit buses repeat the opcode in both halves.
+            _ => {
+                let opcode = halfword_of_word(word, addr) as u32;
+                opcode | (opcode << 16)
+            }
+        };
+    }
+
+    /// Data accesses to the GamePak take the bus away from the prefetch buffer. Any other access
+    /// leaves it free so the buffer keeps filling.
+    #[inline]
+    fn prefetch_data_access(&mut self, addr: u32, cycles: u32) {
+        if (0x08000000..=0x0FFFFFFF).contains(&addr) {
+            self.sysctl.prefetch.stop();
+        } else {
+            self.sysctl.prefetch.idle(cycles);
+        }
+    }
 }
 
 impl ArmMemory for GbaHardware {
-    fn on_internal_cycles(&mut self, _icycles: u32) {
-        /* NOP */
+    fn on_internal_cycles(&mut self, icycles: u32) {
+        self.sysctl.prefetch.idle(icycles);
     }
 
     fn read_code_word(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u32 {
-        // TODO get rid of last_code_read somehow
-        self.last_code_read = self.read_data_word(addr, seq, cycles);
-        return self.last_code_read;
+        self.allow_bios_access = addr < 0x4000;
+        let value = if is_gamepak_rom(addr) {
+            *cycles += self.code_cycles_word(addr, seq);
+            self.gamepak_read32(addr & 0xFFFFFFFC, true)
+        } else {
+            self.sysctl.prefetch.stop();
+            self.read_data_word(addr, seq, cycles)
+        };
+        self.latch_opcode(addr, value, false);
+        value
     }
 
     fn read_code_halfword(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u16 {
         // I don't rotate the value in here like I do for data because unaligned values shouldn't
         // make it in here...hopefully.
-        self.last_code_read = self.read_data_word(addr, seq, cycles);
-        halfword_of_word(self.last_code_read, addr)
+        self.allow_bios_access = addr < 0x4000;
+        let value = if is_gamepak_rom(addr) {
+            let bus_cycles = self.bus_code_cycles_halfword(addr, seq);
+            *cycles += self.code_fetch_cycles(addr, seq, 1, bus_cycles);
+            self.gamepak_read32(addr & 0xFFFFFFFC, true)
+        } else {
+            self.sysctl.prefetch.stop();
+            self.read_data_word(addr, seq, cycles)
+        };
+        self.latch_opcode(addr, value, true);
+        halfword_of_word(value, addr)
     }
 
     fn read_data_word(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u32 {
         let addr = addr & 0xFFFFFFFC; // word align the address
 
-        match Region::from_address(addr) {
+        let start = *cycles;
+        let value = match Region::from_address(addr) {
             Region::BIOS => {
                 *cycles += 1;
                 self.bios_read32(addr)
@@ -1124,13 +1463,13 @@ impl ArmMemory for GbaHardware {
             Region::Unused0x1 => {
                 *cycles += 1;
                 self.bad_read(32, addr, "unused region 0x01");
-                self.last_code_read
+                self.open_bus
             }
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
                     *cycles += 1;
                     self.bad_read(32, addr, "disabled RAM");
-                    self.last_code_read
+                    self.open_bus
                 } else if !self.sysctl.ram_external {
                     *cycles += 1;
                     read_u32(&*self.iwram, addr as usize % (32 * 1024))
@@ -1144,7 +1483,7 @@ impl ArmMemory for GbaHardware {
                 *cycles += 1;
                 if self.sysctl.ram_disabled {
                     self.bad_read(32, addr, "disabled RAM");
-                    self.last_code_read
+                    self.open_bus
                 } else {
                     read_u32(&*self.iwram, addr as usize % (32 * 1024))
                 }
@@ -1152,17 +1491,22 @@ impl ArmMemory for GbaHardware {
 
             Region::IORegisters => {
                 *cycles += 1;
-                self.io_read32(addr, true)
+                let value = self.io_read32(addr, true);
+                self.io_read_effects(addr, 4);
+                value
             }
             Region::Palette => {
+                self.video_memory_access(Region::Palette, false, cycles);
                 *cycles += 2;
                 self.pal.read32(addr as usize % (1 * 1024))
             }
             Region::VRAM => {
+                self.video_memory_access(Region::VRAM, false, cycles);
                 *cycles += 2;
                 read_u32(&*self.vram, Self::vram_off(addr))
             }
             Region::OAM => {
+                self.video_memory_access(Region::OAM, false, cycles);
                 *cycles += 1;
                 read_u32(&*self.oam, addr as usize % (1 * 1024))
             }
@@ -1185,14 +1529,17 @@ impl ArmMemory for GbaHardware {
             Region::Unused0xF => {
                 *cycles += 1;
                 self.bad_read(32, addr, "unused region 0x0F");
-                self.last_code_read
+                self.open_bus
             }
-        }
+        };
+        self.prefetch_data_access(addr, *cycles - start);
+        value
     }
 
     fn read_data_halfword(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u16 {
         let addr = addr & 0xFFFFFFFE; // halfword align the address
 
+        let start = *cycles;
         let value = match Region::from_address(addr) {
             Region::BIOS => {
                 *cycles += 1;
@@ -1201,43 +1548,48 @@ impl ArmMemory for GbaHardware {
             Region::Unused0x1 => {
                 *cycles += 1;
                 self.bad_read(16, addr, "unused region 0x01");
-                halfword_of_word(self.last_code_read, addr)
+                halfword_of_word(self.open_bus, addr)
             }
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
                     *cycles += 1;
                     self.bad_read(16, addr, "disabled RAM");
-                    halfword_of_word(self.last_code_read, addr)
-                } else if self.sysctl.ram_external {
+                    halfword_of_word(self.open_bus, addr)
+                } else if !self.sysctl.ram_external {
                     *cycles += 1;
-                    read_u16(&*self.ewram, addr as usize % (256 * 1024))
+                    read_u16(&*self.iwram, addr as usize % (32 * 1024))
                 } else {
                     *cycles += self.sysctl.ram_cycles.halfword.get(true); // same timing for seq and nonseq
-                    read_u16(&*self.iwram, addr as usize % (32 * 1024))
+                    read_u16(&*self.ewram, addr as usize % (256 * 1024))
                 }
             }
             Region::InternalRAM => {
                 *cycles += 1;
                 if self.sysctl.ram_disabled {
                     self.bad_read(16, addr, "disabled RAM");
-                    halfword_of_word(self.last_code_read, addr)
+                    halfword_of_word(self.open_bus, addr)
                 } else {
                     read_u16(&*self.iwram, addr as usize % (32 * 1024))
                 }
             }
             Region::IORegisters => {
                 *cycles += 1;
-                self.io_read16(addr, true)
+                let value = self.io_read16(addr, true);
+                self.io_read_effects(addr, 2);
+                value
             }
             Region::Palette => {
+                self.video_memory_access(Region::Palette, false, cycles);
                 *cycles += 1;
                 self.pal.read16(addr as usize % (1 * 1024))
             }
             Region::VRAM => {
+                self.video_memory_access(Region::VRAM, false, cycles);
                 *cycles += 1;
                 read_u16(&*self.vram, Self::vram_off(addr))
             }
             Region::OAM => {
+                self.video_memory_access(Region::OAM, false, cycles);
                 *cycles += 1;
                 read_u16(&*self.oam, addr as usize % (1 * 1024))
             }
@@ -1260,15 +1612,17 @@ impl ArmMemory for GbaHardware {
             Region::Unused0xF => {
                 *cycles += 1;
                 self.bad_read(16, addr, "unused region 0x0F");
-                halfword_of_word(self.last_code_read, addr)
+                halfword_of_word(self.open_bus, addr)
             }
         };
 
+        self.prefetch_data_access(addr, *cycles - start);
         value.rotate_right((addr & 1) << 3)
     }
 
     fn read_data_byte(&mut self, addr: u32, seq: bool, cycles: &mut u32) -> u8 {
-        match Region::from_address(addr) {
+        let start = *cycles;
+        let value = match Region::from_address(addr) {
             Region::BIOS => {
                 *cycles += 1;
                 self.bios_read8(addr)
@@ -1276,13 +1630,13 @@ impl ArmMemory for GbaHardware {
             Region::Unused0x1 => {
                 *cycles += 1;
                 self.bad_read(8, addr, "unused region 0x01");
-                byte_of_word(self.last_code_read, addr)
+                byte_of_word(self.open_bus, addr)
             }
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
                     *cycles += 1;
                     self.bad_read(8, addr, "disabled RAM");
-                    byte_of_word(self.last_code_read, addr)
+                    byte_of_word(self.open_bus, addr)
                 } else if !self.sysctl.ram_external {
                     *cycles += 1;
                     self.iwram[addr as usize % (32 * 1024)]
@@ -1295,24 +1649,29 @@ impl ArmMemory for GbaHardware {
                 *cycles += 1;
                 if self.sysctl.ram_disabled {
                     self.bad_read(8, addr, "disabled RAM");
-                    byte_of_word(self.last_code_read, addr)
+                    byte_of_word(self.open_bus, addr)
                 } else {
                     self.iwram[addr as usize % (32 * 1024)]
                 }
             }
             Region::IORegisters => {
                 *cycles += 1;
-                self.io_read8(addr, true)
+                let value = self.io_read8(addr, true);
+                self.io_read_effects(addr, 1);
+                value
             }
             Region::Palette => {
+                self.video_memory_access(Region::Palette, false, cycles);
                 *cycles += 1;
                 self.pal.read8(addr as usize % (1 * 1024))
             }
             Region::VRAM => {
+                self.video_memory_access(Region::VRAM, false, cycles);
                 *cycles += 1;
                 self.vram[Self::vram_off(addr)]
             }
             Region::OAM => {
+                self.video_memory_access(Region::OAM, false, cycles);
                 *cycles += 1;
                 self.oam[addr as usize % (1 * 1024)]
             }
@@ -1334,14 +1693,17 @@ impl ArmMemory for GbaHardware {
             }
             Region::Unused0xF => {
                 self.bad_read(8, addr, "unused region 0x0F");
-                byte_of_word(self.last_code_read, addr)
+                byte_of_word(self.open_bus, addr)
             }
-        }
+        };
+        self.prefetch_data_access(addr, *cycles - start);
+        value
     }
 
     fn write_data_word(&mut self, addr: u32, data: u32, seq: bool, cycles: &mut u32) {
         let addr = addr & 0xFFFFFFFC; // word align the address
 
+        let start = *cycles;
         match Region::from_address(addr) {
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
@@ -1349,10 +1711,12 @@ impl ArmMemory for GbaHardware {
                     self.bad_write(32, addr, data, "disabled RAM");
                 } else if !self.sysctl.ram_external {
                     *cycles += 1;
-                    write_u32(&mut *self.iwram, addr as usize % (32 * 1024), data)
+                    write_u32(&mut *self.iwram, addr as usize % (32 * 1024), data);
+                    self.on_ram_write(0x03000000 | (addr % (32 * 1024)));
                 } else {
                     *cycles += self.sysctl.ram_cycles.word.get(true); // same for seq and nonseq
-                    write_u32(&mut *self.ewram, addr as usize % (256 * 1024), data)
+                    write_u32(&mut *self.ewram, addr as usize % (256 * 1024), data);
+                    self.on_ram_write(0x02000000 | (addr % (256 * 1024)));
                 }
             }
             Region::InternalRAM => {
@@ -1360,7 +1724,8 @@ impl ArmMemory for GbaHardware {
                 if self.sysctl.ram_disabled {
                     self.bad_write(32, addr, data, "disabled RAM");
                 } else {
-                    write_u32(&mut *self.iwram, addr as usize % (32 * 1024), data)
+                    write_u32(&mut *self.iwram, addr as usize % (32 * 1024), data);
+                    self.on_ram_write(0x03000000 | (addr % (32 * 1024)));
                 }
             }
             Region::IORegisters => {
@@ -1368,14 +1733,17 @@ impl ArmMemory for GbaHardware {
                 self.io_write32(addr, data, true);
             }
             Region::Palette => {
+                self.video_memory_access(Region::Palette, true, cycles);
                 *cycles += 2;
                 self.pal.write32(addr as usize % (1 * 1024), data)
             }
             Region::VRAM => {
+                self.video_memory_access(Region::VRAM, true, cycles);
                 *cycles += 2;
                 write_u32(&mut *self.vram, Self::vram_off(addr), data)
             }
             Region::OAM => {
+                self.video_memory_access(Region::OAM, true, cycles);
                 *cycles += 1;
                 write_u32(&mut *self.oam, addr as usize % (1 * 1024), data)
             }
@@ -1400,11 +1768,13 @@ impl ArmMemory for GbaHardware {
                 self.bad_write(32, addr, data, "out of range memory address");
             }
         }
+        self.prefetch_data_access(addr, *cycles - start);
     }
 
     fn write_data_halfword(&mut self, addr: u32, data: u16, seq: bool, cycles: &mut u32) {
         let addr = addr & 0xFFFFFFFE; // halfword align the address
 
+        let start = *cycles;
         match Region::from_address(addr) {
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
@@ -1413,9 +1783,11 @@ impl ArmMemory for GbaHardware {
                 } else if !self.sysctl.ram_external {
                     *cycles += 1;
                     write_u16(&mut *self.iwram, addr as usize % (32 * 1024), data);
+                    self.on_ram_write(0x03000000 | (addr % (32 * 1024)));
                 } else {
                     *cycles += self.sysctl.ram_cycles.halfword.get(true); // same for seq and nonseq
                     write_u16(&mut *self.ewram, addr as usize % (256 * 1024), data);
+                    self.on_ram_write(0x02000000 | (addr % (256 * 1024)));
                 }
             }
             Region::InternalRAM => {
@@ -1423,7 +1795,8 @@ impl ArmMemory for GbaHardware {
                 if self.sysctl.ram_disabled {
                     self.bad_write(16, addr, data as u32, "disabled RAM");
                 } else {
-                    write_u16(&mut *self.iwram, addr as usize % (32 * 1024), data)
+                    write_u16(&mut *self.iwram, addr as usize % (32 * 1024), data);
+                    self.on_ram_write(0x03000000 | (addr % (32 * 1024)));
                 }
             }
             Region::IORegisters => {
@@ -1431,14 +1804,17 @@ impl ArmMemory for GbaHardware {
                 self.io_write16(addr, data, true);
             }
             Region::Palette => {
+                self.video_memory_access(Region::Palette, true, cycles);
                 *cycles += 1;
                 self.pal.write16(addr as usize % (1 * 1024), data)
             }
             Region::VRAM => {
+                self.video_memory_access(Region::VRAM, true, cycles);
                 *cycles += 1;
                 write_u16(&mut *self.vram, Self::vram_off(addr), data)
             }
             Region::OAM => {
+                self.video_memory_access(Region::OAM, true, cycles);
                 *cycles += 1;
                 write_u16(&mut *self.oam, addr as usize % (1 * 1024), data)
             }
@@ -1463,9 +1839,11 @@ impl ArmMemory for GbaHardware {
                 self.bad_write(16, addr, data as u32, "out of range memory address");
             }
         }
+        self.prefetch_data_access(addr, *cycles - start);
     }
 
     fn write_data_byte(&mut self, addr: u32, data: u8, seq: bool, cycles: &mut u32) {
+        let start = *cycles;
         match Region::from_address(addr) {
             Region::ExternalRAM => {
                 if self.sysctl.ram_disabled {
@@ -1473,10 +1851,12 @@ impl ArmMemory for GbaHardware {
                     self.bad_write(8, addr, data as u32, "disabled RAM");
                 } else if !self.sysctl.ram_external {
                     *cycles += 1;
-                    self.iwram[addr as usize % (32 * 1024)] = data
+                    self.iwram[addr as usize % (32 * 1024)] = data;
+                    self.on_ram_write(0x03000000 | (addr % (32 * 1024)));
                 } else {
                     *cycles += 1;
-                    self.ewram[addr as usize % (256 * 1024)] = data
+                    self.ewram[addr as usize % (256 * 1024)] = data;
+                    self.on_ram_write(0x02000000 | (addr % (256 * 1024)));
                 }
             }
             Region::InternalRAM => {
@@ -1484,7 +1864,8 @@ impl ArmMemory for GbaHardware {
                 if self.sysctl.ram_disabled {
                     self.bad_write(8, addr, data as u32, "disabled RAM");
                 } else {
-                    self.iwram[addr as usize % (32 * 1024)] = data
+                    self.iwram[addr as usize % (32 * 1024)] = data;
+                    self.on_ram_write(0x03000000 | (addr % (32 * 1024)));
                 }
             }
             Region::IORegisters => {
@@ -1492,6 +1873,7 @@ impl ArmMemory for GbaHardware {
                 self.io_write8(addr, data, true);
             }
             Region::Palette => {
+                self.video_memory_access(Region::Palette, true, cycles);
                 *cycles += 1;
                 // Writes to BG (6000000h-600FFFFh) (or 6000000h-6013FFFh in Bitmap mode) and to
                 // Palette (5000000h-50003FFh) are writing the new 8bit value to BOTH upper and
@@ -1502,14 +1884,22 @@ impl ArmMemory for GbaHardware {
                 );
             }
             Region::VRAM => {
+                self.video_memory_access(Region::VRAM, true, cycles);
                 *cycles += 1;
                 // Writes to BG (6000000h-600FFFFh) (or 6000000h-6013FFFh in Bitmap mode) and to
                 // Palette (5000000h-50003FFh) are writing the new 8bit value to BOTH upper and
                 // lower 8bits of the addressed halfword, ie. "[addr AND NOT 1]=data*101h".
-                if addr < 0x6014000 {
+                // Writes to OBJ VRAM are ignored.
+                let offset = Self::vram_off(addr);
+                let bg_vram_size = if self.lcd.registers.dispcnt.mode() >= 3 {
+                    0x14000
+                } else {
+                    0x10000
+                };
+                if offset < bg_vram_size {
                     write_u16(
                         &mut *self.vram,
-                        Self::vram_off(addr) & 0xFFFFFFFE,
+                        offset & 0xFFFFFFFE,
                         data as u16 * 0x101, // same as (data << 8) | data
                     );
                 } else {
@@ -1517,6 +1907,7 @@ impl ArmMemory for GbaHardware {
                 }
             }
             Region::OAM => {
+                self.video_memory_access(Region::OAM, true, cycles);
                 // 8-bit writes to OAM are ignored
                 *cycles += 1;
                 self.bad_write(8, addr, data as u32, "8-bit OAM write");
@@ -1543,6 +1934,7 @@ impl ArmMemory for GbaHardware {
                 self.bad_write(8, addr, data as u32, "out of range memory address");
             }
         }
+        self.prefetch_data_access(addr, *cycles - start);
     }
 
     fn view_word(&self, addr: u32) -> u32 {
@@ -1556,58 +1948,69 @@ impl ArmMemory for GbaHardware {
     }
 
     fn code_cycles_word(&mut self, addr: u32, seq: bool) -> u32 {
-        match Region::from_address(addr) {
-            Region::BIOS => 1,
-            Region::Unused0x1 => 1,
-            Region::ExternalRAM => {
-                if self.sysctl.ram_disabled || !self.sysctl.ram_external {
-                    1
-                } else {
-                    self.sysctl.ram_cycles.word.get(true) // sequential and non-sequential are the same
-                }
-            }
-            Region::InternalRAM => 1,
-            Region::IORegisters => 1,
-            Region::Palette => 2,
-            Region::VRAM => 2,
-            Region::OAM => 1,
-            Region::GamePak0Lo | Region::GamePak0Hi => self.sysctl.gamepak_cycles[0].word.get(seq),
-            Region::GamePak1Lo | Region::GamePak1Hi => self.sysctl.gamepak_cycles[1].word.get(seq),
-            Region::GamePak2Lo | Region::GamePak2Hi => self.sysctl.gamepak_cycles[2].word.get(seq),
-            Region::SRAM => self.sysctl.sram_cycles.word.get(true), // same for seq and nonseq
-            Region::Unused0xF => 1,
-        }
+        let bus_cycles = self.bus_code_cycles_word(addr, seq);
+        self.code_fetch_cycles(addr, seq, 2, bus_cycles)
     }
+
     fn code_cycles_halfword(&mut self, addr: u32, seq: bool) -> u32 {
+        let bus_cycles = self.bus_code_cycles_halfword(addr, seq);
+        self.code_fetch_cycles(addr, seq, 1, bus_cycles)
+    }
+
+    fn code_cacheable(&self, addr: u32) -> bool {
         match Region::from_address(addr) {
-            Region::BIOS => 1,
-            Region::Unused0x1 => 1,
+            Region::BIOS => addr < 0x4000,
+            // Writes are reported with the unmirrored address so code that runs from a mirror of
+            // EWRAM or IWRAM can't be cached.
             Region::ExternalRAM => {
-                if self.sysctl.ram_disabled || !self.sysctl.ram_external {
-                    1
-                } else {
-                    self.sysctl.ram_cycles.halfword.get(true) // sequential and non-sequential are the same
-                }
+                !self.sysctl.ram_disabled && self.sysctl.ram_external && addr < 0x02040000
             }
-            Region::InternalRAM => 1,
-            Region::IORegisters => 1,
-            Region::Palette => 1,
-            Region::VRAM => 1,
-            Region::OAM => 1,
-            Region::GamePak0Lo | Region::GamePak0Hi => {
-                self.sysctl.gamepak_cycles[0].halfword.get(seq)
-            }
-            Region::GamePak1Lo | Region::GamePak1Hi => {
-                self.sysctl.gamepak_cycles[1].halfword.get(seq)
-            }
-            Region::GamePak2Lo | Region::GamePak2Hi => {
-                self.sysctl.gamepak_cycles[2].halfword.get(seq)
-            }
-            Region::SRAM => self.sysctl.sram_cycles.word.get(true), // same for seq and nonseq
-            Region::Unused0xF => 1,
+            Region::InternalRAM => !self.sysctl.ram_disabled && addr < 0x03008000,
+            Region::GamePak0Lo
+            | Region::GamePak0Hi
+            | Region::GamePak1Lo
+            | Region::GamePak1Hi
+            | Region::GamePak2Lo
+            | Region::GamePak2Hi => true,
+
+            // Writes to VRAM, palette and OAM aren't reported to the block cache.
+            _ => false,
         }
     }
 
+    fn cached_code_cycles_word(&mut self, addr: u32, value: u32, seq: bool) -> u32 {
+        self.allow_bios_access = addr < 0x4000;
+        self.latch_opcode(addr, value, false);
+        self.code_cycles_word(addr, seq)
+    }
+
+    fn cached_code_cycles_halfword(&mut self, addr: u32, value: u32, seq: bool) -> u32 {
+        // #NOTE Outside of the GamePak ROM `read_code_halfword` does a word read so the cycles have
+        //       to match that.
+        self.allow_bios_access = addr < 0x4000;
+        self.latch_opcode(addr, value, true);
+        let bus_cycles = if is_gamepak_rom(addr) {
+            self.bus_code_cycles_halfword(addr, seq)
+        } else {
+            self.bus_code_cycles_word(addr, seq)
+        };
+        self.code_fetch_cycles(addr, seq, 1, bus_cycles)
+    }
+
+    #[cfg(feature = "jit")]
+    fn jit_budget(&self) -> u32 {
+        self.scheduler.cycles_until_next_event()
+    }
+
+    /// This does the same thing as `Gba::step` for the cycles of instructions that ran without
+    /// returning from `ArmCpu::step`. The JIT never passes enough cycles for an event to fire.
+    #[cfg(feature = "jit")]
+    fn jit_advance(&mut self, cycles: u32) {
+        self.timers.step(cycles);
+        let fired = self.scheduler.step(cycles);
+        debug_assert!(!fired, "JIT advanced past a scheduled event");
+    }
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -1629,6 +2032,19 @@ impl ArmMemory for GbaHardware {
 //     (word & !(0xFFFF << shift)) | ((value as u32) << shift)
 // }
 
+/// Reading past the end of the GamePak ROM returns the lower 16 bits of the halfword address
+/// that were left on the GamePak's shared address/data bus.
+#[inline(always)]
+fn gamepak_open_bus(addr: u32) -> u16 {
+    (addr >> 1) as u16
+}
+
+/// True for addresses in the GamePak ROM (all three wait state regions).
+#[inline(always)]
+fn is_gamepak_rom(addr: u32) -> bool {
+    (0x08000000..=0x0DFFFFFF).contains(&addr)
+}
+
 /// Select the first halfword or the second halfword a full 32-bit word depending on the given address.
 #[inline(always)]
 const fn halfword_of_word(word: u32, addr: u32) -> u16 {
@@ -0,0 +1,80 @@
+//! Logs of state hashes taken at the end of every frame. Comparing the logs of two runs (or two
+//! builds) with the same input finds the first frame in which they stopped being the same.
+
+use crate::Gba;
+use pyrite_common::StateError;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameHashLog {
+    hashes: Vec<u64>,
+}
+
+impl FrameHashLog {
+    pub fn new() -> FrameHashLog {
+        FrameHashLog { hashes: Vec::new() }
+    }
+
+    /// Hashes the current state of `gba` and adds it to the log.
+    pub fn record(&mut self, gba: &Gba) -> Result<u64, StateError> {
+        let hash = gba.state_hash()?;
+        self.hashes.push(hash);
+        Ok(hash)
+    }
+
+    pub fn hashes(&self) -> &[u64] {
+        &self.hashes
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Returns the first frame with a different hash in both logs. Frames that are only in one of
+    /// the logs are not compared.
+    pub fn first_divergence(&self, other: &FrameHashLog) -> Option<usize> {
+        self.hashes
+            .iter()
+            .zip(other.hashes.iter())
+            .position(|(a, b)| a != b)
+    }
+
+    /// Writes the log as text with one `<frame> <hash>` line per frame.
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (frame, hash) in self.hashes.iter().enumerate() {
+            writeln!(out, "{} {:016X}", frame, hash)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a log that was written by `write_to`.
+    pub fn parse(text: &str) -> Result<FrameHashLog, StateError> {
+        let mut hashes = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let bad_line = || StateError::InvalidData(format!("bad hash log line {}", index + 1));
+            let mut parts = line.split_whitespace();
+            let frame: usize = parts
+                .next()
+                .and_then(|frame| frame.parse().ok())
+                .ok_or_else(bad_line)?;
+            let hash = parts
+                .next()
+                .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+                .ok_or_else(bad_line)?;
+            if frame != hashes.len() || parts.next().is_some() {
+                return Err(bad_line());
+            }
+            hashes.push(hash);
+        }
+        Ok(FrameHashLog { hashes })
+    }
+}
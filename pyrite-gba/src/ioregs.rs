@@ -61,7 +61,9 @@ pub const SOUNDCNT_X_H: u16 = 0x0086;
 pub const SOUNDBIAS: u16 = 0x0088;
 pub const SOUNDBIAS_H: u16 = 0x008A;
 pub const FIFO_A: u16 = 0x00A0;
+pub const FIFO_A_H: u16 = 0x00A2;
 pub const FIFO_B: u16 = 0x00A4;
+pub const FIFO_B_H: u16 = 0x00A6;
 
 // Sound Registers (Using NR names)
 pub const NR10: u16 = 0x0060;
@@ -145,7 +147,9 @@ pub const RCNT: u16 = 0x0134;
 pub const IR: u16 = 0x0136;
 pub const JOYCNT: u16 = 0x0140;
 pub const JOY_RECV: u16 = 0x0150;
+pub const JOY_RECV_H: u16 = 0x0152;
 pub const JOY_TRANS: u16 = 0x0154;
+pub const JOY_TRANS_H: u16 = 0x0156;
 pub const JOYSTAT: u16 = 0x0158;
 
 // Interrupt, Waitstate, and Power-Down Control
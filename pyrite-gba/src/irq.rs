@@ -1,5 +1,25 @@
 use crate::dma::DMAChannelIndex;
+use crate::scheduler::{GbaEvent, SharedGbaScheduler};
 use crate::timers::TimerIndex;
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+const IRQ_STATE_VERSION: u32 = 1;
+
+/// Cycles between a change to IF, IE or IME and the CPU seeing the new state of the IRQ line.
+pub(crate) const IRQ_DELAY: u32 = 7;
+
+/// The interrupts that can end Stop mode. Everything else is turned off in Stop mode.
+pub(crate) const STOP_WAKE_INTERRUPTS: u16 =
+    Interrupt::SerialCommunication.mask() | Interrupt::Keypad.mask() | Interrupt::GamePak.mask();
+
+/// Lets the CPU see the state of IF, IE and IME after `IRQ_DELAY` cycles. The update reads the
+/// registers when it happens so one that is already on its way picks up this change as well and
+/// no other one is scheduled.
+pub(crate) fn schedule_line_update(scheduler: &SharedGbaScheduler) {
+    if scheduler.cycles_until(GbaEvent::IRQLine).is_none() {
+        scheduler.schedule(GbaEvent::IRQLine, IRQ_DELAY);
+    }
+}
 
 pub struct GbaInterruptControl {
     /// (IME Register) Interrupt master enable bit
@@ -8,7 +28,7 @@ pub struct GbaInterruptControl {
     /// Bits representing enabled interrupts. See `Interrupt`.
     pub(crate) enabled: u16,
 
-    /// (IE Register) Request / Acknowledge interrupt bits.
+    /// (IF Register) Request / Acknowledge interrupt bits.
     request_ack: u16,
 }
 
@@ -21,6 +41,21 @@ impl GbaInterruptControl {
         }
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(IRQ_STATE_VERSION);
+        state.write_bool(self.master_enable);
+        state.write_u16(self.enabled);
+        state.write_u16(self.request_ack);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_version("IRQ", IRQ_STATE_VERSION)?;
+        self.master_enable = state.read_bool()?;
+        self.enabled = state.read_u16()?;
+        self.request_ack = state.read_u16()?;
+        Ok(())
+    }
+
     pub(crate) fn read_if(&self) -> u16 {
         self.request_ack
     }
@@ -31,19 +66,22 @@ impl GbaInterruptControl {
         self.request_ack &= !value;
     }
 
-    /// Requests an interrupt. Returns true if the interrupt was enabled and the request was
-    /// successfully made (IRQ request flag set).
-    pub(crate) fn request(&mut self, interrupt: Interrupt) -> bool {
-        if self.master_enable && self.is_enabled(interrupt) {
+    /// Requests an interrupt. The request flag in IF is set even if the interrupt is not enabled
+    /// in IE or IME is off, which is how games can poll for interrupts.
+    pub(crate) fn request(&mut self, interrupt: Interrupt) {
+        if interrupt != Interrupt::None {
             self.request_ack |= interrupt.mask();
-            return true;
-        } else {
-            return false;
         }
     }
 
-    pub(crate) fn is_enabled(&self, interrupt: Interrupt) -> bool {
-        (self.enabled & interrupt.mask()) != 0
+    /// The interrupts that are both requested and enabled.
+    pub(crate) fn pending(&self) -> u16 {
+        self.enabled & self.request_ack
+    }
+
+    /// Returns true if the IRQ line going to the CPU is asserted.
+    pub(crate) fn irq_line(&self) -> bool {
+        self.master_enable && self.pending() != 0
     }
 }
 
@@ -73,6 +111,27 @@ impl Interrupt {
         1 << (self as u8 as u16)
     }
 
+    pub fn from_index(index: u8) -> Option<Interrupt> {
+        match index {
+            0 => Some(Interrupt::LCDVBlank),
+            1 => Some(Interrupt::LCDHBlank),
+            2 => Some(Interrupt::LCDVCounterMatch),
+            3 => Some(Interrupt::Timer0Overflow),
+            4 => Some(Interrupt::Timer1Overflow),
+            5 => Some(Interrupt::Timer2Overflow),
+            6 => Some(Interrupt::Timer3Overflow),
+            7 => Some(Interrupt::SerialCommunication),
+            8 => Some(Interrupt::DMA0),
+            9 => Some(Interrupt::DMA1),
+            10 => Some(Interrupt::DMA2),
+            11 => Some(Interrupt::DMA3),
+            12 => Some(Interrupt::Keypad),
+            13 => Some(Interrupt::GamePak),
+            14 => Some(Interrupt::None),
+            _ => None,
+        }
+    }
+
     pub fn timer(timer_index: TimerIndex) -> Interrupt {
         match timer_index {
             TimerIndex::TM0 => Interrupt::Timer0Overflow,
@@ -1,6 +1,20 @@
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::cell::Cell;
+
+const KEYPAD_STATE_VERSION: u32 = 1;
+
+const KEYCNT_KEYS: u16 = 0x03FF;
+const KEYCNT_IRQ: u16 = 0x4000;
+
+/// Set if all of the selected keys have to be pressed instead of any one of them.
+const KEYCNT_AND: u16 = 0x8000;
+
 pub struct GbaKeypad {
     pub input: u16,
     pub control: u16,
+
+    /// Set when KEYINPUT is read. Frames in which it isn't are lag frames.
+    pub(crate) polled: Cell<bool>,
 }
 
 impl GbaKeypad {
@@ -8,21 +22,55 @@ impl GbaKeypad {
         GbaKeypad {
             input: 0x03FF,
             control: 0x0000,
+            polled: Cell::new(false),
         }
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(KEYPAD_STATE_VERSION);
+        state.write_u16(self.input);
+        state.write_u16(self.control);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_version("keypad", KEYPAD_STATE_VERSION)?;
+        self.input = state.read_u16()?;
+        self.control = state.read_u16()?;
+        Ok(())
+    }
+
     #[inline]
     pub fn is_pressed(&self, input: KeypadInput) -> bool {
         self.input & (input.mask()) == 0
     }
 
+    /// Returns true if the input changed.
     #[inline]
-    pub fn set_pressed(&mut self, input: KeypadInput, pressed: bool) {
+    pub fn set_pressed(&mut self, input: KeypadInput, pressed: bool) -> bool {
+        let old_input = self.input;
         if pressed {
             self.input &= !input.mask();
         } else {
             self.input |= input.mask();
         }
+        self.input != old_input
+    }
+
+    /// Returns true if KEYCNT asks for a keypad interrupt with the keys that are pressed right
+    /// now.
+    pub fn irq_condition(&self) -> bool {
+        if (self.control & KEYCNT_IRQ) == 0 {
+            return false;
+        }
+
+        let selected = self.control & KEYCNT_KEYS;
+        let pressed = !self.input & selected;
+        if (self.control & KEYCNT_AND) != 0 {
+            // #NOTE With no keys selected the condition is never met.
+            selected != 0 && pressed == selected
+        } else {
+            pressed != 0
+        }
     }
 }
 
@@ -42,6 +90,19 @@ pub enum KeypadInput {
 }
 
 impl KeypadInput {
+    pub const ALL: [KeypadInput; 10] = [
+        KeypadInput::ButtonA,
+        KeypadInput::ButtonB,
+        KeypadInput::Select,
+        KeypadInput::Start,
+        KeypadInput::Right,
+        KeypadInput::Left,
+        KeypadInput::Up,
+        KeypadInput::Down,
+        KeypadInput::ButtonR,
+        KeypadInput::ButtonL,
+    ];
+
     #[inline]
     pub fn mask(self) -> u16 {
         1 << (self as u16)
@@ -158,7 +158,7 @@ fn render_mode5_bitmap(
     second_target: bool,
     pixels: &mut LCDLineBuffer,
 ) {
-    assert!(line < 160);
+    assert!(line < 128);
 
     let pflags = Pixel::layer_mask(Layer::BG2)
         | (if first_target { Pixel::FIRST_TARGET } else { 0 })
@@ -168,7 +168,8 @@ fn render_mode5_bitmap(
             0
         });
 
-    let line_offset = 480 * line;
+    // mode 5 frames are only 160 pixels wide:
+    let line_offset = 320 * line;
     for x in 0..160 {
         let pixel_metadata = if pixels.windows.enabled {
             if let Some(window_effects_mask) = pixels.windows.check_pixel(Layer::BG2, x) {
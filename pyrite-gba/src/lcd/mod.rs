@@ -1,20 +1,24 @@
 pub mod bitmap;
 pub mod obj;
 pub mod palette;
+pub mod reference;
+pub mod renderer;
 pub mod tile;
 
 pub const WINOUT: u16 = 0;
 pub const WINOBJ: u16 = 1;
 
 use self::palette::GbaPalette;
+use self::renderer::{LineRenderer, LineSnapshot, ScanlineRenderer};
 use crate::dma::GbaDMA;
-use crate::hardware::{OAM, VRAM};
+use crate::hardware::{Region, OAM, VRAM};
 use crate::irq::Interrupt;
 use crate::scheduler::{GbaEvent, SharedGbaScheduler};
 use crate::util::fixedpoint::{FixedPoint16, FixedPoint32};
 use crate::util::CBool;
 use crate::GbaVideoOutput;
 use pyrite_common::bits;
+use pyrite_common::{StateError, StateReader, StateWriter};
 
 pub const OBJ_LAYER: u16 = 4;
 pub const BD_LAYER: u16 = 5;
@@ -22,26 +26,146 @@ pub const BD_LAYER: u16 = 5;
 pub const HDRAW_CYCLES: u32 = 960;
 pub const HBLANK_CYCLES: u32 = 272;
 
+/// Cycles between the last pixel of a line and the start of HBlank (the HBlank flag in DISPSTAT,
+/// the HBlank IRQ and HBlank DMA) in accurate mode. The flag is set at cycle 1006 of a line on
+/// hardware.
+pub const HBLANK_DELAY_CYCLES: u32 = 46;
+
+/// Cycles that it takes to draw one pixel.
+const CYCLES_PER_DOT: u32 = 4;
+
+const LCD_STATE_VERSION: u32 = 2;
+
 pub struct GbaLCD {
     pub(crate) registers: LCDRegisters,
-    pub(crate) pixels: LCDLineBuffer,
     scheduler: SharedGbaScheduler,
+    renderer: Box<dyn LineRenderer>,
+    rendered: [u16; 240],
+
+    /// In accurate mode the pixels of a line are drawn in step with the dot clock instead of all
+    /// at once in HBlank. This is a setting and not part of the save state.
+    accurate: bool,
+
+    /// The pixels of the current line that have been drawn so far in accurate mode.
+    line: [u16; 240],
+    drawn: usize,
 }
 
 impl GbaLCD {
     pub fn new(scheduler: SharedGbaScheduler) -> GbaLCD {
         GbaLCD {
             registers: LCDRegisters::default(),
-            pixels: LCDLineBuffer::new(),
             scheduler: scheduler,
+            renderer: Box::new(ScanlineRenderer::new()),
+            rendered: [0; 240],
+            accurate: false,
+            line: [0; 240],
+            drawn: 0,
         }
     }
 
+    /// Switches between drawing whole lines in HBlank and drawing pixels in step with the dot
+    /// clock. Accurate mode shows writes to the LCD registers, palette, VRAM and OAM in the middle
+    /// of a line, sets the HBlank flag at the right time and makes the CPU wait for the LCD when
+    /// they both access video memory. The change takes effect with the next line.
+    pub fn set_accurate(&mut self, accurate: bool) {
+        self.accurate = accurate;
+    }
+
+    #[inline(always)]
+    pub fn accurate(&self) -> bool {
+        self.accurate
+    }
+
+    /// Replaces the renderer that turns lines into pixels and returns the previous one.
+    pub fn set_renderer(&mut self, renderer: Box<dyn LineRenderer>) -> Box<dyn LineRenderer> {
+        std::mem::replace(&mut self.renderer, renderer)
+    }
+
+    /// The pixel that the LCD is drawing right now or `None` if it isn't drawing (in HBlank or
+    /// VBlank or outside of accurate mode). Past the last pixel of a line this is 240 until HBlank
+    /// starts.
+    pub(crate) fn drawing_dot(&self) -> Option<usize> {
+        if !self.accurate || self.registers.line >= 160 || self.registers.dispstat.hblank() {
+            return None;
+        }
+        let remaining = self.scheduler.cycles_until(GbaEvent::HBlank)?;
+        let elapsed = (HDRAW_CYCLES + HBLANK_DELAY_CYCLES).saturating_sub(remaining);
+        Some(std::cmp::min(240, (elapsed / CYCLES_PER_DOT) as usize))
+    }
+
+    /// Returns true if the CPU has to wait for the LCD to access palette RAM, VRAM or OAM
+    /// (`region`). Palette RAM and VRAM are only used while a line is drawn. The objects are read
+    /// from OAM during HBlank as well unless DISPCNT has the "H-Blank interval free" bit set.
+    #[inline]
+    pub(crate) fn video_memory_busy(&self, region: Region) -> bool {
+        if !self.accurate || self.registers.dispcnt.forced_blank() {
+            return false;
+        }
+
+        match region {
+            Region::OAM => {
+                self.registers.line < 160
+                    && self.registers.dispcnt.display_layer(Layer::OBJ)
+                    && (!self.registers.dispcnt.hblank_interval_free()
+                        || self.drawing_dot().is_some())
+            }
+            _ => self.drawing_dot().is_some(),
+        }
+    }
+
+    /// Draws the pixels of the current line up to (not including) `dot` with the current state
+    /// of the registers and video memory. This has to be called in accurate mode before anything
+    /// that changes what the line looks like.
+    pub(crate) fn catch_up(&mut self, dot: usize, vram: &VRAM, oam: &OAM, palette: &GbaPalette) {
+        if dot <= self.drawn {
+            return;
+        }
+
+        // #NOTE The whole line is drawn again and only the new pixels are kept. This is slow but
+        //       mid-line writes are rare and it means that the normal renderers can be used.
+        self.render_line(vram, oam, palette);
+        self.line[self.drawn..dot].copy_from_slice(&self.rendered[self.drawn..dot]);
+        self.drawn = dot;
+    }
+
+    /// The pixels of the current line that were drawn in accurate mode are saved as well so that
+    /// a line doesn't change when a state is loaded in the middle of it.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(LCD_STATE_VERSION);
+        self.registers.save_state(state);
+        for &pixel in self.line.iter() {
+            state.write_u16(pixel);
+        }
+        state.write_u32(self.drawn as u32);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let version = state.read_version("LCD", LCD_STATE_VERSION)?;
+        self.registers.load_state(state)?;
+        if version >= 2 {
+            for pixel in self.line.iter_mut() {
+                *pixel = state.read_u16()?;
+            }
+            self.drawn = std::cmp::min(240, state.read_u32()? as usize);
+        } else {
+            self.line = [0; 240];
+            self.drawn = 0;
+        }
+        Ok(())
+    }
+
     pub fn hdraw(&mut self, dma: &mut GbaDMA) {
-        self.scheduler.schedule(GbaEvent::HBlank, HBLANK_CYCLES);
+        if self.accurate {
+            self.scheduler
+                .schedule(GbaEvent::HBlank, HDRAW_CYCLES + HBLANK_DELAY_CYCLES);
+        } else {
+            self.scheduler.schedule(GbaEvent::HBlank, HBLANK_CYCLES);
+        }
 
         self.registers.dispstat.set_hblank(false);
         self.registers.line += 1;
+        self.drawn = 0;
 
         match self.registers.line {
             160 => {
@@ -78,23 +202,43 @@ impl GbaLCD {
         video: &mut dyn GbaVideoOutput,
         dma: &mut GbaDMA,
     ) -> bool {
-        self.scheduler.schedule(GbaEvent::HDraw, HDRAW_CYCLES);
+        if self.accurate {
+            self.scheduler
+                .schedule(GbaEvent::HDraw, HBLANK_CYCLES - HBLANK_DELAY_CYCLES);
+        } else {
+            self.scheduler.schedule(GbaEvent::HDraw, HDRAW_CYCLES);
+        }
 
         if self.registers.dispstat.hblank_irq_enable() {
             self.scheduler
                 .schedule(GbaEvent::IRQ(Interrupt::LCDHBlank), 0);
         }
         self.registers.dispstat.set_hblank(true);
+        dma.video_capture(self.registers.line);
 
         if self.registers.line < 160 {
             dma.start_hblank(); // NOTE: this does not occure during VBLANK
             if self.registers.line == 0 {
                 video.pre_frame();
             }
-            self.draw_line(vram, oam);
-            self.pixels
-                .mix(palette, self.registers.effects.effect(), &self.registers);
-            video.display_line(self.registers.line as u32, &self.pixels.mixed);
+            if self.accurate {
+                self.catch_up(240, vram, oam, palette);
+                video.display_line(self.registers.line as u32, &self.line);
+            } else {
+                self.render_line(vram, oam, palette);
+                video.display_line(self.registers.line as u32, &self.rendered);
+            }
+
+            // The affine backgrounds move on to the next line once the whole line was drawn. This
+            // happens in every mode so a background that is turned on in the middle of a frame
+            // starts from the right line.
+            self.registers
+                .bg2_affine_params
+                .increment_reference_points();
+            self.registers
+                .bg3_affine_params
+                .increment_reference_points();
+
             if self.registers.line == 159 {
                 video.post_frame();
             }
@@ -103,105 +247,37 @@ impl GbaLCD {
         return self.registers.line == 159;
     }
 
-    fn draw_line(&mut self, vram: &VRAM, oam: &OAM) {
-        // setting up the backdrop:
-        let backdrop = Pixel(Pixel::layer_mask(Layer::Backdrop) | 0);
-        self.pixels.clear(backdrop);
-
-        let mode = self.registers.dispcnt.mode();
-        self.pixels.windows.enabled = self.registers.dispcnt.windows_enabled();
-        self.pixels.windows.win0_enabled = self.registers.dispcnt.display_window0();
-        self.pixels.windows.win1_enabled = self.registers.dispcnt.display_window1();
-        self.pixels.windows.win_obj_enabled = self.registers.dispcnt.display_window_obj();
-        self.pixels.windows.clear_pixel_bits();
-
-        self.draw_objects_and_windows(vram, oam);
-
-        match mode {
-            0 => tile::render_mode0(&self.registers, vram, &mut self.pixels),
-            1 => tile::render_mode1(&mut self.registers, vram, &mut self.pixels),
-            2 => tile::render_mode2(&mut self.registers, vram, &mut self.pixels),
-            3 => bitmap::render_mode3(&self.registers, vram, &mut self.pixels),
-            4 => bitmap::render_mode4(&self.registers, vram, &mut self.pixels),
-            5 => bitmap::render_mode5(&self.registers, vram, &mut self.pixels),
-            _ => log::warn!("bad mode {}", mode),
+    fn render_line(&mut self, vram: &VRAM, oam: &OAM, palette: &GbaPalette) {
+        // The LCD outputs white during forced blank and doesn't touch video memory at all:
+        if self.registers.dispcnt.forced_blank() {
+            self.rendered = [0xFFFF; 240];
+            return;
         }
-    }
 
-    fn draw_objects_and_windows(&mut self, vram: &VRAM, oam: &OAM) {
-        let render_objects = self.registers.dispcnt.display_layer(Layer::OBJ);
-
-        // setup obj cycles:
-        self.pixels.obj_cycles = if self.registers.dispcnt.hblank_interval_free() {
-            954
-        } else {
-            1210
+        let snapshot = LineSnapshot {
+            registers: &self.registers,
+            vram,
+            oam,
+            palette,
         };
-
-        let object_priorities = obj::ObjectPriority::sorted(oam);
-
-        if is_bitmap_mode(self.registers.dispcnt.mode()) {
-            if render_objects && self.registers.dispcnt.display_window_obj() {
-                obj::process_objs::<obj::OBJWindow, obj::BitmapMode>(
-                    &self.registers,
-                    object_priorities.window_objects(),
-                    vram,
-                    oam,
-                    &mut self.pixels,
-                );
-            }
-
-            self.pixels.windows.calculate_masks(
-                self.registers.line,
-                self.registers.win0_bounds,
-                self.registers.win1_bounds,
-                self.registers.winin,
-                self.registers.winout,
-                self.registers.dispcnt,
-            );
-
-            if render_objects {
-                obj::process_objs::<obj::OBJRender, obj::BitmapMode>(
-                    &self.registers,
-                    object_priorities.visible_objects(),
-                    vram,
-                    oam,
-                    &mut self.pixels,
-                );
-            }
-        } else {
-            if render_objects && self.registers.dispcnt.display_window_obj() {
-                obj::process_objs::<obj::OBJWindow, obj::TileMode>(
-                    &self.registers,
-                    object_priorities.window_objects(),
-                    vram,
-                    oam,
-                    &mut self.pixels,
-                );
-            }
-
-            self.pixels.windows.calculate_masks(
-                self.registers.line,
-                self.registers.win0_bounds,
-                self.registers.win1_bounds,
-                self.registers.winin,
-                self.registers.winout,
-                self.registers.dispcnt,
-            );
-
-            if render_objects {
-                obj::process_objs::<obj::OBJRender, obj::TileMode>(
-                    &self.registers,
-                    object_priorities.visible_objects(),
-                    vram,
-                    oam,
-                    &mut self.pixels,
-                );
-            }
+        self.renderer.render_line(&snapshot, &mut self.rendered);
+        if self.registers.greenswap & 1 != 0 {
+            green_swap(&mut self.rendered);
         }
     }
 }
 
+/// Swaps the green components of every pair of pixels on a line (0 and 1, 2 and 3 and so on)
+/// for GREENSWAP. Red and blue stay where they are.
+fn green_swap(line: &mut [u16; 240]) {
+    const GREEN: u16 = 0x03E0;
+    for pair in line.chunks_exact_mut(2) {
+        let (left, right) = (pair[0], pair[1]);
+        pair[0] = (left & !GREEN) | (right & GREEN);
+        pair[1] = (right & !GREEN) | (left & GREEN);
+    }
+}
+
 pub struct LCDLineBuffer {
     mixed: [u16; 240],
     unmixed: [Pixels2; 240],
@@ -689,7 +765,7 @@ pub struct LCDRegisters {
     /// LCD status register.
     pub dispstat: DisplayStatus,
 
-    // @TODO implement whatever this is.
+    /// Undocumented green swap register. Only bit 0 is used.
     pub greenswap: u16,
 
     // Background Control Registers:
@@ -714,6 +790,65 @@ pub struct LCDRegisters {
 }
 
 impl LCDRegisters {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.line);
+        state.write_u16(self.dispcnt.value);
+        state.write_u16(self.dispstat.value);
+        state.write_u16(self.greenswap);
+        for bg in 0..4 {
+            state.write_u16(self.bg_cnt[bg].value);
+            state.write_u16(self.bg_ofs[bg].x);
+            state.write_u16(self.bg_ofs[bg].y);
+        }
+        self.bg2_affine_params.save_state(state);
+        self.bg3_affine_params.save_state(state);
+        for bounds in [self.win0_bounds, self.win1_bounds].iter() {
+            state.write_u16(bounds.left);
+            state.write_u16(bounds.top);
+            state.write_u16(bounds.right);
+            state.write_u16(bounds.bottom);
+        }
+        state.write_u16(self.winin.value());
+        state.write_u16(self.winout.value());
+        state.write_u8(self.mosaic.bg.0);
+        state.write_u8(self.mosaic.bg.1);
+        state.write_u8(self.mosaic.obj.0);
+        state.write_u8(self.mosaic.obj.1);
+        state.write_u16(self.effects.value());
+        state.write_u16(self.alpha);
+        state.write_u16(self.brightness);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.line = state.read_u16()?;
+        self.dispcnt.value = state.read_u16()?;
+        self.dispstat.value = state.read_u16()?;
+        self.greenswap = state.read_u16()?;
+        for bg in 0..4 {
+            self.bg_cnt[bg].value = state.read_u16()?;
+            self.bg_ofs[bg].x = state.read_u16()?;
+            self.bg_ofs[bg].y = state.read_u16()?;
+        }
+        self.bg2_affine_params.load_state(state)?;
+        self.bg3_affine_params.load_state(state)?;
+        for bounds in [&mut self.win0_bounds, &mut self.win1_bounds].iter_mut() {
+            bounds.left = state.read_u16()?;
+            bounds.top = state.read_u16()?;
+            bounds.right = state.read_u16()?;
+            bounds.bottom = state.read_u16()?;
+        }
+        self.winin.set_value(state.read_u16()?);
+        self.winout.set_value(state.read_u16()?);
+        self.mosaic.bg.0 = state.read_u8()?;
+        self.mosaic.bg.1 = state.read_u8()?;
+        self.mosaic.obj.0 = state.read_u8()?;
+        self.mosaic.obj.1 = state.read_u8()?;
+        self.effects.set_value(state.read_u16()?);
+        self.alpha = state.read_u16()?;
+        self.brightness = state.read_u16()?;
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn set_dispstat(&mut self, value: u16) {
         pub const DISPSTAT_WRITEABLE: u16 = 0xFFB8;
@@ -1117,7 +1252,7 @@ impl BGOffset {
     }
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct AffineBGParams {
     pub internal_x: FixedPoint32,
     pub internal_y: FixedPoint32,
@@ -1131,7 +1266,59 @@ pub struct AffineBGParams {
     pub y: FixedPoint32,
 }
 
+impl Default for AffineBGParams {
+    /// PA and PD start out as 1.0 (0x0100) so the background isn't scaled until they are written.
+    fn default() -> Self {
+        let one = FixedPoint32::from(FixedPoint16::wrap(0x0100));
+        AffineBGParams {
+            internal_x: FixedPoint32::default(),
+            internal_y: FixedPoint32::default(),
+            a: one,
+            b: FixedPoint32::default(),
+            c: FixedPoint32::default(),
+            d: one,
+            x: FixedPoint32::default(),
+            y: FixedPoint32::default(),
+        }
+    }
+}
+
 impl AffineBGParams {
+    fn save_state(&self, state: &mut StateWriter) {
+        for value in [
+            self.internal_x,
+            self.internal_y,
+            self.a,
+            self.b,
+            self.c,
+            self.d,
+            self.x,
+            self.y,
+        ]
+        .iter()
+        {
+            state.write_u32(value.to_inner() as u32);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for value in [
+            &mut self.internal_x,
+            &mut self.internal_y,
+            &mut self.a,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.x,
+            &mut self.y,
+        ]
+        .iter_mut()
+        {
+            **value = FixedPoint32::wrap(state.read_u32()? as i32);
+        }
+        Ok(())
+    }
+
     /// Copies the reference point registers into the internal reference point registers.
     pub fn copy_reference_points(&mut self) {
         self.internal_x = self.x;
@@ -1143,17 +1330,24 @@ impl AffineBGParams {
         self.internal_y += self.d; // increment by dmy
     }
 
+    // #NOTE Writing to a reference point register also copies it into its internal register right
+    // away, even in the middle of a frame. Only the axis that was written to is copied so a write
+    // to X doesn't throw away the lines that Y has already been moved by.
+
     pub fn set_x(&mut self, value: u32) {
         self.x = FixedPoint32::wrap(((value as i32) << 4) >> 4);
+        self.internal_x = self.x;
     }
 
     pub fn set_y(&mut self, value: u32) {
         self.y = FixedPoint32::wrap(((value as i32) << 4) >> 4);
+        self.internal_y = self.y;
     }
 
     pub fn set_x_lo(&mut self, value: u16) {
         let raw_x = (self.x.to_inner() & 0xFFFF0000u32 as i32) | (value as i32);
         self.x = FixedPoint32::wrap(raw_x);
+        self.internal_x = self.x;
     }
 
     pub fn set_x_hi(&mut self, value: u16) {
@@ -1164,6 +1358,7 @@ impl AffineBGParams {
     pub fn set_y_lo(&mut self, value: u16) {
         let raw_y = (self.y.to_inner() & 0xFFFF0000u32 as i32) | (value as i32);
         self.y = FixedPoint32::wrap(raw_y);
+        self.internal_y = self.y;
     }
 
     pub fn set_y_hi(&mut self, value: u16) {
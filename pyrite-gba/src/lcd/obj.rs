@@ -244,11 +244,13 @@ pub fn process_objs<IsOBJWindowMode: CBool, IsBitmapMode: CBool>(
                         apply_mosaic_cond(attrs.0.mosaic(), obj_y.integer() as u16, mosaic_y)
                             as usize;
 
+                    // 256 color tiles are twice as large so there are only 512 of them in the
+                    // 32K of object VRAM and only the last 256 can be used in the bitmap modes.
                     let tile = (((attrs.2.first_tile_index() / 2) as usize)
                         + ((obj_y_i / 8) * tile_stride)
                         + (obj_x_i / 8))
-                        & 0x3FF;
-                    if !IsBitmapMode::BOOL || tile >= 512 {
+                        & 0x1FF;
+                    if !IsBitmapMode::BOOL || tile >= 256 {
                         let pixel_offset = (tile * BYTES_PER_TILE)
                             + ((obj_y_i % 8) * BYTES_PER_LINE)
                             + (obj_x_i % 8);
@@ -293,20 +295,22 @@ pub fn process_objs<IsOBJWindowMode: CBool, IsBitmapMode: CBool>(
                         + ((obj_y_i / 8) * tile_stride)
                         + (obj_x_i / 8))
                         & 0x3FF;
-                    let pixel_offset = (tile * BYTES_PER_TILE)
-                        + ((obj_y_i % 8) * BYTES_PER_LINE)
-                        + (obj_x_i % 8) / 2;
-                    let palette_entry =
-                        (tile_data[pixel_offset as usize] >> ((obj_x_i % 2) << 2)) & 0xF;
-
-                    if palette_entry != 0 {
-                        if IsOBJWindowMode::BOOL {
-                            pixels.windows.winobj.set(obj_screen_draw);
-                        } else {
-                            pixels.push_obj_pixel(
-                                obj_screen_draw,
-                                Pixel(pflags | (palette_entry as u16)),
-                            );
+                    if !IsBitmapMode::BOOL || tile >= 512 {
+                        let pixel_offset = (tile * BYTES_PER_TILE)
+                            + ((obj_y_i % 8) * BYTES_PER_LINE)
+                            + (obj_x_i % 8) / 2;
+                        let palette_entry =
+                            (tile_data[pixel_offset as usize] >> ((obj_x_i % 2) << 2)) & 0xF;
+
+                        if palette_entry != 0 {
+                            if IsOBJWindowMode::BOOL {
+                                pixels.windows.winobj.set(obj_screen_draw);
+                            } else {
+                                pixels.push_obj_pixel(
+                                    obj_screen_draw,
+                                    Pixel(pflags | (palette_entry as u16)),
+                                );
+                            }
                         }
                     }
                 }
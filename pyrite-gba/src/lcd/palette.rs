@@ -1,3 +1,5 @@
+use pyrite_common::{StateError, StateReader, StateWriter};
+
 pub struct GbaPalette {
     bg: [u16; 256],
     obj: [u16; 256],
@@ -31,6 +33,19 @@ impl GbaPalette {
         return self.obj[palette * 16 + index] | 0x8000;
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        for &color in self.bg.iter().chain(self.obj.iter()) {
+            state.write_u16(color);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for color in self.bg.iter_mut().chain(self.obj.iter_mut()) {
+            *color = state.read_u16()?;
+        }
+        Ok(())
+    }
+
     pub fn write32(&mut self, offset: usize, value: u32) {
         self.write16(offset, value as u16);
         self.write16(offset + 2, (value >> 16) as u16);
@@ -0,0 +1,503 @@
+use super::obj::{ObjAttr0, ObjAttr1, ObjAttr2, ObjMode};
+use super::renderer::{LineRenderer, LineSnapshot};
+use super::{LCDLineBuffer, LCDRegisters, Layer, SpecialEffect, Window};
+use crate::util::memory::read_u16;
+use pyrite_common::bits;
+
+/// A renderer that works out every pixel on its own by following the rules in GBATEK as directly
+/// as possible. It is a lot slower than `ScanlineRenderer` and is meant for checking other
+/// renderers against.
+///
+/// Unlike the default renderer this applies the affine parameters to the bitmap modes and draws
+/// objects in OAM order when working out which ones fit in a line's object rendering cycles.
+pub struct ReferenceRenderer;
+
+impl Default for ReferenceRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReferenceRenderer {
+    pub fn new() -> ReferenceRenderer {
+        ReferenceRenderer
+    }
+}
+
+impl LineRenderer for ReferenceRenderer {
+    fn render_line(&mut self, snapshot: &LineSnapshot, output: &mut [u16; 240]) {
+        let y = snapshot.registers.line as u32;
+        let obj_columns = obj_columns(snapshot, y);
+        for (x, color) in output.iter_mut().enumerate() {
+            *color = pixel_color(snapshot, &obj_columns, x as u32, y);
+        }
+    }
+}
+
+/// A layer's pixel before it is mixed with the pixel under it.
+#[derive(Clone, Copy)]
+struct LayerPixel {
+    color: u16,
+    first_target: bool,
+    second_target: bool,
+    semi_transparent: bool,
+}
+
+fn pixel_color(s: &LineSnapshot, obj_columns: &[u16; 128], x: u32, y: u32) -> u16 {
+    let registers = s.registers;
+    let (visible_layers, effects_enabled) = window_at(s, obj_columns, x, y);
+    let visible = |layer: Layer| {
+        registers.dispcnt.display_layer(layer) && (visible_layers & (1 << layer.index())) != 0
+    };
+    let layer_pixel = |layer: Layer, color: u16| LayerPixel {
+        color,
+        first_target: effects_enabled && registers.effects.is_first_target(layer),
+        second_target: effects_enabled && registers.effects.is_second_target(layer),
+        semi_transparent: false,
+    };
+
+    // Lower sort keys are in front. Objects are in front of backgrounds with the same priority and
+    // backgrounds with lower numbers are in front of those with higher numbers.
+    let mut top = (u16::MAX, layer_pixel(Layer::Backdrop, s.palette.backdrop()));
+    let mut bot = top;
+    let mut consider = |key: u16, pixel: LayerPixel| {
+        if key < top.0 {
+            bot = top;
+            top = (key, pixel);
+        } else if key < bot.0 {
+            bot = (key, pixel);
+        }
+    };
+
+    for bg in 0..4 {
+        let layer = Layer::from_bg(bg);
+        if !visible(layer) {
+            continue;
+        }
+        if let Some(color) = bg_pixel(s, bg, x, y) {
+            let key = registers.bg_cnt[bg as usize].priority() * 8 + 1 + bg;
+            consider(key, layer_pixel(layer, color));
+        }
+    }
+
+    if visible(Layer::OBJ) {
+        if let Some((priority, color, semi_transparent)) = obj_pixel(s, obj_columns, x, y) {
+            let mut pixel = layer_pixel(Layer::OBJ, color);
+            pixel.semi_transparent = semi_transparent;
+            consider(priority * 8, pixel);
+        }
+    }
+
+    mix(registers, top.1, bot.1)
+}
+
+fn mix(registers: &LCDRegisters, top: LayerPixel, bot: LayerPixel) -> u16 {
+    let eva = std::cmp::min(16, bits!(registers.alpha, 0, 4));
+    let evb = std::cmp::min(16, bits!(registers.alpha, 8, 12));
+    let evy = std::cmp::min(16, bits!(registers.brightness, 0, 4));
+
+    // Semi-transparent objects are always alpha blended if there is a second target under them.
+    if top.semi_transparent && bot.second_target {
+        return LCDLineBuffer::alpha_blend(top.color, bot.color, eva, evb);
+    }
+
+    match registers.effects.effect() {
+        SpecialEffect::AlphaBlending if top.first_target && bot.second_target => {
+            LCDLineBuffer::alpha_blend(top.color, bot.color, eva, evb)
+        }
+        SpecialEffect::BrightnessIncrease if top.first_target => {
+            LCDLineBuffer::brightness_increase(top.color, evy)
+        }
+        SpecialEffect::BrightnessDecrease if top.first_target => {
+            LCDLineBuffer::brightness_decrease(top.color, evy)
+        }
+        _ => top.color,
+    }
+}
+
+/// Returns a bit for each layer that is visible in the window containing this pixel and whether
+/// special effects are enabled there.
+fn window_at(s: &LineSnapshot, obj_columns: &[u16; 128], x: u32, y: u32) -> (u16, bool) {
+    let registers = s.registers;
+    let dispcnt = registers.dispcnt;
+    if !dispcnt.windows_enabled() {
+        return (0x1F, true);
+    }
+
+    let (control, window) = if dispcnt.display_window0()
+        && registers.win0_bounds.contains_horizontal(x as u16)
+        && registers.win0_bounds.contains_vertical(y as u16)
+    {
+        (registers.winin, Window::Win0)
+    } else if dispcnt.display_window1()
+        && registers.win1_bounds.contains_horizontal(x as u16)
+        && registers.win1_bounds.contains_vertical(y as u16)
+    {
+        (registers.winin, Window::Win1)
+    } else if dispcnt.display_window_obj()
+        && dispcnt.display_layer(Layer::OBJ)
+        && in_obj_window(s, obj_columns, x, y)
+    {
+        (registers.winout, Window::OBJ)
+    } else {
+        (registers.winout, Window::Outside)
+    };
+
+    let mut layers = 0;
+    for layer in 0..=4 {
+        if control.layer_enabled(window, Layer::from_index(layer)) {
+            layers |= 1 << layer;
+        }
+    }
+    (layers, control.effects_enabled(window))
+}
+
+fn bg_pixel(s: &LineSnapshot, bg: u16, x: u32, y: u32) -> Option<u16> {
+    match (s.registers.dispcnt.mode(), bg) {
+        (0, _) | (1, 0) | (1, 1) => text_bg_pixel(s, bg as usize, x, y),
+        (1, 2) | (2, 2) | (2, 3) => affine_bg_pixel(s, bg as usize, x, y),
+        (3, 2) | (4, 2) | (5, 2) => bitmap_bg_pixel(s, x, y),
+        _ => None,
+    }
+}
+
+/// BG2 in the bitmap modes. The bitmap is a rotation/scaling background that is transparent
+/// outside of it and never wraps around.
+fn bitmap_bg_pixel(s: &LineSnapshot, x: u32, y: u32) -> Option<u16> {
+    let mode = s.registers.dispcnt.mode();
+    let (width, height) = if mode == 5 { (160, 128) } else { (240, 160) };
+    let (bx, by) = affine_point(s, 2, x, y);
+    if bx < 0 || by < 0 || bx >= width || by >= height {
+        return None;
+    }
+    let (bx, by) = (bx as u32, by as u32);
+
+    match mode {
+        3 => Some(read_u16(s.vram, ((by * 240 + bx) * 2) as usize) | 0x8000),
+        4 => {
+            let entry = s.vram[(frame_base(s.registers) + by * 240 + bx) as usize];
+            if entry == 0 {
+                None
+            } else {
+                Some(s.palette.bg256(entry as usize))
+            }
+        }
+        _ => {
+            let offset = frame_base(s.registers) + (by * 160 + bx) * 2;
+            Some(read_u16(s.vram, offset as usize) | 0x8000)
+        }
+    }
+}
+
+fn frame_base(registers: &LCDRegisters) -> u32 {
+    if registers.dispcnt.frame_select() == 0 {
+        0x0000
+    } else {
+        0xA000
+    }
+}
+
+/// Returns the coordinates of the pixel that is shown in place of (x, y) when mosaic is on.
+fn bg_mosaic(s: &LineSnapshot, bg: usize, x: u32, y: u32) -> (u32, u32) {
+    let (width, height) = s.registers.mosaic.bg;
+    if !s.registers.bg_cnt[bg].mosaic() || width == 0 {
+        return (x, y);
+    }
+    (x - x % width as u32, y - y % height as u32)
+}
+
+fn text_bg_pixel(s: &LineSnapshot, bg: usize, x: u32, y: u32) -> Option<u16> {
+    let control = s.registers.bg_cnt[bg];
+    let (width, height) =
+        [(256, 256), (512, 256), (256, 512), (512, 512)][control.screen_size() as usize];
+    let (x, y) = bg_mosaic(s, bg, x, y);
+    let bx = (x + s.registers.bg_ofs[bg].x as u32) % width;
+    let by = (y + s.registers.bg_ofs[bg].y as u32) % height;
+
+    // The map is made up of 32x32 tile screens: left to right, then top to bottom.
+    let screen = (bx / 256) + (by / 256) * (width / 256);
+    let map_offset = control.screen_base_block() as u32 * 0x800
+        + screen * 0x800
+        + ((by % 256) / 8) * 64
+        + ((bx % 256) / 8) * 2;
+    let entry = read_u16(s.vram, map_offset as usize) as u32;
+
+    let tile = entry & 0x3FF;
+    let tx = if entry & 0x400 != 0 {
+        7 - bx % 8
+    } else {
+        bx % 8
+    };
+    let ty = if entry & 0x800 != 0 {
+        7 - by % 8
+    } else {
+        by % 8
+    };
+    let char_base = control.char_base_block() as u32 * 0x4000;
+
+    if control.palette256() {
+        let offset = char_base + tile * 64 + ty * 8 + tx;
+        if offset >= 0x10000 {
+            return None;
+        }
+        let index = s.vram[offset as usize];
+        if index == 0 {
+            None
+        } else {
+            Some(s.palette.bg256(index as usize))
+        }
+    } else {
+        let offset = char_base + tile * 32 + ty * 4 + tx / 2;
+        let index = (s.vram[offset as usize] >> ((tx % 2) * 4)) & 0xF;
+        if index == 0 {
+            None
+        } else {
+            Some(s.palette.bg16((entry >> 12) as usize, index as usize))
+        }
+    }
+}
+
+/// The point of a rotation/scaling background (in whole pixels) that is shown at (x, y).
+fn affine_point(s: &LineSnapshot, bg: usize, x: u32, y: u32) -> (i32, i32) {
+    let params = if bg == 2 {
+        &s.registers.bg2_affine_params
+    } else {
+        &s.registers.bg3_affine_params
+    };
+
+    // The reference point registers have already been moved on to this line so to use the pixel
+    // from a line above for mosaic, they are moved back.
+    let (mx, my) = bg_mosaic(s, bg, x, y);
+    let lines_back = (y - my) as i32;
+    let ref_x = params.internal_x.to_inner() - params.b.to_inner() * lines_back;
+    let ref_y = params.internal_y.to_inner() - params.d.to_inner() * lines_back;
+    (
+        (ref_x + params.a.to_inner() * mx as i32) >> 8,
+        (ref_y + params.c.to_inner() * mx as i32) >> 8,
+    )
+}
+
+fn affine_bg_pixel(s: &LineSnapshot, bg: usize, x: u32, y: u32) -> Option<u16> {
+    let control = s.registers.bg_cnt[bg];
+    let size = 128 << control.screen_size();
+    let (mut tx, mut ty) = affine_point(s, bg, x, y);
+
+    if control.wraparound() {
+        tx &= size - 1;
+        ty &= size - 1;
+    } else if tx < 0 || ty < 0 || tx >= size || ty >= size {
+        return None;
+    }
+
+    let map_offset = control.screen_base_block() as i32 * 0x800 + (ty / 8) * (size / 8) + tx / 8;
+    let tile = s.vram[map_offset as usize] as i32;
+    let offset = control.char_base_block() as i32 * 0x4000 + tile * 64 + (ty % 8) * 8 + tx % 8;
+    let index = s.vram[offset as usize];
+    if index == 0 {
+        None
+    } else {
+        Some(s.palette.bg256(index as usize))
+    }
+}
+
+/// The number of columns of each object that are drawn on line `y`. Objects are drawn in OAM
+/// order and one that is on the line takes as many cycles as it is wide, or 10 + 2 per column if
+/// it is an affine object (GBATEK). There are 1210 cycles for a line or 954 if OAM can be
+/// accessed during H-Blank. The object that they run out in is cut off and the rest aren't drawn.
+fn obj_columns(s: &LineSnapshot, y: u32) -> [u16; 128] {
+    let mut columns = [0; 128];
+    let mut cycles: u16 = if s.registers.dispcnt.hblank_interval_free() {
+        954
+    } else {
+        1210
+    };
+
+    for (index, columns) in columns.iter_mut().enumerate() {
+        let (attr0, attr1, _) = obj_attrs(s, index);
+        if !attr0.affine() && attr0.disabled() {
+            continue;
+        }
+        let (box_width, box_height) = obj_box(attr0, attr1);
+        if (y as i32 - attr0.y() as i32) & 0xFF >= box_height {
+            continue;
+        }
+
+        let box_width = box_width as u16;
+        if attr0.affine() {
+            if cycles <= 10 {
+                break;
+            }
+            cycles -= 10;
+            *columns = std::cmp::min(box_width, cycles / 2);
+            cycles = if *columns < box_width {
+                0
+            } else {
+                cycles - box_width * 2
+            };
+        } else {
+            *columns = std::cmp::min(box_width, cycles);
+            cycles -= *columns;
+        }
+        if cycles == 0 {
+            break;
+        }
+    }
+    columns
+}
+
+/// The front-most object pixel at (x, y) as its priority, color and whether it is
+/// semi-transparent.
+fn obj_pixel(
+    s: &LineSnapshot,
+    obj_columns: &[u16; 128],
+    x: u32,
+    y: u32,
+) -> Option<(u16, u16, bool)> {
+    let mut front: Option<(u16, u16, bool)> = None;
+    for index in 0..128 {
+        let attrs = obj_attrs(s, index);
+        if attrs.0.mode() == ObjMode::Window {
+            continue;
+        }
+        let priority = attrs.2.priority();
+        if front.is_some_and(|(p, _, _)| p <= priority) {
+            continue;
+        }
+        if let Some(color) = obj_color(s, attrs, obj_columns[index], x, y) {
+            front = Some((priority, color, attrs.0.mode() == ObjMode::SemiTransparent));
+        }
+    }
+    front
+}
+
+fn in_obj_window(s: &LineSnapshot, obj_columns: &[u16; 128], x: u32, y: u32) -> bool {
+    (0..128).any(|index| {
+        let attrs = obj_attrs(s, index);
+        attrs.0.mode() == ObjMode::Window && obj_color(s, attrs, obj_columns[index], x, y).is_some()
+    })
+}
+
+fn obj_attrs(s: &LineSnapshot, index: usize) -> (ObjAttr0, ObjAttr1, ObjAttr2) {
+    (
+        ObjAttr0::wrap(read_u16(s.oam, index * 8)),
+        ObjAttr1::wrap(read_u16(s.oam, index * 8 + 2)),
+        ObjAttr2::wrap(read_u16(s.oam, index * 8 + 4)),
+    )
+}
+
+/// The size of the area that an object covers on the screen.
+fn obj_box(attr0: ObjAttr0, attr1: ObjAttr1) -> (i32, i32) {
+    let (width, height) = attr0.shape().size(attr1.size_select());
+    if attr0.affine() && attr0.double_size() {
+        (width as i32 * 2, height as i32 * 2)
+    } else {
+        (width as i32, height as i32)
+    }
+}
+
+/// The color of an object at a point on the screen or `None` if the object doesn't cover it or
+/// the pixel is transparent. Only the first `columns` columns of the object are drawn.
+fn obj_color(
+    s: &LineSnapshot,
+    attrs: (ObjAttr0, ObjAttr1, ObjAttr2),
+    columns: u16,
+    x: u32,
+    y: u32,
+) -> Option<u16> {
+    let (attr0, attr1, attr2) = attrs;
+    if !attr0.affine() && attr0.disabled() {
+        return None;
+    }
+
+    let (width, height) = attr0.shape().size(attr1.size_select());
+    let (width, height) = (width as i32, height as i32);
+    let (box_width, box_height) = obj_box(attr0, attr1);
+
+    // Positions wrap around at 512 horizontally and 256 vertically.
+    let dx = (x as i32 - attr1.x() as i32) & 0x1FF;
+    let dy = (y as i32 - attr0.y() as i32) & 0xFF;
+    if dx >= box_width || dx >= columns as i32 || dy >= box_height {
+        return None;
+    }
+
+    let (mut tx, mut ty) = if attr0.affine() {
+        let params = attr1.affine_param_index() as usize * 32;
+        let pa = read_u16(s.oam, params + 0x06) as i16 as i32;
+        let pb = read_u16(s.oam, params + 0x0E) as i16 as i32;
+        let pc = read_u16(s.oam, params + 0x16) as i16 as i32;
+        let pd = read_u16(s.oam, params + 0x1E) as i16 as i32;
+        let (cx, cy) = (dx - box_width / 2, dy - box_height / 2);
+        (
+            ((pa * cx + pb * cy) >> 8) + width / 2,
+            ((pc * cx + pd * cy) >> 8) + height / 2,
+        )
+    } else {
+        (
+            if attr1.flip_horizontal() {
+                width - 1 - dx
+            } else {
+                dx
+            },
+            if attr1.flip_vertical() {
+                height - 1 - dy
+            } else {
+                dy
+            },
+        )
+    };
+    if tx < 0 || ty < 0 || tx >= width || ty >= height {
+        return None;
+    }
+
+    // #NOTE Mosaic is applied to the coordinates inside of the object the same way that the
+    //       default renderer does it.
+    if attr0.mosaic() {
+        let (mosaic_x, mosaic_y) = s.registers.mosaic.obj;
+        if mosaic_x > 0 {
+            tx -= tx % mosaic_x as i32;
+        }
+        if mosaic_y > 0 {
+            ty -= ty % mosaic_y as i32;
+        }
+    }
+
+    // Tile numbers count 32 byte blocks even for 256 color objects.
+    let one_dimensional = s.registers.dispcnt.one_dimensional_obj();
+    let (tx, ty) = (tx as u32, ty as u32);
+    let palette256 = attr0.palette256();
+    let tiles_per_row = if one_dimensional {
+        (width as u32 / 8) * if palette256 { 2 } else { 1 }
+    } else {
+        32
+    };
+    let tile_step = if palette256 { 2 } else { 1 };
+    let tile =
+        (attr2.first_tile_index() as u32 + (ty / 8) * tiles_per_row + (tx / 8) * tile_step) & 0x3FF;
+
+    // In the bitmap modes the first half of object VRAM is used by the background.
+    if s.registers.dispcnt.mode() >= 3 && tile < 512 {
+        return None;
+    }
+
+    // Object tiles are in the last 32K of VRAM and the last 256 color tile wraps around.
+    let tile_data = |offset: u32| 0x10000 + ((tile * 32 + offset) & 0x7FFF) as usize;
+    if palette256 {
+        let index = s.vram[tile_data((ty % 8) * 8 + tx % 8)];
+        if index == 0 {
+            None
+        } else {
+            Some(s.palette.obj256(index as usize))
+        }
+    } else {
+        let byte = s.vram[tile_data((ty % 8) * 4 + (tx % 8) / 2)];
+        let index = (byte >> ((tx % 2) * 4)) & 0xF;
+        if index == 0 {
+            None
+        } else {
+            Some(
+                s.palette
+                    .obj16(attr2.palette_number() as usize, index as usize),
+            )
+        }
+    }
+}
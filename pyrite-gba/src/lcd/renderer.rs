@@ -0,0 +1,153 @@
+use super::palette::GbaPalette;
+use super::{bitmap, obj, tile, LCDLineBuffer, LCDRegisters, Layer, Pixel};
+use crate::hardware::{OAM, VRAM};
+
+/// Everything that the LCD reads while it draws a line, as it is at the time the line is drawn.
+pub struct LineSnapshot<'a> {
+    pub registers: &'a LCDRegisters,
+    pub vram: &'a VRAM,
+    pub oam: &'a OAM,
+    pub palette: &'a GbaPalette,
+}
+
+/// Turns the state of the LCD for one line into colors. Renderers are only responsible for
+/// producing pixels. Timing, the affine reference points and when lines are drawn are all handled
+/// by `GbaLCD` so renderers can be swapped at any time.
+pub trait LineRenderer {
+    /// Draws the line `snapshot.registers.line` into `output` as 15-bit BGR colors with bit 15
+    /// set.
+    fn render_line(&mut self, snapshot: &LineSnapshot, output: &mut [u16; 240]);
+}
+
+/// The default renderer. It draws each layer of a line at a time into a line buffer and then
+/// mixes the layers together.
+pub struct ScanlineRenderer {
+    pixels: LCDLineBuffer,
+}
+
+impl Default for ScanlineRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScanlineRenderer {
+    pub fn new() -> ScanlineRenderer {
+        ScanlineRenderer {
+            pixels: LCDLineBuffer::new(),
+        }
+    }
+
+    fn draw_line(&mut self, registers: &LCDRegisters, vram: &VRAM, oam: &OAM) {
+        // setting up the backdrop. It is behind everything else so objects of any priority are
+        // drawn over it:
+        let backdrop = Pixel(Pixel::layer_mask(Layer::Backdrop) | Pixel::priority_mask(3));
+        self.pixels.clear(backdrop);
+
+        let mode = registers.dispcnt.mode();
+        self.pixels.windows.enabled = registers.dispcnt.windows_enabled();
+        self.pixels.windows.win0_enabled = registers.dispcnt.display_window0();
+        self.pixels.windows.win1_enabled = registers.dispcnt.display_window1();
+        self.pixels.windows.win_obj_enabled = registers.dispcnt.display_window_obj();
+        self.pixels.windows.clear_pixel_bits();
+
+        self.draw_objects_and_windows(registers, vram, oam);
+
+        match mode {
+            0 => tile::render_mode0(registers, vram, &mut self.pixels),
+            1 => tile::render_mode1(registers, vram, &mut self.pixels),
+            2 => tile::render_mode2(registers, vram, &mut self.pixels),
+            3 => bitmap::render_mode3(registers, vram, &mut self.pixels),
+            4 => bitmap::render_mode4(registers, vram, &mut self.pixels),
+            5 => bitmap::render_mode5(registers, vram, &mut self.pixels),
+            _ => log::warn!("bad mode {}", mode),
+        }
+    }
+
+    fn draw_objects_and_windows(&mut self, registers: &LCDRegisters, vram: &VRAM, oam: &OAM) {
+        let render_objects = registers.dispcnt.display_layer(Layer::OBJ);
+
+        // setup obj cycles:
+        self.pixels.obj_cycles = if registers.dispcnt.hblank_interval_free() {
+            954
+        } else {
+            1210
+        };
+
+        let object_priorities = obj::ObjectPriority::sorted(oam);
+
+        // All of the bitmap modes use the first half of object VRAM for the background, including
+        // mode 4 which uses it for its second frame.
+        if registers.dispcnt.mode() >= 3 {
+            if render_objects && registers.dispcnt.display_window_obj() {
+                obj::process_objs::<obj::OBJWindow, obj::BitmapMode>(
+                    registers,
+                    object_priorities.window_objects(),
+                    vram,
+                    oam,
+                    &mut self.pixels,
+                );
+            }
+
+            self.pixels.windows.calculate_masks(
+                registers.line,
+                registers.win0_bounds,
+                registers.win1_bounds,
+                registers.winin,
+                registers.winout,
+                registers.dispcnt,
+            );
+
+            if render_objects {
+                obj::process_objs::<obj::OBJRender, obj::BitmapMode>(
+                    registers,
+                    object_priorities.visible_objects(),
+                    vram,
+                    oam,
+                    &mut self.pixels,
+                );
+            }
+        } else {
+            if render_objects && registers.dispcnt.display_window_obj() {
+                obj::process_objs::<obj::OBJWindow, obj::TileMode>(
+                    registers,
+                    object_priorities.window_objects(),
+                    vram,
+                    oam,
+                    &mut self.pixels,
+                );
+            }
+
+            self.pixels.windows.calculate_masks(
+                registers.line,
+                registers.win0_bounds,
+                registers.win1_bounds,
+                registers.winin,
+                registers.winout,
+                registers.dispcnt,
+            );
+
+            if render_objects {
+                obj::process_objs::<obj::OBJRender, obj::TileMode>(
+                    registers,
+                    object_priorities.visible_objects(),
+                    vram,
+                    oam,
+                    &mut self.pixels,
+                );
+            }
+        }
+    }
+}
+
+impl LineRenderer for ScanlineRenderer {
+    fn render_line(&mut self, snapshot: &LineSnapshot, output: &mut [u16; 240]) {
+        self.draw_line(snapshot.registers, snapshot.vram, snapshot.oam);
+        self.pixels.mix(
+            snapshot.palette,
+            snapshot.registers.effects.effect(),
+            snapshot.registers,
+        );
+        output.copy_from_slice(&self.pixels.mixed);
+    }
+}
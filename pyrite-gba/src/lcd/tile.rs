@@ -34,7 +34,7 @@ pub fn render_mode0(registers: &LCDRegisters, vram: &VRAM, pixels: &mut LCDLineB
     }
 }
 
-pub fn render_mode1(registers: &mut LCDRegisters, vram: &VRAM, pixels: &mut LCDLineBuffer) {
+pub fn render_mode1(registers: &LCDRegisters, vram: &VRAM, pixels: &mut LCDLineBuffer) {
     for priority in (0usize..=3).rev() {
         for bg_index in (0usize..=2).rev() {
             let layer = Layer::from_bg(bg_index as u16);
@@ -77,12 +77,9 @@ pub fn render_mode1(registers: &mut LCDRegisters, vram: &VRAM, pixels: &mut LCDL
             }
         }
     }
-
-    registers.bg2_affine_params.increment_reference_points();
-    registers.bg3_affine_params.increment_reference_points();
 }
 
-pub fn render_mode2(registers: &mut LCDRegisters, vram: &VRAM, pixels: &mut LCDLineBuffer) {
+pub fn render_mode2(registers: &LCDRegisters, vram: &VRAM, pixels: &mut LCDLineBuffer) {
     for priority in (0usize..=3).rev() {
         for bg_index in (2usize..=3).rev() {
             let layer = Layer::from_bg(bg_index as u16);
@@ -111,9 +108,6 @@ pub fn render_mode2(registers: &mut LCDRegisters, vram: &VRAM, pixels: &mut LCDL
             }
         }
     }
-
-    registers.bg2_affine_params.increment_reference_points();
-    registers.bg3_affine_params.increment_reference_points();
 }
 
 pub fn draw_affine_bg(bg: &AffineBG, vram: &VRAM, pixels: &mut LCDLineBuffer) {
@@ -129,9 +123,6 @@ pub fn draw_affine_bg(bg: &AffineBG, vram: &VRAM, pixels: &mut LCDLineBuffer) {
     let mut y = bg.params.internal_y;
 
     for idx in 0..240 {
-        x += bg.params.a; // x + dx
-        y += bg.params.c; // y + dy
-
         let ix = apply_mosaic((x.integer() & x_mask) as u32, bg.mosaic_x as u32);
         let iy = apply_mosaic((y.integer() & y_mask) as u32, bg.mosaic_y as u32);
 
@@ -156,6 +147,9 @@ pub fn draw_affine_bg(bg: &AffineBG, vram: &VRAM, pixels: &mut LCDLineBuffer) {
                 }
             }
         }
+
+        x += bg.params.a; // x + dx
+        y += bg.params.c; // y + dy
     }
 }
 
@@ -3,25 +3,42 @@ mod util;
 pub mod audio;
 pub mod dma;
 mod hardware;
+pub mod hashlog;
 #[allow(dead_code)]
 mod ioregs;
 pub mod irq;
 pub mod keypad;
 pub mod lcd;
+pub mod link;
+pub mod movie;
+pub mod netplay;
+mod prefetch;
+pub mod rewind;
+pub mod runahead;
 mod scheduler;
+pub mod sio;
+mod state;
 mod sysctl;
 pub mod timers;
+pub mod wireless;
 
 use hardware::GbaHardware;
+use hashlog::FrameHashLog;
 use pyrite_arm::cpu::CpuException;
 use pyrite_arm::ArmCpu;
 use scheduler::{GbaEvent, SharedGbaScheduler};
 
+pub use hardware::RomError;
+pub use pyrite_common::StateError;
+
 pub struct Gba {
     pub cpu: ArmCpu,
     pub hardware: GbaHardware,
     pub scheduler: SharedGbaScheduler,
     state: GbaSystemState,
+
+    /// Set while the state hash is logged at the end of every frame.
+    frame_hash_log: Option<FrameHashLog>,
 }
 
 impl Gba {
@@ -34,6 +51,7 @@ impl Gba {
             hardware: GbaHardware::new(hw_scheduler),
             state: GbaSystemState::Running,
             scheduler: scheduler,
+            frame_hash_log: None,
         };
         g.setup_handler();
         return g;
@@ -48,6 +66,7 @@ impl Gba {
             hardware: GbaHardware::new(hw_scheduler),
             state: GbaSystemState::Running,
             scheduler: scheduler,
+            frame_hash_log: None,
         });
         g.setup_handler();
         return g;
@@ -59,6 +78,7 @@ impl Gba {
                 match exception {
                     CpuException::Reset => false,
                     CpuException::SWI => false,
+                    CpuException::Undefined => false,
                     CpuException::IRQ => false,
                     _ => {
                         log::warn!("{} exception at 0x{:08X}", exception.name(), exception_addr);
@@ -108,6 +128,8 @@ impl Gba {
 
             // Set the post boot flag:
             self.hardware.sysctl.reg_postflg = true;
+
+            self.hardware.bios_open_bus = hardware::BIOS_OPEN_BUS_AFTER_STARTUP;
         } else {
             self.cpu.registers.setf_i(); // Disables IRQ interrupts
             let _ = self.cpu.set_pc(0x00000000, &mut self.hardware);
@@ -116,16 +138,69 @@ impl Gba {
                 .write_mode(registers::CpuMode::Supervisor);
         }
 
+        // The LCD starts at the beginning of line 0 so the first thing it does is draw that line.
         self.scheduler.clear();
-        self.scheduler.schedule(GbaEvent::HDraw, lcd::HDRAW_CYCLES);
+        self.scheduler.schedule(GbaEvent::HBlank, lcd::HDRAW_CYCLES);
     }
 
-    pub fn set_rom(&mut self, rom: Vec<u8>) {
-        self.hardware.set_gamepak_rom(rom);
+    /// Resets the system as if it was turned off and on again. Unlike `reset` this also clears
+    /// all memory and hardware registers so the same input will always give the same results
+    /// afterwards.
+    pub fn power_on(&mut self, skip_bios: bool) {
+        let mut fresh = Gba::alloc();
+        fresh.set_bios(self.hardware.bios.to_vec());
+        fresh
+            .set_rom(self.hardware.gamepak.to_vec())
+            .expect("failed to set power on ROM");
+        fresh.reset(skip_bios);
+        let state = fresh
+            .save_state(false)
+            .expect("failed to save power on state");
+        self.load_state(&state)
+            .expect("failed to load power on state");
+    }
+
+    /// Sets the GamePak ROM. ROMs that are larger than 32MB are rejected.
+    pub fn set_rom(&mut self, rom: Vec<u8>) -> Result<(), RomError> {
+        self.hardware.set_gamepak_rom(rom)?;
+        self.hardware.invalidate_code();
+        Ok(())
     }
 
     pub fn set_bios(&mut self, bios: Vec<u8>) {
         self.hardware.set_bios_rom(&bios);
+        self.hardware.invalidate_code();
+    }
+
+    /// Switches the CPU between the normal interpreter and the cached (pre-decoded blocks)
+    /// interpreter. Both run at the same speed as far as the emulated hardware is concerned.
+    pub fn set_block_cache_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self.hardware.code_invalidator = Some(self.cpu.enable_block_cache());
+        } else {
+            self.cpu.disable_block_cache();
+            self.hardware.code_invalidator = None;
+        }
+    }
+
+    pub fn block_cache_enabled(&self) -> bool {
+        self.cpu.block_cache_enabled()
+    }
+
+    /// Switches the CPU between the cached interpreter and the JIT. Enabling the JIT also enables
+    /// the block cache and disabling it leaves the block cache enabled.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self.hardware.code_invalidator = Some(self.cpu.enable_jit());
+        } else {
+            self.cpu.disable_jit();
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn jit_enabled(&self) -> bool {
+        self.cpu.jit_enabled()
     }
 
     /// Returns a tuple with the first value being true if this step marked the end of a video
@@ -136,6 +211,17 @@ impl Gba {
         video: &mut dyn GbaVideoOutput,
         audio: &mut dyn GbaAudioOutput,
     ) -> (bool, bool) {
+        let (video_frame, _cycles) = self.step_cycles(video, audio);
+        (video_frame, false)
+    }
+
+    /// Like `step` but returns the number of cycles that were run instead of the audio frame flag.
+    #[inline]
+    pub(crate) fn step_cycles(
+        &mut self,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) -> (bool, u32) {
         // NOTE the call to `cpu.step` here is kind of misleading.
         // Despite `step` being only one line:
         //
@@ -154,7 +240,11 @@ impl Gba {
             false
         };
 
-        return (video_frame, false);
+        if video_frame && self.frame_hash_log.is_some() {
+            self.log_frame_hash();
+        }
+
+        (video_frame, cycles)
     }
 
     #[inline]
@@ -197,22 +287,13 @@ impl Gba {
             GbaEvent::HDraw => self.hardware.lcd.hdraw(&mut self.hardware.dma),
 
             GbaEvent::IRQ(irq) => {
-                if self.cpu.exception_enabled(CpuException::IRQ) && self.hardware.irq.request(irq) {
-                    self.state = GbaSystemState::Running;
-
-                    // This will automatically put the CPU in an active state if it's idling,
-                    // and then change the next execution target to the exception handler.
-                    self.cpu.set_pending_exception_active(CpuException::IRQ);
-
-                    // Calling `set_pending_exception_active` will change the CPU's next execution
-                    // so we call `resume_transfer` to resume a DMA transfer if one was in
-                    // progress. It doesn't matter that this will override the exception because
-                    // the CPU will "remember" that there is an exception waiting to be
-                    // processed the next time we try to resume regular execution.
-                    self.hardware.dma.resume_transfer(&mut self.cpu);
-                }
+                // IF is set right away but the CPU only notices a little later.
+                self.hardware.irq.request(irq);
+                irq::schedule_line_update(&self.scheduler);
             }
 
+            GbaEvent::IRQLine => self.update_irq_line(),
+
             GbaEvent::DMA(dma) => self.hardware.dma.begin_transfer(dma, &mut self.cpu),
 
             GbaEvent::Halt => {
@@ -220,6 +301,9 @@ impl Gba {
 
                 // We don't want to be too fine grained here or performance is bad.
                 self.cpu.set_idle(true, 4);
+
+                // Halting with an interrupt that is already waiting doesn't do anything.
+                self.update_irq_line();
             }
 
             GbaEvent::Stop => {
@@ -227,12 +311,20 @@ impl Gba {
 
                 // we use big steps for stop because everything we need high fidelity for is off.
                 self.cpu.set_idle(true, 16);
+                self.update_irq_line();
             }
 
             GbaEvent::TimerOverflows => {
-                self.hardware.timers.process_overflows();
+                let overflows = self.hardware.timers.process_overflows();
+                self.hardware
+                    .audio
+                    .timer_overflows(overflows, &mut self.hardware.dma);
             }
 
+            GbaEvent::SerialTransfer => self.hardware.sio.complete_transfer(),
+            GbaEvent::WirelessAdapterPoll => self.hardware.sio.poll_wireless_adapter(),
+            GbaEvent::UartReceive => self.hardware.sio.poll_uart(),
+
             GbaEvent::AudioUpdate => self.hardware.audio.update(audio),
             GbaEvent::StopPSGChannel(channel) => {
                 self.hardware.audio.psg_stop_channel(audio, channel)
@@ -248,6 +340,33 @@ impl Gba {
         video_frame
     }
 
+    /// Passes the state of the interrupt registers on to the CPU. Halt mode ends as soon as an
+    /// interrupt is both requested and enabled in IE, even with IME off, and Stop mode ends the
+    /// same way for the interrupts that can still happen in Stop mode. The CPU only takes the
+    /// IRQ exception if IME is on as well and it doesn't have IRQs disabled.
+    fn update_irq_line(&mut self) {
+        let pending = self.hardware.irq.pending();
+        match self.state {
+            GbaSystemState::Running => {}
+            GbaSystemState::Halted if pending != 0 => {}
+            GbaSystemState::Stopped if (pending & irq::STOP_WAKE_INTERRUPTS) != 0 => {}
+            GbaSystemState::Halted | GbaSystemState::Stopped => {
+                self.cpu.set_irq_line(false);
+                return;
+            }
+        }
+
+        self.state = GbaSystemState::Running;
+        self.cpu.set_idle(false, 0);
+        self.cpu.set_irq_line(self.hardware.irq.irq_line());
+
+        // Both of the calls above can change the CPU's next execution so we call
+        // `resume_transfer` to resume a DMA transfer if one was in progress. It doesn't matter
+        // that this will override the exception because the CPU will "remember" that there is an
+        // exception waiting to be processed the next time we try to resume regular execution.
+        self.hardware.dma.resume_transfer(&mut self.cpu);
+    }
+
     /// Steps the GBA until the end of a video frame.
     #[inline(always)]
     pub fn video_frame(&mut self, video: &mut dyn GbaVideoOutput, audio: &mut dyn GbaAudioOutput) {
@@ -256,16 +375,72 @@ impl Gba {
 
     #[inline]
     pub fn set_key_pressed(&mut self, key: keypad::KeypadInput, pressed: bool) {
+        // KEYCNT is checked even if the key didn't change because the condition is a level.
         self.hardware.keypad.set_pressed(key, pressed);
+        self.hardware.check_keypad_irq();
     }
 
     #[inline]
     pub fn is_key_pressed(&mut self, key: keypad::KeypadInput) -> bool {
         self.hardware.keypad.is_pressed(key)
     }
+
+    /// While this is enabled a hash of the entire state is taken and logged at the end of every
+    /// frame. Enabling it starts a new log.
+    pub fn set_frame_hash_logging(&mut self, enabled: bool) {
+        self.frame_hash_log = if enabled {
+            Some(FrameHashLog::new())
+        } else {
+            None
+        };
+    }
+
+    /// The state hashes that have been logged since `set_frame_hash_logging` was enabled.
+    pub fn frame_hash_log(&self) -> Option<&FrameHashLog> {
+        self.frame_hash_log.as_ref()
+    }
+
+    #[cold]
+    fn log_frame_hash(&mut self) {
+        let mut frame_hash_log = match self.frame_hash_log.take() {
+            Some(frame_hash_log) => frame_hash_log,
+            None => return,
+        };
+        match frame_hash_log.record(self) {
+            Ok(hash) => log::info!(
+                "frame {} state hash: {:016X}",
+                frame_hash_log.len() - 1,
+                hash
+            ),
+            Err(err) => log::error!("failed to hash state: {}", err),
+        }
+        self.frame_hash_log = Some(frame_hash_log);
+    }
+
+    /// Draws pixels in step with the LCD's dot clock instead of a whole line at a time. This is
+    /// slower but shows mid-line effects and makes the CPU wait for the LCD on video memory
+    /// accesses.
+    pub fn set_accurate_lcd(&mut self, accurate: bool) {
+        self.hardware.lcd.set_accurate(accurate);
+    }
+
+    pub fn accurate_lcd(&self) -> bool {
+        self.hardware.lcd.accurate()
+    }
+
+    /// Whether the CPU is running or waiting in Halt or Stop mode.
+    pub fn system_state(&self) -> GbaSystemState {
+        self.state
+    }
+
+    /// Returns true if the game has read KEYINPUT since the last call.
+    #[inline]
+    pub fn take_keypad_polled(&mut self) -> bool {
+        self.hardware.keypad.polled.replace(false)
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum GbaSystemState {
     Running = 0,
     Halted = 1,
@@ -0,0 +1,246 @@
+//! A link cable that connects the link ports of 2 to 4 consoles in the same process.
+//!
+//! The consoles are run in lockstep: every console runs for `SYNC_CYCLES` cycles and then the
+//! cable looks at their serial ports, starts the transfers that were requested and updates the SI
+//! and SD terminals. A transfer that was started is completed by every console that takes part in
+//! it once the time that the transfer takes has passed.
+//!
+//! In Multiplayer mode console 0 is the parent and the others are children. In Normal mode the
+//! consoles are connected in pairs, 0 with 1 and 2 with 3.
+
+use crate::sio::{SerialMode, DISCONNECTED};
+use crate::{Gba, GbaAudioOutput, GbaVideoOutput};
+
+mod remote;
+
+pub use remote::{RemoteLink, DEFAULT_REMOTE_SYNC_CYCLES};
+
+pub const MIN_CONSOLES: usize = 2;
+pub const MAX_CONSOLES: usize = 4;
+
+/// The number of cycles that the consoles run between synchronizations. This is less than the
+/// time until the first line of a frame is drawn so that a frame that ends during a sync period
+/// isn't overwritten before `video_frame` returns.
+const SYNC_CYCLES: u32 = 256;
+
+pub struct LinkCable {
+    consoles: Vec<Gba>,
+}
+
+impl LinkCable {
+    /// Connects `consoles` to each other. Panics if there are less than 2 or more than 4.
+    pub fn new(mut consoles: Vec<Gba>) -> LinkCable {
+        assert!(
+            consoles.len() >= MIN_CONSOLES && consoles.len() <= MAX_CONSOLES,
+            "a link cable connects 2 to 4 consoles"
+        );
+
+        for console in consoles.iter_mut() {
+            console.hardware.sio.linked = true;
+        }
+        let mut cable = LinkCable { consoles };
+        cable.synchronize();
+        cable
+    }
+
+    pub fn consoles(&self) -> &[Gba] {
+        &self.consoles
+    }
+
+    pub fn consoles_mut(&mut self) -> &mut [Gba] {
+        &mut self.consoles
+    }
+
+    /// Disconnects the consoles and gives them back.
+    pub fn into_consoles(mut self) -> Vec<Gba> {
+        for console in self.consoles.iter_mut() {
+            console.hardware.sio.linked = false;
+        }
+        self.consoles
+    }
+
+    /// Runs all consoles until console 0 has finished a video frame. `video` and `audio` have an
+    /// output for every console.
+    pub fn video_frame(
+        &mut self,
+        video: &mut [&mut dyn GbaVideoOutput],
+        audio: &mut [&mut dyn GbaAudioOutput],
+    ) {
+        assert_eq!(
+            video.len(),
+            self.consoles.len(),
+            "wrong number of video outputs"
+        );
+        assert_eq!(
+            audio.len(),
+            self.consoles.len(),
+            "wrong number of audio outputs"
+        );
+
+        loop {
+            let mut frame_done = false;
+            for (index, console) in self.consoles.iter_mut().enumerate() {
+                let (video_frame, _) = run_period(
+                    console,
+                    SYNC_CYCLES,
+                    false,
+                    &mut *video[index],
+                    &mut *audio[index],
+                );
+                frame_done |= index == 0 && video_frame;
+            }
+            self.synchronize();
+
+            if frame_done {
+                return;
+            }
+        }
+    }
+
+    fn synchronize(&mut self) {
+        let count = self.consoles.len() as u32;
+        let ports: Vec<LinkPort> = self
+            .consoles
+            .iter()
+            .map(|console| console.hardware.sio.link_port(count))
+            .collect();
+        let updates = connect_ports(&ports);
+        for (console, update) in self.consoles.iter_mut().zip(updates.iter()) {
+            console.hardware.sio.apply_link_update(update);
+        }
+    }
+}
+
+/// The state of a console's serial port that matters to the other consoles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LinkPort {
+    pub(crate) mode: SerialMode,
+    pub(crate) waiting: bool,
+    pub(crate) internal_clock: bool,
+    pub(crate) so_level: bool,
+    pub(crate) send: u16,
+    pub(crate) normal_outgoing: [u16; 4],
+    pub(crate) transfer_cycles: u32,
+}
+
+impl LinkPort {
+    fn is_normal(&self) -> bool {
+        self.mode == SerialMode::Normal8 || self.mode == SerialMode::Normal32
+    }
+}
+
+/// What a console's serial port is told by the link cable after a synchronization.
+pub(crate) struct LinkUpdate {
+    pub(crate) si: bool,
+    pub(crate) sd: bool,
+    pub(crate) transfer: Option<LinkTransfer>,
+}
+
+pub(crate) struct LinkTransfer {
+    pub(crate) incoming: [u16; 4],
+    pub(crate) id: u16,
+    pub(crate) cycles: u32,
+}
+
+/// Works out the SI and SD terminals of every port and the transfers that start now. This only
+/// depends on `ports` so consoles in different processes can each work it out on their own.
+pub(crate) fn connect_ports(ports: &[LinkPort]) -> Vec<LinkUpdate> {
+    // The SD terminal is only high while every console is ready for Multiplayer mode.
+    let all_multiplayer = ports
+        .iter()
+        .all(|port| port.mode == SerialMode::Multiplayer);
+    let mut updates: Vec<LinkUpdate> = ports
+        .iter()
+        .enumerate()
+        .map(|(index, port)| {
+            let si = if port.mode == SerialMode::Multiplayer {
+                index != 0
+            } else {
+                // Without a partner SI is pulled high.
+                ports.get(index ^ 1).is_none_or(|partner| partner.so_level)
+            };
+            LinkUpdate {
+                si,
+                sd: all_multiplayer,
+                transfer: None,
+            }
+        })
+        .collect();
+
+    // Only the parent can start a Multiplayer transfer. Every console in Multiplayer mode gets
+    // the SIOMLT_SEND value of the others.
+    if ports[0].mode == SerialMode::Multiplayer && ports[0].waiting {
+        let mut sent = DISCONNECTED;
+        for (index, port) in ports.iter().enumerate() {
+            if port.mode == SerialMode::Multiplayer {
+                sent[index] = port.send;
+            }
+        }
+        for (index, port) in ports.iter().enumerate() {
+            if port.mode == SerialMode::Multiplayer {
+                updates[index].transfer = Some(LinkTransfer {
+                    incoming: sent,
+                    id: index as u16,
+                    cycles: ports[0].transfer_cycles,
+                });
+            }
+        }
+    }
+
+    // A Normal mode master exchanges data with its partner if the partner is a slave that is
+    // ready. Otherwise the master only receives 1s.
+    for (master, port) in ports.iter().enumerate() {
+        if !(port.is_normal() && port.internal_clock && port.waiting) {
+            continue;
+        }
+
+        let slave = master ^ 1;
+        let slave_ready = ports.get(slave).is_some_and(|partner| {
+            partner.is_normal() && !partner.internal_clock && partner.waiting
+        });
+        let incoming = if slave_ready {
+            updates[slave].transfer = Some(LinkTransfer {
+                incoming: port.normal_outgoing,
+                id: 0,
+                cycles: port.transfer_cycles,
+            });
+            ports[slave].normal_outgoing
+        } else {
+            DISCONNECTED
+        };
+        updates[master].transfer = Some(LinkTransfer {
+            incoming,
+            id: 0,
+            cycles: port.transfer_cycles,
+        });
+    }
+
+    updates
+}
+
+/// Runs `gba` until `period` cycles have passed since the last synchronization. If
+/// `stop_at_frame` is set this also stops at the end of a video frame. Returns whether a video
+/// frame ended and whether the period is over.
+fn run_period(
+    gba: &mut Gba,
+    period: u32,
+    stop_at_frame: bool,
+    video: &mut dyn GbaVideoOutput,
+    audio: &mut dyn GbaAudioOutput,
+) -> (bool, bool) {
+    let mut video_frame = false;
+    let mut ran = gba.hardware.sio.link_lead;
+    while ran < period && !(stop_at_frame && video_frame) {
+        let (frame, step_cycles) = gba.step_cycles(video, audio);
+        video_frame |= frame;
+        ran += step_cycles;
+    }
+
+    if ran >= period {
+        gba.hardware.sio.link_lead = ran - period;
+        (video_frame, true)
+    } else {
+        gba.hardware.sio.link_lead = ran;
+        (video_frame, false)
+    }
+}
@@ -0,0 +1,318 @@
+//! A link cable between consoles in different processes, connected through TCP or Unix domain
+//! sockets.
+//!
+//! One process is the host and runs console 0. The other processes connect to it and get the
+//! next console numbers in the order that they connected. Every process runs its console for
+//! `sync_cycles` cycles and then sends the state of its serial port to the host, which sends the
+//! ports of all consoles back to everyone. Every process then works out what happens on the cable
+//! on its own with `connect_ports`, just like the in-process `LinkCable` does, so all consoles stay
+//! synchronized to within `sync_cycles` cycles.
+//!
+//! Smaller sync periods make transfers start sooner after they are requested but every period
+//! needs a round trip through the host.
+
+use super::{connect_ports, run_period, LinkPort, MAX_CONSOLES, MIN_CONSOLES};
+use crate::sio::SerialMode;
+use crate::{Gba, GbaAudioOutput, GbaVideoOutput};
+use pyrite_common::{StateReader, StateWriter};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+/// About 4 lines of the display.
+pub const DEFAULT_REMOTE_SYNC_CYCLES: u32 = 4096;
+
+const MAGIC: &[u8; 4] = b"PYRL";
+const PROTOCOL_VERSION: u32 = 1;
+const PORT_SIZE: usize = 16;
+
+/// A stream to another process.
+trait LinkStream: Read + Write {}
+impl<T: Read + Write> LinkStream for T {}
+
+pub struct RemoteLink {
+    index: usize,
+    consoles: usize,
+    sync_cycles: u32,
+
+    /// The host has a stream to every other console (in order) and the others only have one to
+    /// the host.
+    streams: Vec<Box<dyn LinkStream>>,
+}
+
+impl RemoteLink {
+    /// Waits for `consoles - 1` other processes to connect to `listener`.
+    pub fn host_tcp(
+        listener: &TcpListener,
+        consoles: usize,
+        sync_cycles: u32,
+    ) -> io::Result<RemoteLink> {
+        check_host_settings(consoles, sync_cycles)?;
+        let mut streams = Vec::with_capacity(consoles - 1);
+        for _ in 1..consoles {
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            streams.push(stream);
+        }
+        RemoteLink::host(streams, sync_cycles)
+    }
+
+    pub fn join_tcp<A: ToSocketAddrs>(host: A) -> io::Result<RemoteLink> {
+        let stream = TcpStream::connect(host)?;
+        stream.set_nodelay(true)?;
+        RemoteLink::join(stream)
+    }
+
+    /// Waits for `consoles - 1` other processes to connect to `listener`.
+    #[cfg(unix)]
+    pub fn host_unix(
+        listener: &UnixListener,
+        consoles: usize,
+        sync_cycles: u32,
+    ) -> io::Result<RemoteLink> {
+        check_host_settings(consoles, sync_cycles)?;
+        let mut streams = Vec::with_capacity(consoles - 1);
+        for _ in 1..consoles {
+            let (stream, _) = listener.accept()?;
+            streams.push(stream);
+        }
+        RemoteLink::host(streams, sync_cycles)
+    }
+
+    #[cfg(unix)]
+    pub fn join_unix<P: AsRef<Path>>(path: P) -> io::Result<RemoteLink> {
+        RemoteLink::join(UnixStream::connect(path)?)
+    }
+
+    /// Hosts the link with a stream to each of the other consoles. The consoles are numbered in
+    /// the order of `streams`, starting with 1.
+    pub fn host<S: Read + Write + 'static>(
+        streams: Vec<S>,
+        sync_cycles: u32,
+    ) -> io::Result<RemoteLink> {
+        let consoles = streams.len() + 1;
+        check_host_settings(consoles, sync_cycles)?;
+
+        let mut link = RemoteLink {
+            index: 0,
+            consoles,
+            sync_cycles,
+            streams: Vec::with_capacity(streams.len()),
+        };
+
+        for (index, mut stream) in streams.into_iter().enumerate() {
+            let mut hello = [0u8; 8];
+            stream.read_exact(&mut hello)?;
+            check_hello(&hello)?;
+
+            let mut welcome = StateWriter::new();
+            welcome.write_bytes(MAGIC);
+            welcome.write_u32(PROTOCOL_VERSION);
+            welcome.write_u8(index as u8 + 1);
+            welcome.write_u8(consoles as u8);
+            welcome.write_u32(sync_cycles);
+            stream.write_all(&welcome.into_inner())?;
+            stream.flush()?;
+
+            link.streams.push(Box::new(stream));
+        }
+
+        Ok(link)
+    }
+
+    /// Joins a link through a stream to its host. The number of consoles and the sync period are
+    /// decided by the host.
+    pub fn join<S: Read + Write + 'static>(mut stream: S) -> io::Result<RemoteLink> {
+        let mut hello = StateWriter::new();
+        hello.write_bytes(MAGIC);
+        hello.write_u32(PROTOCOL_VERSION);
+        stream.write_all(&hello.into_inner())?;
+        stream.flush()?;
+
+        let mut welcome = [0u8; 14];
+        stream.read_exact(&mut welcome)?;
+        check_hello(&welcome[0..8])?;
+        let mut reader = StateReader::new(&welcome[8..]);
+        let index = reader.read_u8().map_err(|_| invalid_data("bad welcome"))? as usize;
+        let consoles = reader.read_u8().map_err(|_| invalid_data("bad welcome"))? as usize;
+        let sync_cycles = reader.read_u32().map_err(|_| invalid_data("bad welcome"))?;
+
+        if !(MIN_CONSOLES..=MAX_CONSOLES).contains(&consoles) || index == 0 || index >= consoles {
+            return Err(invalid_data("bad console number"));
+        }
+        if sync_cycles == 0 {
+            return Err(invalid_data("the sync period can't be 0 cycles"));
+        }
+
+        Ok(RemoteLink {
+            index,
+            consoles,
+            sync_cycles,
+            streams: vec![Box::new(stream)],
+        })
+    }
+
+    /// The number of this process's console on the cable. The host has console 0.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn consoles(&self) -> usize {
+        self.consoles
+    }
+
+    pub fn sync_cycles(&self) -> u32 {
+        self.sync_cycles
+    }
+
+    /// Runs `gba` until the end of a video frame, stopping to synchronize with the other
+    /// processes every `sync_cycles` cycles. This blocks while the other processes catch up.
+    /// If an error is returned the console is disconnected from the cable.
+    pub fn video_frame(
+        &mut self,
+        gba: &mut Gba,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) -> io::Result<()> {
+        gba.hardware.sio.linked = true;
+        loop {
+            let (video_frame, period_over) = run_period(gba, self.sync_cycles, true, video, audio);
+            if period_over {
+                if let Err(err) = self.synchronize(gba) {
+                    gba.hardware.sio.linked = false;
+                    return Err(err);
+                }
+            }
+
+            if video_frame {
+                return Ok(());
+            }
+        }
+    }
+
+    fn synchronize(&mut self, gba: &mut Gba) -> io::Result<()> {
+        let own = gba.hardware.sio.link_port(self.consoles as u32);
+
+        let mut ports = Vec::with_capacity(self.consoles);
+        if self.index == 0 {
+            ports.push(own);
+            let mut buffer = [0u8; PORT_SIZE];
+            for stream in self.streams.iter_mut() {
+                stream.read_exact(&mut buffer)?;
+                ports.push(read_port(&buffer)?);
+            }
+
+            let mut all = StateWriter::new();
+            for port in ports.iter() {
+                write_port(&mut all, port);
+            }
+            let all = all.into_inner();
+            for stream in self.streams.iter_mut() {
+                stream.write_all(&all)?;
+                stream.flush()?;
+            }
+        } else {
+            let mut message = StateWriter::new();
+            write_port(&mut message, &own);
+            let host = &mut self.streams[0];
+            host.write_all(&message.into_inner())?;
+            host.flush()?;
+
+            let mut buffer = vec![0u8; PORT_SIZE * self.consoles];
+            host.read_exact(&mut buffer)?;
+            for data in buffer.chunks(PORT_SIZE) {
+                ports.push(read_port(data)?);
+            }
+
+            if ports[self.index] != own {
+                return Err(invalid_data("the host sent back a different port state"));
+            }
+        }
+
+        let updates = connect_ports(&ports);
+        gba.hardware.sio.apply_link_update(&updates[self.index]);
+        Ok(())
+    }
+}
+
+fn check_hello(hello: &[u8]) -> io::Result<()> {
+    if &hello[0..4] != MAGIC {
+        return Err(invalid_data("not a pyrite link"));
+    }
+    let mut reader = StateReader::new(&hello[4..8]);
+    let version = reader.read_u32().map_err(|_| invalid_data("bad hello"))?;
+    if version != PROTOCOL_VERSION {
+        return Err(invalid_data("unsupported link protocol version"));
+    }
+    Ok(())
+}
+
+fn write_port(writer: &mut StateWriter, port: &LinkPort) {
+    let mode = match port.mode {
+        SerialMode::Normal8 => 0,
+        SerialMode::Normal32 => 1,
+        SerialMode::Multiplayer => 2,
+        SerialMode::Uart => 3,
+        SerialMode::GeneralPurpose => 4,
+        SerialMode::JoyBus => 5,
+    };
+    writer.write_u8(mode);
+    writer.write_u8(
+        (port.waiting as u8) | ((port.internal_clock as u8) << 1) | ((port.so_level as u8) << 2),
+    );
+    writer.write_u16(port.send);
+    for &value in port.normal_outgoing.iter() {
+        writer.write_u16(value);
+    }
+    writer.write_u32(port.transfer_cycles);
+}
+
+fn read_port(data: &[u8]) -> io::Result<LinkPort> {
+    let bad_port = |_| invalid_data("bad port state");
+    let mut reader = StateReader::new(data);
+    let mode = match reader.read_u8().map_err(bad_port)? {
+        0 => SerialMode::Normal8,
+        1 => SerialMode::Normal32,
+        2 => SerialMode::Multiplayer,
+        3 => SerialMode::Uart,
+        4 => SerialMode::GeneralPurpose,
+        5 => SerialMode::JoyBus,
+        _ => return Err(invalid_data("bad serial mode")),
+    };
+    let flags = reader.read_u8().map_err(bad_port)?;
+    let send = reader.read_u16().map_err(bad_port)?;
+    let mut normal_outgoing = [0u16; 4];
+    for value in normal_outgoing.iter_mut() {
+        *value = reader.read_u16().map_err(bad_port)?;
+    }
+    let transfer_cycles = reader.read_u32().map_err(bad_port)?;
+
+    Ok(LinkPort {
+        mode,
+        waiting: (flags & 1) != 0,
+        internal_clock: (flags & 2) != 0,
+        so_level: (flags & 4) != 0,
+        send,
+        normal_outgoing,
+        transfer_cycles,
+    })
+}
+
+/// Checks the number of consoles and the sync period before anyone is waited for.
+fn check_host_settings(consoles: usize, sync_cycles: u32) -> io::Result<()> {
+    if !(MIN_CONSOLES..=MAX_CONSOLES).contains(&consoles) {
+        return Err(invalid_data("a link cable connects 2 to 4 consoles"));
+    }
+    if sync_cycles == 0 {
+        return Err(invalid_data("the sync period can't be 0 cycles"));
+    }
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
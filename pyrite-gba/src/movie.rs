@@ -0,0 +1,364 @@
+//! Input movies: recordings of the keys that were pressed on every frame, which can be played back
+//! to get exactly the same run of a game again.
+//!
+//! A movie file starts with the magic bytes `PYRITEMV` and a format version (u32), followed by:
+//! - the version of the emulator that recorded the movie (u8 length + UTF-8)
+//! - the hash of the GamePak ROM (u64)
+//! - the start condition: 0 (power on) followed by the skip BIOS flag (u8), or 1 (save state)
+//!   followed by the length of the state (u32) and the state
+//! - the rerecord count (u32)
+//! - the number of frames (u32) and then one u16 per frame: bits 0-9 are the pressed keys (in the
+//!   same order as KEYINPUT but 1 means pressed) and bit 15 is set on lag frames.
+
+use crate::keypad::KeypadInput;
+use crate::{Gba, GbaAudioOutput, GbaVideoOutput};
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+const MAGIC: &[u8; 8] = b"PYRITEMV";
+const FORMAT_VERSION: u32 = 1;
+
+const START_POWER_ON: u8 = 0;
+const START_SAVE_STATE: u8 = 1;
+
+const FRAME_KEYS_MASK: u16 = 0x03FF;
+const FRAME_LAG: u16 = 0x8000;
+
+/// The version that is written into recorded movies.
+pub const EMULATOR_VERSION: &str = concat!("pyrite ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieStart {
+    /// The movie starts by turning the system on.
+    PowerOn { skip_bios: bool },
+
+    /// The movie starts from a save state (see `Gba::save_state`).
+    SaveState(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MovieFrame {
+    /// The keys that were pressed during the frame. Bits are in the same order as KEYINPUT but 1
+    /// means that a key is pressed.
+    pub keys: u16,
+
+    /// True if the game didn't read KEYINPUT during the frame.
+    pub lag: bool,
+}
+
+impl MovieFrame {
+    #[inline]
+    pub fn is_pressed(self, key: KeypadInput) -> bool {
+        (self.keys & key.mask()) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub emulator_version: String,
+    pub rom_hash: u64,
+    pub start: MovieStart,
+    pub rerecord_count: u32,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn lag_frame_count(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.lag).count()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        out.write_bytes(MAGIC);
+        out.write_u32(FORMAT_VERSION);
+
+        let version = self.emulator_version.as_bytes();
+        let version = &version[..version.len().min(255)];
+        out.write_u8(version.len() as u8);
+        out.write_bytes(version);
+
+        out.write_u64(self.rom_hash);
+        match self.start {
+            MovieStart::PowerOn { skip_bios } => {
+                out.write_u8(START_POWER_ON);
+                out.write_bool(skip_bios);
+            }
+            MovieStart::SaveState(ref state) => {
+                out.write_u8(START_SAVE_STATE);
+                out.write_u32(state.len() as u32);
+                out.write_bytes(state);
+            }
+        }
+        out.write_u32(self.rerecord_count);
+
+        out.write_u32(self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            out.write_u16((frame.keys & FRAME_KEYS_MASK) | if frame.lag { FRAME_LAG } else { 0 });
+        }
+        out.into_inner()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, StateError> {
+        let mut reader = StateReader::new(data);
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::InvalidData(String::from("not a movie")));
+        }
+        reader.read_version("movie format", FORMAT_VERSION)?;
+
+        let version_len = reader.read_u8()? as usize;
+        let emulator_version =
+            String::from_utf8_lossy(reader.read_bytes(version_len)?).into_owned();
+        let rom_hash = reader.read_u64()?;
+        let start = match reader.read_u8()? {
+            START_POWER_ON => MovieStart::PowerOn {
+                skip_bios: reader.read_bool()?,
+            },
+            START_SAVE_STATE => {
+                let len = reader.read_u32()? as usize;
+                MovieStart::SaveState(reader.read_bytes(len)?.to_vec())
+            }
+            value => {
+                return Err(StateError::InvalidData(format!(
+                    "bad movie start: {}",
+                    value
+                )))
+            }
+        };
+        let rerecord_count = reader.read_u32()?;
+
+        let frame_count = reader.read_u32()? as usize;
+        if frame_count > reader.remaining() / 2 {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let mut frames = Vec::with_capacity(frame_count);
+        for _ in 0..frame_count {
+            let frame = reader.read_u16()?;
+            frames.push(MovieFrame {
+                keys: frame & FRAME_KEYS_MASK,
+                lag: (frame & FRAME_LAG) != 0,
+            });
+        }
+
+        Ok(Movie {
+            emulator_version,
+            rom_hash,
+            start,
+            rerecord_count,
+            frames,
+        })
+    }
+
+    /// Imports a VisualBoyAdvance movie (`.vbm`) for the ROM that is loaded into `gba`. Only GBA
+    /// movies that start from power on are supported. VBM movies don't record lag frames.
+    pub fn from_vbm(data: &[u8], gba: &Gba) -> Result<Movie, StateError> {
+        const VBM_MAGIC: &[u8; 4] = b"VBM\x1A";
+        const START_FROM_SNAPSHOT: u8 = 0x01;
+        const START_FROM_SRAM: u8 = 0x02;
+        const SYSTEM_GBA: u8 = 0x01;
+        const OPTION_USE_BIOS: u8 = 0x01;
+        const OPTION_SKIP_BIOS: u8 = 0x02;
+
+        let mut reader = StateReader::new(data);
+        if reader.read_bytes(4)? != VBM_MAGIC {
+            return Err(StateError::InvalidData(String::from("not a VBM movie")));
+        }
+        reader.read_version("VBM", 1)?;
+        let _uid = reader.read_u32()?;
+        let frame_count = reader.read_u32()? as usize;
+        let rerecord_count = reader.read_u32()?;
+        let start_flags = reader.read_u8()?;
+        let controller_flags = reader.read_u8()?;
+        let system_flags = reader.read_u8()?;
+        let option_flags = reader.read_u8()?;
+
+        if (system_flags & SYSTEM_GBA) == 0 {
+            return Err(StateError::InvalidData(String::from(
+                "VBM movie is not for the GBA",
+            )));
+        }
+        if (start_flags & START_FROM_SNAPSHOT) != 0 {
+            return Err(StateError::InvalidData(String::from(
+                "VBM movies that start from a snapshot are not supported",
+            )));
+        }
+        if (start_flags & START_FROM_SRAM) != 0 {
+            // @TODO support this once backup memory is emulated.
+            return Err(StateError::InvalidData(String::from(
+                "VBM movies that start with SRAM are not supported",
+            )));
+        }
+
+        // The game code is checked to make sure that the movie was made for this ROM.
+        let mut header = StateReader::new(&data[0x34.min(data.len())..]);
+        let game_code = header.read_bytes(4)?;
+        if gba.hardware.gamepak.len() >= 0xB0 && game_code != &gba.hardware.gamepak[0xAC..0xB0] {
+            return Err(StateError::InvalidData(String::from(
+                "VBM movie was recorded with a different ROM",
+            )));
+        }
+        let _state_offset = header.read_u32()?;
+        let input_offset = header.read_u32()? as usize;
+
+        // Every controller that is in use has 2 bytes per frame. Only the first one is used.
+        let controllers = (controller_flags & 0xF).count_ones().max(1) as usize;
+        if input_offset > data.len() {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let mut input = StateReader::new(&data[input_offset..]);
+        if frame_count > input.remaining() / (2 * controllers) {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let mut frames = Vec::with_capacity(frame_count);
+        for _ in 0..frame_count {
+            let keys = input.read_u16()?;
+            input.read_bytes(2 * (controllers - 1))?;
+            frames.push(MovieFrame {
+                keys: keys & FRAME_KEYS_MASK,
+                lag: false,
+            });
+        }
+
+        let use_bios = (option_flags & OPTION_USE_BIOS) != 0;
+        let skip_bios = !use_bios || (option_flags & OPTION_SKIP_BIOS) != 0;
+        Ok(Movie {
+            emulator_version: String::from("VisualBoyAdvance"),
+            rom_hash: gba.hardware.gamepak_hash,
+            start: MovieStart::PowerOn { skip_bios },
+            rerecord_count,
+            frames,
+        })
+    }
+}
+
+/// Applies a movie's start condition to `gba`.
+fn start_movie(gba: &mut Gba, start: &MovieStart) -> Result<(), StateError> {
+    match start {
+        MovieStart::PowerOn { skip_bios } => gba.power_on(*skip_bios),
+        MovieStart::SaveState(state) => gba.load_state(state)?,
+    }
+    gba.take_keypad_polled();
+    Ok(())
+}
+
+/// Records the keys that are pressed on every frame.
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    /// Starts recording a movie. This applies the start condition to `gba`, so a movie that starts
+    /// from power on will reset the system.
+    pub fn start(gba: &mut Gba, start: MovieStart) -> Result<MovieRecorder, StateError> {
+        start_movie(gba, &start)?;
+        Ok(MovieRecorder {
+            movie: Movie {
+                emulator_version: String::from(EMULATOR_VERSION),
+                rom_hash: gba.hardware.gamepak_hash,
+                start,
+                rerecord_count: 0,
+                frames: Vec::new(),
+            },
+        })
+    }
+
+    /// Runs one frame with the keys that are currently pressed and records them.
+    pub fn record_frame(
+        &mut self,
+        gba: &mut Gba,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) {
+        let keys = !gba.hardware.keypad.input & FRAME_KEYS_MASK;
+        gba.take_keypad_polled();
+        gba.video_frame(video, audio);
+        let lag = !gba.take_keypad_polled();
+        self.movie.frames.push(MovieFrame { keys, lag });
+    }
+
+    /// Throws away every frame after the first `frame` frames and counts a rerecord. This should
+    /// be called after loading a save state that was taken at that frame.
+    pub fn rerecord(&mut self, frame: usize) {
+        self.movie.frames.truncate(frame);
+        self.movie.rerecord_count += 1;
+    }
+
+    /// The number of frames that have been recorded.
+    pub fn frame(&self) -> usize {
+        self.movie.frames.len()
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Plays a movie back by pressing the recorded keys on every frame.
+pub struct MoviePlayer {
+    movie: Movie,
+    position: usize,
+    lag_frames: usize,
+}
+
+impl MoviePlayer {
+    /// Starts playing a movie. Fails if the movie was recorded with a different ROM.
+    pub fn start(gba: &mut Gba, movie: Movie) -> Result<MoviePlayer, StateError> {
+        if movie.rom_hash != gba.hardware.gamepak_hash {
+            return Err(StateError::InvalidData(String::from(
+                "the movie was recorded with a different GamePak ROM",
+            )));
+        }
+        start_movie(gba, &movie.start)?;
+        Ok(MoviePlayer {
+            movie,
+            position: 0,
+            lag_frames: 0,
+        })
+    }
+
+    /// Runs the next frame of the movie. Returns false without running anything once the movie
+    /// has finished.
+    pub fn play_frame(
+        &mut self,
+        gba: &mut Gba,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) -> bool {
+        let frame = match self.movie.frames.get(self.position) {
+            Some(&frame) => frame,
+            None => return false,
+        };
+
+        for &key in KeypadInput::ALL.iter() {
+            gba.set_key_pressed(key, frame.is_pressed(key));
+        }
+        gba.take_keypad_polled();
+        gba.video_frame(video, audio);
+        if !gba.take_keypad_polled() {
+            self.lag_frames += 1;
+        }
+
+        self.position += 1;
+        true
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.movie.frames.len()
+    }
+
+    /// The number of frames that have been played.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The number of lag frames that have been played.
+    pub fn lag_frames(&self) -> usize {
+        self.lag_frames
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
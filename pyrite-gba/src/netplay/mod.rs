@@ -0,0 +1,508 @@
+//! Rollback netplay between two peers.
+//!
+//! Every peer emulates the consoles of both players. The local player's console gets the local
+//! input right away and the other console gets the input that was last received from the other
+//! peer as a prediction of its real input. When the real input for a frame arrives and it is not
+//! what was predicted, the consoles are rolled back to a save state from before that frame and
+//! the frames since then are run again with the right input.
+//!
+//! Every `HASH_INTERVAL` frames whose input has been confirmed by both peers, the state of the
+//! consoles is hashed and the hash is sent to the other peer. Different hashes for the same frame
+//! mean that the peers have desynced.
+//!
+//! The consoles are connected with a `LinkCable` so games that use the link port see each other.
+
+mod udp;
+
+pub use udp::UdpTransport;
+
+use crate::keypad::KeypadInput;
+use crate::link::LinkCable;
+use crate::{Gba, GbaAudioOutput, GbaVideoOutput, NoAudioOutput, NoVideoOutput};
+use pyrite_common::state::hash;
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+
+/// The number of players (and consoles) in a session.
+pub const PLAYERS: usize = 2;
+
+const PROTOCOL_VERSION: u32 = 2;
+
+/// The most frames that can be run with predicted input. The session waits for the other peer
+/// after this.
+const MAX_PREDICTION_FRAMES: u32 = 8;
+
+/// The number of past frames of input that are sent in every packet so that a lost packet
+/// doesn't have to be sent again.
+const INPUT_REDUNDANCY: u32 = 16;
+
+/// The number of confirmed frames between two state hashes.
+const HASH_INTERVAL: u32 = 30;
+
+/// The number of hashes that are kept around to compare with the other peer's.
+const MAX_KEPT_HASHES: usize = 16;
+
+const MAX_PACKET_SIZE: usize = 512;
+const PACKET_HELLO: u8 = 0;
+const PACKET_INPUT: u8 = 1;
+
+const KEYS_MASK: u16 = 0x03FF;
+
+/// Sends packets to and receives packets from the other peer.
+pub trait NetplayTransport {
+    /// Sends a packet. Packets might be lost or arrive out of order.
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+
+    /// Receives a packet into `buf` without blocking. Returns `None` if there are none waiting.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+}
+
+#[derive(Debug)]
+pub enum NetplayError {
+    Io(io::Error),
+    State(StateError),
+
+    /// The other peer can't play with this one (e.g. it is using a different ROM).
+    Incompatible(String),
+}
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetplayError::Io(err) => write!(f, "netplay I/O error: {}", err),
+            NetplayError::State(err) => write!(f, "netplay state error: {}", err),
+            NetplayError::Incompatible(message) => write!(f, "incompatible peer: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for NetplayError {}
+
+impl From<io::Error> for NetplayError {
+    fn from(err: io::Error) -> NetplayError {
+        NetplayError::Io(err)
+    }
+}
+
+impl From<StateError> for NetplayError {
+    fn from(err: StateError) -> NetplayError {
+        NetplayError::State(err)
+    }
+}
+
+pub struct NetplaySession<T: NetplayTransport> {
+    transport: T,
+    local_player: usize,
+    link: LinkCable,
+    connected: bool,
+
+    /// The next frame that will be run.
+    frame: u32,
+
+    /// All frames before this one have been run with the real input of both players.
+    confirmed_frame: u32,
+
+    /// The local input for every frame that has been run.
+    local_inputs: Vec<u16>,
+
+    /// The input that was received from the other peer.
+    remote_inputs: Vec<Option<u16>>,
+
+    /// The remote input that every frame was actually run with.
+    used_remote_inputs: Vec<u16>,
+
+    /// The state of all consoles at the start of every frame from `confirmed_frame` to `frame - 1`.
+    snapshots: VecDeque<Vec<u8>>,
+
+    local_hashes: BTreeMap<u32, u64>,
+    remote_hashes: BTreeMap<u32, u64>,
+    desync_frame: Option<u32>,
+    rollbacks: u32,
+}
+
+impl<T: NetplayTransport> NetplaySession<T> {
+    /// Creates a session. `consoles` has a console for every player (with the same ROM that the
+    /// other peer is using) and all of them are powered on. `advance_frame` has to be called until
+    /// the other peer answers.
+    pub fn new(
+        transport: T,
+        mut consoles: Vec<Gba>,
+        local_player: usize,
+        skip_bios: bool,
+    ) -> NetplaySession<T> {
+        assert_eq!(consoles.len(), PLAYERS, "wrong number of consoles");
+        assert!(local_player < PLAYERS, "bad local player");
+
+        for console in consoles.iter_mut() {
+            console.power_on(skip_bios);
+        }
+
+        NetplaySession {
+            transport,
+            local_player,
+            link: LinkCable::new(consoles),
+            connected: false,
+
+            frame: 0,
+            confirmed_frame: 0,
+            local_inputs: Vec::new(),
+            remote_inputs: Vec::new(),
+            used_remote_inputs: Vec::new(),
+            snapshots: VecDeque::new(),
+
+            local_hashes: BTreeMap::new(),
+            remote_hashes: BTreeMap::new(),
+            desync_frame: None,
+            rollbacks: 0,
+        }
+    }
+
+    /// Runs the next frame with the keys that are pressed locally (bits in the same order as
+    /// KEYINPUT but 1 means pressed). The local player's console is sent to `video` and `audio`.
+    /// Returns false without running anything if the session has to wait for the other peer.
+    pub fn advance_frame(
+        &mut self,
+        local_keys: u16,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) -> Result<bool, NetplayError> {
+        self.receive()?;
+        if !self.connected {
+            self.send_hello()?;
+            return Ok(false);
+        }
+
+        if self.frame - self.confirmed_frame >= MAX_PREDICTION_FRAMES {
+            self.send_inputs()?;
+            return Ok(false);
+        }
+
+        self.local_inputs.push(local_keys & KEYS_MASK);
+        self.send_inputs()?;
+
+        let remote = self.remote_input(self.frame);
+        self.used_remote_inputs.push(remote);
+        let snapshot = self.save_consoles()?;
+        self.snapshots.push_back(snapshot);
+        self.run_frame(self.frame, video, audio);
+        self.frame += 1;
+
+        self.advance_confirmed()?;
+        Ok(true)
+    }
+
+    /// Receives everything that the other peer has sent without running a frame. This should be
+    /// called regularly while the local player isn't advancing so that the other peer keeps
+    /// getting our input.
+    pub fn poll(&mut self) -> Result<(), NetplayError> {
+        self.receive()?;
+        if self.connected {
+            self.send_inputs()?;
+        } else {
+            self.send_hello()?;
+        }
+        Ok(())
+    }
+
+    /// Receives everything that the other peer has sent and rolls back if a prediction was wrong.
+    fn receive(&mut self) -> Result<(), NetplayError> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        while let Some(len) = self.transport.recv(&mut buf)? {
+            if let Err(err) = self.handle_packet(&buf[..len]) {
+                match err {
+                    NetplayError::State(err) => log::warn!("bad netplay packet: {}", err),
+                    err => return Err(err),
+                }
+            }
+        }
+
+        let first_wrong = (self.confirmed_frame..self.frame).find(|&frame| {
+            match self.remote_inputs.get(frame as usize) {
+                Some(&Some(keys)) => keys != self.used_remote_inputs[frame as usize],
+                _ => false,
+            }
+        });
+        if let Some(frame) = first_wrong {
+            self.rollback(frame)?;
+        }
+        self.advance_confirmed()
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> Result<(), NetplayError> {
+        let mut reader = StateReader::new(packet);
+        match reader.read_u8()? {
+            PACKET_HELLO => {
+                let version = reader.read_u32()?;
+                if version != PROTOCOL_VERSION {
+                    return Err(NetplayError::Incompatible(format!(
+                        "protocol version {} (expected {})",
+                        version, PROTOCOL_VERSION
+                    )));
+                }
+                let player = reader.read_u8()? as usize;
+                let rom_hash = reader.read_u64()?;
+                let acknowledged = reader.read_bool()?;
+                if player == self.local_player || player >= PLAYERS {
+                    return Err(NetplayError::Incompatible(format!(
+                        "both peers are player {}",
+                        player
+                    )));
+                }
+                if rom_hash != self.rom_hash() {
+                    return Err(NetplayError::Incompatible(String::from(
+                        "the other peer is using a different ROM",
+                    )));
+                }
+                self.connected = true;
+                if !acknowledged {
+                    // The other peer hasn't gotten our hello yet. This is answered every time
+                    // because the answer might be lost.
+                    self.send_hello()?;
+                }
+            }
+
+            PACKET_INPUT => {
+                // Input from a peer that hasn't sent a valid hello yet is ignored.
+                if !self.connected {
+                    return Ok(());
+                }
+
+                let start = reader.read_u32()?;
+                let count = reader.read_u8()? as u32;
+                // The other peer can't be further ahead than this.
+                if start.saturating_add(count) > self.frame + 2 * MAX_PREDICTION_FRAMES {
+                    return Err(NetplayError::State(StateError::InvalidData(String::from(
+                        "input is too far ahead",
+                    ))));
+                }
+                for frame in start..(start + count) {
+                    let keys = reader.read_u16()? & KEYS_MASK;
+                    if self.remote_inputs.len() <= frame as usize {
+                        self.remote_inputs.resize(frame as usize + 1, None);
+                    }
+                    self.remote_inputs[frame as usize] = Some(keys);
+                }
+
+                let hash_frame = reader.read_u32()?;
+                let hash = reader.read_u64()?;
+                if hash_frame != 0 {
+                    self.remote_hashes.insert(hash_frame, hash);
+                    trim_hashes(&mut self.remote_hashes);
+                    self.check_hash(hash_frame);
+                }
+            }
+
+            kind => {
+                return Err(NetplayError::State(StateError::InvalidData(format!(
+                    "unknown packet kind: {}",
+                    kind
+                ))))
+            }
+        }
+        Ok(())
+    }
+
+    fn send_hello(&mut self) -> Result<(), NetplayError> {
+        let mut packet = StateWriter::new();
+        packet.write_u8(PACKET_HELLO);
+        packet.write_u32(PROTOCOL_VERSION);
+        packet.write_u8(self.local_player as u8);
+        packet.write_u64(self.rom_hash());
+        // Tells the other peer whether it still has to answer.
+        packet.write_bool(self.connected);
+        self.transport.send(&packet.into_inner())?;
+        Ok(())
+    }
+
+    /// Sends the last few frames of local input and the latest state hash.
+    fn send_inputs(&mut self) -> Result<(), NetplayError> {
+        let end = self.local_inputs.len() as u32;
+        let start = end.saturating_sub(INPUT_REDUNDANCY);
+        let (hash_frame, hash) = self
+            .local_hashes
+            .iter()
+            .next_back()
+            .map(|(&frame, &hash)| (frame, hash))
+            .unwrap_or((0, 0));
+
+        let mut packet = StateWriter::new();
+        packet.write_u8(PACKET_INPUT);
+        packet.write_u32(start);
+        packet.write_u8((end - start) as u8);
+        for frame in start..end {
+            packet.write_u16(self.local_inputs[frame as usize]);
+        }
+        packet.write_u32(hash_frame);
+        packet.write_u64(hash);
+        self.transport.send(&packet.into_inner())?;
+        Ok(())
+    }
+
+    /// The input of the other player for a frame, or a prediction of it if it hasn't been
+    /// received yet. The last input that was received is used as the prediction.
+    fn remote_input(&self, frame: u32) -> u16 {
+        let received = &self.remote_inputs[..(frame as usize + 1).min(self.remote_inputs.len())];
+        received.iter().rev().find_map(|&keys| keys).unwrap_or(0)
+    }
+
+    /// Goes back to the start of `frame` and runs all frames after it again.
+    fn rollback(&mut self, frame: u32) -> Result<(), NetplayError> {
+        let first = (frame - self.confirmed_frame) as usize;
+        self.load_consoles(&self.snapshots[first].clone())?;
+        self.rollbacks += 1;
+
+        for frame in frame..self.frame {
+            let index = (frame - self.confirmed_frame) as usize;
+            if index != first {
+                self.snapshots[index] = self.save_consoles()?;
+            }
+            self.used_remote_inputs[frame as usize] = self.remote_input(frame);
+            self.run_frame(frame, &mut NoVideoOutput, &mut NoAudioOutput);
+        }
+        Ok(())
+    }
+
+    /// Moves `confirmed_frame` past every frame that was run with the real input of the other
+    /// player and hashes the state every `HASH_INTERVAL` frames.
+    fn advance_confirmed(&mut self) -> Result<(), NetplayError> {
+        while self.confirmed_frame < self.frame {
+            match self.remote_inputs.get(self.confirmed_frame as usize) {
+                Some(Some(_)) => {}
+                _ => break,
+            }
+            self.confirmed_frame += 1;
+            self.snapshots.pop_front();
+
+            if self.confirmed_frame.is_multiple_of(HASH_INTERVAL) {
+                let state = match self.snapshots.front() {
+                    Some(snapshot) => hash(snapshot),
+                    None => hash(&self.save_consoles()?),
+                };
+                self.local_hashes.insert(self.confirmed_frame, state);
+                trim_hashes(&mut self.local_hashes);
+                self.check_hash(self.confirmed_frame);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_hash(&mut self, frame: u32) {
+        if self.desync_frame.is_some() {
+            return;
+        }
+        if let (Some(local), Some(remote)) = (
+            self.local_hashes.get(&frame),
+            self.remote_hashes.get(&frame),
+        ) {
+            if local != remote {
+                log::error!(
+                    "netplay desync at frame {}: {:016X} != {:016X}",
+                    frame,
+                    local,
+                    remote
+                );
+                self.desync_frame = Some(frame);
+            }
+        }
+    }
+
+    fn run_frame(
+        &mut self,
+        frame: u32,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) {
+        for player in 0..PLAYERS {
+            let keys = if player == self.local_player {
+                self.local_inputs[frame as usize]
+            } else {
+                self.used_remote_inputs[frame as usize]
+            };
+
+            let console = &mut self.link.consoles_mut()[player];
+            for &key in KeypadInput::ALL.iter() {
+                console.set_key_pressed(key, (keys & key.mask()) != 0);
+            }
+        }
+
+        let mut other_video = NoVideoOutput;
+        let mut other_audio = NoAudioOutput;
+        let mut videos: [&mut dyn GbaVideoOutput; PLAYERS] = [video, &mut other_video];
+        let mut audios: [&mut dyn GbaAudioOutput; PLAYERS] = [audio, &mut other_audio];
+        if self.local_player != 0 {
+            videos.swap(0, self.local_player);
+            audios.swap(0, self.local_player);
+        }
+        self.link.video_frame(&mut videos, &mut audios);
+    }
+
+    fn save_consoles(&self) -> Result<Vec<u8>, StateError> {
+        let mut state = StateWriter::new();
+        for console in self.link.consoles().iter() {
+            let console_state = console.save_state(false)?;
+            state.write_u32(console_state.len() as u32);
+            state.write_bytes(&console_state);
+        }
+        Ok(state.into_inner())
+    }
+
+    fn load_consoles(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        for console in self.link.consoles_mut().iter_mut() {
+            let len = state.read_u32()? as usize;
+            console.load_state(state.read_bytes(len)?)?;
+        }
+        Ok(())
+    }
+
+    fn rom_hash(&self) -> u64 {
+        self.link.consoles().iter().fold(0, |acc, console| {
+            acc.rotate_left(17) ^ console.hardware.gamepak_hash
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn local_player(&self) -> usize {
+        self.local_player
+    }
+
+    /// The number of frames that have been run.
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// The number of frames that have been run with the real input of both players.
+    pub fn confirmed_frame(&self) -> u32 {
+        self.confirmed_frame
+    }
+
+    /// The first frame in which the state hashes of the two peers were different.
+    pub fn desync_frame(&self) -> Option<u32> {
+        self.desync_frame
+    }
+
+    /// The number of times that the session had to roll back because of a wrong prediction.
+    pub fn rollbacks(&self) -> u32 {
+        self.rollbacks
+    }
+
+    pub fn console(&self, player: usize) -> &Gba {
+        &self.link.consoles()[player]
+    }
+
+    pub fn console_mut(&mut self, player: usize) -> &mut Gba {
+        &mut self.link.consoles_mut()[player]
+    }
+}
+
+fn trim_hashes(hashes: &mut BTreeMap<u32, u64>) {
+    while hashes.len() > MAX_KEPT_HASHES {
+        let oldest = *hashes.keys().next().unwrap();
+        hashes.remove(&oldest);
+    }
+}
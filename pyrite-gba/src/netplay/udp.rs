@@ -0,0 +1,50 @@
+use super::NetplayTransport;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// Sends netplay packets over UDP.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Binds a socket to `local`. `connect` has to be called before anything is sent.
+    pub fn bind<A: ToSocketAddrs>(local: A) -> io::Result<UdpTransport> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+
+    /// Sets the address of the other peer. Packets from other addresses are ignored.
+    pub fn connect<A: ToSocketAddrs>(&self, peer: A) -> io::Result<()> {
+        self.socket.connect(peer)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl NetplayTransport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.socket.send(packet) {
+            Ok(_) => Ok(()),
+            // The other peer isn't listening yet (or anymore). Packets can get lost anyway.
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.socket.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
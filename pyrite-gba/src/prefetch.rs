@@ -0,0 +1,128 @@
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+/// The prefetch buffer holds up to 8 halfwords.
+const PREFETCH_BUFFER_SIZE: u32 = 8;
+
+/// The GamePak prefetch buffer. While the CPU is running code from the GamePak and isn't using the
+/// GamePak bus itself (internal cycles and accesses to other memory) the buffer keeps reading the
+/// halfwords that come after the last opcode that was fetched. Sequential opcode fetches that are
+/// already in the buffer only take 1 cycle.
+pub struct GamePakPrefetch {
+    /// Set while the buffer is following the CPU's opcode fetches.
+    active: bool,
+
+    /// The address of the first halfword in the buffer, or of the halfword that is being fetched
+    /// if the buffer is empty.
+    head: u32,
+
+    /// The number of halfwords in the buffer.
+    count: u32,
+
+    /// Cycles that have been spent fetching the halfword that comes after the buffer.
+    progress: u32,
+
+    /// Cycles for a sequential halfword access to the wait state region that is being read.
+    seq_cycles: u32,
+}
+
+impl GamePakPrefetch {
+    pub fn new() -> GamePakPrefetch {
+        GamePakPrefetch {
+            active: false,
+            head: 0,
+            count: 0,
+            progress: 0,
+            seq_cycles: 1,
+        }
+    }
+
+    /// Empties the buffer. This happens whenever something else takes over the GamePak bus or
+    /// the CPU stops running code from the GamePak.
+    #[inline]
+    pub fn stop(&mut self) {
+        self.active = false;
+        self.count = 0;
+        self.progress = 0;
+    }
+
+    /// Starts filling an empty buffer from `address` after a non-sequential opcode fetch.
+    #[inline]
+    pub fn restart(&mut self, address: u32, seq_cycles: u32) {
+        self.active = true;
+        self.head = address;
+        self.count = 0;
+        self.progress = 0;
+        self.seq_cycles = seq_cycles.max(1);
+    }
+
+    /// Lets the buffer fill for `cycles` cycles in which the GamePak bus isn't used.
+    #[inline]
+    pub fn idle(&mut self, cycles: u32) {
+        if !self.active || self.count >= PREFETCH_BUFFER_SIZE {
+            return;
+        }
+
+        self.progress += cycles;
+        while self.progress >= self.seq_cycles && self.count < PREFETCH_BUFFER_SIZE {
+            self.progress -= self.seq_cycles;
+            self.count += 1;
+        }
+
+        if self.count >= PREFETCH_BUFFER_SIZE {
+            self.progress = 0;
+        }
+    }
+
+    /// Takes `halfwords` halfwords starting at `address` for an opcode fetch. Returns the cycles
+    /// that the fetch took or None if the buffer isn't reading from `address`. Halfwords that
+    /// aren't in the buffer yet have to wait until the buffer is done fetching them.
+    #[inline]
+    pub fn fetch(&mut self, address: u32, halfwords: u32) -> Option<u32> {
+        if !self.active || address != self.head {
+            return None;
+        }
+
+        let mut cycles = 0;
+        for _ in 0..halfwords {
+            if self.count > 0 {
+                self.count -= 1;
+            } else {
+                cycles += self.seq_cycles - self.progress;
+                self.progress = 0;
+            }
+            self.head = self.head.wrapping_add(2);
+        }
+
+        if cycles == 0 {
+            // Everything was in the buffer so the fetch only takes 1 cycle and the buffer keeps
+            // going in the meantime.
+            cycles = 1;
+            self.idle(1);
+        }
+
+        Some(cycles)
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.active);
+        state.write_u32(self.head);
+        state.write_u32(self.count);
+        state.write_u32(self.progress);
+        state.write_u32(self.seq_cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.active = state.read_bool()?;
+        self.head = state.read_u32()?;
+        self.count = state.read_u32()?;
+        self.progress = state.read_u32()?;
+        self.seq_cycles = state.read_u32()?;
+        if self.count > PREFETCH_BUFFER_SIZE || self.seq_cycles == 0 {
+            return Err(StateError::InvalidData(format!(
+                "bad prefetch buffer state: {} halfwords, {} cycles",
+                self.count, self.seq_cycles
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! A rewind buffer built on save states.
+//!
+//! Only the newest snapshot is kept in full. Every older snapshot is stored as the difference
+//! (XOR) between it and the snapshot that came after it, compressed with
+//! `pyrite_common::state::compress`. Most of a state doesn't change between two frames so those
+//! differences are mostly zeroes and compress very well.
+
+use crate::Gba;
+use pyrite_common::state::{compress, decompress};
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RewindConfig {
+    /// The maximum number of bytes used by all snapshots. The oldest snapshots are dropped to
+    /// stay under it.
+    pub memory_budget: usize,
+
+    /// The number of frames between two snapshots. 1 rewinds frame by frame.
+    pub granularity: u32,
+}
+
+impl Default for RewindConfig {
+    fn default() -> RewindConfig {
+        RewindConfig {
+            memory_budget: 32 * 1024 * 1024,
+            granularity: 1,
+        }
+    }
+}
+
+pub struct Rewind {
+    config: RewindConfig,
+    frames_until_snapshot: u32,
+
+    /// The newest snapshot.
+    latest: Option<Vec<u8>>,
+
+    /// Compressed differences between snapshots, newest at the back. Applying the one at the back
+    /// to `latest` gives the snapshot that was taken before it.
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Rewind {
+        Rewind {
+            config: RewindConfig {
+                memory_budget: config.memory_budget,
+                granularity: config.granularity.max(1),
+            },
+            frames_until_snapshot: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    /// Should be called after every frame. Takes a snapshot every `granularity` frames.
+    pub fn on_frame(&mut self, gba: &Gba) -> Result<(), StateError> {
+        if self.frames_until_snapshot > 0 {
+            self.frames_until_snapshot -= 1;
+            return Ok(());
+        }
+        self.frames_until_snapshot = self.config.granularity - 1;
+        self.snapshot(gba)
+    }
+
+    /// Takes a snapshot right now.
+    pub fn snapshot(&mut self, gba: &Gba) -> Result<(), StateError> {
+        let state = gba.save_state(false)?;
+        if let Some(previous) = self.latest.take() {
+            let delta = encode_delta(&previous, &state);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(state);
+
+        while self.memory_used() > self.config.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Goes back one snapshot and loads it into `gba`. Returns false without changing anything if
+    /// there are no older snapshots left.
+    pub fn rewind(&mut self, gba: &mut Gba) -> Result<bool, StateError> {
+        let latest = match self.latest {
+            Some(ref latest) => latest,
+            None => return Ok(false),
+        };
+        let delta = match self.deltas.back() {
+            Some(delta) => delta,
+            None => return Ok(false),
+        };
+
+        let previous = decode_delta(latest, delta)?;
+        gba.load_state(&previous)?;
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.deltas_size -= delta.len();
+        }
+        self.latest = Some(previous);
+        self.frames_until_snapshot = self.config.granularity;
+        Ok(true)
+    }
+
+    /// Drops all snapshots. This should be done after loading a different ROM or a save state.
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+        self.frames_until_snapshot = 0;
+    }
+
+    /// The number of snapshots that are in the buffer.
+    pub fn len(&self) -> usize {
+        if self.latest.is_some() {
+            self.deltas.len() + 1
+        } else {
+            0
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// The number of bytes used by all snapshots.
+    pub fn memory_used(&self) -> usize {
+        self.latest.as_ref().map(|latest| latest.len()).unwrap_or(0) + self.deltas_size
+    }
+}
+
+/// Encodes `older` as a difference from `newer`.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let diff: Vec<u8> = older
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| byte ^ newer.get(index).copied().unwrap_or(0))
+        .collect();
+
+    let mut delta = StateWriter::new();
+    delta.write_u32(older.len() as u32);
+    delta.write_bytes(&compress(&diff));
+    delta.into_inner()
+}
+
+/// Decodes a delta created by `encode_delta` and returns the older state.
+fn decode_delta(newer: &[u8], delta: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut reader = StateReader::new(delta);
+    let length = reader.read_u32()? as usize;
+    let mut older = decompress(reader.read_bytes(reader.remaining())?, length)?;
+    if older.len() != length {
+        return Err(StateError::UnexpectedEnd);
+    }
+
+    for (index, byte) in older.iter_mut().enumerate() {
+        *byte ^= newer.get(index).copied().unwrap_or(0);
+    }
+    Ok(older)
+}
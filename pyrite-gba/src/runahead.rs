@@ -0,0 +1,59 @@
+//! Run-ahead: hides the input latency of a game by running a few frames ahead with the current
+//! input, showing the last of them and then going back to the real frame.
+//!
+//! Every frame:
+//! 1. The real frame is run with audio but without video.
+//! 2. The state is saved.
+//! 3. `frames - 1` hidden frames are run without video or audio.
+//! 4. One more frame is run with video but without audio. This is the frame that is shown.
+//! 5. The state from step 2 is loaded again.
+//!
+//! Only the real frame is logged by the frame hash log so the log is the same with or without
+//! run-ahead.
+
+use crate::{Gba, GbaAudioOutput, GbaVideoOutput, NoAudioOutput, NoVideoOutput};
+use pyrite_common::StateError;
+
+pub struct RunAhead {
+    frames: u32,
+}
+
+impl RunAhead {
+    /// Creates a run-ahead that runs `frames` frames ahead. 0 disables it.
+    pub fn new(frames: u32) -> RunAhead {
+        RunAhead { frames }
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn set_frames(&mut self, frames: u32) {
+        self.frames = frames;
+    }
+
+    /// Runs one frame. When this returns `gba` is one frame further along but `video` has been
+    /// given the frame that is `frames` frames after that.
+    pub fn run_frame(
+        &mut self,
+        gba: &mut Gba,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) -> Result<(), StateError> {
+        if self.frames == 0 {
+            gba.video_frame(video, audio);
+            return Ok(());
+        }
+
+        gba.video_frame(&mut NoVideoOutput, audio);
+        let state = gba.save_state(false)?;
+        let frame_hash_log = gba.frame_hash_log.take();
+        for _ in 1..self.frames {
+            gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+        }
+        gba.video_frame(video, &mut NoAudioOutput);
+        gba.frame_hash_log = frame_hash_log;
+        gba.load_state(&state)?;
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
 use super::audio::PSGChannel;
 use super::dma::DMAChannelIndex;
 use super::irq::Interrupt;
+use pyrite_common::{StateError, StateReader, StateWriter};
 
 use std::cell::UnsafeCell;
 use std::rc::Rc;
 
 pub const MAX_GBA_EVENTS: usize = 32;
 
+const SCHEDULER_STATE_VERSION: u32 = 1;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GbaEvent {
     None,
@@ -22,6 +25,73 @@ pub enum GbaEvent {
     PSGChannelStepEnvelope(PSGChannel),
     PSGChannel0StepSweep,
     Padding,
+    SerialTransfer,
+    WirelessAdapterPoll,
+    UartReceive,
+    IRQLine,
+}
+
+impl GbaEvent {
+    /// Writes the event as a kind and an argument.
+    fn save_state(self, state: &mut StateWriter) {
+        let (kind, argument) = match self {
+            GbaEvent::None => (0, 0),
+            GbaEvent::Halt => (1, 0),
+            GbaEvent::Stop => (2, 0),
+            GbaEvent::IRQ(interrupt) => (3, interrupt as u8),
+            GbaEvent::DMA(channel) => (4, u8::from(channel)),
+            GbaEvent::HBlank => (5, 0),
+            GbaEvent::HDraw => (6, 0),
+            GbaEvent::TimerOverflows => (7, 0),
+            GbaEvent::AudioUpdate => (8, 0),
+            GbaEvent::StopPSGChannel(channel) => (9, channel.index8()),
+            GbaEvent::PSGChannelStepEnvelope(channel) => (10, channel.index8()),
+            GbaEvent::PSGChannel0StepSweep => (11, 0),
+            GbaEvent::Padding => (12, 0),
+            GbaEvent::SerialTransfer => (13, 0),
+            GbaEvent::WirelessAdapterPoll => (14, 0),
+            GbaEvent::UartReceive => (15, 0),
+            GbaEvent::IRQLine => (16, 0),
+        };
+        state.write_u8(kind);
+        state.write_u8(argument);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<GbaEvent, StateError> {
+        let kind = state.read_u8()?;
+        let argument = state.read_u8()?;
+        let bad_event = || StateError::InvalidData(format!("bad event: {} {}", kind, argument));
+
+        let psg_channel = || {
+            if argument < 4 {
+                Ok(PSGChannel::from_index(argument as usize))
+            } else {
+                Err(bad_event())
+            }
+        };
+
+        let event = match kind {
+            0 => GbaEvent::None,
+            1 => GbaEvent::Halt,
+            2 => GbaEvent::Stop,
+            3 => GbaEvent::IRQ(Interrupt::from_index(argument).ok_or_else(bad_event)?),
+            4 => GbaEvent::DMA(DMAChannelIndex::from_index(argument).ok_or_else(bad_event)?),
+            5 => GbaEvent::HBlank,
+            6 => GbaEvent::HDraw,
+            7 => GbaEvent::TimerOverflows,
+            8 => GbaEvent::AudioUpdate,
+            9 => GbaEvent::StopPSGChannel(psg_channel()?),
+            10 => GbaEvent::PSGChannelStepEnvelope(psg_channel()?),
+            11 => GbaEvent::PSGChannel0StepSweep,
+            12 => GbaEvent::Padding,
+            13 => GbaEvent::SerialTransfer,
+            14 => GbaEvent::WirelessAdapterPoll,
+            15 => GbaEvent::UartReceive,
+            16 => GbaEvent::IRQLine,
+            _ => return Err(bad_event()),
+        };
+        Ok(event)
+    }
 }
 
 impl Default for GbaEvent {
@@ -60,6 +130,42 @@ impl GbaScheduler {
         sched
     }
 
+    /// Writes the queued events with the cycles remaining until each of them.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(SCHEDULER_STATE_VERSION);
+        state.write_u32(self.late);
+        state.write_u32(self.event_count as u32);
+        // The first node's cycles are used even while there are no events.
+        state.write_u32(self.events[0].cycles);
+        for node in self.events[..self.event_count].iter() {
+            state.write_u32(node.cycles);
+            node.event.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_version("scheduler", SCHEDULER_STATE_VERSION)?;
+        let late = state.read_u32()?;
+        let event_count = state.read_u32()? as usize;
+        if event_count > MAX_GBA_EVENTS {
+            return Err(StateError::InvalidData(format!(
+                "too many scheduled events: {}",
+                event_count
+            )));
+        }
+        let first_cycles = state.read_u32()?;
+
+        self.clear();
+        for node in self.events[..event_count].iter_mut() {
+            node.cycles = state.read_u32()?;
+            node.event = GbaEvent::load_state(state)?;
+        }
+        self.events[0].cycles = first_cycles;
+        self.event_count = event_count;
+        self.late = late;
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         self.events
             .iter_mut()
@@ -93,6 +199,12 @@ impl GbaScheduler {
         }
     }
 
+    /// The number of cycles that can pass before `step` returns true.
+    #[inline(always)]
+    pub fn cycles_until_next_event(&self) -> u32 {
+        self.events[0].cycles
+    }
+
     #[inline(always)]
     pub fn step(&mut self, cycles: u32) -> bool {
         if cycles < self.events[0].cycles {
@@ -105,6 +217,19 @@ impl GbaScheduler {
         }
     }
 
+    /// The number of cycles until the first occurence of `event` fires or `None` if it isn't
+    /// scheduled.
+    pub fn cycles_until(&self, event: GbaEvent) -> Option<u32> {
+        let mut cycles = 0;
+        for node in self.events[0..self.event_count].iter() {
+            cycles += node.cycles;
+            if node.event == event {
+                return Some(cycles);
+            }
+        }
+        None
+    }
+
     // pub fn contains(&self, event: GbaEvent) -> bool {
     //     self.events.iter().any(|node| node.event == event)
     // }
@@ -213,6 +338,16 @@ impl SharedGbaScheduler {
         unsafe { (*self.0.get()).step(cycles) }
     }
 
+    #[inline]
+    pub fn cycles_until_next_event(&self) -> u32 {
+        unsafe { (*self.0.get()).cycles_until_next_event() }
+    }
+
+    #[inline]
+    pub fn cycles_until(&self, event: GbaEvent) -> Option<u32> {
+        unsafe { (*self.0.get()).cycles_until(event) }
+    }
+
     #[inline]
     pub fn pop_event(
         &self,
@@ -251,6 +386,14 @@ impl SharedGbaScheduler {
     pub fn clear(&self) {
         unsafe { (*self.0.get()).clear() };
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        unsafe { (*self.0.get()).save_state(state) };
+    }
+
+    pub fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        unsafe { (*self.0.get()).load_state(state) }
+    }
 }
 
 impl Clone for SharedGbaScheduler {
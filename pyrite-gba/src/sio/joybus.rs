@@ -0,0 +1,293 @@
+//! JOY Bus mode, which is how a GameCube talks to a GBA through the GBA link cable.
+//!
+//! The GameCube is always in charge. It sends a command and the GBA answers right away without
+//! the CPU's help, so the only thing that games do is fill JOY_TRANS, read JOY_RECV and look at
+//! the flags in JOYCNT and JOYSTAT. `GameCube` plays the GameCube's side for tests and scripts.
+
+use super::{GbaSerial, SerialMode};
+use crate::irq::Interrupt;
+use crate::scheduler::GbaEvent;
+use crate::{Gba, GbaAudioOutput, GbaVideoOutput};
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+/// The device type that a GBA reports in response to the status and reset commands.
+pub const JOYBUS_DEVICE_GBA: u16 = 0x0004;
+
+const JOYCNT_RESET: u16 = 0x0001;
+const JOYCNT_RECEIVE: u16 = 0x0002;
+const JOYCNT_SEND: u16 = 0x0004;
+const JOYCNT_IRQ: u16 = 0x0040;
+
+const JOYSTAT_RECEIVE: u16 = 0x0002;
+const JOYSTAT_SEND: u16 = 0x0008;
+const JOYSTAT_GENERAL_PURPOSE: u16 = 0x0030;
+
+const CMD_STATUS: u8 = 0x00;
+const CMD_READ: u8 = 0x14;
+const CMD_WRITE: u8 = 0x15;
+const CMD_RESET: u8 = 0xFF;
+
+/// A command that the GameCube sends. `Read` reads JOY_TRANS and `Write` writes JOY_RECV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoyBusCommand {
+    Reset,
+    Status,
+    Read,
+    Write(u32),
+}
+
+impl JoyBusCommand {
+    /// Decodes a command as it is sent on the bus.
+    pub fn from_bytes(bytes: &[u8]) -> Option<JoyBusCommand> {
+        match *bytes.first()? {
+            CMD_RESET => Some(JoyBusCommand::Reset),
+            CMD_STATUS => Some(JoyBusCommand::Status),
+            CMD_READ => Some(JoyBusCommand::Read),
+            CMD_WRITE if bytes.len() >= 5 => {
+                let mut data = [0u8; 4];
+                data.copy_from_slice(&bytes[1..5]);
+                Some(JoyBusCommand::Write(u32::from_le_bytes(data)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            JoyBusCommand::Reset => vec![CMD_RESET],
+            JoyBusCommand::Status => vec![CMD_STATUS],
+            JoyBusCommand::Read => vec![CMD_READ],
+            JoyBusCommand::Write(data) => {
+                let mut bytes = vec![CMD_WRITE];
+                bytes.extend_from_slice(&data.to_le_bytes());
+                bytes
+            }
+        }
+    }
+}
+
+/// The GBA's answer to a `JoyBusCommand`. Every answer ends with JOYSTAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoyBusResponse {
+    Status { device: u16, joystat: u8 },
+    Read { data: u32, joystat: u8 },
+    Write { joystat: u8 },
+}
+
+impl JoyBusResponse {
+    pub fn joystat(self) -> u8 {
+        match self {
+            JoyBusResponse::Status { joystat, .. } => joystat,
+            JoyBusResponse::Read { joystat, .. } => joystat,
+            JoyBusResponse::Write { joystat } => joystat,
+        }
+    }
+
+    /// Encodes the response as it is sent on the bus.
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            JoyBusResponse::Status { device, joystat } => {
+                vec![(device >> 8) as u8, device as u8, joystat]
+            }
+            JoyBusResponse::Read { data, joystat } => {
+                let mut bytes = data.to_le_bytes().to_vec();
+                bytes.push(joystat);
+                bytes
+            }
+            JoyBusResponse::Write { joystat } => vec![joystat],
+        }
+    }
+}
+
+/// The JOY Bus registers.
+#[derive(Default)]
+pub(crate) struct JoyBusState {
+    joycnt: u16,
+    joystat: u16,
+    receive: [u16; 2],
+    send: [u16; 2],
+}
+
+impl JoyBusState {
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.joycnt);
+        state.write_u16(self.joystat);
+        for &value in self.receive.iter().chain(self.send.iter()) {
+            state.write_u16(value);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.joycnt = state.read_u16()?;
+        self.joystat = state.read_u16()?;
+        for value in self.receive.iter_mut().chain(self.send.iter_mut()) {
+            *value = state.read_u16()?;
+        }
+        Ok(())
+    }
+}
+
+impl GbaSerial {
+    pub fn read_joycnt(&self) -> u16 {
+        self.joybus.joycnt
+    }
+
+    /// The flags are acknowledged by writing 1s to them, like IF.
+    pub fn write_joycnt(&mut self, value: u16) {
+        let flags = JOYCNT_RESET | JOYCNT_RECEIVE | JOYCNT_SEND;
+        self.joybus.joycnt = (self.joybus.joycnt & flags & !value) | (value & JOYCNT_IRQ);
+    }
+
+    pub fn read_joystat(&self) -> u16 {
+        self.joybus.joystat
+    }
+
+    /// Only the general purpose flags can be written.
+    pub fn write_joystat(&mut self, value: u16) {
+        self.joybus.joystat =
+            (self.joybus.joystat & !JOYSTAT_GENERAL_PURPOSE) | (value & JOYSTAT_GENERAL_PURPOSE);
+    }
+
+    /// Reads the low (`index` 0) or high half of JOY_RECV.
+    pub fn read_joy_receive(&self, index: usize) -> u16 {
+        self.joybus.receive[index]
+    }
+
+    pub fn write_joy_receive(&mut self, index: usize, value: u16) {
+        self.joybus.receive[index] = value;
+    }
+
+    /// Reads the low (`index` 0) or high half of JOY_TRANS.
+    pub fn read_joy_send(&self, index: usize) -> u16 {
+        self.joybus.send[index]
+    }
+
+    /// The GameCube can see that there is something to read once JOY_TRANS was written.
+    pub fn write_joy_send(&mut self, index: usize, value: u16) {
+        self.joybus.send[index] = value;
+        self.joybus.joystat |= JOYSTAT_SEND;
+    }
+
+    /// Reading JOY_RECV tells the GameCube that the data was received.
+    pub(crate) fn joy_receive_read(&mut self) {
+        self.joybus.joystat &= !JOYSTAT_RECEIVE;
+    }
+
+    /// Carries out a command from the GameCube. There is no answer unless the serial port is in
+    /// JOY Bus mode.
+    pub fn joybus_command(&mut self, command: JoyBusCommand) -> Option<JoyBusResponse> {
+        if self.mode() != SerialMode::JoyBus {
+            return None;
+        }
+
+        let response = match command {
+            JoyBusCommand::Reset | JoyBusCommand::Status => {
+                if command == JoyBusCommand::Reset {
+                    self.joybus_flag(JOYCNT_RESET);
+                }
+                JoyBusResponse::Status {
+                    device: JOYBUS_DEVICE_GBA,
+                    joystat: self.joybus.joystat as u8,
+                }
+            }
+
+            JoyBusCommand::Read => {
+                let data = (self.joybus.send[0] as u32) | ((self.joybus.send[1] as u32) << 16);
+                self.joybus.joystat &= !JOYSTAT_SEND;
+                self.joybus_flag(JOYCNT_SEND);
+                JoyBusResponse::Read {
+                    data,
+                    joystat: self.joybus.joystat as u8,
+                }
+            }
+
+            JoyBusCommand::Write(data) => {
+                self.joybus.receive = [data as u16, (data >> 16) as u16];
+                self.joybus.joystat |= JOYSTAT_RECEIVE;
+                self.joybus_flag(JOYCNT_RECEIVE);
+                JoyBusResponse::Write {
+                    joystat: self.joybus.joystat as u8,
+                }
+            }
+        };
+        Some(response)
+    }
+
+    fn joybus_flag(&mut self, flag: u16) {
+        self.joybus.joycnt |= flag;
+        if (self.joybus.joycnt & JOYCNT_IRQ) != 0 {
+            self.scheduler
+                .schedule(GbaEvent::IRQ(Interrupt::SerialCommunication), 0);
+        }
+    }
+}
+
+/// Plays the GameCube's side of the JOY Bus. Every command and its answer are kept so that
+/// scripts can check what happened afterwards.
+#[derive(Default)]
+pub struct GameCube {
+    history: Vec<(JoyBusCommand, Option<JoyBusResponse>)>,
+}
+
+impl GameCube {
+    pub fn new() -> GameCube {
+        GameCube::default()
+    }
+
+    pub fn send(&mut self, gba: &mut Gba, command: JoyBusCommand) -> Option<JoyBusResponse> {
+        let response = gba.hardware.sio.joybus_command(command);
+        self.history.push((command, response));
+        response
+    }
+
+    /// Resets the GBA's JOY Bus and returns JOYSTAT.
+    pub fn reset(&mut self, gba: &mut Gba) -> Option<u8> {
+        self.send(gba, JoyBusCommand::Reset)
+            .map(JoyBusResponse::joystat)
+    }
+
+    /// Returns JOYSTAT.
+    pub fn status(&mut self, gba: &mut Gba) -> Option<u8> {
+        self.send(gba, JoyBusCommand::Status)
+            .map(JoyBusResponse::joystat)
+    }
+
+    /// Reads JOY_TRANS.
+    pub fn read(&mut self, gba: &mut Gba) -> Option<u32> {
+        match self.send(gba, JoyBusCommand::Read)? {
+            JoyBusResponse::Read { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Writes JOY_RECV and returns JOYSTAT.
+    pub fn write(&mut self, gba: &mut Gba, data: u32) -> Option<u8> {
+        self.send(gba, JoyBusCommand::Write(data))
+            .map(JoyBusResponse::joystat)
+    }
+
+    /// Runs the GBA for up to `frames` frames, checking its status after every frame, and reads
+    /// JOY_TRANS as soon as the GBA wrote to it.
+    pub fn wait_for_data(
+        &mut self,
+        gba: &mut Gba,
+        frames: usize,
+        video: &mut dyn GbaVideoOutput,
+        audio: &mut dyn GbaAudioOutput,
+    ) -> Option<u32> {
+        for frame in 0..=frames {
+            if (self.status(gba)? as u16 & JOYSTAT_SEND) != 0 {
+                return self.read(gba);
+            }
+            if frame < frames {
+                gba.video_frame(video, audio);
+            }
+        }
+        None
+    }
+
+    /// Every command that was sent with its answer, oldest first.
+    pub fn history(&self) -> &[(JoyBusCommand, Option<JoyBusResponse>)] {
+        &self.history
+    }
+}
@@ -0,0 +1,506 @@
+//! Serial communication through the link port in Normal, Multiplayer, UART and JOY Bus mode.
+//!
+//! A console on its own behaves as if nothing was plugged into its link port. Transfers only reach
+//! other consoles once they are connected with a `LinkCable` (see `crate::link`), which starts the
+//! transfers that were requested and tells every console what it received. A Wireless Adapter
+//! (see `crate::wireless`) can be plugged in instead of a cable and answers Normal 32-bit
+//! transfers. UART mode talks to the host instead (see `UartHost`) and JOY Bus mode answers the
+//! commands of a GameCube (see `GameCube`).
+
+use crate::irq::Interrupt;
+use crate::link::{LinkPort, LinkUpdate};
+use crate::scheduler::{GbaEvent, SharedGbaScheduler};
+use crate::wireless::WirelessAdapter;
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+mod joybus;
+mod uart;
+use joybus::JoyBusState;
+pub use joybus::{GameCube, JoyBusCommand, JoyBusResponse, JOYBUS_DEVICE_GBA};
+use uart::UartState;
+pub use uart::{UartHost, UartStream};
+
+const SIO_STATE_VERSION: u32 = 3;
+
+const CPU_FREQUENCY: u32 = 16 * 1024 * 1024;
+
+/// Cycles per bit with the 256KHz and 2MHz Normal mode clocks.
+const NORMAL_SLOW_BIT_CYCLES: u32 = 64;
+const NORMAL_FAST_BIT_CYCLES: u32 = 8;
+
+const MULTIPLAYER_BAUD_RATES: [u32; 4] = [9600, 38400, 57600, 115200];
+
+/// Bits that are sent for every console in a Multiplayer transfer (start bit, data and stop bit).
+const MULTIPLAYER_BITS_PER_CONSOLE: u32 = 18;
+
+const RCNT_WRITE_MASK: u16 = 0xC1FF;
+
+/// How often a Wireless Adapter that has the clock checks whether it has something to send
+/// (16 lines).
+const WIRELESS_POLL_CYCLES: u32 = 16 * 1232;
+
+/// What is received from a link port that nothing is connected to (or from a console that is not
+/// taking part in a transfer).
+pub(crate) const DISCONNECTED: [u16; 4] = [0xFFFF; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialMode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    GeneralPurpose,
+    JoyBus,
+}
+
+pub struct GbaSerial {
+    /// SIOCNT without the read-only bits.
+    control: SerialControl,
+    rcnt: u16,
+
+    /// SIODATA32 or SIOMULTI0-3.
+    data: [u16; 4],
+
+    /// SIODATA8 or SIOMLT_SEND.
+    send: u16,
+
+    /// Written to the data registers once the transfer that is in progress completes.
+    incoming: [u16; 4],
+
+    /// Set after a transfer was started (or a Normal mode slave became ready) while the link
+    /// cable hasn't carried it out yet.
+    waiting: bool,
+
+    /// The state of the SI and SD terminals while the console is connected to a link cable.
+    si: bool,
+    sd: bool,
+
+    multiplayer_id: u16,
+
+    /// The number of cycles that the console has run past the last point where the link cable
+    /// synchronized it with the other consoles.
+    pub(crate) link_lead: u32,
+
+    /// Set while the console is connected to a link cable. This is not part of the save state.
+    pub(crate) linked: bool,
+
+    /// A Wireless Adapter that is plugged into the link port. It is only used while the console
+    /// isn't connected to a link cable.
+    /// @TODO The adapter isn't part of the save state so loading one (and rewinding or running
+    ///       ahead) while it is talking to other adapters confuses the game.
+    adapter: Option<WirelessAdapter>,
+
+    uart: UartState,
+
+    /// Where UART mode sends and receives bytes. This is not part of the save state.
+    uart_host: Option<Box<dyn UartHost>>,
+
+    joybus: JoyBusState,
+
+    scheduler: SharedGbaScheduler,
+}
+
+impl GbaSerial {
+    pub fn new(scheduler: SharedGbaScheduler) -> GbaSerial {
+        GbaSerial {
+            control: SerialControl::default(),
+            rcnt: 0,
+            data: [0; 4],
+            send: 0,
+            incoming: DISCONNECTED,
+            waiting: false,
+            si: false,
+            sd: false,
+            multiplayer_id: 0,
+            link_lead: 0,
+            linked: false,
+            adapter: None,
+            uart: UartState::default(),
+            uart_host: None,
+            joybus: JoyBusState::default(),
+            scheduler,
+        }
+    }
+
+    pub fn mode(&self) -> SerialMode {
+        if (self.rcnt & 0x8000) == 0 {
+            match self.control.mode() {
+                0 => SerialMode::Normal8,
+                1 => SerialMode::Normal32,
+                2 => SerialMode::Multiplayer,
+                _ => SerialMode::Uart,
+            }
+        } else if (self.rcnt & 0x4000) == 0 {
+            SerialMode::GeneralPurpose
+        } else {
+            SerialMode::JoyBus
+        }
+    }
+
+    pub fn read_control(&self) -> u16 {
+        if self.mode() == SerialMode::Uart {
+            return self.read_uart_control();
+        }
+
+        let mut control = self.control;
+        control.set_si(self.si_state());
+        if self.mode() == SerialMode::Multiplayer {
+            control.set_sd(self.sd_state());
+            control.set_multiplayer_id(self.multiplayer_id);
+            control.set_error(false);
+        }
+        control.value
+    }
+
+    pub fn write_control(&mut self, value: u16) {
+        let was_uart = self.mode() == SerialMode::Uart;
+        let was_receiving = self.uart_receiving();
+        let old = self.control.value;
+        let busy = !was_uart && self.control.start();
+        self.control.value = value;
+
+        // Bit 7 is the data length in UART mode rather than the start bit.
+        if was_uart || self.mode() == SerialMode::Uart {
+            self.uart_control_changed(old, was_uart, was_receiving);
+            return;
+        }
+
+        // In Multiplayer mode the start bit is a busy flag that only the parent can set.
+        if self.mode() == SerialMode::Multiplayer {
+            let parent = !self.si_state();
+            self.control
+                .set_start(busy || (parent && self.control.start()));
+        }
+
+        if !busy && self.control.start() {
+            self.start_transfer();
+        } else if busy && !self.control.start() {
+            // Stopping a Normal mode transfer before it is done just abandons it.
+            self.waiting = false;
+            self.scheduler.purge(GbaEvent::SerialTransfer);
+            self.scheduler.purge(GbaEvent::WirelessAdapterPoll);
+        }
+    }
+
+    pub fn read_rcnt(&self) -> u16 {
+        self.rcnt
+    }
+
+    pub fn write_rcnt(&mut self, value: u16) {
+        let was_general_purpose = self.mode() == SerialMode::GeneralPurpose;
+        let was_uart = self.mode() == SerialMode::Uart;
+        let was_receiving = self.uart_receiving();
+        self.rcnt = value & RCNT_WRITE_MASK;
+
+        if was_uart != (self.mode() == SerialMode::Uart) {
+            self.uart_control_changed(self.control.value, was_uart, was_receiving);
+        }
+
+        // Games reset the Wireless Adapter by taking over SD in General Purpose mode.
+        if !was_general_purpose && self.mode() == SerialMode::GeneralPurpose {
+            if let Some(ref mut adapter) = self.adapter {
+                adapter.reset();
+            }
+        }
+    }
+
+    /// Plugs a Wireless Adapter into the link port, replacing the one that was there before.
+    pub fn attach_wireless_adapter(&mut self, adapter: WirelessAdapter) -> Option<WirelessAdapter> {
+        self.adapter.replace(adapter)
+    }
+
+    pub fn detach_wireless_adapter(&mut self) -> Option<WirelessAdapter> {
+        self.adapter.take()
+    }
+
+    pub fn wireless_adapter(&self) -> Option<&WirelessAdapter> {
+        self.adapter.as_ref()
+    }
+
+    /// Reads SIODATA32 (`index` 0 and 1) or SIOMULTI0-3.
+    pub fn read_data(&self, index: usize) -> u16 {
+        self.data[index]
+    }
+
+    pub fn write_data(&mut self, index: usize, value: u16) {
+        self.data[index] = value;
+    }
+
+    /// Reads SIODATA8 or SIOMLT_SEND.
+    pub fn read_send(&self) -> u16 {
+        if self.mode() == SerialMode::Uart {
+            self.read_uart_data()
+        } else {
+            self.send
+        }
+    }
+
+    pub fn write_send(&mut self, value: u16) {
+        if self.mode() == SerialMode::Uart {
+            self.write_uart_data(value);
+        } else {
+            self.send = value;
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(SIO_STATE_VERSION);
+        state.write_u16(self.control.value);
+        state.write_u16(self.rcnt);
+        for &value in self.data.iter().chain(self.incoming.iter()) {
+            state.write_u16(value);
+        }
+        state.write_u16(self.send);
+        state.write_bool(self.waiting);
+        state.write_bool(self.si);
+        state.write_bool(self.sd);
+        state.write_u16(self.multiplayer_id);
+        state.write_u32(self.link_lead);
+        self.uart.save_state(state);
+        self.joybus.save_state(state);
+    }
+
+    /// Puts everything that is part of the save state back the way it is at power on. This is
+    /// used for states that were saved before the serial port was part of them.
+    pub(crate) fn reset_state(&mut self) {
+        self.control = SerialControl::default();
+        self.rcnt = 0;
+        self.data = [0; 4];
+        self.send = 0;
+        self.incoming = DISCONNECTED;
+        self.waiting = false;
+        self.si = false;
+        self.sd = false;
+        self.multiplayer_id = 0;
+        self.link_lead = 0;
+        self.uart = UartState::default();
+        self.joybus = JoyBusState::default();
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let version = state.read_version("SIO", SIO_STATE_VERSION)?;
+        self.control.value = state.read_u16()?;
+        self.rcnt = state.read_u16()? & RCNT_WRITE_MASK;
+        for value in self.data.iter_mut().chain(self.incoming.iter_mut()) {
+            *value = state.read_u16()?;
+        }
+        self.send = state.read_u16()?;
+        self.waiting = state.read_bool()?;
+        self.si = state.read_bool()?;
+        self.sd = state.read_bool()?;
+        self.multiplayer_id = state.read_u16()? & 3;
+        self.link_lead = state.read_u32()?;
+        if version >= 2 {
+            self.uart.load_state(state)?;
+        } else {
+            self.uart = UartState::default();
+        }
+        if version >= 3 {
+            self.joybus.load_state(state)?;
+        } else {
+            self.joybus = JoyBusState::default();
+        }
+        Ok(())
+    }
+
+    fn start_transfer(&mut self) {
+        match self.mode() {
+            SerialMode::Normal32 if !self.linked && self.adapter.is_some() => {
+                self.start_adapter_transfer();
+            }
+
+            SerialMode::Normal8 | SerialMode::Normal32 => {
+                if !self.linked && self.control.internal_clock() {
+                    // Nothing is connected so SI stays high and only 1s are shifted in.
+                    self.begin_transfer(DISCONNECTED, 0, self.transfer_cycles(1));
+                } else {
+                    // With an external clock this waits for a master's clock, which never comes
+                    // without a cable.
+                    self.waiting = true;
+                }
+            }
+
+            SerialMode::Multiplayer => {
+                if self.linked {
+                    self.waiting = true;
+                } else {
+                    let mut incoming = DISCONNECTED;
+                    incoming[0] = self.send;
+                    self.begin_transfer(incoming, 0, self.transfer_cycles(1));
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    /// Exchanges a word with the Wireless Adapter. With an external clock the adapter decides when
+    /// the transfer happens so this keeps checking until it has something to send.
+    fn start_adapter_transfer(&mut self) {
+        let outgoing = self.normal_outgoing();
+        let sent = (outgoing[0] as u32) | ((outgoing[1] as u32) << 16);
+        let adapter = match self.adapter {
+            Some(ref mut adapter) => adapter,
+            None => return,
+        };
+
+        let received = if self.control.internal_clock() {
+            Some(adapter.exchange(sent))
+        } else {
+            adapter.drive()
+        };
+
+        match received {
+            Some(received) => {
+                let mut incoming = DISCONNECTED;
+                incoming[0] = received as u16;
+                incoming[1] = (received >> 16) as u16;
+                self.begin_transfer(incoming, 0, self.transfer_cycles(1));
+            }
+
+            None => {
+                self.waiting = true;
+                self.scheduler.purge(GbaEvent::WirelessAdapterPoll);
+                self.scheduler
+                    .schedule(GbaEvent::WirelessAdapterPoll, WIRELESS_POLL_CYCLES);
+            }
+        }
+    }
+
+    /// Called regularly while the console waits for the Wireless Adapter's clock.
+    pub(crate) fn poll_wireless_adapter(&mut self) {
+        if self.waiting && self.control.start() && self.mode() == SerialMode::Normal32 {
+            self.start_adapter_transfer();
+        }
+    }
+
+    /// Starts a transfer that will complete after `cycles` cycles.
+    fn begin_transfer(&mut self, incoming: [u16; 4], id: u16, cycles: u32) {
+        self.waiting = false;
+        self.incoming = incoming;
+        self.control.set_start(true);
+        if self.mode() == SerialMode::Multiplayer {
+            self.multiplayer_id = id;
+            self.data = DISCONNECTED;
+        }
+        self.scheduler.purge(GbaEvent::SerialTransfer);
+        self.scheduler.schedule(GbaEvent::SerialTransfer, cycles);
+    }
+
+    /// Called once a transfer's time is up.
+    pub(crate) fn complete_transfer(&mut self) {
+        if self.mode() == SerialMode::Uart {
+            self.complete_uart_send();
+            return;
+        }
+
+        match self.mode() {
+            SerialMode::Normal8 => self.send = (self.send & 0xFF00) | (self.incoming[0] & 0xFF),
+            SerialMode::Normal32 => self.data[0..2].copy_from_slice(&self.incoming[0..2]),
+            SerialMode::Multiplayer => self.data = self.incoming,
+            _ => {}
+        }
+
+        self.control.set_start(false);
+        if self.control.irq() {
+            self.scheduler
+                .schedule(GbaEvent::IRQ(Interrupt::SerialCommunication), 0);
+        }
+    }
+
+    /// What the link cable needs to know about the port when `consoles` consoles are connected.
+    pub(crate) fn link_port(&self, consoles: u32) -> LinkPort {
+        LinkPort {
+            mode: self.mode(),
+            waiting: self.waiting,
+            internal_clock: self.control.internal_clock(),
+            so_level: self.control.sd(),
+            send: self.send,
+            normal_outgoing: self.normal_outgoing(),
+            transfer_cycles: self.transfer_cycles(consoles),
+        }
+    }
+
+    pub(crate) fn apply_link_update(&mut self, update: &LinkUpdate) {
+        self.si = update.si;
+        self.sd = update.sd;
+        if let Some(ref transfer) = update.transfer {
+            self.begin_transfer(transfer.incoming, transfer.id, transfer.cycles);
+        }
+    }
+
+    /// The number of cycles that a transfer takes in the current mode between `consoles`
+    /// consoles. With an external clock this depends on the other console so the link cable uses
+    /// the master's timing for both sides.
+    fn transfer_cycles(&self, consoles: u32) -> u32 {
+        let normal_bit_cycles = if self.control.fast_clock() {
+            NORMAL_FAST_BIT_CYCLES
+        } else {
+            NORMAL_SLOW_BIT_CYCLES
+        };
+
+        match self.mode() {
+            SerialMode::Normal8 => 8 * normal_bit_cycles,
+            SerialMode::Normal32 => 32 * normal_bit_cycles,
+            _ => {
+                // #NOTE This ignores the time that passes between the data of every console.
+                let baud_rate = MULTIPLAYER_BAUD_RATES[self.control.baud_rate() as usize];
+                (CPU_FREQUENCY / baud_rate) * MULTIPLAYER_BITS_PER_CONSOLE * consoles
+            }
+        }
+    }
+
+    /// The data that is sent in Normal mode, in the same layout as `incoming`.
+    fn normal_outgoing(&self) -> [u16; 4] {
+        let mut outgoing = DISCONNECTED;
+        if self.mode() == SerialMode::Normal32 {
+            outgoing[0..2].copy_from_slice(&self.data[0..2]);
+        } else {
+            outgoing[0] = self.send | 0xFF00;
+        }
+        outgoing
+    }
+
+    fn si_state(&self) -> bool {
+        if self.linked {
+            self.si
+        } else {
+            // Without a cable a console is its own Multiplayer parent (SI low) and SI is pulled
+            // high in Normal mode.
+            self.mode() != SerialMode::Multiplayer
+        }
+    }
+
+    fn sd_state(&self) -> bool {
+        self.linked && self.sd
+    }
+}
+
+bitfields! (SerialControl: u16 {
+    internal_clock, set_internal_clock: bool = [0, 0],
+    fast_clock, set_fast_clock: bool = [1, 1],
+    baud_rate, set_baud_rate: u16 = [0, 1],
+    si, set_si: bool = [2, 2],
+    sd, set_sd: bool = [3, 3],
+    multiplayer_id, set_multiplayer_id: u16 = [4, 5],
+    error, set_error: bool = [6, 6],
+    start, set_start: bool = [7, 7],
+    mode, set_mode: u16 = [12, 13],
+    irq, set_irq: bool = [14, 14],
+});
+
+// SIOCNT in UART mode.
+bitfields! (UartControl: u16 {
+    baud_rate, set_baud_rate: u16 = [0, 1],
+    cts, set_cts: bool = [2, 2],
+    odd_parity, set_odd_parity: bool = [3, 3],
+    send_full, set_send_full: bool = [4, 4],
+    receive_empty, set_receive_empty: bool = [5, 5],
+    error, set_error: bool = [6, 6],
+    eight_bits, set_eight_bits: bool = [7, 7],
+    fifo, set_fifo: bool = [8, 8],
+    parity, set_parity: bool = [9, 9],
+    send_enabled, set_send_enabled: bool = [10, 10],
+    receive_enabled, set_receive_enabled: bool = [11, 11],
+    irq, set_irq: bool = [14, 14],
+});
@@ -0,0 +1,540 @@
+//! UART (RS-232) mode and a bridge that connects it to a byte stream on the host.
+//!
+//! The console's side of the UART lives in `GbaSerial` like the other modes. Bytes that it sends
+//! go to a `UartHost` and bytes from the `UartHost` are received one at a time at the selected
+//! baud rate. `UartStream` is a `UartHost` for any byte stream, like a Unix socket or (on Linux)
+//! a pseudo-terminal that a terminal program can be attached to.
+
+use super::{GbaSerial, SerialMode, UartControl, CPU_FREQUENCY, MULTIPLAYER_BAUD_RATES};
+use crate::irq::Interrupt;
+use crate::scheduler::GbaEvent;
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[cfg(unix)]
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/// The size of the send and receive FIFOs when they are enabled. Without them there is room for
+/// a single byte in each direction.
+const UART_FIFO_SIZE: usize = 4;
+
+/// The other end of the UART.
+pub trait UartHost {
+    /// Called with every byte that the console finished sending.
+    fn send(&mut self, byte: u8);
+
+    /// Returns the next byte for the console if there is one.
+    fn receive(&mut self) -> Option<u8>;
+}
+
+/// The UART's FIFOs and flags.
+#[derive(Default)]
+pub(crate) struct UartState {
+    send_fifo: VecDeque<u8>,
+    receive_fifo: VecDeque<u8>,
+
+    /// Set while the byte at the front of the send FIFO is being shifted out.
+    sending: bool,
+
+    error: bool,
+}
+
+impl UartState {
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        for fifo in [&self.send_fifo, &self.receive_fifo].iter() {
+            state.write_u8(fifo.len() as u8);
+            for &byte in fifo.iter() {
+                state.write_u8(byte);
+            }
+        }
+        state.write_bool(self.sending);
+        state.write_bool(self.error);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for fifo in [&mut self.send_fifo, &mut self.receive_fifo].iter_mut() {
+            let len = state.read_u8()? as usize;
+            if len > UART_FIFO_SIZE {
+                return Err(StateError::InvalidData(format!(
+                    "bad UART FIFO size: {}",
+                    len
+                )));
+            }
+            fifo.clear();
+            for _ in 0..len {
+                fifo.push_back(state.read_u8()?);
+            }
+        }
+        self.sending = state.read_bool()?;
+        self.error = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl GbaSerial {
+    /// Connects the UART to something on the host, replacing what was connected before.
+    pub fn attach_uart(&mut self, host: Box<dyn UartHost>) -> Option<Box<dyn UartHost>> {
+        let previous = self.uart_host.replace(host);
+        self.try_uart_send();
+        previous
+    }
+
+    pub fn detach_uart(&mut self) -> Option<Box<dyn UartHost>> {
+        self.uart_host.take()
+    }
+
+    pub(super) fn read_uart_control(&self) -> u16 {
+        let mut control = UartControl::wrap(self.control.value);
+        control.set_send_full(self.uart.send_fifo.len() >= self.uart_fifo_size());
+        control.set_receive_empty(self.uart.receive_fifo.is_empty());
+        control.set_error(self.uart.error);
+        control.value
+    }
+
+    /// Called after SIOCNT or RCNT were written while the UART was (or now is) selected.
+    pub(super) fn uart_control_changed(&mut self, old: u16, was_uart: bool, was_receiving: bool) {
+        let control = UartControl::wrap(self.control.value);
+        let old = UartControl::wrap(old);
+        let uart = self.mode() == SerialMode::Uart;
+
+        if uart != was_uart {
+            // Whatever the other mode (or the UART) was doing is abandoned.
+            self.waiting = false;
+            self.uart.sending = false;
+            self.scheduler.purge(GbaEvent::SerialTransfer);
+        }
+
+        // The FIFOs are reset by disabling them.
+        if !uart || (old.fifo() && !control.fifo()) {
+            self.uart.send_fifo.clear();
+            self.uart.receive_fifo.clear();
+            if self.uart.sending {
+                self.uart.sending = false;
+                self.scheduler.purge(GbaEvent::SerialTransfer);
+            }
+        }
+
+        let receiving = self.uart_receiving();
+        if receiving && !was_receiving {
+            self.scheduler
+                .schedule(GbaEvent::UartReceive, self.uart_byte_cycles());
+        } else if !receiving && was_receiving {
+            self.scheduler.purge(GbaEvent::UartReceive);
+        }
+
+        if uart {
+            self.try_uart_send();
+        }
+    }
+
+    /// SIODATA8 shows the oldest received byte.
+    pub(super) fn read_uart_data(&self) -> u16 {
+        match self.uart.receive_fifo.front() {
+            Some(&byte) => byte as u16,
+            None => self.send & 0xFF,
+        }
+    }
+
+    /// Reading SIODATA8 takes the byte out of the receive FIFO.
+    pub(crate) fn uart_data_read(&mut self) {
+        if self.mode() == SerialMode::Uart {
+            if let Some(byte) = self.uart.receive_fifo.pop_front() {
+                self.send = byte as u16;
+            }
+        }
+    }
+
+    /// Reading SIOCNT clears the error flag.
+    pub(crate) fn uart_control_read(&mut self) {
+        if self.mode() == SerialMode::Uart {
+            self.uart.error = false;
+        }
+    }
+
+    pub(super) fn write_uart_data(&mut self, value: u16) {
+        if self.uart.send_fifo.len() < self.uart_fifo_size() {
+            self.uart.send_fifo.push_back(value as u8);
+        } else if let Some(last) = self.uart.send_fifo.back_mut() {
+            // Without room the byte that is waiting last is replaced.
+            *last = value as u8;
+        }
+        self.try_uart_send();
+    }
+
+    /// Called once the byte at the front of the send FIFO was shifted out.
+    pub(super) fn complete_uart_send(&mut self) {
+        self.uart.sending = false;
+        let control = UartControl::wrap(self.control.value);
+        if let Some(byte) = self.uart.send_fifo.pop_front() {
+            let byte = if control.eight_bits() {
+                byte
+            } else {
+                byte & 0x7F
+            };
+            if let Some(ref mut host) = self.uart_host {
+                host.send(byte);
+            }
+        }
+
+        if self.uart.send_fifo.is_empty() {
+            self.uart_irq();
+        }
+        self.try_uart_send();
+    }
+
+    /// Called every time that a byte could have been received while receiving is enabled.
+    pub(crate) fn poll_uart(&mut self) {
+        if !self.uart_receiving() {
+            return;
+        }
+
+        let control = UartControl::wrap(self.control.value);
+        let received = self.uart_host.as_mut().and_then(|host| host.receive());
+        if let Some(byte) = received {
+            if self.uart.receive_fifo.len() < self.uart_fifo_size() {
+                let byte = if control.eight_bits() {
+                    byte
+                } else {
+                    byte & 0x7F
+                };
+                self.uart.receive_fifo.push_back(byte);
+            } else {
+                // #NOTE The byte that doesn't fit is lost. Parity and stop bit errors can't
+                //       happen because the host only deals in whole bytes.
+                self.uart.error = true;
+            }
+            self.uart_irq();
+        }
+
+        self.scheduler
+            .schedule(GbaEvent::UartReceive, self.uart_byte_cycles());
+    }
+
+    /// Starts shifting out the next byte if sending is enabled and allowed.
+    fn try_uart_send(&mut self) {
+        let control = UartControl::wrap(self.control.value);
+        if self.mode() != SerialMode::Uart
+            || self.uart.sending
+            || self.uart.send_fifo.is_empty()
+            || !control.send_enabled()
+        {
+            return;
+        }
+
+        // With CTS enabled the console only sends while the other side pulls SC low, which is
+        // when something is connected on the host.
+        if control.cts() && self.uart_host.is_none() {
+            return;
+        }
+
+        self.uart.sending = true;
+        self.scheduler
+            .schedule(GbaEvent::SerialTransfer, self.uart_byte_cycles());
+    }
+
+    pub(super) fn uart_receiving(&self) -> bool {
+        self.mode() == SerialMode::Uart && UartControl::wrap(self.control.value).receive_enabled()
+    }
+
+    fn uart_fifo_size(&self) -> usize {
+        if UartControl::wrap(self.control.value).fifo() {
+            UART_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// The number of cycles that it takes to send or receive a byte with its start bit, parity
+    /// bit and stop bit.
+    fn uart_byte_cycles(&self) -> u32 {
+        let control = UartControl::wrap(self.control.value);
+        let baud_rate = MULTIPLAYER_BAUD_RATES[control.baud_rate() as usize];
+        let bits = 2 + if control.eight_bits() { 8 } else { 7 } + control.parity() as u32;
+        (CPU_FREQUENCY / baud_rate) * bits
+    }
+
+    fn uart_irq(&mut self) {
+        if UartControl::wrap(self.control.value).irq() {
+            self.scheduler
+                .schedule(GbaEvent::IRQ(Interrupt::SerialCommunication), 0);
+        }
+    }
+}
+
+/// A `UartHost` for a byte stream. Reading and writing happen on other threads so a slow or
+/// missing reader on the host never stalls the emulator. The threads stop when the stream is
+/// dropped, except for a reader thread that is blocked on a stream that was passed to `new`,
+/// which stops once that stream has more bytes or is closed.
+pub struct UartStream {
+    incoming: Arc<Mutex<VecDeque<u8>>>,
+    outgoing: Sender<u8>,
+
+    /// Where the writer thread sends bytes. Bytes are dropped while nothing is connected.
+    writer: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+
+    /// Set when the stream is dropped so that the threads that read from the host stop.
+    stopped: Arc<AtomicBool>,
+
+    /// The socket that `listen_unix` is listening on.
+    #[cfg(unix)]
+    socket: Option<UartSocket>,
+
+    /// Kept open so that reading from a pseudo-terminal blocks instead of failing while no
+    /// terminal program has it open.
+    #[cfg(target_os = "linux")]
+    _pty_slave: Option<std::fs::File>,
+}
+
+impl UartStream {
+    fn unconnected() -> UartStream {
+        let (outgoing, receiver) = mpsc::channel();
+        let stream = UartStream {
+            incoming: Arc::new(Mutex::new(VecDeque::new())),
+            outgoing,
+            writer: Arc::new(Mutex::new(None)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            #[cfg(unix)]
+            socket: None,
+            #[cfg(target_os = "linux")]
+            _pty_slave: None,
+        };
+        let writer = stream.writer.clone();
+        thread::spawn(move || write_bytes(&receiver, &writer));
+        stream
+    }
+
+    /// Bridges the UART to a stream that is split into a reading and a writing half.
+    pub fn new<R, W>(reader: R, writer: W) -> UartStream
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let stream = UartStream::unconnected();
+        stream.connect(reader, writer);
+        stream
+    }
+
+    /// Listens for connections on a Unix domain socket. One program can be connected at a time
+    /// and a new connection replaces the old one.
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(path: P) -> io::Result<UartStream> {
+        let listener = UnixListener::bind(path.as_ref())?;
+        let mut stream = UartStream::unconnected();
+        let current = Arc::new(Mutex::new(None));
+        stream.socket = Some(UartSocket {
+            path: path.as_ref().to_path_buf(),
+            current: current.clone(),
+        });
+
+        let incoming = stream.incoming.clone();
+        let writer = stream.writer.clone();
+        let stopped = stream.stopped.clone();
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let connection = match connection {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        log::warn!("UART socket error: {}", err);
+                        continue;
+                    }
+                };
+                let (reader, control) = match (connection.try_clone(), connection.try_clone()) {
+                    (Ok(reader), Ok(control)) => (reader, control),
+                    (Err(err), _) | (_, Err(err)) => {
+                        log::warn!("UART socket error: {}", err);
+                        continue;
+                    }
+                };
+
+                // The old connection is shut down so that its reader thread stops.
+                let mut current = current
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                if stopped.load(Ordering::SeqCst) {
+                    let _ = control.shutdown(Shutdown::Both);
+                    return;
+                }
+                if let Some(old) = current.replace(control) {
+                    let _ = old.shutdown(Shutdown::Both);
+                }
+                connect_stream(&incoming, &writer, &stopped, reader, connection);
+            }
+        });
+        Ok(stream)
+    }
+
+    /// Creates a pseudo-terminal and returns the path of the terminal end, which a terminal
+    /// program (e.g. `screen` or `minicom`) can open.
+    #[cfg(target_os = "linux")]
+    pub fn pty() -> io::Result<(UartStream, std::path::PathBuf)> {
+        use std::fs::{File, OpenOptions};
+        use std::os::unix::io::FromRawFd;
+
+        let check = |result: libc::c_int| {
+            if result < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(result)
+            }
+        };
+
+        let master = unsafe {
+            let fd = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            let master = File::from_raw_fd(fd);
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+            master
+        };
+
+        let mut name = [0 as libc::c_char; 128];
+        let path = unsafe {
+            use std::os::unix::io::AsRawFd;
+            let result = libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len());
+            if result != 0 {
+                return Err(io::Error::from_raw_os_error(result));
+            }
+            let name = std::ffi::CStr::from_ptr(name.as_ptr());
+            std::path::PathBuf::from(name.to_string_lossy().into_owned())
+        };
+
+        // Bytes go through the terminal exactly as they were sent.
+        let slave = OpenOptions::new().read(true).write(true).open(&path)?;
+        unsafe {
+            use std::os::unix::io::AsRawFd;
+            let mut termios: libc::termios = std::mem::zeroed();
+            check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+        }
+
+        let mut stream = UartStream::unconnected();
+        stream.connect(master.try_clone()?, master);
+        stream._pty_slave = Some(slave);
+        Ok((stream, path))
+    }
+
+    fn connect<R, W>(&self, reader: R, writer: W)
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        connect_stream(&self.incoming, &self.writer, &self.stopped, reader, writer);
+    }
+}
+
+impl Drop for UartStream {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        #[cfg(unix)]
+        if let Some(ref socket) = self.socket {
+            let current = socket
+                .current
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .take();
+            if let Some(connection) = current {
+                let _ = connection.shutdown(Shutdown::Both);
+            }
+
+            // Wakes the listener thread up so that it sees that it has to stop.
+            let _ = UnixStream::connect(&socket.path);
+            let _ = std::fs::remove_file(&socket.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+struct UartSocket {
+    path: PathBuf,
+
+    /// The program that is connected right now.
+    current: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl UartHost for UartStream {
+    fn send(&mut self, byte: u8) {
+        let _ = self.outgoing.send(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.incoming
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop_front()
+    }
+}
+
+fn connect_stream<R, W>(
+    incoming: &Arc<Mutex<VecDeque<u8>>>,
+    writer: &Mutex<Option<Box<dyn Write + Send>>>,
+    stopped: &Arc<AtomicBool>,
+    mut reader: R,
+    stream_writer: W,
+) where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    *writer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Box::new(stream_writer));
+
+    let incoming = incoming.clone();
+    let stopped = stopped.clone();
+    thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        loop {
+            let result = reader.read(&mut buffer);
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            match result {
+                Ok(0) => return,
+                Ok(len) => incoming
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .extend(&buffer[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    log::warn!("UART read error: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Writes everything that the console sends, one batch at a time, until the `UartStream` is
+/// dropped.
+fn write_bytes(receiver: &Receiver<u8>, writer: &Mutex<Option<Box<dyn Write + Send>>>) {
+    while let Ok(byte) = receiver.recv() {
+        let mut batch = vec![byte];
+        batch.extend(receiver.try_iter());
+
+        let mut writer = writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let failed = match *writer {
+            Some(ref mut stream) => stream
+                .write_all(&batch)
+                .and_then(|_| stream.flush())
+                .is_err(),
+            None => false,
+        };
+        if failed {
+            *writer = None;
+        }
+    }
+}
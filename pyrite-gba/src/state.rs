@@ -0,0 +1,309 @@
+//! Save states for the whole system.
+//!
+//! A state starts with a header:
+//! - the magic bytes `PYRITEST`
+//! - the version of the format (u32)
+//! - flags (u32), bit 0 is set if the body is compressed (see `pyrite_common::state::compress`)
+//! - the length of the uncompressed body (u32)
+//!
+//! The body is a list of chunks. Every chunk has a 4 byte tag, the length of its data (u32) and the
+//! data itself, which starts with the version of the component that wrote it. Chunks with tags that
+//! aren't known are skipped and every known chunk has to be present, except for chunks that were
+//! added in a later version of the format.
+//!
+//! Format versions:
+//! 1. the first version
+//! 2. adds the `SIO ` chunk
+//!
+//! The BIOS and GamePak ROM are not part of a state. The hash of the ROM is saved instead and
+//! states saved with a different ROM are rejected.
+//!
+//! @TODO Backup memory (SRAM, Flash and EEPROM) isn't emulated yet. It should get its own chunk
+//!       once it is.
+
+use crate::dma::GbaDMA;
+use crate::{Gba, GbaSystemState};
+use pyrite_arm::cpu::ExecutionFn;
+use pyrite_common::state::{compress, decompress};
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+const MAGIC: &[u8; 8] = b"PYRITEST";
+const FORMAT_VERSION: u32 = 2;
+const FLAG_COMPRESSED: u32 = 1;
+
+/// Bodies larger than this are rejected before decompressing them.
+const MAX_BODY_LENGTH: usize = 4 * 1024 * 1024;
+
+const SYSTEM_STATE_VERSION: u32 = 1;
+
+const CHUNK_INFO: &[u8; 4] = b"INFO";
+const CHUNK_CPU: &[u8; 4] = b"CPU ";
+const CHUNK_MEMORY: &[u8; 4] = b"MEM ";
+const CHUNK_SYSCTL: &[u8; 4] = b"SYS ";
+const CHUNK_LCD: &[u8; 4] = b"LCD ";
+const CHUNK_AUDIO: &[u8; 4] = b"AUD ";
+const CHUNK_DMA: &[u8; 4] = b"DMA ";
+const CHUNK_TIMERS: &[u8; 4] = b"TMR ";
+const CHUNK_SERIAL: &[u8; 4] = b"SIO ";
+const CHUNK_IRQ: &[u8; 4] = b"IRQ ";
+const CHUNK_KEYPAD: &[u8; 4] = b"KEY ";
+const CHUNK_SCHEDULER: &[u8; 4] = b"SCHD";
+
+/// A chunk's tag and data.
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Functions that the CPU's execution might be overridden with.
+const CPU_OVERRIDES: &[ExecutionFn] = &[GbaDMA::cpu_step_override];
+
+impl Gba {
+    /// Saves the state of the entire system. The state can be restored with `load_state` on a
+    /// `Gba` with the same GamePak ROM.
+    pub fn save_state(&self, compressed: bool) -> Result<Vec<u8>, StateError> {
+        let body = self.save_state_body()?;
+
+        let mut state = StateWriter::new();
+        state.write_bytes(MAGIC);
+        state.write_u32(FORMAT_VERSION);
+        state.write_u32(if compressed { FLAG_COMPRESSED } else { 0 });
+        state.write_u32(body.len() as u32);
+        if compressed {
+            state.write_bytes(&compress(&body));
+        } else {
+            state.write_bytes(&body);
+        }
+        Ok(state.into_inner())
+    }
+
+    /// Loads a state that was saved with `save_state`. Nothing is changed if the state can't be
+    /// loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        if state.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::InvalidData(String::from("not a save state")));
+        }
+        let version = state.read_version("save state format", FORMAT_VERSION)?;
+        let flags = state.read_u32()?;
+        let body_length = state.read_u32()? as usize;
+        if body_length > MAX_BODY_LENGTH {
+            return Err(StateError::InvalidData(String::from("state is too large")));
+        }
+
+        let rest = state.read_bytes(state.remaining())?;
+        let decompressed;
+        let body = if (flags & FLAG_COMPRESSED) != 0 {
+            decompressed = decompress(rest, body_length)?;
+            &decompressed[..]
+        } else {
+            rest
+        };
+        if body.len() != body_length {
+            return Err(StateError::UnexpectedEnd);
+        }
+
+        let chunks = read_chunks(body)?;
+        let mut info = StateReader::new(find_chunk(&chunks, CHUNK_INFO)?);
+        info.read_version("system", SYSTEM_STATE_VERSION)?;
+        if info.read_u64()? != self.hardware.gamepak_hash {
+            return Err(StateError::InvalidData(String::from(
+                "the state was saved with a different GamePak ROM",
+            )));
+        }
+
+        // Loading can fail halfway through so the current state is kept around to go back to.
+        let backup = self.save_state_body()?;
+        if let Err(err) = self.load_chunks(&chunks, version) {
+            let backup_chunks = read_chunks(&backup).expect("failed to read backup state");
+            self.load_chunks(&backup_chunks, FORMAT_VERSION)
+                .expect("failed to restore backup state");
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// A hash of everything that would be in a save state. Two systems that are in the same state
+    /// have the same hash. The state is hashed while it is written so nothing is allocated for it.
+    pub fn state_hash(&self) -> Result<u64, StateError> {
+        let mut hasher = StateWriter::hasher();
+        self.write_state_body(&mut hasher)?;
+        Ok(hasher.finish_hash().expect("state hasher without a hash"))
+    }
+
+    fn save_state_body(&self) -> Result<Vec<u8>, StateError> {
+        let mut body = StateWriter::new();
+        self.write_state_body(&mut body)?;
+        Ok(body.into_inner())
+    }
+
+    fn write_state_body(&self, body: &mut StateWriter) -> Result<(), StateError> {
+        write_chunk(body, CHUNK_INFO, |state| {
+            state.write_u32(SYSTEM_STATE_VERSION);
+            state.write_u64(self.hardware.gamepak_hash);
+            state.write_u8(self.state as u8);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_CPU, |state| {
+            self.cpu.save_state(state, CPU_OVERRIDES)
+        })?;
+        write_chunk(body, CHUNK_MEMORY, |state| {
+            self.hardware.save_memory_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_SYSCTL, |state| {
+            self.hardware.sysctl.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_LCD, |state| {
+            self.hardware.lcd.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_AUDIO, |state| {
+            self.hardware.audio.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_DMA, |state| {
+            self.hardware.dma.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_TIMERS, |state| {
+            self.hardware.timers.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_SERIAL, |state| {
+            self.hardware.sio.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_IRQ, |state| {
+            self.hardware.irq.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_KEYPAD, |state| {
+            self.hardware.keypad.save_state(state);
+            Ok(())
+        })?;
+        write_chunk(body, CHUNK_SCHEDULER, |state| {
+            self.scheduler.save_state(state);
+            Ok(())
+        })
+    }
+
+    fn load_chunks(&mut self, chunks: &[Chunk], version: u32) -> Result<(), StateError> {
+        let mut info = StateReader::new(find_chunk(chunks, CHUNK_INFO)?);
+        info.read_version("system", SYSTEM_STATE_VERSION)?;
+        info.read_u64()?;
+        self.state = match info.read_u8()? {
+            0 => GbaSystemState::Running,
+            1 => GbaSystemState::Halted,
+            2 => GbaSystemState::Stopped,
+            value => {
+                return Err(StateError::InvalidData(format!(
+                    "bad system state: {}",
+                    value
+                )))
+            }
+        };
+        finish_chunk(CHUNK_INFO, &info)?;
+
+        // The memory is loaded before the CPU so that the CPU's block cache is not filled from
+        // the old memory.
+        read_chunk(chunks, CHUNK_MEMORY, |state| {
+            self.hardware.load_memory_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_CPU, |state| {
+            self.cpu.load_state(state, CPU_OVERRIDES)
+        })?;
+        read_chunk(chunks, CHUNK_SYSCTL, |state| {
+            self.hardware.sysctl.load_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_LCD, |state| {
+            self.hardware.lcd.load_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_AUDIO, |state| {
+            self.hardware.audio.load_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_DMA, |state| {
+            self.hardware.dma.load_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_TIMERS, |state| {
+            self.hardware.timers.load_state(state)
+        })?;
+        if version >= 2 {
+            read_chunk(chunks, CHUNK_SERIAL, |state| {
+                self.hardware.sio.load_state(state)
+            })?;
+        } else {
+            self.hardware.sio.reset_state();
+        }
+        read_chunk(chunks, CHUNK_IRQ, |state| {
+            self.hardware.irq.load_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_KEYPAD, |state| {
+            self.hardware.keypad.load_state(state)
+        })?;
+        read_chunk(chunks, CHUNK_SCHEDULER, |state| {
+            self.scheduler.load_state(state)
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Writes a chunk with its tag and length. Hashing writers only get the tag and the chunk's data
+/// because they don't need the length to find the next chunk.
+fn write_chunk<F>(body: &mut StateWriter, tag: &[u8; 4], f: F) -> Result<(), StateError>
+where
+    F: FnOnce(&mut StateWriter) -> Result<(), StateError>,
+{
+    if body.is_hasher() {
+        body.write_bytes(tag);
+        return f(body);
+    }
+
+    let mut chunk = StateWriter::new();
+    f(&mut chunk)?;
+    body.write_bytes(tag);
+    body.write_u32(chunk.len() as u32);
+    body.write_bytes(&chunk.into_inner());
+    Ok(())
+}
+
+fn read_chunks(body: &[u8]) -> Result<Vec<Chunk<'_>>, StateError> {
+    let mut chunks = Vec::new();
+    let mut state = StateReader::new(body);
+    while state.remaining() > 0 {
+        let mut tag = [0u8; 4];
+        state.read_into(&mut tag)?;
+        let length = state.read_u32()? as usize;
+        chunks.push((tag, state.read_bytes(length)?));
+    }
+    Ok(chunks)
+}
+
+fn find_chunk<'a>(chunks: &[Chunk<'a>], tag: &[u8; 4]) -> Result<&'a [u8], StateError> {
+    match chunks.iter().find(|(chunk_tag, _)| chunk_tag == tag) {
+        Some((_, data)) => Ok(data),
+        None => Err(StateError::InvalidData(format!(
+            "missing {} chunk",
+            String::from_utf8_lossy(tag).trim_end()
+        ))),
+    }
+}
+
+/// Loads a chunk and checks that all of its data was used.
+fn read_chunk<F>(chunks: &[Chunk], tag: &[u8; 4], f: F) -> Result<(), StateError>
+where
+    F: FnOnce(&mut StateReader) -> Result<(), StateError>,
+{
+    let mut state = StateReader::new(find_chunk(chunks, tag)?);
+    f(&mut state)?;
+    finish_chunk(tag, &state)
+}
+
+fn finish_chunk(tag: &[u8; 4], state: &StateReader) -> Result<(), StateError> {
+    if state.remaining() != 0 {
+        return Err(StateError::InvalidData(format!(
+            "{} chunk is too long",
+            String::from_utf8_lossy(tag).trim_end()
+        )));
+    }
+    Ok(())
+}
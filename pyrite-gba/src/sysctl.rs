@@ -1,4 +1,8 @@
+use crate::prefetch::GamePakPrefetch;
 use pyrite_common::{bits, bits_b};
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+const SYSCTL_STATE_VERSION: u32 = 2;
 
 macro_rules! set_timings {
     ($Width:ident, $Region:expr, 1, $FirstAccess:expr, $SecondAccess:expr) => {
@@ -58,6 +62,10 @@ pub struct GbaSystemControl {
     pub ram_disabled: bool,
     pub ram_external: bool,
 
+    /// Set by bit 14 of WAITCNT.
+    pub prefetch_enabled: bool,
+    pub prefetch: GamePakPrefetch,
+
     // registers:
     pub reg_waitcnt: u16,
     pub reg_postflg: bool,
@@ -78,12 +86,38 @@ impl GbaSystemControl {
             ram_disabled: false,
             ram_external: true,
 
+            prefetch_enabled: false,
+            prefetch: GamePakPrefetch::new(),
+
             reg_waitcnt: 0,
             reg_postflg: false,
             reg_imemctl: 0,
         }
     }
 
+    /// Only the registers are saved. The access timings are calculated from them again when the
+    /// state is loaded.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(SYSCTL_STATE_VERSION);
+        state.write_u16(self.reg_waitcnt);
+        state.write_bool(self.reg_postflg);
+        state.write_u32(self.reg_imemctl);
+        self.prefetch.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let version = state.read_version("system control", SYSCTL_STATE_VERSION)?;
+        self.set_reg_waitcnt(state.read_u16()?);
+        self.reg_postflg = state.read_bool()?;
+        self.set_imemctl(state.read_u32()?);
+        if version >= 2 {
+            self.prefetch.load_state(state)?;
+        } else {
+            self.prefetch = GamePakPrefetch::new();
+        }
+        Ok(())
+    }
+
     pub fn set_imemctl_lo(&mut self, lo: u16) {
         let value = (self.reg_imemctl & 0xFFFF0000) | (lo as u32);
         self.set_imemctl(value);
@@ -116,6 +150,11 @@ impl GbaSystemControl {
 
         self.reg_waitcnt = waitcnt;
 
+        self.prefetch_enabled = bits_b!(self.reg_waitcnt, 14, 14);
+        if !self.prefetch_enabled {
+            self.prefetch.stop();
+        }
+
         let sram_first_access_byte =
             CART_FIRST_ACCESS[bits!(self.reg_waitcnt, 0, 1) as usize] as u8;
         let waitstate0_first_access_halfword =
@@ -229,4 +268,11 @@ impl GbaSystemControl {
             sram_first_access_byte
         );
     }
+
+    /// Cycles for a sequential halfword read from the wait state region of the GamePak ROM
+    /// address `address`.
+    pub fn gamepak_seq_cycles(&self, address: u32) -> u32 {
+        let waitstate = ((address >> 25) - 4) as usize;
+        self.gamepak_cycles[waitstate].halfword.sequential as u32
+    }
 }
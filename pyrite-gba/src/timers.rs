@@ -1,4 +1,7 @@
 use crate::scheduler::{GbaEvent, SharedGbaScheduler};
+use pyrite_common::{StateError, StateReader, StateWriter};
+
+const TIMERS_STATE_VERSION: u32 = 1;
 
 pub struct GbaTimers {
     timers: [GbaTimer; 4],
@@ -6,6 +9,9 @@ pub struct GbaTimers {
     cycles_acc: u32,
     scheduler: SharedGbaScheduler,
     last_overflow_calc: u32,
+    /// Overflows of timer 0 and timer 1 since the last call to `process_overflows`. These are used
+    /// by the sound FIFOs.
+    sound_overflows: [u32; 2],
 }
 
 impl GbaTimers {
@@ -21,6 +27,7 @@ impl GbaTimers {
             cycles_acc: 0,
             scheduler: scheduler,
             last_overflow_calc: std::u32::MAX,
+            sound_overflows: [0; 2],
         }
     }
 
@@ -74,6 +81,31 @@ impl GbaTimers {
         self.active_timers != 0
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(TIMERS_STATE_VERSION);
+        state.write_u8(self.active_timers);
+        state.write_u32(self.cycles_acc);
+        state.write_u32(self.last_overflow_calc);
+        for timer in self.timers.iter() {
+            state.write_u32(timer.counter);
+            state.write_u16(timer.reload);
+            state.write_u16(timer.control.value);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_version("timers", TIMERS_STATE_VERSION)?;
+        self.active_timers = state.read_u8()? & 0xF;
+        self.cycles_acc = state.read_u32()?;
+        self.last_overflow_calc = state.read_u32()?;
+        for timer in self.timers.iter_mut() {
+            timer.counter = state.read_u32()?;
+            timer.reload = state.read_u16()?;
+            timer.control.value = state.read_u16()?;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub(crate) fn step(&mut self, cycles: u32) {
         // I do a wrapping add because I don't really care if this overflows.
@@ -82,11 +114,13 @@ impl GbaTimers {
         self.cycles_acc = self.cycles_acc.wrapping_add(cycles);
     }
 
-    pub(crate) fn process_overflows(&mut self) {
+    /// Returns the number of times that timer 0 and timer 1 overflowed.
+    pub(crate) fn process_overflows(&mut self) -> [u32; 2] {
         self.internal_step(self.cycles_acc);
         self.cycles_acc = 0;
         self.last_overflow_calc = std::u32::MAX;
         self.calc_next_overflow();
+        std::mem::take(&mut self.sound_overflows)
     }
 
     fn flush_acc_cycles(&mut self) {
@@ -162,6 +196,10 @@ impl GbaTimers {
                 return;
             }
 
+            if timer < 2 {
+                self.sound_overflows[timer] += overflows;
+            }
+
             if self.timers[timer].control.irq() {
                 self.scheduler
                     .schedule(GbaEvent::IRQ(crate::irq::Interrupt::timer(timer_index)), 0);
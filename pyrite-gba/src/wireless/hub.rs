@@ -0,0 +1,313 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+
+/// The number of clients that a host can have.
+pub const MAX_CLIENTS: usize = 4;
+
+/// What the hub knows about a host that can be connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostInfo {
+    pub id: u16,
+    pub clients: u8,
+    pub broadcast: [u32; 6],
+}
+
+/// Data that was sent by an adapter. `slot` is 0 for data from the host and 1 to 4 for data
+/// from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WirelessMessage {
+    pub slot: u8,
+    pub bytes: u8,
+    pub words: Vec<u32>,
+}
+
+/// Relays traffic between wireless adapters. Every adapter gets an ID when it registers and
+/// passes it to everything else.
+pub trait WirelessHub {
+    fn register(&mut self) -> io::Result<u16>;
+    fn unregister(&mut self, id: u16) -> io::Result<()>;
+    fn set_broadcast(&mut self, id: u16, data: [u32; 6]) -> io::Result<()>;
+
+    /// Starts hosting a room that clients can find and connect to.
+    fn start_host(&mut self, id: u16) -> io::Result<()>;
+
+    /// Stops accepting new clients but keeps the ones that are connected.
+    fn close_host(&mut self, id: u16) -> io::Result<()>;
+
+    fn hosts(&mut self, id: u16) -> io::Result<Vec<HostInfo>>;
+
+    /// Connects to a host and returns the client number (0 to 3) on success.
+    fn connect(&mut self, id: u16, host: u16) -> io::Result<Option<u8>>;
+
+    /// The IDs and client numbers of the clients of a host.
+    fn clients(&mut self, id: u16) -> io::Result<Vec<(u16, u8)>>;
+
+    /// A host sends data to all of its clients and a client sends data to its host.
+    fn send(&mut self, id: u16, bytes: u8, words: &[u32]) -> io::Result<()>;
+
+    /// Takes the oldest message from every slot.
+    fn receive(&mut self, id: u16) -> io::Result<Vec<WirelessMessage>>;
+
+    fn has_data(&mut self, id: u16) -> io::Result<bool>;
+
+    /// Leaves the room that the adapter is hosting or connected to.
+    fn disconnect(&mut self, id: u16) -> io::Result<()>;
+}
+
+#[derive(Default)]
+struct HubAdapter {
+    broadcast: [u32; 6],
+    hosting: bool,
+    open: bool,
+
+    /// The host and the client number for a client.
+    host: Option<(u16, u8)>,
+
+    /// The clients of a host by client number.
+    clients: [Option<u16>; MAX_CLIENTS],
+
+    inbox: VecDeque<WirelessMessage>,
+}
+
+/// The state of a hub. Every operation on unknown IDs is ignored.
+#[derive(Default)]
+pub struct HubState {
+    next_id: u16,
+    adapters: BTreeMap<u16, HubAdapter>,
+}
+
+impl HubState {
+    pub fn new() -> HubState {
+        HubState::default()
+    }
+
+    pub fn register(&mut self) -> u16 {
+        // IDs are never 0 and never reused while an adapter with that ID exists.
+        loop {
+            self.next_id = self.next_id.wrapping_add(1).max(1);
+            if !self.adapters.contains_key(&self.next_id) {
+                self.adapters.insert(self.next_id, HubAdapter::default());
+                return self.next_id;
+            }
+        }
+    }
+
+    pub fn unregister(&mut self, id: u16) {
+        self.disconnect(id);
+        self.adapters.remove(&id);
+    }
+
+    pub fn set_broadcast(&mut self, id: u16, data: [u32; 6]) {
+        if let Some(adapter) = self.adapters.get_mut(&id) {
+            adapter.broadcast = data;
+        }
+    }
+
+    pub fn start_host(&mut self, id: u16) {
+        self.disconnect(id);
+        if let Some(adapter) = self.adapters.get_mut(&id) {
+            adapter.hosting = true;
+            adapter.open = true;
+        }
+    }
+
+    pub fn close_host(&mut self, id: u16) {
+        if let Some(adapter) = self.adapters.get_mut(&id) {
+            adapter.open = false;
+        }
+    }
+
+    pub fn hosts(&self, id: u16) -> Vec<HostInfo> {
+        self.adapters
+            .iter()
+            .filter(|&(&host, adapter)| host != id && adapter.hosting && adapter.open)
+            .map(|(&host, adapter)| HostInfo {
+                id: host,
+                clients: adapter.clients.iter().filter(|c| c.is_some()).count() as u8,
+                broadcast: adapter.broadcast,
+            })
+            .collect()
+    }
+
+    pub fn connect(&mut self, id: u16, host: u16) -> Option<u8> {
+        if id == host || !self.adapters.contains_key(&id) {
+            return None;
+        }
+        self.disconnect(id);
+
+        let number = {
+            let host_adapter = self.adapters.get_mut(&host)?;
+            if !host_adapter.hosting || !host_adapter.open {
+                return None;
+            }
+            let number = host_adapter.clients.iter().position(Option::is_none)?;
+            host_adapter.clients[number] = Some(id);
+            number as u8
+        };
+
+        self.adapters.get_mut(&id)?.host = Some((host, number));
+        Some(number)
+    }
+
+    pub fn clients(&self, id: u16) -> Vec<(u16, u8)> {
+        match self.adapters.get(&id) {
+            Some(adapter) => adapter
+                .clients
+                .iter()
+                .enumerate()
+                .filter_map(|(number, client)| client.map(|client| (client, number as u8)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn send(&mut self, id: u16, bytes: u8, words: &[u32]) {
+        let (hosting, host, clients) = match self.adapters.get(&id) {
+            Some(adapter) => (adapter.hosting, adapter.host, adapter.clients),
+            None => return,
+        };
+
+        if hosting {
+            let message = WirelessMessage {
+                slot: 0,
+                bytes,
+                words: words.to_vec(),
+            };
+            for client in clients.iter().flatten() {
+                if let Some(client) = self.adapters.get_mut(client) {
+                    client.inbox.push_back(message.clone());
+                }
+            }
+        } else if let Some((host, number)) = host {
+            if let Some(host) = self.adapters.get_mut(&host) {
+                host.inbox.push_back(WirelessMessage {
+                    slot: number + 1,
+                    bytes,
+                    words: words.to_vec(),
+                });
+            }
+        }
+    }
+
+    pub fn receive(&mut self, id: u16) -> Vec<WirelessMessage> {
+        let adapter = match self.adapters.get_mut(&id) {
+            Some(adapter) => adapter,
+            None => return Vec::new(),
+        };
+
+        let mut received: Vec<WirelessMessage> = Vec::new();
+        let mut kept = VecDeque::new();
+        for message in adapter.inbox.drain(..) {
+            if received.iter().any(|other| other.slot == message.slot) {
+                kept.push_back(message);
+            } else {
+                received.push(message);
+            }
+        }
+        adapter.inbox = kept;
+        received.sort_by_key(|message| message.slot);
+        received
+    }
+
+    pub fn has_data(&self, id: u16) -> bool {
+        self.adapters
+            .get(&id)
+            .is_some_and(|adapter| !adapter.inbox.is_empty())
+    }
+
+    pub fn disconnect(&mut self, id: u16) {
+        let (clients, host) = match self.adapters.get_mut(&id) {
+            Some(adapter) => {
+                adapter.hosting = false;
+                adapter.open = false;
+                adapter.inbox.clear();
+                (
+                    std::mem::replace(&mut adapter.clients, [None; MAX_CLIENTS]),
+                    adapter.host.take(),
+                )
+            }
+            None => return,
+        };
+
+        for client in clients.iter().flatten() {
+            if let Some(client) = self.adapters.get_mut(client) {
+                client.host = None;
+            }
+        }
+        if let Some((host, number)) = host {
+            if let Some(host) = self.adapters.get_mut(&host) {
+                host.clients[number as usize] = None;
+            }
+        }
+    }
+}
+
+/// A hub for adapters in the same process. Every adapter gets a clone.
+#[derive(Clone, Default)]
+pub struct LocalHub {
+    state: Rc<RefCell<HubState>>,
+}
+
+impl LocalHub {
+    pub fn new() -> LocalHub {
+        LocalHub::default()
+    }
+}
+
+impl WirelessHub for LocalHub {
+    fn register(&mut self) -> io::Result<u16> {
+        Ok(self.state.borrow_mut().register())
+    }
+
+    fn unregister(&mut self, id: u16) -> io::Result<()> {
+        self.state.borrow_mut().unregister(id);
+        Ok(())
+    }
+
+    fn set_broadcast(&mut self, id: u16, data: [u32; 6]) -> io::Result<()> {
+        self.state.borrow_mut().set_broadcast(id, data);
+        Ok(())
+    }
+
+    fn start_host(&mut self, id: u16) -> io::Result<()> {
+        self.state.borrow_mut().start_host(id);
+        Ok(())
+    }
+
+    fn close_host(&mut self, id: u16) -> io::Result<()> {
+        self.state.borrow_mut().close_host(id);
+        Ok(())
+    }
+
+    fn hosts(&mut self, id: u16) -> io::Result<Vec<HostInfo>> {
+        Ok(self.state.borrow().hosts(id))
+    }
+
+    fn connect(&mut self, id: u16, host: u16) -> io::Result<Option<u8>> {
+        Ok(self.state.borrow_mut().connect(id, host))
+    }
+
+    fn clients(&mut self, id: u16) -> io::Result<Vec<(u16, u8)>> {
+        Ok(self.state.borrow().clients(id))
+    }
+
+    fn send(&mut self, id: u16, bytes: u8, words: &[u32]) -> io::Result<()> {
+        self.state.borrow_mut().send(id, bytes, words);
+        Ok(())
+    }
+
+    fn receive(&mut self, id: u16) -> io::Result<Vec<WirelessMessage>> {
+        Ok(self.state.borrow_mut().receive(id))
+    }
+
+    fn has_data(&mut self, id: u16) -> io::Result<bool> {
+        Ok(self.state.borrow().has_data(id))
+    }
+
+    fn disconnect(&mut self, id: u16) -> io::Result<()> {
+        self.state.borrow_mut().disconnect(id);
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+//! The GBA Wireless Adapter.
+//!
+//! The adapter is plugged into the link port and talks to the console in Normal 32-bit mode. After
+//! a login handshake the console sends it commands (`0x9966LLCC`, where `LL` is the number of
+//! parameter words and `CC` is the command) and clocks out a response (`0x9966RR00 | CC + 0x80`)
+//! followed by `RR` words. For the commands that wait for data the clock is inverted: the console
+//! becomes the slave and the adapter sends it a command of its own once something arrives.
+//!
+//! Adapters don't talk to each other directly. Every adapter registers with a `WirelessHub`, which
+//! keeps track of who is hosting, who is connected to whom and the data that is on its way. A
+//! `LocalHub` connects consoles in the same process and a `HubServer` connects consoles in
+//! different processes through `RemoteHub`s.
+
+mod hub;
+mod remote;
+
+pub use hub::{HostInfo, HubState, LocalHub, WirelessHub, WirelessMessage, MAX_CLIENTS};
+pub use remote::{HubServer, RemoteHub};
+
+use std::collections::VecDeque;
+use std::io;
+
+/// The words that the console sends during the login handshake (after an initial packet of
+/// `LOGIN_PARTS[0]`).
+const LOGIN_PARTS: [u16; 9] = [
+    0x494E, 0x494E, 0x544E, 0x544E, 0x4E45, 0x4E45, 0x4F44, 0x4F44, 0x8001,
+];
+
+const COMMAND_HEADER: u32 = 0x9966_0000;
+const COMMAND_ACK: u32 = 0x8000_0000;
+
+/// What the adapter sends back when it doesn't have anything else to say.
+const IDLE_RESPONSE: u32 = 0xFFFF_FFFF;
+
+const ERROR_RESPONSE: u8 = 0xEE;
+const ERROR_UNKNOWN_COMMAND: u32 = 2;
+
+const CMD_HELLO: u8 = 0x10;
+const CMD_LINK_POWER: u8 = 0x11;
+const CMD_VERSION: u8 = 0x12;
+const CMD_SYSTEM_STATUS: u8 = 0x13;
+const CMD_SLOT_STATUS: u8 = 0x14;
+const CMD_BROADCAST: u8 = 0x16;
+const CMD_SETUP: u8 = 0x17;
+const CMD_START_HOST: u8 = 0x19;
+const CMD_POLL_CONNECTIONS: u8 = 0x1A;
+const CMD_END_HOST: u8 = 0x1B;
+const CMD_BROADCAST_READ_START: u8 = 0x1C;
+const CMD_BROADCAST_READ_POLL: u8 = 0x1D;
+const CMD_BROADCAST_READ_END: u8 = 0x1E;
+const CMD_CONNECT: u8 = 0x1F;
+const CMD_IS_FINISHED_CONNECT: u8 = 0x20;
+const CMD_FINISH_CONNECTION: u8 = 0x21;
+const CMD_SEND_DATA: u8 = 0x24;
+const CMD_SEND_DATA_AND_WAIT: u8 = 0x25;
+const CMD_RECEIVE_DATA: u8 = 0x26;
+const CMD_WAIT: u8 = 0x27;
+const CMD_DISCONNECT: u8 = 0x30;
+const CMD_BYE: u8 = 0x3D;
+
+/// Sent by the adapter while the clock is inverted to tell the console that data arrived.
+const CMD_DATA_AVAILABLE: u8 = 0x28;
+
+/// The version that a real adapter reports.
+const ADAPTER_VERSION: u32 = 0x0083_0117;
+
+/// The client number that is reported by `IsFinishedConnect` when the host refused the
+/// connection. Games only accept client numbers below 4.
+const CONNECT_FAILED: u32 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Idle,
+    Searching,
+    Connecting,
+    Host,
+    Client(u8),
+}
+
+enum AdapterState {
+    /// Waiting for the login handshake. `previous` is the last word that the console sent.
+    Login { previous: Option<u16> },
+
+    /// Waiting for a command header.
+    Idle,
+
+    /// Receiving the parameters of a command.
+    Params {
+        command: u8,
+        remaining: u8,
+        params: Vec<u32>,
+    },
+
+    /// Waiting for the console to clock out the response of a command.
+    Response { words: VecDeque<u32>, wait: bool },
+
+    /// The clock is inverted and the adapter sends `CMD_DATA_AVAILABLE` once data arrives.
+    Waiting,
+
+    /// The adapter sent a command and waits for the console to answer it.
+    Acknowledge,
+}
+
+pub struct WirelessAdapter {
+    hub: Box<dyn WirelessHub>,
+    id: u16,
+    state: AdapterState,
+    role: Role,
+
+    /// The host that a `Connect` command was sent to and its answer.
+    connection: Option<(u16, Option<u8>)>,
+}
+
+impl WirelessAdapter {
+    /// Creates an adapter that is registered with `hub`.
+    pub fn new(mut hub: Box<dyn WirelessHub>) -> io::Result<WirelessAdapter> {
+        let id = hub.register()?;
+        Ok(WirelessAdapter {
+            hub,
+            id,
+            state: AdapterState::Login { previous: None },
+            role: Role::Idle,
+            connection: None,
+        })
+    }
+
+    /// The device ID that the hub gave the adapter.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Turns the adapter off and on again, which is what games do by pulling SD low through RCNT.
+    pub fn reset(&mut self) {
+        if self.role != Role::Idle {
+            let result = self.hub.disconnect(self.id);
+            self.check(result);
+        }
+        self.state = AdapterState::Login { previous: None };
+        self.role = Role::Idle;
+        self.connection = None;
+    }
+
+    /// A 32-bit transfer that was clocked by the console. Returns the word that the adapter sent
+    /// back.
+    pub fn exchange(&mut self, sent: u32) -> u32 {
+        match std::mem::replace(&mut self.state, AdapterState::Idle) {
+            AdapterState::Login { previous } => self.login(previous, sent),
+
+            AdapterState::Acknowledge if (sent >> 16) == (COMMAND_HEADER >> 16) => {
+                // The console answers the adapter's command with a response header.
+                COMMAND_ACK
+            }
+
+            AdapterState::Idle | AdapterState::Waiting | AdapterState::Acknowledge => {
+                if (sent >> 16) == (COMMAND_HEADER >> 16) {
+                    self.command_header(sent)
+                } else if (sent & 0xFFFF) as u16 == LOGIN_PARTS[0] {
+                    self.login(None, sent)
+                } else {
+                    IDLE_RESPONSE
+                }
+            }
+
+            AdapterState::Params {
+                command,
+                remaining,
+                mut params,
+            } => {
+                params.push(sent);
+                if remaining > 1 {
+                    self.state = AdapterState::Params {
+                        command,
+                        remaining: remaining - 1,
+                        params,
+                    };
+                } else {
+                    self.run_command(command, &params);
+                }
+                COMMAND_ACK
+            }
+
+            AdapterState::Response { mut words, wait } => {
+                // The response header is sent for the first `0x80000000`.
+                let response = words.pop_front().unwrap_or(IDLE_RESPONSE);
+
+                if !words.is_empty() {
+                    self.state = AdapterState::Response { words, wait };
+                } else if wait {
+                    self.state = AdapterState::Waiting;
+                }
+                response
+            }
+        }
+    }
+
+    /// The word that the adapter clocks out while the clock is inverted. Returns `None` if the
+    /// adapter doesn't have anything to send yet.
+    pub fn drive(&mut self) -> Option<u32> {
+        if let AdapterState::Waiting = self.state {
+            let result = self.hub.has_data(self.id);
+            if self.check(result).unwrap_or(false) {
+                self.state = AdapterState::Acknowledge;
+                return Some(COMMAND_HEADER | CMD_DATA_AVAILABLE as u32);
+            }
+        }
+        None
+    }
+
+    fn login(&mut self, previous: Option<u16>, sent: u32) -> u32 {
+        let data = sent as u16;
+        let response_high = if previous.is_some() { data } else { 0 };
+        let response_low = !previous.unwrap_or(0);
+
+        if data == LOGIN_PARTS[LOGIN_PARTS.len() - 1] {
+            self.state = AdapterState::Idle;
+        } else {
+            self.state = AdapterState::Login {
+                previous: Some(data),
+            };
+        }
+        ((response_high as u32) << 16) | (response_low as u32)
+    }
+
+    fn command_header(&mut self, sent: u32) -> u32 {
+        let command = sent as u8;
+        let params = (sent >> 8) as u8;
+        if params == 0 {
+            self.run_command(command, &[]);
+        } else {
+            self.state = AdapterState::Params {
+                command,
+                remaining: params,
+                params: Vec::with_capacity(params as usize),
+            };
+        }
+        COMMAND_ACK
+    }
+
+    fn run_command(&mut self, command: u8, params: &[u32]) {
+        let mut wait = false;
+        let response = match command {
+            CMD_HELLO | CMD_SETUP => Some(Vec::new()),
+            CMD_LINK_POWER => Some(vec![0]),
+            CMD_VERSION => Some(vec![ADAPTER_VERSION]),
+            CMD_SYSTEM_STATUS => Some(vec![self.system_status()]),
+            CMD_SLOT_STATUS => Some(self.slot_status()),
+
+            CMD_BROADCAST => {
+                let mut data = [0u32; 6];
+                for (dest, &src) in data.iter_mut().zip(params.iter()) {
+                    *dest = src;
+                }
+                let result = self.hub.set_broadcast(self.id, data);
+                self.check(result);
+                Some(Vec::new())
+            }
+
+            CMD_START_HOST => {
+                let result = self.hub.start_host(self.id);
+                if self.check(result).is_some() {
+                    self.role = Role::Host;
+                }
+                Some(Vec::new())
+            }
+
+            CMD_POLL_CONNECTIONS => Some(self.connected_clients()),
+
+            CMD_END_HOST => {
+                let result = self.hub.close_host(self.id);
+                self.check(result);
+                Some(self.connected_clients())
+            }
+
+            CMD_BROADCAST_READ_START => {
+                self.role = Role::Searching;
+                Some(Vec::new())
+            }
+
+            CMD_BROADCAST_READ_POLL | CMD_BROADCAST_READ_END => {
+                let result = self.hub.hosts(self.id);
+                let words = self
+                    .check(result)
+                    .unwrap_or_default()
+                    .iter()
+                    .flat_map(|host| {
+                        std::iter::once(host.id as u32 | ((host.clients as u32) << 16))
+                            .chain(host.broadcast.iter().copied())
+                    })
+                    .collect();
+                if command == CMD_BROADCAST_READ_END {
+                    self.role = Role::Idle;
+                }
+                Some(words)
+            }
+
+            CMD_CONNECT => {
+                let host = params.first().copied().unwrap_or(0) as u16;
+                let result = self.hub.connect(self.id, host);
+                let number = self.check(result).flatten();
+                self.connection = Some((host, number));
+                self.role = Role::Connecting;
+                Some(Vec::new())
+            }
+
+            CMD_IS_FINISHED_CONNECT | CMD_FINISH_CONNECTION => {
+                let status = match self.connection {
+                    Some((_, Some(number))) => {
+                        if command == CMD_FINISH_CONNECTION {
+                            self.role = Role::Client(number);
+                        }
+                        self.id as u32 | ((number as u32) << 16)
+                    }
+                    Some((_, None)) => self.id as u32 | (CONNECT_FAILED << 16),
+                    None => 0x0100_0000,
+                };
+                Some(vec![status])
+            }
+
+            CMD_SEND_DATA | CMD_SEND_DATA_AND_WAIT => {
+                if let Some((&header, words)) = params.split_first() {
+                    let bytes = self.sent_bytes(header);
+                    let result = self.hub.send(self.id, bytes, words);
+                    self.check(result);
+                }
+                wait = command == CMD_SEND_DATA_AND_WAIT;
+                Some(Vec::new())
+            }
+
+            CMD_RECEIVE_DATA => Some(self.receive_data()),
+
+            CMD_WAIT => {
+                wait = true;
+                Some(Vec::new())
+            }
+
+            CMD_DISCONNECT | CMD_BYE => {
+                let result = self.hub.disconnect(self.id);
+                self.check(result);
+                self.role = Role::Idle;
+                self.connection = None;
+                Some(Vec::new())
+            }
+
+            _ => None,
+        };
+
+        let mut words = VecDeque::new();
+        match response {
+            Some(response) => {
+                words.push_back(
+                    COMMAND_HEADER
+                        | ((response.len() as u32 & 0xFF) << 8)
+                        | (command.wrapping_add(0x80) as u32),
+                );
+                words.extend(response);
+            }
+
+            None => {
+                log::warn!("unknown wireless adapter command 0x{:02X}", command);
+                words.push_back(COMMAND_HEADER | 0x100 | ERROR_RESPONSE as u32);
+                words.push_back(ERROR_UNKNOWN_COMMAND);
+            }
+        }
+
+        self.state = AdapterState::Response { words, wait };
+    }
+
+    fn system_status(&mut self) -> u32 {
+        let (slots, state) = match self.role {
+            Role::Idle => (0, 0),
+            Role::Host => (0, 1),
+            Role::Searching => (0, 2),
+            Role::Connecting => (0, 3),
+            Role::Client(number) => (1u32 << number, 4),
+        };
+        self.id as u32 | (slots << 16) | (state << 24)
+    }
+
+    fn slot_status(&mut self) -> Vec<u32> {
+        let clients = self.connected_clients();
+        let next = (0..MAX_CLIENTS as u32)
+            .find(|&number| !clients.iter().any(|&client| client >> 16 == number))
+            .unwrap_or(0xFF);
+        std::iter::once(next).chain(clients).collect()
+    }
+
+    /// `id | client number << 16` for every client of the room that the adapter is hosting.
+    fn connected_clients(&mut self) -> Vec<u32> {
+        if self.role != Role::Host {
+            return Vec::new();
+        }
+        let result = self.hub.clients(self.id);
+        self.check(result)
+            .unwrap_or_default()
+            .iter()
+            .map(|&(id, number)| id as u32 | ((number as u32) << 16))
+            .collect()
+    }
+
+    /// The number of bytes in a `SendData` header. Hosts use bits 0-6 and client N uses 5 bits
+    /// starting at bit `8 + 5 * N`.
+    fn sent_bytes(&self, header: u32) -> u8 {
+        match self.role {
+            Role::Client(number) => ((header >> (8 + 5 * number as u32)) & 0x1F) as u8,
+            _ => (header & 0x7F) as u8,
+        }
+    }
+
+    fn receive_data(&mut self) -> Vec<u32> {
+        let result = self.hub.receive(self.id);
+        let messages = self.check(result).unwrap_or_default();
+        if messages.is_empty() {
+            return Vec::new();
+        }
+
+        let mut header = 0;
+        let mut words = vec![0];
+        for message in messages.iter() {
+            if message.slot == 0 {
+                header |= message.bytes as u32 & 0x7F;
+            } else {
+                header |= (message.bytes as u32 & 0x1F) << (8 + 5 * (message.slot as u32 - 1));
+            }
+            words.extend(message.words.iter().copied());
+        }
+        words[0] = header;
+        words
+    }
+
+    /// Logs hub errors. The adapter behaves as if it lost the connection when the hub fails.
+    fn check<T>(&mut self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::warn!("wireless hub error: {}", err);
+                self.role = Role::Idle;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for WirelessAdapter {
+    fn drop(&mut self) {
+        let _ = self.hub.unregister(self.id);
+    }
+}
@@ -0,0 +1,403 @@
+//! A wireless hub that is shared by consoles in different processes.
+//!
+//! One process runs a `HubServer` and every adapter in the other processes (or the same one)
+//! connects to it with a `RemoteHub`. Every call on a `RemoteHub` is sent to the server as a
+//! length-prefixed request and blocks until the answer comes back. Adapters that were registered
+//! through a connection are unregistered when the connection is closed.
+
+use super::hub::{HostInfo, HubState, WirelessHub, WirelessMessage};
+use pyrite_common::{StateError, StateReader, StateWriter};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"PYRW";
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Requests and responses are much smaller than this. Anything bigger is a broken stream.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const OP_REGISTER: u8 = 0;
+const OP_UNREGISTER: u8 = 1;
+const OP_SET_BROADCAST: u8 = 2;
+const OP_START_HOST: u8 = 3;
+const OP_CLOSE_HOST: u8 = 4;
+const OP_HOSTS: u8 = 5;
+const OP_CONNECT: u8 = 6;
+const OP_CLIENTS: u8 = 7;
+const OP_SEND: u8 = 8;
+const OP_RECEIVE: u8 = 9;
+const OP_HAS_DATA: u8 = 10;
+const OP_DISCONNECT: u8 = 11;
+
+/// Sent by `OP_CONNECT` when the connection was refused.
+const NO_CLIENT_NUMBER: u8 = 0xFF;
+
+/// A stream to the hub server.
+trait HubStream: Read + Write {}
+impl<T: Read + Write> HubStream for T {}
+
+/// Runs a hub for `RemoteHub`s. Clones share the same hub.
+#[derive(Clone, Default)]
+pub struct HubServer {
+    state: Arc<Mutex<HubState>>,
+}
+
+impl HubServer {
+    pub fn new() -> HubServer {
+        HubServer::default()
+    }
+
+    /// Accepts connections from `listener` until accepting one fails.
+    pub fn serve_tcp(&self, listener: &TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            self.serve(stream);
+        }
+    }
+
+    /// Accepts connections from `listener` until accepting one fails.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: &UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            self.serve(stream);
+        }
+    }
+
+    /// Answers the requests from a single `RemoteHub` on another thread until the stream is
+    /// closed.
+    pub fn serve<S: Read + Write + Send + 'static>(&self, stream: S) -> JoinHandle<io::Result<()>> {
+        let state = self.state.clone();
+        thread::spawn(move || serve_connection(&state, stream))
+    }
+}
+
+fn serve_connection<S: Read + Write>(state: &Mutex<HubState>, mut stream: S) -> io::Result<()> {
+    let mut hello = [0u8; 8];
+    stream.read_exact(&mut hello)?;
+    check_hello(&hello)?;
+    stream.write_all(&hello_message())?;
+    stream.flush()?;
+
+    let mut registered = Vec::new();
+    let result = loop {
+        let request = match read_message(&mut stream) {
+            Ok(request) => request,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(err) => break Err(err),
+        };
+
+        let response = {
+            let mut state = state
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            handle_request(&mut state, &mut registered, &request)
+        };
+        let written = response
+            .map_err(|err| invalid_data(&err.to_string()))
+            .and_then(|response| write_message(&mut stream, &response));
+        if let Err(err) = written {
+            break Err(err);
+        }
+    };
+
+    let mut state = state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for id in registered {
+        state.unregister(id);
+    }
+    result
+}
+
+fn handle_request(
+    state: &mut HubState,
+    registered: &mut Vec<u16>,
+    request: &[u8],
+) -> Result<Vec<u8>, StateError> {
+    let mut request = StateReader::new(request);
+    let mut response = StateWriter::new();
+
+    let op = request.read_u8()?;
+    if op == OP_REGISTER {
+        let id = state.register();
+        registered.push(id);
+        response.write_u16(id);
+        return Ok(response.into_inner());
+    }
+
+    // A connection can only use the adapters that it registered.
+    let id = request.read_u16()?;
+    if !registered.contains(&id) {
+        return Err(StateError::InvalidData(format!(
+            "adapter {} belongs to another connection",
+            id
+        )));
+    }
+
+    match op {
+        OP_UNREGISTER => {
+            state.unregister(id);
+            registered.retain(|&other| other != id);
+        }
+
+        OP_SET_BROADCAST => {
+            let mut data = [0u32; 6];
+            request.read_u32_slice(&mut data)?;
+            state.set_broadcast(id, data);
+        }
+
+        OP_START_HOST => state.start_host(id),
+        OP_CLOSE_HOST => state.close_host(id),
+
+        OP_HOSTS => {
+            let hosts = state.hosts(id);
+            response.write_u16(hosts.len() as u16);
+            for host in hosts.iter() {
+                response.write_u16(host.id);
+                response.write_u8(host.clients);
+                response.write_u32_slice(&host.broadcast);
+            }
+        }
+
+        OP_CONNECT => {
+            let host = request.read_u16()?;
+            response.write_u8(state.connect(id, host).unwrap_or(NO_CLIENT_NUMBER));
+        }
+
+        OP_CLIENTS => {
+            let clients = state.clients(id);
+            response.write_u8(clients.len() as u8);
+            for &(client, number) in clients.iter() {
+                response.write_u16(client);
+                response.write_u8(number);
+            }
+        }
+
+        OP_SEND => {
+            let bytes = request.read_u8()?;
+            let mut words = vec![0u32; request.read_u16()? as usize];
+            request.read_u32_slice(&mut words)?;
+            state.send(id, bytes, &words);
+        }
+
+        OP_RECEIVE => {
+            let messages = state.receive(id);
+            response.write_u8(messages.len() as u8);
+            for message in messages.iter() {
+                response.write_u8(message.slot);
+                response.write_u8(message.bytes);
+                response.write_u16(message.words.len() as u16);
+                response.write_u32_slice(&message.words);
+            }
+        }
+
+        OP_HAS_DATA => response.write_bool(state.has_data(id)),
+        OP_DISCONNECT => state.disconnect(id),
+
+        _ => return Err(StateError::InvalidData(format!("bad request: {}", op))),
+    }
+
+    Ok(response.into_inner())
+}
+
+/// A connection to a `HubServer`.
+pub struct RemoteHub {
+    stream: Box<dyn HubStream>,
+}
+
+impl RemoteHub {
+    pub fn connect_tcp<A: ToSocketAddrs>(server: A) -> io::Result<RemoteHub> {
+        let stream = TcpStream::connect(server)?;
+        stream.set_nodelay(true)?;
+        RemoteHub::new(stream)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<RemoteHub> {
+        RemoteHub::new(UnixStream::connect(path)?)
+    }
+
+    /// Connects to a hub server through a stream that `HubServer::serve` is answering.
+    pub fn new<S: Read + Write + 'static>(mut stream: S) -> io::Result<RemoteHub> {
+        stream.write_all(&hello_message())?;
+        stream.flush()?;
+        let mut hello = [0u8; 8];
+        stream.read_exact(&mut hello)?;
+        check_hello(&hello)?;
+        Ok(RemoteHub {
+            stream: Box::new(stream),
+        })
+    }
+
+    /// Sends a request and waits for the response.
+    fn request<F>(&mut self, op: u8, id: u16, write: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce(&mut StateWriter),
+    {
+        let mut request = StateWriter::new();
+        request.write_u8(op);
+        if op != OP_REGISTER {
+            request.write_u16(id);
+        }
+        write(&mut request);
+        write_message(&mut self.stream, &request.into_inner())?;
+        read_message(&mut self.stream)
+    }
+}
+
+impl WirelessHub for RemoteHub {
+    fn register(&mut self) -> io::Result<u16> {
+        let response = self.request(OP_REGISTER, 0, |_| {})?;
+        StateReader::new(&response).read_u16().map_err(bad_response)
+    }
+
+    fn unregister(&mut self, id: u16) -> io::Result<()> {
+        self.request(OP_UNREGISTER, id, |_| {}).map(drop)
+    }
+
+    fn set_broadcast(&mut self, id: u16, data: [u32; 6]) -> io::Result<()> {
+        self.request(OP_SET_BROADCAST, id, |request| {
+            request.write_u32_slice(&data)
+        })
+        .map(drop)
+    }
+
+    fn start_host(&mut self, id: u16) -> io::Result<()> {
+        self.request(OP_START_HOST, id, |_| {}).map(drop)
+    }
+
+    fn close_host(&mut self, id: u16) -> io::Result<()> {
+        self.request(OP_CLOSE_HOST, id, |_| {}).map(drop)
+    }
+
+    fn hosts(&mut self, id: u16) -> io::Result<Vec<HostInfo>> {
+        let response = self.request(OP_HOSTS, id, |_| {})?;
+        let mut reader = StateReader::new(&response);
+        let count = reader.read_u16().map_err(bad_response)?;
+        let mut hosts = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let id = reader.read_u16().map_err(bad_response)?;
+            let clients = reader.read_u8().map_err(bad_response)?;
+            let mut broadcast = [0u32; 6];
+            reader
+                .read_u32_slice(&mut broadcast)
+                .map_err(bad_response)?;
+            hosts.push(HostInfo {
+                id,
+                clients,
+                broadcast,
+            });
+        }
+        Ok(hosts)
+    }
+
+    fn connect(&mut self, id: u16, host: u16) -> io::Result<Option<u8>> {
+        let response = self.request(OP_CONNECT, id, |request| request.write_u16(host))?;
+        let number = StateReader::new(&response)
+            .read_u8()
+            .map_err(bad_response)?;
+        Ok(Some(number).filter(|&number| number != NO_CLIENT_NUMBER))
+    }
+
+    fn clients(&mut self, id: u16) -> io::Result<Vec<(u16, u8)>> {
+        let response = self.request(OP_CLIENTS, id, |_| {})?;
+        let mut reader = StateReader::new(&response);
+        let count = reader.read_u8().map_err(bad_response)?;
+        let mut clients = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let client = reader.read_u16().map_err(bad_response)?;
+            let number = reader.read_u8().map_err(bad_response)?;
+            clients.push((client, number));
+        }
+        Ok(clients)
+    }
+
+    fn send(&mut self, id: u16, bytes: u8, words: &[u32]) -> io::Result<()> {
+        self.request(OP_SEND, id, |request| {
+            request.write_u8(bytes);
+            request.write_u16(words.len() as u16);
+            request.write_u32_slice(words);
+        })
+        .map(drop)
+    }
+
+    fn receive(&mut self, id: u16) -> io::Result<Vec<WirelessMessage>> {
+        let response = self.request(OP_RECEIVE, id, |_| {})?;
+        let mut reader = StateReader::new(&response);
+        let count = reader.read_u8().map_err(bad_response)?;
+        let mut messages = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let slot = reader.read_u8().map_err(bad_response)?;
+            let bytes = reader.read_u8().map_err(bad_response)?;
+            let mut words = vec![0u32; reader.read_u16().map_err(bad_response)? as usize];
+            reader.read_u32_slice(&mut words).map_err(bad_response)?;
+            messages.push(WirelessMessage { slot, bytes, words });
+        }
+        Ok(messages)
+    }
+
+    fn has_data(&mut self, id: u16) -> io::Result<bool> {
+        let response = self.request(OP_HAS_DATA, id, |_| {})?;
+        StateReader::new(&response)
+            .read_bool()
+            .map_err(bad_response)
+    }
+
+    fn disconnect(&mut self, id: u16) -> io::Result<()> {
+        self.request(OP_DISCONNECT, id, |_| {}).map(drop)
+    }
+}
+
+fn hello_message() -> Vec<u8> {
+    let mut hello = StateWriter::new();
+    hello.write_bytes(MAGIC);
+    hello.write_u32(PROTOCOL_VERSION);
+    hello.into_inner()
+}
+
+fn check_hello(hello: &[u8]) -> io::Result<()> {
+    if &hello[0..4] != MAGIC {
+        return Err(invalid_data("not a pyrite wireless hub"));
+    }
+    let mut reader = StateReader::new(&hello[4..8]);
+    let version = reader.read_u32().map_err(bad_response)?;
+    if version != PROTOCOL_VERSION {
+        return Err(invalid_data("unsupported wireless hub protocol version"));
+    }
+    Ok(())
+}
+
+fn write_message<S: Write + ?Sized>(stream: &mut S, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&(message.len() as u32).to_le_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()
+}
+
+fn read_message<S: Read + ?Sized>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid_data("wireless hub message is too big"));
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn bad_response(err: StateError) -> io::Error {
+    invalid_data(&err.to_string())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
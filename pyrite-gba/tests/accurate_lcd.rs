@@ -0,0 +1,130 @@
+mod common;
+mod util;
+use common::{ready_console, step_to_line, write16};
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::{Gba, NoAudioOutput};
+
+const DISPSTAT: u32 = 0x04000004;
+const TM0CNT_L: u32 = 0x04000100;
+const TM0CNT_H: u32 = 0x04000102;
+const VRAM: u32 = 0x06000000;
+const OAM: u32 = 0x07000000;
+const DISPCNT: u32 = 0x04000000;
+
+const DISPCNT_HBLANK_FREE: u16 = 0x0020;
+const DISPCNT_OBJ: u16 = 0x1000;
+const DISPSTAT_HBLANK: u16 = 0x0002;
+const TMCNT_START: u16 = 0x0080;
+
+fn in_hblank(gba: &mut Gba) -> bool {
+    gba.hardware.view_halfword(DISPSTAT) & DISPSTAT_HBLANK != 0
+}
+
+fn pixel_addr(x: u32, y: u32) -> u32 {
+    VRAM + (y * 240 + x) * 2
+}
+
+#[test]
+pub fn test_mid_line_writes() {
+    let red = rgb5!(31, 0, 0);
+    let green = rgb5!(0, 31, 0);
+
+    let (mut gba, mut video) = ready_console(true);
+    let original = video.at(0u32, 10u32);
+    step_to_line(&mut gba, &mut video, 10);
+
+    // The end of the line hasn't been drawn yet but the first pixel is gone as soon as the LCD
+    // has moved on.
+    write16(&mut gba, pixel_addr(239, 10), red);
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, |gba| {
+        write16(gba, pixel_addr(0, 10), green);
+        in_hblank(gba)
+    });
+    assert_eq!(video.at(239u32, 10u32), red);
+    assert_eq!(video.at(0u32, 10u32), original);
+
+    step_to_line(&mut gba, &mut video, 11);
+    step_to_line(&mut gba, &mut video, 10);
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, in_hblank);
+    assert_eq!(video.at(0u32, 10u32), green);
+}
+
+#[test]
+pub fn test_whole_lines_without_accurate_mode() {
+    let red = rgb5!(31, 0, 0);
+
+    let (mut gba, mut video) = ready_console(false);
+    step_to_line(&mut gba, &mut video, 10);
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, |gba| {
+        write16(gba, pixel_addr(0, 10), red);
+        in_hblank(gba)
+    });
+    assert_eq!(video.at(0u32, 10u32), red);
+}
+
+/// Measures the number of cycles between the start of a line and the HBlank flag with a timer.
+fn hblank_flag_delay(accurate: bool) -> u16 {
+    let (mut gba, mut video) = ready_console(accurate);
+    write16(&mut gba, TM0CNT_L, 0);
+    write16(&mut gba, TM0CNT_H, TMCNT_START);
+    step_to_line(&mut gba, &mut video, 20);
+    let start = gba.hardware.view_halfword(TM0CNT_L);
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, in_hblank);
+    let end = gba.hardware.view_halfword(TM0CNT_L);
+    end.wrapping_sub(start)
+}
+
+#[test]
+pub fn test_hblank_flag_timing() {
+    // Steps are a whole instruction long so the times are only accurate to a few cycles.
+    let delay = hblank_flag_delay(true);
+    assert!((1000..=1012).contains(&delay), "delay: {}", delay);
+    // Without accurate mode the LCD keeps its old timing.
+    let delay = hblank_flag_delay(false);
+    assert!((272..=284).contains(&delay), "delay: {}", delay);
+}
+
+#[test]
+pub fn test_video_memory_stalls() {
+    let (mut gba, mut video) = ready_console(true);
+    step_to_line(&mut gba, &mut video, 30);
+
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(VRAM, false, &mut cycles);
+    assert_eq!(cycles, 2);
+
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, in_hblank);
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(VRAM, false, &mut cycles);
+    assert_eq!(cycles, 1);
+
+    gba.set_accurate_lcd(false);
+    step_to_line(&mut gba, &mut video, 31);
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(VRAM, false, &mut cycles);
+    assert_eq!(cycles, 1);
+}
+
+#[test]
+pub fn test_oam_stalls_in_hblank() {
+    let (mut gba, mut video) = ready_console(true);
+    let dispcnt = gba.hardware.view_halfword(DISPCNT) | DISPCNT_OBJ;
+    write16(&mut gba, DISPCNT, dispcnt);
+    step_to_line(&mut gba, &mut video, 30);
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, in_hblank);
+
+    // The objects of the next line are read from OAM in HBlank but VRAM is free.
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(OAM, false, &mut cycles);
+    assert_eq!(cycles, 2);
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(VRAM, false, &mut cycles);
+    assert_eq!(cycles, 1);
+
+    write16(&mut gba, DISPCNT, dispcnt | DISPCNT_HBLANK_FREE);
+    step_to_line(&mut gba, &mut video, 31);
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, in_hblank);
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(OAM, false, &mut cycles);
+    assert_eq!(cycles, 1);
+}
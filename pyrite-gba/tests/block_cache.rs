@@ -0,0 +1,123 @@
+mod util;
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+
+/// Runs the same ROM with and without the CPU's block cache and makes sure that both end up
+/// executing the same instructions at the same times.
+#[test]
+pub fn test_block_cache_lockstep() {
+    const STEPS: u32 = 500000;
+
+    let mut interpreted = Gba::alloc();
+    let mut cached = Gba::alloc();
+    util::load_rom(&mut interpreted, "../roms/test/timer-stress.gba");
+    util::load_rom(&mut cached, "../roms/test/timer-stress.gba");
+    interpreted.reset(true);
+    cached.reset(true);
+    cached.set_block_cache_enabled(true);
+
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    for step in 0..STEPS {
+        let interpreted_frame = interpreted.step(&mut video, &mut audio);
+        let cached_frame = cached.step(&mut video, &mut audio);
+        assert_eq!(
+            interpreted_frame, cached_frame,
+            "frame mismatch at step {}",
+            step
+        );
+
+        for register in 0..16 {
+            assert_eq!(
+                interpreted.cpu.registers.read(register),
+                cached.cpu.registers.read(register),
+                "r{} mismatch at step {}",
+                register,
+                step
+            );
+        }
+        assert_eq!(
+            interpreted.cpu.registers.read_cpsr(),
+            cached.cpu.registers.read_cpsr(),
+            "CPSR mismatch at step {}",
+            step
+        );
+    }
+}
+
+/// Runs the same ROM with the interpreter and the JIT and makes sure that both are in the same
+/// state at the end of every frame. The JIT can run many instructions in one step so the two can't
+/// be compared after every step.
+#[cfg(feature = "jit")]
+#[test]
+pub fn test_jit_frame_lockstep() {
+    const FRAMES: u32 = 120;
+
+    let mut interpreted = Gba::alloc();
+    let mut jit = Gba::alloc();
+    util::load_rom(&mut interpreted, "../roms/test/timer-stress.gba");
+    util::load_rom(&mut jit, "../roms/test/timer-stress.gba");
+    interpreted.reset(true);
+    jit.reset(true);
+    jit.set_jit_enabled(true);
+
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    for frame in 0..FRAMES {
+        while !interpreted.step(&mut video, &mut audio).0 {}
+        while !jit.step(&mut video, &mut audio).0 {}
+
+        for register in 0..16 {
+            assert_eq!(
+                interpreted.cpu.registers.read(register),
+                jit.cpu.registers.read(register),
+                "r{} mismatch at frame {}",
+                register,
+                frame
+            );
+        }
+        assert_eq!(
+            interpreted.cpu.registers.read_cpsr(),
+            jit.cpu.registers.read_cpsr(),
+            "CPSR mismatch at frame {}",
+            frame
+        );
+    }
+}
+
+/// Code that runs from a mirror of IWRAM has to see writes to the unmirrored address.
+#[test]
+pub fn test_block_cache_ram_mirror() {
+    const MOV_R0_1: u32 = 0xE3A00001;
+    const MOV_R0_2: u32 = 0xE3A00002;
+    const B_LOOP: u32 = 0xEAFFFFFD; // b 0x03000000
+
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/mode3.gba");
+    gba.reset(true);
+    gba.set_block_cache_enabled(true);
+    gba.cpu.registers.setf_i();
+
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_word(0x03000000, MOV_R0_1, false, &mut cycles);
+    gba.hardware
+        .write_data_word(0x03000004, B_LOOP, false, &mut cycles);
+    let _ = gba.cpu.set_pc(0x03008000, &mut gba.hardware);
+
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+    for _ in 0..10 {
+        gba.step(&mut video, &mut audio);
+    }
+    assert_eq!(gba.cpu.registers.read(0), 1);
+
+    gba.hardware
+        .write_data_word(0x03000000, MOV_R0_2, false, &mut cycles);
+    for _ in 0..10 {
+        gba.step(&mut video, &mut audio);
+    }
+    assert_eq!(gba.cpu.registers.read(0), 2);
+}
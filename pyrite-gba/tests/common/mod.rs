@@ -0,0 +1,69 @@
+//! Consoles and register helpers that are shared by the tests of the hardware around the CPU.
+//! Every test only uses some of them.
+#![allow(dead_code)]
+
+use crate::util::{self, GbaTestVideo, TestStatus};
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::{Gba, NoAudioOutput};
+
+const DISPSTAT: u32 = 0x04000004;
+const VCOUNT: u32 = 0x04000006;
+
+const DISPSTAT_HBLANK: u16 = 0x0002;
+
+/// Only shows a still image so it doesn't touch the serial registers, the keypad or the
+/// interrupts.
+pub const STILL_ROM: &str = "../roms/third-party/tonc/m3_demo.gba";
+
+/// A console that was powered on with `STILL_ROM`.
+pub fn still_console() -> Gba {
+    let mut gba = Gba::new();
+    util::load_rom(&mut gba, STILL_ROM);
+    gba.power_on(true);
+    gba
+}
+
+/// Draws a still image in mode 3 and then spins so the CPU is never halted.
+pub const TEST_ROM: &str = "../roms/test/mode3.gba";
+
+/// A console that was reset with `TEST_ROM` and has run until it is ready.
+pub fn ready_console(accurate_lcd: bool) -> (Gba, GbaTestVideo) {
+    let mut gba = Gba::new();
+    util::load_rom(&mut gba, TEST_ROM);
+    gba.reset(true);
+    gba.set_accurate_lcd(accurate_lcd);
+
+    let mut video = GbaTestVideo::new();
+    util::step_until_status(&mut gba, &mut video, &mut NoAudioOutput, TestStatus::Ready);
+    (gba, video)
+}
+
+/// Steps until the LCD has just started drawing `line`.
+pub fn step_to_line(gba: &mut Gba, video: &mut GbaTestVideo, line: u16) {
+    util::step_until(gba, video, &mut NoAudioOutput, |gba| {
+        view16(gba, VCOUNT) == line && view16(gba, DISPSTAT) & DISPSTAT_HBLANK == 0
+    });
+}
+
+pub fn write16(gba: &mut Gba, addr: u32, value: u16) {
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_halfword(addr, value, false, &mut cycles);
+}
+
+pub fn write32(gba: &mut Gba, addr: u32, value: u32) {
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_word(addr, value, false, &mut cycles);
+}
+
+/// A read by the CPU, which can change the register.
+pub fn read16(gba: &mut Gba, addr: u32) -> u16 {
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(addr, false, &mut cycles)
+}
+
+/// Reads a register without the side effects that a read by the CPU might have.
+pub fn view16(gba: &Gba, addr: u32) -> u16 {
+    gba.hardware.view_halfword(addr)
+}
@@ -0,0 +1,350 @@
+mod common;
+mod util;
+use common::{ready_console, step_to_line, write16, write32};
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::audio::fifo::FifoChannel;
+use pyrite_gba::dma::{DMAChannelIndex, GbaDMA};
+use pyrite_gba::{Gba, NoAudioOutput};
+use util::GbaTestVideo;
+
+const SOUNDCNT_H: u32 = 0x04000082;
+const SOUNDCNT_X: u32 = 0x04000084;
+const FIFO_A: u32 = 0x040000A0;
+const TM0CNT_L: u32 = 0x04000100;
+const TM0CNT_H: u32 = 0x04000102;
+const WAITCNT: u32 = 0x04000204;
+
+const DMA0: u32 = 0x040000B0;
+const DMA1: u32 = 0x040000BC;
+const DMA3: u32 = 0x040000D4;
+
+const DMA_SRC_FIXED: u16 = 0x0100;
+const DMA_REPEAT: u16 = 0x0200;
+const DMA_WORD: u16 = 0x0400;
+const DMA_VBLANK: u16 = 0x1000;
+const DMA_HBLANK: u16 = 0x2000;
+const DMA_SPECIAL: u16 = 0x3000;
+const DMA_ENABLE: u16 = 0x8000;
+
+const SOURCE: u32 = 0x02010000;
+const DESTINATION: u32 = 0x02020000;
+const IWRAM_DESTINATION: u32 = 0x03001000;
+const ROM_START: u32 = 0x08000000;
+
+fn console() -> (Gba, GbaTestVideo) {
+    let (mut gba, video) = ready_console(false);
+
+    // a pattern of bytes to copy from
+    for offset in (0..0x1000).step_by(4) {
+        let bytes = [offset, offset + 1, offset + 2, offset + 3];
+        write32(
+            &mut gba,
+            SOURCE + offset,
+            u32::from_le_bytes(bytes.map(|b| b as u8)),
+        );
+    }
+    (gba, video)
+}
+
+/// Sets up a DMA channel's addresses and count and then writes its control register.
+fn start_dma(gba: &mut Gba, channel: u32, source: u32, destination: u32, count: u16, control: u16) {
+    write32(gba, channel, source);
+    write32(gba, channel + 4, destination);
+    write16(gba, channel + 8, count);
+    write16(gba, channel + 10, control);
+}
+
+/// Starts a channel that was set up with `start_dma` right away and returns the number of cycles
+/// that each unit took until every active channel has finished. `preempt` is called before each
+/// unit with the number of units done so far.
+fn run_dma(
+    gba: &mut Gba,
+    channel: DMAChannelIndex,
+    mut preempt: impl FnMut(&mut Gba, usize),
+) -> Vec<u32> {
+    gba.hardware.dma.begin_transfer(channel, &mut gba.cpu);
+    let mut units = Vec::new();
+    while gba.hardware.dma.active() {
+        preempt(gba, units.len());
+        units.push(GbaDMA::cpu_step_override(
+            &mut gba.cpu,
+            &mut gba.hardware,
+            0,
+        ));
+    }
+    units
+}
+
+/// Counts the halfwords at `DESTINATION` that were written before the first zero.
+fn transferred_halfwords(gba: &Gba) -> u32 {
+    let mut count = 0;
+    while gba.hardware.view_halfword(DESTINATION + count * 2) != 0 {
+        count += 1;
+    }
+    count
+}
+
+#[test]
+pub fn test_video_capture() {
+    let (mut gba, mut video) = console();
+    step_to_line(&mut gba, &mut video, 0);
+    let control = DMA_ENABLE | DMA_SPECIAL | DMA_REPEAT | DMA_SRC_FIXED;
+    start_dma(&mut gba, DMA3, SOURCE + 2, DESTINATION, 1, control);
+
+    // One transfer for every line from 2 through 161 and then the channel turns itself off.
+    step_to_line(&mut gba, &mut video, 100);
+    assert_eq!(transferred_halfwords(&gba), 98);
+    step_to_line(&mut gba, &mut video, 200);
+    assert_eq!(transferred_halfwords(&gba), 160);
+    assert_eq!(gba.hardware.view_halfword(DMA3 + 10) & DMA_ENABLE, 0);
+
+    step_to_line(&mut gba, &mut video, 0);
+    step_to_line(&mut gba, &mut video, 200);
+    assert_eq!(transferred_halfwords(&gba), 160);
+}
+
+#[test]
+pub fn test_dma0_special_timing_never_starts() {
+    let (mut gba, mut video) = console();
+    start_dma(
+        &mut gba,
+        DMA0,
+        SOURCE + 2,
+        DESTINATION,
+        1,
+        DMA_ENABLE | DMA_SPECIAL | DMA_REPEAT,
+    );
+    step_to_line(&mut gba, &mut video, 0);
+    step_to_line(&mut gba, &mut video, 200);
+    assert_eq!(transferred_halfwords(&gba), 0);
+    assert_ne!(gba.hardware.view_halfword(DMA0 + 10) & DMA_ENABLE, 0);
+}
+
+#[test]
+pub fn test_repeat_reloads_count() {
+    let (mut gba, mut video) = console();
+    step_to_line(&mut gba, &mut video, 0);
+    let control = DMA_ENABLE | DMA_HBLANK | DMA_REPEAT | DMA_SRC_FIXED;
+    start_dma(&mut gba, DMA0, SOURCE + 2, DESTINATION, 2, control);
+    step_to_line(&mut gba, &mut video, 10);
+    assert_eq!(transferred_halfwords(&gba), 20);
+}
+
+#[test]
+pub fn test_count_limits() {
+    // DMA0-2 only use the lower 14 bits of the word count.
+    let (mut gba, mut video) = console();
+    start_dma(&mut gba, DMA0, SOURCE + 2, DESTINATION, 0xC003, DMA_ENABLE);
+    step_to_line(&mut gba, &mut video, 0);
+    assert_eq!(transferred_halfwords(&gba), 3);
+
+    // DMA3 uses all 16 bits.
+    let (mut gba, mut video) = console();
+    start_dma(
+        &mut gba,
+        DMA3,
+        SOURCE + 2,
+        DESTINATION,
+        0x8000,
+        DMA_ENABLE | DMA_SRC_FIXED,
+    );
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, |gba| {
+        gba.hardware.view_halfword(DMA3 + 10) & DMA_ENABLE == 0
+    });
+    assert_eq!(transferred_halfwords(&gba), 0x8000);
+}
+
+#[test]
+pub fn test_address_masks() {
+    // DMA1 can read from the GamePak even though it can only write to internal memory.
+    let (mut gba, mut video) = console();
+    start_dma(
+        &mut gba,
+        DMA1,
+        0x08000000,
+        DESTINATION,
+        8,
+        DMA_ENABLE | DMA_WORD,
+    );
+    step_to_line(&mut gba, &mut video, 0);
+    for offset in (0..32).step_by(4) {
+        assert_eq!(
+            gba.hardware.view_word(DESTINATION + offset),
+            gba.hardware.view_word(0x08000000 + offset)
+        );
+    }
+
+    // DMA0's source address is only 27 bits wide so this reads from EWRAM.
+    let (mut gba, mut video) = console();
+    start_dma(
+        &mut gba,
+        DMA0,
+        SOURCE | 0xF8000000,
+        DESTINATION,
+        8,
+        DMA_ENABLE | DMA_WORD,
+    );
+    step_to_line(&mut gba, &mut video, 0);
+    assert_eq!(gba.hardware.view_word(DESTINATION), 0x03020100);
+}
+
+#[test]
+pub fn test_sound_fifo_dma() {
+    let (mut gba, mut video) = console();
+
+    // FIFO A is played with timer 0 and refilled by DMA1. The word count and destination address
+    // control are ignored for sound FIFO transfers.
+    write16(&mut gba, SOUNDCNT_X, 0x0080);
+    write16(&mut gba, SOUNDCNT_H, 0x0B00);
+    let control = DMA_ENABLE | DMA_SPECIAL | DMA_REPEAT;
+    start_dma(&mut gba, DMA1, SOURCE, FIFO_A, 1, control);
+    write16(&mut gba, TM0CNT_L, 0xFF00);
+    write16(&mut gba, TM0CNT_H, 0x0080);
+
+    // The samples are played in order without skipping any so the FIFO was always refilled with
+    // the next 4 words.
+    let mut played = Vec::new();
+    let mut last = 0;
+    util::step_until(&mut gba, &mut video, &mut NoAudioOutput, |gba| {
+        let current = gba.hardware.audio.fifo(FifoChannel::A).current() as u8;
+        if current != last {
+            played.push(current);
+            last = current;
+        }
+        played.len() >= 1000
+    });
+    for (index, &sample) in played.iter().enumerate() {
+        assert_eq!(sample, (index + 1) as u8);
+    }
+    let len = gba.hardware.audio.fifo(FifoChannel::A).len();
+    assert!((16..=32).contains(&len), "FIFO length: {}", len);
+
+    // Resetting the FIFO empties it.
+    write16(&mut gba, TM0CNT_H, 0);
+    write16(&mut gba, SOUNDCNT_H, 0x0B00);
+    assert!(gba.hardware.audio.fifo(FifoChannel::A).is_empty());
+    assert_eq!(gba.hardware.view_halfword(SOUNDCNT_H), 0x0300);
+}
+
+// With WAITCNT set to 0 a GamePak halfword takes 5 cycles (N) or 3 cycles (S) and a word is two
+// halfword accesses. EWRAM always takes 3 cycles for a halfword and 6 for a word and IWRAM always
+// takes 1 cycle. Every transfer spends 2 extra cycles starting up.
+
+#[test]
+pub fn test_transfer_cycles() {
+    let (mut gba, _video) = console();
+    write16(&mut gba, WAITCNT, 0);
+
+    let control = DMA_ENABLE | DMA_VBLANK;
+    start_dma(&mut gba, DMA3, SOURCE, DESTINATION, 4, control);
+    assert_eq!(
+        run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {}),
+        [2 + 3 + 3, 6, 6, 6]
+    );
+
+    start_dma(
+        &mut gba,
+        DMA3,
+        ROM_START,
+        IWRAM_DESTINATION,
+        3,
+        control | DMA_WORD,
+    );
+    assert_eq!(
+        run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {}),
+        [2 + 8 + 1, 6 + 1, 6 + 1]
+    );
+}
+
+#[test]
+pub fn test_gamepak_block_boundary_is_nonsequential() {
+    let (mut gba, _video) = console();
+    write16(&mut gba, WAITCNT, 0);
+
+    let control = DMA_ENABLE | DMA_VBLANK | DMA_WORD;
+    start_dma(
+        &mut gba,
+        DMA3,
+        ROM_START + 0x1FFF8,
+        IWRAM_DESTINATION,
+        3,
+        control,
+    );
+    assert_eq!(
+        run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {}),
+        [2 + 8 + 1, 6 + 1, 8 + 1]
+    );
+}
+
+#[test]
+pub fn test_gamepak_source_always_increments() {
+    let (mut gba, _video) = console();
+    let control = DMA_ENABLE | DMA_VBLANK | DMA_WORD | DMA_SRC_FIXED;
+    start_dma(&mut gba, DMA3, ROM_START, DESTINATION, 2, control);
+    run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {});
+    assert_eq!(
+        gba.hardware.view_word(DESTINATION),
+        gba.hardware.view_word(ROM_START)
+    );
+    assert_eq!(
+        gba.hardware.view_word(DESTINATION + 4),
+        gba.hardware.view_word(ROM_START + 4)
+    );
+}
+
+#[test]
+pub fn test_priority_preemption() {
+    let (mut gba, _video) = console();
+    write16(&mut gba, WAITCNT, 0);
+
+    let control = DMA_ENABLE | DMA_VBLANK;
+    start_dma(&mut gba, DMA3, ROM_START, DESTINATION, 4, control);
+    start_dma(&mut gba, DMA0, SOURCE, DESTINATION + 0x100, 2, control);
+
+    // DMA0 starts after DMA3 has transferred 2 halfwords and runs to completion before DMA3 picks
+    // up where it left off with a non-sequential access.
+    let units = run_dma(&mut gba, DMAChannelIndex::DMA3, |gba, done| {
+        if done == 2 {
+            gba.hardware
+                .dma
+                .begin_transfer(DMAChannelIndex::DMA0, &mut gba.cpu);
+        }
+    });
+    assert_eq!(units, [2 + 5 + 3, 3 + 3, 2 + 3 + 3, 6, 5 + 3, 3 + 3]);
+
+    for offset in (0..8).step_by(2) {
+        assert_eq!(
+            gba.hardware.view_halfword(DESTINATION + offset),
+            gba.hardware.view_halfword(ROM_START + offset)
+        );
+    }
+    assert_eq!(gba.hardware.view_word(DESTINATION + 0x100), 0x03020100);
+}
+
+#[test]
+pub fn test_open_bus_latch() {
+    let (mut gba, _video) = console();
+    let control = DMA_ENABLE | DMA_VBLANK | DMA_WORD;
+
+    // Reading from the BIOS gives the last word that the DMA controller transferred.
+    start_dma(&mut gba, DMA3, SOURCE + 4, DESTINATION, 1, control);
+    run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {});
+    start_dma(&mut gba, DMA3, 0x00000000, DESTINATION + 4, 2, control);
+    run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {});
+    assert_eq!(gba.hardware.view_word(DESTINATION + 4), 0x07060504);
+    assert_eq!(gba.hardware.view_word(DESTINATION + 8), 0x07060504);
+
+    // A halfword transfer leaves the halfword on both halves of the bus.
+    start_dma(
+        &mut gba,
+        DMA3,
+        SOURCE + 2,
+        DESTINATION,
+        1,
+        DMA_ENABLE | DMA_VBLANK,
+    );
+    run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {});
+    start_dma(&mut gba, DMA3, 0x00000000, DESTINATION + 4, 1, control);
+    run_dma(&mut gba, DMAChannelIndex::DMA3, |_, _| {});
+    assert_eq!(gba.hardware.view_word(DESTINATION + 4), 0x03020302);
+}
@@ -85,3 +85,107 @@ pub fn test_mode4() {
         );
     });
 }
+
+#[test]
+pub fn test_greenswap() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/greenswap.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    let mut audio = NoAudioOutput;
+
+    // Even pixels are green and odd pixels are red. With green swap on the green moves over to
+    // the odd pixels.
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Ready);
+    video.iter().for_each(|(x, y, col)| {
+        let expected = if x % 2 == 0 {
+            rgb5!(0, 0, 0)
+        } else {
+            rgb5!(31, 31, 0)
+        };
+        assert_eq!(expected, col, "color for ({}, {}) is wrong", x, y);
+    });
+
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Break);
+    video.iter().for_each(|(x, y, col)| {
+        let expected = if x % 2 == 0 {
+            rgb5!(0, 31, 0)
+        } else {
+            rgb5!(31, 0, 0)
+        };
+        assert_eq!(expected, col, "color for ({}, {}) is wrong", x, y);
+    });
+}
+
+#[test]
+pub fn test_forced_blank() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/forced_blank.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    let mut audio = NoAudioOutput;
+
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Ready);
+    video.iter().for_each(|(x, y, col)| {
+        assert_eq!(rgb5!(31, 31, 31), col, "color for ({}, {}) is wrong", x, y);
+    });
+
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Break);
+    video.iter().for_each(|(x, y, col)| {
+        assert_eq!(rgb5!(31, 0, 0), col, "color for ({}, {}) is wrong", x, y);
+    });
+}
+
+#[test]
+pub fn test_frame_select() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/frame_select.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    let mut audio = NoAudioOutput;
+
+    // Mode 5 frame 1 is blue and only 160x128 so the green backdrop is shown around it.
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Ready);
+    video.iter().for_each(|(x, y, col)| {
+        let expected = if x < 160 && y < 128 {
+            rgb5!(0, 0, 31)
+        } else {
+            rgb5!(0, 31, 0)
+        };
+        assert_eq!(expected, col, "color for ({}, {}) is wrong", x, y);
+    });
+
+    // Mode 4 frame 1 is blue and the object using tile 0 is hidden.
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Break);
+    video.iter().for_each(|(x, y, col)| {
+        assert_eq!(rgb5!(0, 0, 31), col, "color for ({}, {}) is wrong", x, y);
+    });
+}
+
+#[test]
+pub fn test_mid_frame_affine_reference_points() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/affine_ref.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    let mut audio = NoAudioOutput;
+
+    util::step_until_status(&mut gba, &mut video, &mut audio, TestStatus::Ready);
+
+    // Row N of the tile uses color N + 1 which is (3 * (N + 1), 0, 0). BG2X is written on line 84
+    // without changing the rows and BG2Y is set to row 3 on line 100. The lines that the writes
+    // happen on are skipped.
+    video.iter().for_each(|(x, y, col)| {
+        let row = match y {
+            84 | 100 => return,
+            0..=99 => y % 8,
+            _ => (y - 100 + 3) % 8,
+        };
+        let expected = rgb5!((row as u16 + 1) * 3, 0, 0);
+        assert_eq!(expected, col, "color for ({}, {}) is wrong", x, y);
+    });
+}
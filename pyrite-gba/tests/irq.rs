@@ -0,0 +1,103 @@
+mod common;
+mod util;
+use common::{still_console, view16, write16};
+use pyrite_arm::memory::ArmMemory;
+use pyrite_arm::registers::CpuMode;
+use pyrite_gba::keypad::KeypadInput;
+use pyrite_gba::{Gba, GbaSystemState, NoAudioOutput, NoVideoOutput};
+
+const KEYCNT: u32 = 0x04000132;
+const IE: u32 = 0x04000200;
+const IF: u32 = 0x04000202;
+const IME: u32 = 0x04000208;
+const HALTCNT: u32 = 0x04000301;
+
+const IRQ_KEYPAD: u16 = 0x1000;
+const KEYCNT_IRQ_A: u16 = 0x4001;
+
+fn console() -> Gba {
+    let mut gba = still_console();
+    write16(&mut gba, KEYCNT, KEYCNT_IRQ_A);
+    gba
+}
+
+fn step(gba: &mut Gba, steps: usize) {
+    for _ in 0..steps {
+        gba.step(&mut NoVideoOutput, &mut NoAudioOutput);
+    }
+}
+
+#[test]
+pub fn test_if_without_ie_and_ime() {
+    let mut gba = console();
+    gba.set_key_pressed(KeypadInput::ButtonA, true);
+    step(&mut gba, 1);
+    assert_ne!(view16(&gba, IF) & IRQ_KEYPAD, 0);
+
+    // Byte writes only acknowledge the interrupts in that byte.
+    let mut cycles = 0;
+    gba.hardware.write_data_byte(IF, 0xFF, false, &mut cycles);
+    assert_ne!(view16(&gba, IF) & IRQ_KEYPAD, 0);
+    gba.hardware
+        .write_data_byte(IF + 1, (IRQ_KEYPAD >> 8) as u8, false, &mut cycles);
+    assert_eq!(view16(&gba, IF) & IRQ_KEYPAD, 0);
+}
+
+#[test]
+pub fn test_irq_when_unmasked() {
+    let mut gba = console();
+    gba.cpu.registers.clearf_i();
+    write16(&mut gba, IE, IRQ_KEYPAD);
+    gba.set_key_pressed(KeypadInput::ButtonA, true);
+    step(&mut gba, 100);
+    assert_eq!(gba.cpu.registers.read_mode(), CpuMode::System);
+
+    // The waiting interrupt is taken soon after IME is turned on.
+    write16(&mut gba, IME, 1);
+    step(&mut gba, 20);
+    assert_eq!(gba.cpu.registers.read_mode(), CpuMode::IRQ);
+    assert!(gba.cpu.registers.getf_i());
+}
+
+#[test]
+pub fn test_halt_without_ime() {
+    let mut gba = console();
+    write16(&mut gba, IE, IRQ_KEYPAD);
+    let mut cycles = 0;
+    gba.hardware.write_data_byte(HALTCNT, 0, false, &mut cycles);
+    gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+    assert_eq!(gba.system_state(), GbaSystemState::Halted);
+
+    // The CPU carries on after the halt without taking the IRQ.
+    gba.set_key_pressed(KeypadInput::ButtonA, true);
+    step(&mut gba, 20);
+    assert_eq!(gba.system_state(), GbaSystemState::Running);
+    assert_eq!(gba.cpu.registers.read_mode(), CpuMode::System);
+
+    // Halting with an interrupt that is already waiting doesn't do anything.
+    gba.hardware.write_data_byte(HALTCNT, 0, false, &mut cycles);
+    step(&mut gba, 20);
+    assert_eq!(gba.system_state(), GbaSystemState::Running);
+}
+
+#[test]
+pub fn test_many_interrupt_register_writes() {
+    let mut gba = console();
+    gba.cpu.registers.clearf_i();
+    step(&mut gba, 100);
+    write16(&mut gba, IME, 1);
+
+    // Every write changes the IRQ line but the CPU only looks at it once, after the last one.
+    for _ in 0..64 {
+        write16(&mut gba, IE, IRQ_KEYPAD);
+        write16(&mut gba, IE, 0);
+    }
+    gba.set_key_pressed(KeypadInput::ButtonA, true);
+    step(&mut gba, 20);
+    assert_eq!(gba.cpu.registers.read_mode(), CpuMode::System);
+    assert_ne!(view16(&gba, IF) & IRQ_KEYPAD, 0);
+
+    write16(&mut gba, IE, IRQ_KEYPAD);
+    step(&mut gba, 20);
+    assert_eq!(gba.cpu.registers.read_mode(), CpuMode::IRQ);
+}
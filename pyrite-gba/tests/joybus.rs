@@ -0,0 +1,150 @@
+mod common;
+mod util;
+use common::{read16, still_console, view16, write16};
+use pyrite_gba::sio::{GameCube, JoyBusCommand, JoyBusResponse, JOYBUS_DEVICE_GBA};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+
+const RCNT: u32 = 0x04000134;
+const JOYCNT: u32 = 0x04000140;
+const JOY_RECV: u32 = 0x04000150;
+const JOY_TRANS: u32 = 0x04000154;
+const JOYSTAT: u32 = 0x04000158;
+
+const RCNT_JOYBUS: u16 = 0xC000;
+
+const JOYCNT_RESET: u16 = 0x0001;
+const JOYCNT_RECEIVE: u16 = 0x0002;
+const JOYCNT_SEND: u16 = 0x0004;
+const JOYCNT_IRQ: u16 = 0x0040;
+
+const JOYSTAT_RECEIVE: u8 = 0x02;
+const JOYSTAT_SEND: u8 = 0x08;
+
+fn console() -> Gba {
+    let mut gba = still_console();
+    write16(&mut gba, RCNT, RCNT_JOYBUS);
+    gba
+}
+
+#[test]
+pub fn test_reset_and_status() {
+    let mut gba = console();
+    let mut gc = GameCube::new();
+
+    assert_eq!(
+        gc.send(&mut gba, JoyBusCommand::Status),
+        Some(JoyBusResponse::Status {
+            device: JOYBUS_DEVICE_GBA,
+            joystat: 0
+        })
+    );
+    assert_eq!(view16(&gba, JOYCNT), 0);
+    assert_eq!(gc.reset(&mut gba), Some(0));
+    assert_eq!(view16(&gba, JOYCNT), JOYCNT_RESET);
+
+    // The general purpose flags are the only ones that the GBA can set itself.
+    write16(&mut gba, JOYSTAT, 0xFF);
+    assert_eq!(gc.status(&mut gba), Some(0x30));
+
+    // The flags in JOYCNT are acknowledged by writing 1s.
+    write16(&mut gba, JOYCNT, JOYCNT_IRQ);
+    assert_eq!(view16(&gba, JOYCNT), JOYCNT_RESET | JOYCNT_IRQ);
+    write16(&mut gba, JOYCNT, JOYCNT_RESET);
+    assert_eq!(view16(&gba, JOYCNT), 0);
+
+    assert_eq!(gc.history().len(), 3);
+    assert_eq!(gc.history()[1].0, JoyBusCommand::Reset);
+}
+
+#[test]
+pub fn test_gamecube_writes() {
+    let mut gba = console();
+    let mut gc = GameCube::new();
+
+    assert_eq!(gc.write(&mut gba, 0xCAFEBABE), Some(JOYSTAT_RECEIVE));
+    assert_eq!(view16(&gba, JOYCNT), JOYCNT_RECEIVE);
+    assert_eq!(view16(&gba, JOY_RECV + 2), 0xCAFE);
+
+    // Viewing JOY_RECV leaves the flag alone but a read by the CPU clears it.
+    assert_eq!(view16(&gba, JOY_RECV), 0xBABE);
+    assert_eq!(gc.status(&mut gba), Some(JOYSTAT_RECEIVE));
+    assert_eq!(read16(&mut gba, JOY_RECV), 0xBABE);
+    assert_eq!(gc.status(&mut gba), Some(0));
+}
+
+#[test]
+pub fn test_gamecube_reads() {
+    let mut gba = console();
+    let mut gc = GameCube::new();
+
+    write16(&mut gba, JOY_TRANS, 0x5678);
+    write16(&mut gba, JOY_TRANS + 2, 0x1234);
+    assert_eq!(view16(&gba, JOYSTAT) as u8, JOYSTAT_SEND);
+    assert_eq!(
+        gc.send(&mut gba, JoyBusCommand::Read),
+        Some(JoyBusResponse::Read {
+            data: 0x12345678,
+            joystat: 0
+        })
+    );
+    assert_eq!(view16(&gba, JOYCNT), JOYCNT_SEND);
+
+    // Nothing was written since, so there's nothing to wait for.
+    let data = gc.wait_for_data(&mut gba, 2, &mut NoVideoOutput, &mut NoAudioOutput);
+    assert_eq!(data, None);
+    write16(&mut gba, JOY_TRANS, 0x0042);
+    let data = gc.wait_for_data(&mut gba, 2, &mut NoVideoOutput, &mut NoAudioOutput);
+    assert_eq!(data, Some(0x12340042));
+}
+
+#[test]
+pub fn test_commands_on_the_wire() {
+    for &command in [
+        JoyBusCommand::Reset,
+        JoyBusCommand::Status,
+        JoyBusCommand::Read,
+        JoyBusCommand::Write(0x01020304),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            JoyBusCommand::from_bytes(&command.to_bytes()),
+            Some(command)
+        );
+    }
+    assert_eq!(JoyBusCommand::from_bytes(&[0x15, 1, 2]), None);
+
+    let status = JoyBusResponse::Status {
+        device: JOYBUS_DEVICE_GBA,
+        joystat: 0x30,
+    };
+    assert_eq!(status.to_bytes(), [0x00, 0x04, 0x30]);
+}
+
+#[test]
+pub fn test_not_in_joybus_mode() {
+    let mut gba = console();
+    write16(&mut gba, RCNT, 0);
+    let mut gc = GameCube::new();
+    assert_eq!(gc.status(&mut gba), None);
+    assert_eq!(gc.write(&mut gba, 1), None);
+    assert_eq!(view16(&gba, JOYCNT), 0);
+}
+
+#[test]
+pub fn test_save_state() {
+    let mut gba = console();
+    let mut gc = GameCube::new();
+    gc.write(&mut gba, 0xAABBCCDD);
+    write16(&mut gba, JOY_TRANS, 0x1111);
+    let state = gba.save_state(false).unwrap();
+
+    read16(&mut gba, JOY_RECV);
+    gc.read(&mut gba);
+    assert_eq!(gc.status(&mut gba), Some(0));
+
+    gba.load_state(&state).unwrap();
+    assert_eq!(gc.status(&mut gba), Some(JOYSTAT_RECEIVE | JOYSTAT_SEND));
+    assert_eq!(view16(&gba, JOY_RECV), 0xCCDD);
+    assert_eq!(gc.read(&mut gba), Some(0x1111));
+}
@@ -0,0 +1,97 @@
+mod common;
+mod util;
+use common::{still_console, write16};
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::keypad::{GbaKeypad, KeypadInput};
+use pyrite_gba::{Gba, GbaSystemState, NoAudioOutput, NoVideoOutput};
+
+const KEYCNT: u32 = 0x04000132;
+const IE: u32 = 0x04000200;
+const HALTCNT: u32 = 0x04000301;
+
+const KEYCNT_IRQ: u16 = 0x4000;
+const KEYCNT_AND: u16 = 0x8000;
+
+const IE_KEYPAD: u16 = 0x1000;
+
+fn keypad(control: u16, pressed: &[KeypadInput]) -> GbaKeypad {
+    let mut keypad = GbaKeypad::new();
+    keypad.control = control;
+    for &key in pressed {
+        keypad.set_pressed(key, true);
+    }
+    keypad
+}
+
+fn run_frame(gba: &mut Gba) {
+    gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+}
+
+#[test]
+pub fn test_keycnt_condition() {
+    let a_or_b = KeypadInput::ButtonA.mask() | KeypadInput::ButtonB.mask();
+    let a = [KeypadInput::ButtonA];
+    let a_and_b = [KeypadInput::ButtonA, KeypadInput::ButtonB];
+
+    assert!(!keypad(a_or_b, &a).irq_condition());
+    assert!(!keypad(KEYCNT_IRQ | a_or_b, &[]).irq_condition());
+    assert!(!keypad(KEYCNT_IRQ | a_or_b, &[KeypadInput::Start]).irq_condition());
+    assert!(keypad(KEYCNT_IRQ | a_or_b, &a).irq_condition());
+
+    assert!(!keypad(KEYCNT_IRQ | KEYCNT_AND | a_or_b, &a).irq_condition());
+    assert!(keypad(KEYCNT_IRQ | KEYCNT_AND | a_or_b, &a_and_b).irq_condition());
+    assert!(!keypad(KEYCNT_IRQ | KEYCNT_AND, &a_and_b).irq_condition());
+}
+
+#[test]
+pub fn test_wake_from_stop() {
+    let mut gba = still_console();
+    write16(&mut gba, IE, IE_KEYPAD);
+    write16(&mut gba, KEYCNT, KEYCNT_IRQ | KeypadInput::Start.mask());
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_byte(HALTCNT, 0x80, false, &mut cycles);
+    run_frame(&mut gba);
+    assert_eq!(gba.system_state(), GbaSystemState::Stopped);
+
+    // Keys that KEYCNT doesn't care about don't do anything.
+    gba.set_key_pressed(KeypadInput::ButtonA, true);
+    run_frame(&mut gba);
+    assert_eq!(gba.system_state(), GbaSystemState::Stopped);
+
+    gba.set_key_pressed(KeypadInput::Start, true);
+    run_frame(&mut gba);
+    assert_eq!(gba.system_state(), GbaSystemState::Running);
+}
+
+#[test]
+pub fn test_keypad_irq_is_level_checked() {
+    const IF: u32 = 0x04000202;
+
+    let mut gba = still_console();
+    write16(&mut gba, KEYCNT, KEYCNT_IRQ | KeypadInput::Start.mask());
+    gba.set_key_pressed(KeypadInput::Start, true);
+    run_frame(&mut gba);
+    assert_ne!(gba.hardware.view16(IF) & IE_KEYPAD, 0);
+
+    // Acknowledging the interrupt while the key is still held requests it again.
+    write16(&mut gba, IF, IE_KEYPAD);
+    run_frame(&mut gba);
+    assert_ne!(gba.hardware.view16(IF) & IE_KEYPAD, 0);
+
+    // Pressing it again without a change is checked too.
+    write16(&mut gba, KEYCNT, 0);
+    write16(&mut gba, IF, IE_KEYPAD);
+    run_frame(&mut gba);
+    assert_eq!(gba.hardware.view16(IF) & IE_KEYPAD, 0);
+    gba.hardware.keypad.control = KEYCNT_IRQ | KeypadInput::Start.mask();
+    gba.set_key_pressed(KeypadInput::Start, true);
+    run_frame(&mut gba);
+    assert_ne!(gba.hardware.view16(IF) & IE_KEYPAD, 0);
+
+    // Once the key is released the acknowledged flag stays clear.
+    gba.set_key_pressed(KeypadInput::Start, false);
+    write16(&mut gba, IF, IE_KEYPAD);
+    run_frame(&mut gba);
+    assert_eq!(gba.hardware.view16(IF) & IE_KEYPAD, 0);
+}
@@ -0,0 +1,168 @@
+mod common;
+mod util;
+use common::{still_console, view16, write16};
+use pyrite_gba::link::LinkCable;
+use pyrite_gba::{GbaAudioOutput, GbaVideoOutput, NoAudioOutput, NoVideoOutput};
+
+const SIODATA32: u32 = 0x04000120;
+const SIOMULTI0: u32 = 0x04000120;
+const SIOCNT: u32 = 0x04000128;
+const SIOMLT_SEND: u32 = 0x0400012A;
+const SIODATA8: u32 = 0x0400012A;
+const RCNT: u32 = 0x04000134;
+const IF: u32 = 0x04000202;
+
+const SIOCNT_START: u16 = 0x0080;
+const SIOCNT_IRQ: u16 = 0x4000;
+
+const IF_SERIAL: u16 = 0x0080;
+
+fn linked(count: usize) -> LinkCable {
+    LinkCable::new((0..count).map(|_| still_console()).collect())
+}
+
+fn run_frame(link: &mut LinkCable) {
+    let mut video = [NoVideoOutput, NoVideoOutput, NoVideoOutput, NoVideoOutput];
+    let mut audio = [NoAudioOutput, NoAudioOutput, NoAudioOutput, NoAudioOutput];
+    let count = link.consoles().len();
+    let mut video: Vec<&mut dyn GbaVideoOutput> = video
+        .iter_mut()
+        .take(count)
+        .map(|output| output as &mut dyn GbaVideoOutput)
+        .collect();
+    let mut audio: Vec<&mut dyn GbaAudioOutput> = audio
+        .iter_mut()
+        .take(count)
+        .map(|output| output as &mut dyn GbaAudioOutput)
+        .collect();
+    link.video_frame(&mut video, &mut audio);
+}
+
+#[test]
+pub fn test_multiplayer_transfer() {
+    let mut link = linked(3);
+    for (index, gba) in link.consoles_mut().iter_mut().enumerate() {
+        write16(gba, RCNT, 0);
+        write16(gba, SIOCNT, 0x2003 | SIOCNT_IRQ);
+        write16(gba, SIOMLT_SEND, 0x1111 * (index as u16 + 1));
+    }
+    run_frame(&mut link);
+
+    // SI is low for the parent and high for the children. SD is high because everyone is ready.
+    for (index, gba) in link.consoles().iter().enumerate() {
+        let control = view16(gba, SIOCNT);
+        assert_eq!(control & 0x4 != 0, index != 0, "SI of console {}", index);
+        assert!(control & 0x8 != 0, "SD of console {}", index);
+    }
+
+    // Children can't start a transfer.
+    write16(&mut link.consoles_mut()[1], SIOCNT, 0x2003 | SIOCNT_START);
+    assert_eq!(view16(&link.consoles()[1], SIOCNT) & SIOCNT_START, 0);
+    write16(&mut link.consoles_mut()[1], SIOCNT, 0x2003 | SIOCNT_IRQ);
+
+    for gba in link.consoles() {
+        assert_eq!(view16(gba, IF) & IF_SERIAL, 0);
+    }
+    write16(
+        &mut link.consoles_mut()[0],
+        SIOCNT,
+        0x2003 | SIOCNT_IRQ | SIOCNT_START,
+    );
+    run_frame(&mut link);
+
+    for (index, gba) in link.consoles().iter().enumerate() {
+        let received: Vec<u16> = (0..4).map(|n| view16(gba, SIOMULTI0 + n * 2)).collect();
+        assert_eq!(received, [0x1111, 0x2222, 0x3333, 0xFFFF]);
+
+        let control = view16(gba, SIOCNT);
+        assert_eq!(control & SIOCNT_START, 0, "console {} is still busy", index);
+        assert_eq!((control >> 4) & 3, index as u16, "ID of console {}", index);
+
+        // The interrupt is requested even though the game has interrupts disabled.
+        assert_ne!(view16(gba, IF) & IF_SERIAL, 0, "IRQ of console {}", index);
+    }
+}
+
+#[test]
+pub fn test_multiplayer_not_ready() {
+    let mut link = linked(2);
+    write16(&mut link.consoles_mut()[0], RCNT, 0);
+    write16(&mut link.consoles_mut()[0], SIOCNT, 0x2003);
+    write16(&mut link.consoles_mut()[0], SIOMLT_SEND, 0xABCD);
+    run_frame(&mut link);
+
+    // The child isn't in Multiplayer mode so SD is low and it doesn't answer.
+    assert_eq!(view16(&link.consoles()[0], SIOCNT) & 0x8, 0);
+    write16(&mut link.consoles_mut()[0], SIOCNT, 0x2003 | SIOCNT_START);
+    run_frame(&mut link);
+    assert_eq!(view16(&link.consoles()[0], SIOMULTI0), 0xABCD);
+    assert_eq!(view16(&link.consoles()[0], SIOMULTI0 + 2), 0xFFFF);
+}
+
+#[test]
+pub fn test_normal_32bit_transfer() {
+    let mut link = linked(2);
+    {
+        let slave = &mut link.consoles_mut()[1];
+        write16(slave, SIOCNT, 0x1000);
+        write16(slave, SIODATA32, 0xBABE);
+        write16(slave, SIODATA32 + 2, 0xCAFE);
+        write16(slave, SIOCNT, 0x1000 | SIOCNT_START);
+    }
+    {
+        let master = &mut link.consoles_mut()[0];
+        write16(master, SIOCNT, 0x1001);
+        write16(master, SIODATA32, 0x5678);
+        write16(master, SIODATA32 + 2, 0x1234);
+        write16(master, SIOCNT, 0x1001 | SIOCNT_START);
+    }
+    run_frame(&mut link);
+
+    let master = &link.consoles()[0];
+    let slave = &link.consoles()[1];
+    assert_eq!(view16(master, SIODATA32), 0xBABE);
+    assert_eq!(view16(master, SIODATA32 + 2), 0xCAFE);
+    assert_eq!(view16(slave, SIODATA32), 0x5678);
+    assert_eq!(view16(slave, SIODATA32 + 2), 0x1234);
+    assert_eq!(view16(master, SIOCNT) & SIOCNT_START, 0);
+    assert_eq!(view16(slave, SIOCNT) & SIOCNT_START, 0);
+}
+
+#[test]
+pub fn test_normal_8bit_without_slave() {
+    let mut link = linked(2);
+    {
+        let master = &mut link.consoles_mut()[0];
+        write16(master, SIOCNT, 0x0003);
+        write16(master, SIODATA8, 0x42);
+        write16(master, SIOCNT, 0x0003 | SIOCNT_START);
+    }
+    run_frame(&mut link);
+    assert_eq!(view16(&link.consoles()[0], SIODATA8) & 0xFF, 0xFF);
+    assert_eq!(view16(&link.consoles()[0], SIOCNT) & SIOCNT_START, 0);
+}
+
+#[test]
+pub fn test_unlinked_transfer() {
+    let mut gba = still_console();
+    write16(&mut gba, SIOCNT, 0x0001);
+    write16(&mut gba, SIODATA8, 0x42);
+    write16(&mut gba, SIOCNT, 0x0001 | SIOCNT_START);
+    assert_ne!(view16(&gba, SIOCNT) & SIOCNT_START, 0);
+
+    // A transfer in progress is part of the save state.
+    let state = gba.save_state(false).unwrap();
+    gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+    assert_eq!(view16(&gba, SIODATA8) & 0xFF, 0xFF);
+    assert_eq!(view16(&gba, SIOCNT) & SIOCNT_START, 0);
+
+    gba.load_state(&state).unwrap();
+    assert_ne!(view16(&gba, SIOCNT) & SIOCNT_START, 0);
+    gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+    assert_eq!(view16(&gba, SIOCNT) & SIOCNT_START, 0);
+
+    // An external clock never comes without a cable.
+    write16(&mut gba, SIOCNT, SIOCNT_START);
+    gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+    assert_ne!(view16(&gba, SIOCNT) & SIOCNT_START, 0);
+}
@@ -0,0 +1,191 @@
+mod util;
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::{Gba, RomError};
+
+const ROM: &str = "../roms/test/mode3.gba";
+
+const DISPCNT: u32 = 0x04000000;
+const IMC: u32 = 0x04000800;
+const UNUSED: u32 = 0x10000000;
+
+fn console() -> Box<Gba> {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, ROM);
+    // a BIOS where every word is its own address
+    let bios = (0..0x4000u32)
+        .step_by(4)
+        .flat_map(u32::to_le_bytes)
+        .collect();
+    gba.set_bios(bios);
+    gba.reset(true);
+    gba
+}
+
+fn read32(gba: &mut Gba, addr: u32) -> u32 {
+    let mut cycles = 0;
+    gba.hardware.read_data_word(addr, false, &mut cycles)
+}
+
+fn read16(gba: &mut Gba, addr: u32) -> u16 {
+    let mut cycles = 0;
+    gba.hardware.read_data_halfword(addr, false, &mut cycles)
+}
+
+fn write32(gba: &mut Gba, addr: u32, value: u32) {
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_word(addr, value, false, &mut cycles);
+}
+
+fn write16(gba: &mut Gba, addr: u32, value: u16) {
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_halfword(addr, value, false, &mut cycles);
+}
+
+fn write8(gba: &mut Gba, addr: u32, value: u8) {
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_byte(addr, value, false, &mut cycles);
+}
+
+fn fetch_arm(gba: &mut Gba, addr: u32) -> u32 {
+    let mut cycles = 0;
+    gba.hardware.read_code_word(addr, false, &mut cycles)
+}
+
+fn fetch_thumb(gba: &mut Gba, addr: u32) -> u16 {
+    let mut cycles = 0;
+    gba.hardware.read_code_halfword(addr, false, &mut cycles)
+}
+
+#[test]
+pub fn test_work_ram_halfword_reads() {
+    let mut gba = console();
+    write32(&mut gba, 0x02000010, 0x11112222);
+    write32(&mut gba, 0x03000010, 0x33334444);
+
+    // EWRAM has 2 wait states after boot and IWRAM has none.
+    let mut cycles = 0;
+    let value = gba
+        .hardware
+        .read_data_halfword(0x02000012, false, &mut cycles);
+    assert_eq!((value, cycles), (0x1111, 3));
+    cycles = 0;
+    let value = gba
+        .hardware
+        .read_data_halfword(0x03000012, false, &mut cycles);
+    assert_eq!((value, cycles), (0x3333, 1));
+
+    // Without external RAM the EWRAM region mirrors IWRAM.
+    write32(&mut gba, IMC, 0x0D000000);
+    cycles = 0;
+    let value = gba
+        .hardware
+        .read_data_halfword(0x02000012, false, &mut cycles);
+    assert_eq!((value, cycles), (0x3333, 1));
+}
+
+#[test]
+pub fn test_bios_read_protection() {
+    let mut gba = console();
+
+    // After skipping the BIOS the CPU is running from the ROM so reads get the last opcode that
+    // the BIOS would have fetched.
+    assert_eq!(read32(&mut gba, 0x00000100), 0xE129F000);
+    assert_eq!(read16(&mut gba, 0x00000102), 0xE129);
+    assert_eq!(gba.hardware.view_word(0x00000100), 0x00000100);
+
+    // Code in the BIOS can read it.
+    fetch_arm(&mut gba, 0x00000200);
+    assert_eq!(read32(&mut gba, 0x00000100), 0x00000100);
+
+    // Back in the ROM reads get the last opcode that was fetched from the BIOS.
+    fetch_arm(&mut gba, 0x08000000);
+    assert_eq!(read32(&mut gba, 0x00000100), 0x00000200);
+}
+
+#[test]
+pub fn test_arm_open_bus() {
+    let mut gba = console();
+    let opcode = fetch_arm(&mut gba, 0x08000200);
+    assert_eq!(read32(&mut gba, UNUSED), opcode);
+    assert_eq!(read32(&mut gba, 0x00004000), opcode);
+    assert_eq!(read16(&mut gba, UNUSED + 2), (opcode >> 16) as u16);
+}
+
+#[test]
+pub fn test_thumb_open_bus() {
+    let mut gba = console();
+
+    // 16-bit memory puts the same opcode on both halves of the bus.
+    let opcode = fetch_thumb(&mut gba, 0x08000202) as u32;
+    assert_eq!(read32(&mut gba, UNUSED), opcode | (opcode << 16));
+    write32(&mut gba, 0x02000100, 0x11112222);
+    fetch_thumb(&mut gba, 0x02000100);
+    assert_eq!(read32(&mut gba, UNUSED), 0x22222222);
+
+    // IWRAM leaves the previous opcode in the upper half for word aligned opcodes.
+    write32(&mut gba, 0x03000100, 0x11112222);
+    write32(&mut gba, 0x03000104, 0x33334444);
+    fetch_thumb(&mut gba, 0x03000104);
+    assert_eq!(read32(&mut gba, UNUSED), 0x11114444);
+    fetch_thumb(&mut gba, 0x03000106);
+    assert_eq!(read32(&mut gba, UNUSED), 0x33334444);
+
+    // The BIOS and OAM have 32-bit buses.
+    write32(&mut gba, 0x07000000, 0x55556666);
+    fetch_thumb(&mut gba, 0x07000000);
+    assert_eq!(read32(&mut gba, UNUSED), 0x55556666);
+    fetch_thumb(&mut gba, 0x00000102);
+    assert_eq!(read32(&mut gba, UNUSED), 0x00000100);
+}
+
+#[test]
+pub fn test_gamepak_open_bus() {
+    let mut gba = console();
+    assert_eq!(read16(&mut gba, 0x09FFFFF0), 0xFFF8);
+    assert_eq!(read32(&mut gba, 0x09FFFFF0), 0xFFF9FFF8);
+    assert_eq!(read16(&mut gba, 0x0DFFFFFE), 0xFFFF);
+    assert_eq!(gba.hardware.view_byte(0x08FFFFF3), 0xFF);
+    assert_eq!(gba.hardware.view_byte(0x08FFFFF2), 0xF9);
+}
+
+#[test]
+pub fn test_byte_writes_to_video_memory() {
+    let mut gba = console();
+    write16(&mut gba, DISPCNT, 0x0000);
+
+    // Palette and BG VRAM byte writes go to both bytes of the halfword.
+    write8(&mut gba, 0x05000003, 0xAB);
+    assert_eq!(gba.hardware.view_halfword(0x05000002), 0xABAB);
+    write8(&mut gba, 0x06000001, 0xCD);
+    assert_eq!(gba.hardware.view_halfword(0x06000000), 0xCDCD);
+    write8(&mut gba, 0x06020004, 0xEF);
+    assert_eq!(gba.hardware.view_halfword(0x06000004), 0xEFEF);
+
+    // OBJ VRAM and OAM byte writes are ignored.
+    write8(&mut gba, 0x06010000, 0x12);
+    assert_eq!(gba.hardware.view_halfword(0x06010000), 0x0000);
+    write16(&mut gba, 0x07000000, 0x3456);
+    write8(&mut gba, 0x07000000, 0x12);
+    assert_eq!(gba.hardware.view_halfword(0x07000000), 0x3456);
+
+    // The bitmap modes use the first 16KB of OBJ VRAM for the background.
+    write16(&mut gba, DISPCNT, 0x0003);
+    write8(&mut gba, 0x06010000, 0x12);
+    assert_eq!(gba.hardware.view_halfword(0x06010000), 0x1212);
+    write8(&mut gba, 0x06014000, 0x12);
+    assert_eq!(gba.hardware.view_halfword(0x06014000), 0x0000);
+}
+
+#[test]
+pub fn test_rom_too_large() {
+    let mut gba = console();
+    let rom = vec![0; 32 * 1024 * 1024 + 1];
+    assert_eq!(
+        gba.set_rom(rom),
+        Err(RomError::TooLarge(32 * 1024 * 1024 + 1))
+    );
+    assert!(gba.set_rom(vec![0; 32 * 1024 * 1024]).is_ok());
+}
@@ -0,0 +1,153 @@
+mod util;
+use pyrite_gba::keypad::KeypadInput;
+use pyrite_gba::movie::{Movie, MovieFrame, MoviePlayer, MovieRecorder, MovieStart};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+
+const ROM: &str = "../roms/third-party/tonc/key_demo.gba";
+
+fn setup(rom: &str) -> Box<Gba> {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, rom);
+    gba.reset(true);
+    gba
+}
+
+/// Presses a different set of keys on every frame.
+fn press_keys(gba: &mut Gba, frame: usize) {
+    for (index, &key) in KeypadInput::ALL.iter().enumerate() {
+        gba.set_key_pressed(key, (frame / (index + 1)) % 3 != 1);
+    }
+}
+
+/// Records a movie and makes sure that playing it back on another system ends up in the same
+/// state.
+#[test]
+pub fn test_movie_playback() {
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    let mut gba = setup(ROM);
+    // Run a bit first so that the power on has something to reset.
+    gba.video_frame(&mut video, &mut audio);
+    let mut recorder =
+        MovieRecorder::start(&mut gba, MovieStart::PowerOn { skip_bios: true }).unwrap();
+    for frame in 0..60 {
+        press_keys(&mut gba, frame);
+        recorder.record_frame(&mut gba, &mut video, &mut audio);
+    }
+    let expected = gba.save_state(false).unwrap();
+    let movie = recorder.finish();
+    assert_eq!(movie.frames.len(), 60);
+    assert!(movie.frames[0].is_pressed(KeypadInput::ButtonA));
+    assert!(!movie.frames[1].is_pressed(KeypadInput::ButtonA));
+
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    let mut other = setup(ROM);
+    let mut player = MoviePlayer::start(&mut other, movie.clone()).unwrap();
+    while player.play_frame(&mut other, &mut video, &mut audio) {}
+    assert!(player.is_finished());
+    assert_eq!(player.position(), 60);
+    assert_eq!(player.lag_frames(), movie.lag_frame_count());
+    assert!(other.save_state(false).unwrap() == expected);
+}
+
+#[test]
+pub fn test_movie_from_save_state_with_rerecords() {
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    let mut gba = setup(ROM);
+    for frame in 0..10 {
+        press_keys(&mut gba, frame);
+        gba.video_frame(&mut video, &mut audio);
+    }
+    let start = gba.save_state(true).unwrap();
+    let mut recorder = MovieRecorder::start(&mut gba, MovieStart::SaveState(start)).unwrap();
+    for frame in 0..20 {
+        press_keys(&mut gba, frame);
+        recorder.record_frame(&mut gba, &mut video, &mut audio);
+    }
+
+    // Go back to frame 20 and record something different from there.
+    let checkpoint = gba.save_state(false).unwrap();
+    for frame in 20..30 {
+        press_keys(&mut gba, frame);
+        recorder.record_frame(&mut gba, &mut video, &mut audio);
+    }
+    gba.load_state(&checkpoint).unwrap();
+    recorder.rerecord(20);
+    for frame in 20..30 {
+        press_keys(&mut gba, frame * 7);
+        recorder.record_frame(&mut gba, &mut video, &mut audio);
+    }
+    let expected = gba.save_state(false).unwrap();
+    let movie = recorder.finish();
+    assert_eq!(movie.rerecord_count, 1);
+    assert_eq!(movie.frames.len(), 30);
+
+    let mut other = setup(ROM);
+    let mut player = MoviePlayer::start(&mut other, movie).unwrap();
+    while player.play_frame(&mut other, &mut video, &mut audio) {}
+    assert!(other.save_state(false).unwrap() == expected);
+}
+
+#[test]
+pub fn test_movie_rejects_other_rom() {
+    let mut gba = setup(ROM);
+    let recorder = MovieRecorder::start(&mut gba, MovieStart::PowerOn { skip_bios: true }).unwrap();
+    let movie = recorder.finish();
+
+    let mut other = setup("../roms/test/timer-stress.gba");
+    assert!(MoviePlayer::start(&mut other, movie).is_err());
+}
+
+#[test]
+pub fn test_movie_import_vbm() {
+    let gba = setup(ROM);
+    let rom = std::fs::read(ROM).unwrap();
+
+    let mut vbm = vec![0u8; 0x100];
+    vbm[0x00..0x04].copy_from_slice(b"VBM\x1A");
+    vbm[0x04..0x08].copy_from_slice(&1u32.to_le_bytes());
+    vbm[0x0C..0x10].copy_from_slice(&3u32.to_le_bytes());
+    vbm[0x10..0x14].copy_from_slice(&42u32.to_le_bytes());
+    vbm[0x15] = 0x01; // controller 1
+    vbm[0x16] = 0x01; // GBA
+    vbm[0x17] = 0x03; // BIOS file used but skipped
+    vbm[0x34..0x38].copy_from_slice(&rom[0xAC..0xB0]);
+    vbm[0x3C..0x40].copy_from_slice(&0x100u32.to_le_bytes());
+    for &keys in [0x0001u16, 0x0410, 0x0200].iter() {
+        vbm.extend_from_slice(&keys.to_le_bytes());
+    }
+
+    let movie = Movie::from_vbm(&vbm, &gba).unwrap();
+    assert_eq!(movie.rerecord_count, 42);
+    assert_eq!(movie.start, MovieStart::PowerOn { skip_bios: true });
+    assert_eq!(
+        movie.frames,
+        vec![
+            MovieFrame {
+                keys: 0x0001,
+                lag: false
+            },
+            // the reset bit is dropped
+            MovieFrame {
+                keys: 0x0010,
+                lag: false
+            },
+            MovieFrame {
+                keys: 0x0200,
+                lag: false
+            },
+        ]
+    );
+
+    // Movies that start from a snapshot can't be imported.
+    vbm[0x14] = 0x01;
+    assert!(Movie::from_vbm(&vbm, &gba).is_err());
+    vbm[0x14] = 0x00;
+
+    // Neither can movies for another game.
+    vbm[0x34] ^= 0xFF;
+    assert!(Movie::from_vbm(&vbm, &gba).is_err());
+}
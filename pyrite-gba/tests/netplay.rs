@@ -0,0 +1,233 @@
+mod util;
+use pyrite_arm::memory::ArmMemory;
+use pyrite_common::StateWriter;
+use pyrite_gba::netplay::{NetplayError, NetplaySession, NetplayTransport, UdpTransport, PLAYERS};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+const ROM: &str = "../roms/third-party/tonc/key_demo.gba";
+
+fn consoles(rom: &str) -> Vec<Gba> {
+    (0..PLAYERS)
+        .map(|_| {
+            let mut gba = Gba::new();
+            util::load_rom(&mut gba, rom);
+            gba
+        })
+        .collect()
+}
+
+/// Different keys for every player and frame.
+fn keys(player: usize, frame: u32) -> u16 {
+    ((frame / (3 + player as u32 * 2)) as u16).wrapping_mul(0x0123 + player as u16) & 0x03FF
+}
+
+/// Runs both sessions until they have both confirmed `frames` frames.
+fn run_sessions<A: NetplayTransport, B: NetplayTransport>(
+    a: &mut NetplaySession<A>,
+    b: &mut NetplaySession<B>,
+    frames: u32,
+    mut tick: impl FnMut(),
+) {
+    for _ in 0..(frames * 20) {
+        if a.confirmed_frame() >= frames && b.confirmed_frame() >= frames {
+            break;
+        }
+        tick();
+        if a.frame() < frames {
+            let frame = a.frame();
+            a.advance_frame(
+                keys(a.local_player(), frame),
+                &mut NoVideoOutput,
+                &mut NoAudioOutput,
+            )
+            .unwrap();
+        } else {
+            a.poll().unwrap();
+        }
+        if b.frame() < frames {
+            let frame = b.frame();
+            b.advance_frame(
+                keys(b.local_player(), frame),
+                &mut NoVideoOutput,
+                &mut NoAudioOutput,
+            )
+            .unwrap();
+        } else {
+            b.poll().unwrap();
+        }
+    }
+    assert_eq!(a.confirmed_frame(), frames);
+    assert_eq!(b.confirmed_frame(), frames);
+}
+
+fn assert_same_state<A: NetplayTransport, B: NetplayTransport>(
+    a: &NetplaySession<A>,
+    b: &NetplaySession<B>,
+) {
+    for player in 0..PLAYERS {
+        assert_eq!(
+            a.console(player).state_hash().unwrap(),
+            b.console(player).state_hash().unwrap(),
+            "console {} is different",
+            player
+        );
+    }
+}
+
+#[test]
+pub fn test_netplay_udp() {
+    let transport_a = UdpTransport::bind("127.0.0.1:0").unwrap();
+    let transport_b = UdpTransport::bind("127.0.0.1:0").unwrap();
+    transport_a
+        .connect(transport_b.local_addr().unwrap())
+        .unwrap();
+    transport_b
+        .connect(transport_a.local_addr().unwrap())
+        .unwrap();
+
+    let mut a = NetplaySession::new(transport_a, consoles(ROM), 0, true);
+    let mut b = NetplaySession::new(transport_b, consoles(ROM), 1, true);
+    run_sessions(&mut a, &mut b, 90, || {
+        std::thread::sleep(std::time::Duration::from_millis(1))
+    });
+
+    assert!(a.is_connected() && b.is_connected());
+    assert_eq!(a.desync_frame(), None);
+    assert_eq!(b.desync_frame(), None);
+    assert_same_state(&a, &b);
+}
+
+/// Packets that are on their way and the time at which they arrive.
+type PacketQueue = Rc<RefCell<VecDeque<(u32, Vec<u8>)>>>;
+
+/// A transport that delivers packets after a delay and loses some of them.
+struct LaggyTransport {
+    time: Rc<Cell<u32>>,
+    outgoing: PacketQueue,
+    incoming: PacketQueue,
+    delay: u32,
+    sent: u32,
+}
+
+impl NetplayTransport for LaggyTransport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.sent += 1;
+        if !self.sent.is_multiple_of(4) {
+            self.outgoing
+                .borrow_mut()
+                .push_back((self.time.get() + self.delay, packet.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut incoming = self.incoming.borrow_mut();
+        match incoming.front() {
+            Some(&(arrival, _)) if arrival <= self.time.get() => {
+                let (_, packet) = incoming.pop_front().unwrap();
+                buf[..packet.len()].copy_from_slice(&packet);
+                Ok(Some(packet.len()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn laggy_pair(delay: u32) -> (Rc<Cell<u32>>, LaggyTransport, LaggyTransport) {
+    let time = Rc::new(Cell::new(0));
+    let a_to_b = Rc::new(RefCell::new(VecDeque::new()));
+    let b_to_a = Rc::new(RefCell::new(VecDeque::new()));
+    let a = LaggyTransport {
+        time: time.clone(),
+        outgoing: a_to_b.clone(),
+        incoming: b_to_a.clone(),
+        delay,
+        sent: 0,
+    };
+    let b = LaggyTransport {
+        time: time.clone(),
+        outgoing: b_to_a,
+        incoming: a_to_b,
+        delay,
+        sent: 1,
+    };
+    (time, a, b)
+}
+
+#[test]
+pub fn test_netplay_rollback() {
+    let (time, transport_a, transport_b) = laggy_pair(3);
+    let mut a = NetplaySession::new(transport_a, consoles(ROM), 0, true);
+    let mut b = NetplaySession::new(transport_b, consoles(ROM), 1, true);
+    run_sessions(&mut a, &mut b, 120, || time.set(time.get() + 1));
+
+    // The input changes often enough that some predictions must have been wrong.
+    assert!(a.rollbacks() > 0);
+    assert!(b.rollbacks() > 0);
+    assert_eq!(a.desync_frame(), None);
+    assert_eq!(b.desync_frame(), None);
+    assert_same_state(&a, &b);
+}
+
+#[test]
+pub fn test_netplay_desync_detection() {
+    let (time, transport_a, transport_b) = laggy_pair(1);
+    let mut a = NetplaySession::new(transport_a, consoles(ROM), 0, true);
+    let mut b = NetplaySession::new(transport_b, consoles(ROM), 1, true);
+
+    // Something that isn't part of the input changes the state of one of the peers.
+    let mut cycles = 0;
+    b.console_mut(0)
+        .hardware
+        .write_data_byte(0x03007000, 0x55, false, &mut cycles);
+
+    run_sessions(&mut a, &mut b, 120, || time.set(time.get() + 1));
+    assert!(a.desync_frame().is_some());
+    assert!(b.desync_frame().is_some());
+}
+
+#[test]
+pub fn test_netplay_different_rom() {
+    let (_time, transport_a, transport_b) = laggy_pair(0);
+    let mut a = NetplaySession::new(transport_a, consoles(ROM), 0, true);
+    let mut b = NetplaySession::new(
+        transport_b,
+        consoles("../roms/test/timer-stress.gba"),
+        1,
+        true,
+    );
+
+    a.advance_frame(0, &mut NoVideoOutput, &mut NoAudioOutput)
+        .unwrap();
+    match b.advance_frame(0, &mut NoVideoOutput, &mut NoAudioOutput) {
+        Err(NetplayError::Incompatible(_)) => {}
+        result => panic!("expected an incompatible peer, got {:?}", result),
+    }
+}
+
+#[test]
+pub fn test_netplay_input_before_hello() {
+    let (_time, transport_a, transport_b) = laggy_pair(0);
+    let to_b = transport_a.outgoing.clone();
+    let mut b = NetplaySession::new(transport_b, consoles(ROM), 1, true);
+
+    // An input packet for frame 0 from a peer that never said hello.
+    let mut packet = StateWriter::new();
+    packet.write_u8(1);
+    packet.write_u32(0);
+    packet.write_u8(1);
+    packet.write_u16(0x0001);
+    packet.write_u32(0);
+    packet.write_u64(0);
+    to_b.borrow_mut().push_back((0, packet.into_inner()));
+
+    assert!(!b
+        .advance_frame(0, &mut NoVideoOutput, &mut NoAudioOutput)
+        .unwrap());
+    assert!(!b.is_connected());
+    assert_eq!(b.frame(), 0);
+}
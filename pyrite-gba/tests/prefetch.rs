@@ -0,0 +1,157 @@
+mod util;
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::Gba;
+
+const ROM: &str = "../roms/test/mode3.gba";
+
+const WAITCNT: u32 = 0x04000204;
+
+/// Wait state 0 with 3 cycles for the first access and 1 for the second with prefetch enabled. A
+/// GamePak halfword takes 4 cycles (N) or 2 cycles (S) and a word takes 6 (N) or 4 (S).
+const WAITCNT_PREFETCH: u16 = 0x4317;
+const WAITCNT_NO_PREFETCH: u16 = 0x0317;
+
+const CODE: u32 = 0x08000100;
+
+fn console(waitcnt: u16) -> Box<Gba> {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, ROM);
+    gba.reset(true);
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_halfword(WAITCNT, waitcnt, false, &mut cycles);
+    gba
+}
+
+/// Fetches ARM opcodes starting at `address` (the first one non-sequentially) and returns the
+/// cycles that each fetch took. `between` is called after every fetch.
+fn fetch_arm(
+    gba: &mut Gba,
+    address: u32,
+    count: u32,
+    mut between: impl FnMut(&mut Gba),
+) -> Vec<u32> {
+    let mut fetches = Vec::new();
+    for index in 0..count {
+        let mut cycles = 0;
+        gba.hardware
+            .read_code_word(address + index * 4, index != 0, &mut cycles);
+        fetches.push(cycles);
+        between(gba);
+    }
+    fetches
+}
+
+#[test]
+pub fn test_prefetch_during_internal_cycles() {
+    let mut gba = console(WAITCNT_PREFETCH);
+    let fetches = fetch_arm(&mut gba, CODE, 3, |gba| gba.hardware.on_internal_cycles(4));
+    assert_eq!(fetches, [6, 1, 1]);
+
+    // Without any idle cycles the buffer can't get ahead of the CPU.
+    let fetches = fetch_arm(&mut gba, CODE, 3, |_| {});
+    assert_eq!(fetches, [6, 4, 4]);
+}
+
+#[test]
+pub fn test_prefetch_buffer_size() {
+    let mut gba = console(WAITCNT_PREFETCH);
+    let mut fetches = fetch_arm(&mut gba, CODE, 1, |gba| {
+        gba.hardware.on_internal_cycles(1000)
+    });
+
+    // The 8 halfwords in the buffer and the 2 that were fetched during the 1 cycle fetches are
+    // free. The next fetch has to wait for the rest of one halfword and all of another.
+    for index in 1..7 {
+        let mut cycles = 0;
+        gba.hardware
+            .read_code_word(CODE + index * 4, true, &mut cycles);
+        fetches.push(cycles);
+    }
+    assert_eq!(fetches, [6, 1, 1, 1, 1, 1, 3]);
+}
+
+#[test]
+pub fn test_prefetch_thumb() {
+    let mut gba = console(WAITCNT_PREFETCH);
+    let mut cycles = 0;
+    gba.hardware.read_code_halfword(CODE, false, &mut cycles);
+    gba.hardware.on_internal_cycles(2);
+    for index in 1..3 {
+        cycles = 0;
+        gba.hardware
+            .read_code_halfword(CODE + index * 2, true, &mut cycles);
+        assert_eq!(cycles, 1);
+    }
+}
+
+#[test]
+pub fn test_prefetch_during_data_accesses() {
+    let mut gba = console(WAITCNT_PREFETCH);
+
+    // IWRAM accesses leave the GamePak bus free.
+    let fetches = fetch_arm(&mut gba, CODE, 3, |gba| {
+        let mut cycles = 0;
+        gba.hardware.read_data_word(0x03000000, false, &mut cycles);
+        gba.hardware.read_data_word(0x03000004, true, &mut cycles);
+        gba.hardware.read_data_word(0x03000008, true, &mut cycles);
+        gba.hardware.read_data_word(0x0300000C, true, &mut cycles);
+    });
+    assert_eq!(fetches, [6, 1, 1]);
+
+    // GamePak accesses empty the buffer.
+    let fetches = fetch_arm(&mut gba, CODE, 3, |gba| {
+        gba.hardware.on_internal_cycles(100);
+        let mut cycles = 0;
+        gba.hardware.read_data_word(0x08001000, false, &mut cycles);
+    });
+    assert_eq!(fetches, [6, 4, 4]);
+}
+
+#[test]
+pub fn test_prefetch_disabled() {
+    let mut gba = console(WAITCNT_NO_PREFETCH);
+    let fetches = fetch_arm(&mut gba, CODE, 3, |gba| {
+        gba.hardware.on_internal_cycles(100)
+    });
+    assert_eq!(fetches, [6, 4, 4]);
+}
+
+const THUMB_LOOP_LENGTH: u32 = 16;
+
+/// A ROM that switches to THUMB state and then runs `THUMB_LOOP_LENGTH` `mov r8, r8` followed by a
+/// branch back to the first one forever.
+fn thumb_loop_rom() -> Vec<u8> {
+    let mut rom = Vec::new();
+    rom.extend_from_slice(&0xE28F0001u32.to_le_bytes()); // add r0, pc, #1
+    rom.extend_from_slice(&0xE12FFF10u32.to_le_bytes()); // bx r0
+    for _ in 0..THUMB_LOOP_LENGTH {
+        rom.extend_from_slice(&0x46C0u16.to_le_bytes()); // mov r8, r8
+    }
+    let offset = -(THUMB_LOOP_LENGTH as i32 * 2 + 4) >> 1;
+    rom.extend_from_slice(&(0xE000 | (offset as u16 & 0x7FF)).to_le_bytes()); // b loop
+    rom
+}
+
+#[test]
+pub fn test_thumb_code_from_rom() {
+    let mut gba = Gba::alloc();
+    gba.set_rom(thumb_loop_rom()).unwrap();
+    gba.reset(true);
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_halfword(WAITCNT, WAITCNT_NO_PREFETCH, false, &mut cycles);
+    gba.cpu.step(&mut gba.hardware);
+    gba.cpu.step(&mut gba.hardware);
+
+    // THUMB opcodes are fetched with halfword accesses: 2 cycles (S) for every `mov` and 2 (S) +
+    // 4 (N) + 2 (S) for the branch. The second time around the opcodes come from the code cache.
+    let mut expected = vec![2; THUMB_LOOP_LENGTH as usize];
+    expected.push(8);
+    for _ in 0..2 {
+        let steps: Vec<u32> = (0..=THUMB_LOOP_LENGTH)
+            .map(|_| gba.cpu.step(&mut gba.hardware))
+            .collect();
+        assert_eq!(steps, expected);
+    }
+}
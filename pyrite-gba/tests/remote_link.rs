@@ -0,0 +1,124 @@
+mod common;
+mod util;
+use common::{still_console, view16, write16};
+use pyrite_gba::link::{RemoteLink, DEFAULT_REMOTE_SYNC_CYCLES};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+use std::net::TcpListener;
+use std::thread;
+
+const SIODATA32: u32 = 0x04000120;
+const SIOMULTI0: u32 = 0x04000120;
+const SIOCNT: u32 = 0x04000128;
+const SIOMLT_SEND: u32 = 0x0400012A;
+const RCNT: u32 = 0x04000134;
+
+const SIOCNT_START: u16 = 0x0080;
+
+fn run_frames(link: &mut RemoteLink, gba: &mut Gba, frames: usize) {
+    for _ in 0..frames {
+        link.video_frame(gba, &mut NoVideoOutput, &mut NoAudioOutput)
+            .unwrap();
+    }
+}
+
+/// Console N sends 0x1111 * (N + 1) and the parent starts a Multiplayer transfer after two
+/// frames. Returns the console number, SIOMULTI0-3 and SIOCNT once the transfer is done.
+fn multiplayer_console(mut link: RemoteLink) -> (usize, Vec<u16>) {
+    let mut gba = still_console();
+
+    write16(&mut gba, RCNT, 0);
+    write16(&mut gba, SIOCNT, 0x2003);
+    write16(&mut gba, SIOMLT_SEND, 0x1111 * (link.index() as u16 + 1));
+    run_frames(&mut link, &mut gba, 2);
+
+    // Everyone is in Multiplayer mode so SD is high.
+    assert_ne!(view16(&gba, SIOCNT) & 0x8, 0);
+
+    if link.index() == 0 {
+        write16(&mut gba, SIOCNT, 0x2003 | SIOCNT_START);
+    }
+    run_frames(&mut link, &mut gba, 2);
+
+    let mut result: Vec<u16> = (0..4).map(|n| view16(&gba, SIOMULTI0 + n * 2)).collect();
+    result.push(view16(&gba, SIOCNT));
+    (link.index(), result)
+}
+
+#[test]
+pub fn test_remote_multiplayer_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let children: Vec<_> = (0..2)
+        .map(|_| thread::spawn(move || multiplayer_console(RemoteLink::join_tcp(addr).unwrap())))
+        .collect();
+    let host = RemoteLink::host_tcp(&listener, 3, DEFAULT_REMOTE_SYNC_CYCLES).unwrap();
+    assert_eq!(host.consoles(), 3);
+    let mut results = vec![multiplayer_console(host)];
+    results.extend(children.into_iter().map(|child| child.join().unwrap()));
+    results.sort();
+
+    for (index, (console, result)) in results.iter().enumerate() {
+        assert_eq!(*console, index);
+        assert_eq!(result[0..4], [0x1111, 0x2222, 0x3333, 0xFFFF]);
+        assert_eq!(result[4] & SIOCNT_START, 0, "console {} is busy", index);
+        assert_eq!(
+            (result[4] >> 4) & 3,
+            index as u16,
+            "ID of console {}",
+            index
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_remote_normal_unix() {
+    use std::os::unix::net::UnixListener;
+
+    let path = std::env::temp_dir().join(format!("pyrite-link-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let child_path = path.clone();
+    let child = thread::spawn(move || {
+        let mut link = RemoteLink::join_unix(child_path).unwrap();
+        assert_eq!(link.index(), 1);
+        assert_eq!(link.sync_cycles(), 1024);
+
+        let mut gba = still_console();
+
+        // The slave is ready from the start and waits for the master's clock.
+        write16(&mut gba, SIOCNT, 0x1000);
+        write16(&mut gba, SIODATA32, 0xBABE);
+        write16(&mut gba, SIODATA32 + 2, 0xCAFE);
+        write16(&mut gba, SIOCNT, 0x1000 | SIOCNT_START);
+        run_frames(&mut link, &mut gba, 3);
+        (view16(&gba, SIODATA32), view16(&gba, SIODATA32 + 2))
+    });
+
+    let mut link = RemoteLink::host_unix(&listener, 2, 1024).unwrap();
+    let mut gba = still_console();
+
+    write16(&mut gba, SIOCNT, 0x1001);
+    write16(&mut gba, SIODATA32, 0x5678);
+    write16(&mut gba, SIODATA32 + 2, 0x1234);
+    run_frames(&mut link, &mut gba, 1);
+    write16(&mut gba, SIOCNT, 0x1001 | SIOCNT_START);
+    run_frames(&mut link, &mut gba, 2);
+
+    assert_eq!(view16(&gba, SIODATA32), 0xBABE);
+    assert_eq!(view16(&gba, SIODATA32 + 2), 0xCAFE);
+    assert_eq!(child.join().unwrap(), (0x5678, 0x1234));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+pub fn test_remote_host_rejects_console_count() {
+    // Nobody connects so these would block forever if the count was checked after accepting.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    for &consoles in [0, 1, 5].iter() {
+        assert!(RemoteLink::host_tcp(&listener, consoles, DEFAULT_REMOTE_SYNC_CYCLES).is_err());
+    }
+    assert!(RemoteLink::host_tcp(&listener, 2, 0).is_err());
+}
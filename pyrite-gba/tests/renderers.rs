@@ -0,0 +1,272 @@
+mod util;
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::lcd::reference::ReferenceRenderer;
+use pyrite_gba::lcd::renderer::{LineRenderer, LineSnapshot, ScanlineRenderer};
+use pyrite_gba::{Gba, NoAudioOutput};
+use std::cell::RefCell;
+use std::rc::Rc;
+use util::{GbaTestVideo, TestStatus};
+
+/// (line, x, scanline color, reference color) of the first pixel that differs on a line.
+type Mismatches = Rc<RefCell<Vec<(u16, usize, u16, u16)>>>;
+
+/// Draws every line with both renderers and remembers the lines where they disagree.
+struct CrossCheck {
+    scanline: ScanlineRenderer,
+    reference: ReferenceRenderer,
+    mismatches: Mismatches,
+}
+
+impl LineRenderer for CrossCheck {
+    fn render_line(&mut self, snapshot: &LineSnapshot, output: &mut [u16; 240]) {
+        let mut reference = [0u16; 240];
+        self.scanline.render_line(snapshot, output);
+        self.reference.render_line(snapshot, &mut reference);
+        if let Some(x) = (0..240).find(|&x| output[x] != reference[x]) {
+            self.mismatches.borrow_mut().push((
+                snapshot.registers.line,
+                x,
+                output[x],
+                reference[x],
+            ));
+        }
+    }
+}
+
+fn run_frames(gba: &mut Gba, video: &mut GbaTestVideo, frames: u32) {
+    let mut frame = 0;
+    while frame < frames {
+        if gba.step(video, &mut NoAudioOutput).0 {
+            frame += 1;
+        }
+    }
+}
+
+fn cross_check(rom: &str, frames: u32) {
+    cross_check_path(&format!("../roms/third-party/tonc/{}", rom), frames);
+}
+
+fn cross_check_path(rom: &str, frames: u32) {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, rom);
+    gba.reset(true);
+
+    let mismatches = Rc::new(RefCell::new(Vec::new()));
+    gba.hardware.lcd.set_renderer(Box::new(CrossCheck {
+        scanline: ScanlineRenderer::new(),
+        reference: ReferenceRenderer::new(),
+        mismatches: mismatches.clone(),
+    }));
+
+    run_frames(&mut gba, &mut GbaTestVideo::new(), frames);
+
+    let mismatches = mismatches.borrow();
+    assert!(
+        mismatches.is_empty(),
+        "{}: {} lines differ, first: {:?}",
+        rom,
+        mismatches.len(),
+        mismatches.first()
+    );
+}
+
+#[test]
+pub fn test_cross_check_priorities() {
+    cross_check("prio_demo.gba", 60);
+}
+
+#[test]
+pub fn test_cross_check_affine() {
+    cross_check("sbb_aff.gba", 60);
+    cross_check("obj_aff.gba", 60);
+}
+
+#[test]
+pub fn test_cross_check_effects() {
+    cross_check("win_demo.gba", 60);
+    cross_check("bld_demo.gba", 60);
+}
+
+#[test]
+pub fn test_cross_check_test_roms() {
+    cross_check_path("../roms/test/frame_select.gba", 30);
+    cross_check_path("../roms/test/affine_ref.gba", 30);
+}
+
+fn write16(gba: &mut Gba, addr: u32, value: u16) {
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_halfword(addr, value, false, &mut cycles);
+}
+
+#[test]
+pub fn test_256_color_objects() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/mode3.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    util::step_until_status(&mut gba, &mut video, &mut NoAudioOutput, TestStatus::Ready);
+
+    let mismatches = Rc::new(RefCell::new(Vec::new()));
+    gba.hardware.lcd.set_renderer(Box::new(CrossCheck {
+        scanline: ScanlineRenderer::new(),
+        reference: ReferenceRenderer::new(),
+        mismatches: mismatches.clone(),
+    }));
+
+    // Every pixel of every object tile uses palette entry 1.
+    for offset in (0..0x8000).step_by(2) {
+        write16(&mut gba, 0x06010000 + offset, 0x0101);
+    }
+    write16(&mut gba, 0x05000202, rgb5!(31, 0, 0));
+    for object in 1..128 {
+        write16(&mut gba, 0x07000000 + object * 8, 0x0200);
+    }
+
+    // An 8x16 object using the last tile. Its second row of tiles wraps around to the start of
+    // object VRAM.
+    write16(&mut gba, 0x04000000, 0x1000);
+    write16(&mut gba, 0x07000000, 0xA000);
+    write16(&mut gba, 0x07000002, 0x0000);
+    write16(&mut gba, 0x07000004, 0x03FE);
+    run_frames(&mut gba, &mut video, 2);
+    assert_eq!(video.pixels[8][0], rgb5!(31, 0, 0));
+
+    // The first 256 color tile that can be used in the bitmap modes.
+    write16(&mut gba, 0x04000000, 0x1003);
+    write16(&mut gba, 0x07000000, 0x2000);
+    write16(&mut gba, 0x07000004, 0x0200);
+    run_frames(&mut gba, &mut video, 2);
+    assert_eq!(video.pixels[0][0], rgb5!(31, 0, 0));
+
+    let mismatches = mismatches.borrow();
+    assert!(
+        mismatches.is_empty(),
+        "{} lines differ, first: {:?}",
+        mismatches.len(),
+        mismatches.first()
+    );
+}
+
+#[test]
+pub fn test_swap_renderer() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/mode3.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    util::step_until_status(&mut gba, &mut video, &mut NoAudioOutput, TestStatus::Ready);
+    run_frames(&mut gba, &mut video, 1);
+    let scanline = video.pixels;
+
+    let _previous = gba
+        .hardware
+        .lcd
+        .set_renderer(Box::new(ReferenceRenderer::new()));
+    video.pixels = [[0; 240]; 160];
+    run_frames(&mut gba, &mut video, 1);
+    assert!(scanline.iter().eq(video.pixels.iter()));
+}
+
+/// Loads the mode 3 test ROM and waits until it is ready for the registers to be changed.
+fn ready_mode3_rom(renderer: Box<dyn LineRenderer>) -> (Box<Gba>, GbaTestVideo) {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/mode3.gba");
+    gba.reset(true);
+
+    let mut video = GbaTestVideo::new();
+    util::step_until_status(&mut gba, &mut video, &mut NoAudioOutput, TestStatus::Ready);
+    gba.hardware.lcd.set_renderer(renderer);
+    (gba, video)
+}
+
+#[test]
+pub fn test_reference_bitmap_affine() {
+    let (mut gba, mut video) = ready_mode3_rom(Box::new(ReferenceRenderer::new()));
+
+    let bitmap_color = |x: u32, y: u32| rgb5!(x % 32, y % 32, (x / 32) + (y / 32) * 8);
+    for y in 0..160 {
+        for x in 0..240 {
+            write16(&mut gba, 0x06000000 + (y * 240 + x) * 2, bitmap_color(x, y));
+        }
+    }
+    write16(&mut gba, 0x05000000, rgb5!(31, 31, 31));
+
+    // BG2 in mode 3 scaled up 2x and moved 16 pixels to the right. The area to the left of the
+    // bitmap is transparent.
+    write16(&mut gba, 0x04000000, 0x0403);
+    write16(&mut gba, 0x04000020, 0x0080);
+    write16(&mut gba, 0x04000026, 0x0080);
+    write16(&mut gba, 0x04000028, (-8i16 << 8) as u16);
+    write16(&mut gba, 0x0400002A, 0xFFFF);
+    run_frames(&mut gba, &mut video, 2);
+
+    for y in 0..160 {
+        for x in 0..240 {
+            let expected = if x < 16 {
+                rgb5!(31, 31, 31)
+            } else {
+                bitmap_color((x - 16) / 2, y / 2)
+            };
+            assert_eq!(
+                video.pixels[y as usize][x as usize], expected,
+                "({}, {})",
+                x, y
+            );
+        }
+    }
+}
+
+#[test]
+pub fn test_cross_check_obj_cycle_limit() {
+    let mismatches = Rc::new(RefCell::new(Vec::new()));
+    let (mut gba, mut video) = ready_mode3_rom(Box::new(CrossCheck {
+        scanline: ScanlineRenderer::new(),
+        reference: ReferenceRenderer::new(),
+        mismatches: mismatches.clone(),
+    }));
+
+    // Every pixel of every object tile uses palette entry 1.
+    for offset in (0..0x8000).step_by(2) {
+        write16(&mut gba, 0x06010000 + offset, 0x1111);
+    }
+    write16(&mut gba, 0x05000000, rgb5!(0, 0, 31));
+    write16(&mut gba, 0x05000202, rgb5!(31, 0, 0));
+    for object in 0..128 {
+        write16(&mut gba, 0x07000000 + object * 8, 0x0200);
+    }
+
+    // 64 pixel wide objects take 64 cycles each even when they are off of the screen. The ones
+    // that are on the screen come after the first 18 (or 14 if the H-Blank interval is free) so
+    // the first one is cut off after 58 pixels and the second one isn't drawn at all.
+    for &(dispcnt, offscreen) in &[(0x1040, 18), (0x1060, 14)] {
+        for object in 0..offscreen + 2 {
+            let x = if object < offscreen {
+                300
+            } else if object == offscreen {
+                0
+            } else {
+                100
+            };
+            write16(&mut gba, 0x07000000 + object * 8, 0x0000);
+            write16(&mut gba, 0x07000002 + object * 8, 0xC000 | x);
+            write16(&mut gba, 0x07000004 + object * 8, 0x0000);
+        }
+        write16(&mut gba, 0x04000000, dispcnt);
+        run_frames(&mut gba, &mut video, 2);
+
+        assert_eq!(video.pixels[0][57], rgb5!(31, 0, 0));
+        assert_eq!(video.pixels[0][58], rgb5!(0, 0, 31));
+        assert_eq!(video.pixels[0][100], rgb5!(0, 0, 31));
+        assert_eq!(video.pixels[64][0], rgb5!(0, 0, 31));
+    }
+
+    let mismatches = mismatches.borrow();
+    assert!(
+        mismatches.is_empty(),
+        "{} lines differ, first: {:?}",
+        mismatches.len(),
+        mismatches.first()
+    );
+}
@@ -0,0 +1,91 @@
+mod util;
+use pyrite_gba::rewind::{Rewind, RewindConfig};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+
+fn setup(rom: &str) -> Box<Gba> {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, rom);
+    gba.reset(true);
+    gba
+}
+
+/// Runs a few frames while saving the state after each one and makes sure that rewinding goes
+/// back through exactly the same states.
+#[test]
+pub fn test_rewind_frame_by_frame() {
+    let mut gba = setup("../roms/third-party/tonc/irq_demo.gba");
+    let mut rewind = Rewind::new(RewindConfig::default());
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    let mut expected = Vec::new();
+    for _ in 0..30 {
+        gba.video_frame(&mut video, &mut audio);
+        rewind.on_frame(&gba).unwrap();
+        expected.push(gba.save_state(false).unwrap());
+    }
+    assert_eq!(rewind.len(), 30);
+
+    // The newest snapshot is the current state so rewinding starts at the one before it.
+    for frame in (0..29).rev() {
+        assert!(rewind.rewind(&mut gba).unwrap());
+        assert!(
+            gba.save_state(false).unwrap() == expected[frame],
+            "state mismatch at frame {}",
+            frame
+        );
+    }
+    assert!(!rewind.rewind(&mut gba).unwrap());
+    assert_eq!(rewind.len(), 1);
+}
+
+#[test]
+pub fn test_rewind_granularity() {
+    let mut gba = setup("../roms/test/timer-stress.gba");
+    let mut rewind = Rewind::new(RewindConfig {
+        granularity: 4,
+        ..RewindConfig::default()
+    });
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    let mut expected = Vec::new();
+    for frame in 0..16 {
+        gba.video_frame(&mut video, &mut audio);
+        rewind.on_frame(&gba).unwrap();
+        if frame % 4 == 0 {
+            expected.push(gba.save_state(false).unwrap());
+        }
+    }
+    assert_eq!(rewind.len(), 4);
+
+    assert!(rewind.rewind(&mut gba).unwrap());
+    assert!(gba.save_state(false).unwrap() == expected[2]);
+}
+
+#[test]
+pub fn test_rewind_memory_budget() {
+    const BUDGET: usize = 1024 * 1024;
+
+    let mut gba = setup("../roms/third-party/tonc/m3_demo.gba");
+    let mut rewind = Rewind::new(RewindConfig {
+        memory_budget: BUDGET,
+        granularity: 1,
+    });
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+
+    for _ in 0..120 {
+        gba.video_frame(&mut video, &mut audio);
+        gba.set_key_pressed(pyrite_gba::keypad::KeypadInput::Right, true);
+        rewind.on_frame(&gba).unwrap();
+        assert!(rewind.memory_used() <= BUDGET);
+    }
+    assert!(rewind.len() > 1);
+    assert!(rewind.len() < 120);
+
+    rewind.clear();
+    assert!(rewind.is_empty());
+    assert_eq!(rewind.memory_used(), 0);
+    assert!(!rewind.rewind(&mut gba).unwrap());
+}
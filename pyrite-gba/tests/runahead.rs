@@ -0,0 +1,85 @@
+mod util;
+use pyrite_gba::keypad::KeypadInput;
+use pyrite_gba::runahead::RunAhead;
+use pyrite_gba::{Gba, NoAudioOutput};
+use util::GbaTestVideo;
+
+const ROM: &str = "../roms/third-party/tonc/brin_demo.gba";
+
+/// Makes sure that run-ahead shows the frame that is `frames` frames ahead and that the system is
+/// left on the real frame afterwards.
+fn check_run_ahead(frames: u32) {
+    let mut gba = Gba::alloc();
+    let mut reference = Gba::alloc();
+    util::load_rom(&mut gba, ROM);
+    util::load_rom(&mut reference, ROM);
+    gba.reset(true);
+
+    let mut run_ahead = RunAhead::new(frames);
+    let mut shown = GbaTestVideo::new();
+    let mut expected = GbaTestVideo::new();
+
+    for step in 0..40 {
+        // Scroll around so that the frames are different.
+        gba.set_key_pressed(KeypadInput::Right, (step / 5) % 2 == 0);
+        gba.set_key_pressed(KeypadInput::Down, (step / 7) % 2 == 0);
+
+        let before = gba.save_state(false).unwrap();
+        run_ahead
+            .run_frame(&mut gba, &mut shown, &mut NoAudioOutput)
+            .unwrap();
+
+        reference.load_state(&before).unwrap();
+        reference.video_frame(&mut expected, &mut NoAudioOutput);
+        assert!(
+            gba.save_state(false).unwrap() == reference.save_state(false).unwrap(),
+            "state mismatch at step {}",
+            step
+        );
+        for _ in 0..frames {
+            reference.video_frame(&mut expected, &mut NoAudioOutput);
+        }
+        assert!(
+            shown.pixels[..] == expected.pixels[..],
+            "frame mismatch at step {}",
+            step
+        );
+    }
+}
+
+#[test]
+pub fn test_run_ahead_disabled() {
+    check_run_ahead(0);
+}
+
+#[test]
+pub fn test_run_ahead_one_frame() {
+    check_run_ahead(1);
+}
+
+#[test]
+pub fn test_run_ahead_three_frames() {
+    check_run_ahead(3);
+}
+
+#[test]
+pub fn test_run_ahead_frame_hash_log() {
+    let mut logs = Vec::new();
+    for &frames in [0, 2].iter() {
+        let mut gba = Gba::alloc();
+        util::load_rom(&mut gba, ROM);
+        gba.reset(true);
+        gba.set_frame_hash_logging(true);
+
+        let mut run_ahead = RunAhead::new(frames);
+        for step in 0..20 {
+            gba.set_key_pressed(KeypadInput::Right, (step / 5) % 2 == 0);
+            run_ahead
+                .run_frame(&mut gba, &mut GbaTestVideo::new(), &mut NoAudioOutput)
+                .unwrap();
+        }
+        logs.push(gba.frame_hash_log().unwrap().hashes().to_vec());
+    }
+    assert_eq!(logs[0].len(), 20);
+    assert_eq!(logs[0], logs[1]);
+}
@@ -0,0 +1,297 @@
+mod util;
+use pyrite_arm::memory::ArmMemory;
+use pyrite_gba::audio::fifo::FifoChannel;
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput, StateError};
+
+fn run_frames(gba: &mut Gba, frames: u32) {
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+    for _ in 0..frames {
+        gba.video_frame(&mut video, &mut audio);
+    }
+}
+
+/// Saves a state, runs for a while, loads the state again and makes sure that running for the same
+/// amount of time again ends up in exactly the same state.
+fn check_rerun(rom: &str, compressed: bool) {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, rom);
+    gba.reset(true);
+    run_frames(&mut gba, 30);
+
+    // Stop in the middle of a frame so that the scheduler has events in flight.
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+    for _ in 0..1234 {
+        gba.step(&mut video, &mut audio);
+    }
+
+    let saved = gba.save_state(compressed).expect("failed to save state");
+    run_frames(&mut gba, 20);
+    let expected = gba.save_state(false).unwrap();
+
+    gba.load_state(&saved).expect("failed to load state");
+    assert_eq!(gba.save_state(compressed).unwrap(), saved);
+    run_frames(&mut gba, 20);
+    assert!(
+        gba.save_state(false).unwrap() == expected,
+        "state after rerunning differs"
+    );
+}
+
+#[test]
+pub fn test_save_state_rerun() {
+    check_rerun("../roms/test/timer-stress.gba", false);
+}
+
+#[test]
+pub fn test_save_state_rerun_compressed() {
+    check_rerun("../roms/third-party/tonc/irq_demo.gba", true);
+}
+
+#[test]
+pub fn test_save_state_compression() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/third-party/tonc/m3_demo.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 10);
+
+    let uncompressed = gba.save_state(false).unwrap();
+    let compressed = gba.save_state(true).unwrap();
+    assert!(compressed.len() < uncompressed.len());
+
+    // Both kinds of states can be loaded and restore the same thing.
+    gba.load_state(&compressed).unwrap();
+    assert_eq!(gba.save_state(false).unwrap(), uncompressed);
+}
+
+#[test]
+pub fn test_save_state_rejected() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/timer-stress.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 5);
+    let saved = gba.save_state(false).unwrap();
+    run_frames(&mut gba, 5);
+    let current = gba.save_state(false).unwrap();
+
+    let mut bad_magic = saved.clone();
+    bad_magic[0] = b'X';
+    assert!(matches!(
+        gba.load_state(&bad_magic),
+        Err(StateError::InvalidData(_))
+    ));
+
+    let mut bad_version = saved.clone();
+    bad_version[8..12].copy_from_slice(&99u32.to_le_bytes());
+    assert_eq!(
+        gba.load_state(&bad_version),
+        Err(StateError::UnsupportedVersion {
+            component: "save state format",
+            version: 99
+        })
+    );
+
+    assert_eq!(
+        gba.load_state(&saved[..saved.len() - 1]),
+        Err(StateError::UnexpectedEnd)
+    );
+
+    // The scheduler is loaded last so this fails after everything else was already loaded.
+    let mut bad_scheduler = saved.clone();
+    let schd = saved
+        .windows(4)
+        .position(|tag| tag == b"SCHD")
+        .expect("no scheduler chunk");
+    bad_scheduler[(schd + 8)..(schd + 12)].copy_from_slice(&99u32.to_le_bytes());
+    assert!(matches!(
+        gba.load_state(&bad_scheduler),
+        Err(StateError::UnsupportedVersion { version: 99, .. })
+    ));
+
+    // None of the failed loads should have changed anything.
+    assert_eq!(gba.save_state(false).unwrap(), current);
+
+    let mut other = Gba::alloc();
+    util::load_rom(&mut other, "../roms/test/mode3.gba");
+    other.reset(true);
+    assert!(matches!(
+        other.load_state(&saved),
+        Err(StateError::InvalidData(_))
+    ));
+}
+
+/// Rebuilds an uncompressed state with every chunk passed through `f`. Chunks that `f` returns
+/// `None` for are taken out.
+fn map_chunks<F>(state: &[u8], mut f: F) -> Vec<u8>
+where
+    F: FnMut(&[u8], &[u8]) -> Option<Vec<u8>>,
+{
+    let mut body = Vec::new();
+    let mut offset = 20;
+    while offset < state.len() {
+        let length = u32::from_le_bytes([
+            state[offset + 4],
+            state[offset + 5],
+            state[offset + 6],
+            state[offset + 7],
+        ]) as usize;
+        let tag = &state[offset..(offset + 4)];
+        if let Some(data) = f(tag, &state[(offset + 8)..(offset + 8 + length)]) {
+            body.extend_from_slice(tag);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&data);
+        }
+        offset += 8 + length;
+    }
+
+    let mut mapped = state[..20].to_vec();
+    mapped[16..20].copy_from_slice(&(body.len() as u32).to_le_bytes());
+    mapped.extend_from_slice(&body);
+    mapped
+}
+
+/// Turns an uncompressed state into a version 1 state by taking out the chunks that version 1
+/// didn't have.
+fn version_1_state(state: &[u8]) -> Vec<u8> {
+    let mut old = map_chunks(state, |tag, data| {
+        if tag == b"SIO " {
+            None
+        } else {
+            Some(data.to_vec())
+        }
+    });
+    old[8..12].copy_from_slice(&1u32.to_le_bytes());
+    old
+}
+
+#[test]
+pub fn test_save_state_version_1() {
+    const SIOCNT: u32 = 0x04000128;
+
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/timer-stress.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 5);
+    let saved = gba.save_state(false).unwrap();
+
+    let mut cycles = 0;
+    let siocnt = gba.hardware.read_data_halfword(SIOCNT, false, &mut cycles);
+    gba.hardware
+        .write_data_halfword(SIOCNT, 0x4003, false, &mut cycles);
+    gba.load_state(&version_1_state(&saved))
+        .expect("failed to load version 1 state");
+    assert!(gba.save_state(false).unwrap() == saved);
+
+    // The serial port wasn't part of version 1 states so it is back in its power on state.
+    assert_eq!(
+        gba.hardware.read_data_halfword(SIOCNT, false, &mut cycles),
+        siocnt
+    );
+}
+
+#[test]
+pub fn test_audio_state_version_1() {
+    const FIFO_A: u32 = 0x040000A0;
+
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/timer-stress.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 5);
+
+    let mut cycles = 0;
+    gba.hardware
+        .write_data_word(FIFO_A, 0x04030201, false, &mut cycles);
+    let fifo_a = gba.hardware.audio.fifo(FifoChannel::A).len();
+    let fifo_b = gba.hardware.audio.fifo(FifoChannel::B).len();
+    assert_eq!(fifo_a, 4);
+    let saved = gba.save_state(false).unwrap();
+
+    // Version 1 of the audio chunk ends before the FIFOs.
+    let old = map_chunks(&saved, |tag, data| {
+        if tag != b"AUD " {
+            return Some(data.to_vec());
+        }
+        let mut data = data[..(data.len() - (2 + fifo_a) - (2 + fifo_b))].to_vec();
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        Some(data)
+    });
+    gba.load_state(&old)
+        .expect("failed to load version 1 audio state");
+    assert!(gba.hardware.audio.fifo(FifoChannel::A).is_empty());
+    assert!(gba.hardware.audio.fifo(FifoChannel::B).is_empty());
+}
+
+#[test]
+pub fn test_dma_state_version_1() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/timer-stress.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 5);
+    let saved = gba.save_state(false).unwrap();
+
+    // Version 1 of the DMA chunk doesn't have the last channel after the version, the active
+    // channels and the DMA bus.
+    let old = map_chunks(&saved, |tag, data| {
+        if tag != b"DMA " {
+            return Some(data.to_vec());
+        }
+        let mut data = data.to_vec();
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data.remove(4 + 1 + 4);
+        Some(data)
+    });
+    gba.load_state(&old)
+        .expect("failed to load version 1 DMA state");
+}
+
+#[test]
+pub fn test_sysctl_state_version_1() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/timer-stress.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 5);
+    let saved = gba.save_state(false).unwrap();
+
+    // Version 1 of the system control chunk ends before the prefetch buffer.
+    let old = map_chunks(&saved, |tag, data| {
+        if tag != b"SYS " {
+            return Some(data.to_vec());
+        }
+        let mut data = data[..(data.len() - 17)].to_vec();
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        Some(data)
+    });
+    gba.load_state(&old)
+        .expect("failed to load version 1 system control state");
+}
+
+#[test]
+pub fn test_memory_state_version_1() {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, "../roms/test/timer-stress.gba");
+    gba.reset(true);
+    run_frames(&mut gba, 5);
+    let saved = gba.save_state(false).unwrap();
+
+    // Version 1 of the memory chunk doesn't have the last opcode fetched from the BIOS in front of
+    // the BIOS access flag.
+    let old = map_chunks(&saved, |tag, data| {
+        if tag != b"MEM " {
+            return Some(data.to_vec());
+        }
+        let mut data = data.to_vec();
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data.drain((data.len() - 5)..(data.len() - 1));
+        Some(data)
+    });
+    gba.load_state(&old)
+        .expect("failed to load version 1 memory state");
+
+    // The BIOS can't be read from the GamePak so this is the opcode that the BIOS ends with.
+    let mut cycles = 0;
+    assert_eq!(
+        gba.hardware.read_data_word(0x00000000, false, &mut cycles),
+        0xE129F000
+    );
+}
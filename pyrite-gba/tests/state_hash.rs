@@ -0,0 +1,79 @@
+mod util;
+use pyrite_common::state::hash;
+use pyrite_common::StateWriter;
+use pyrite_gba::hashlog::FrameHashLog;
+use pyrite_gba::keypad::KeypadInput;
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+
+const ROM: &str = "../roms/third-party/tonc/key_demo.gba";
+const FRAMES: usize = 120;
+
+/// Runs `FRAMES` frames with hash logging enabled and presses A at the start of `press_frame`.
+fn run(block_cache: bool, press_frame: Option<usize>) -> FrameHashLog {
+    let mut gba = Gba::alloc();
+    util::load_rom(&mut gba, ROM);
+    gba.power_on(true);
+    gba.set_block_cache_enabled(block_cache);
+    gba.set_frame_hash_logging(true);
+
+    let mut video = NoVideoOutput;
+    let mut audio = NoAudioOutput;
+    for frame in 0..FRAMES {
+        if press_frame == Some(frame) {
+            gba.set_key_pressed(KeypadInput::ButtonA, true);
+        }
+        gba.video_frame(&mut video, &mut audio);
+    }
+    gba.frame_hash_log().unwrap().clone()
+}
+
+#[test]
+pub fn test_state_hash_deterministic() {
+    let first = run(false, None);
+    let second = run(false, None);
+    assert_eq!(first.len(), FRAMES);
+    assert_eq!(first.first_divergence(&second), None);
+    assert_ne!(first.hashes()[0], first.hashes()[FRAMES - 1]);
+
+    // The block cache shouldn't change anything either.
+    let cached = run(true, None);
+    assert_eq!(first.first_divergence(&cached), None);
+}
+
+#[test]
+pub fn test_state_hash_divergence() {
+    let first = run(false, None);
+    let second = run(false, Some(50));
+    assert_eq!(first.first_divergence(&second), Some(50));
+}
+
+#[test]
+pub fn test_state_hash_log_text() {
+    let log = run(false, None);
+    let mut text = Vec::new();
+    log.write_to(&mut text).unwrap();
+    let parsed = FrameHashLog::parse(std::str::from_utf8(&text).unwrap()).unwrap();
+    assert_eq!(parsed, log);
+
+    assert!(FrameHashLog::parse("0 0123\n2 4567\n").is_err());
+    assert!(FrameHashLog::parse("0 xyz\n").is_err());
+}
+
+#[test]
+pub fn test_hashing_state_writer() {
+    let data: Vec<u8> = (0..1000u32).map(|x| (x * 7 + x / 13) as u8).collect();
+
+    // Pieces of every size so that words are split across writes.
+    let mut hasher = StateWriter::hasher();
+    let mut rest = &data[..];
+    let mut size = 0;
+    while !rest.is_empty() {
+        let take = std::cmp::min(size % 11, rest.len());
+        hasher.write_bytes(&rest[..take]);
+        rest = &rest[take..];
+        size += 1;
+    }
+    assert_eq!(hasher.len(), data.len());
+    assert_eq!(hasher.finish_hash(), Some(hash(&data)));
+    assert_ne!(hash(&data[1..]), hash(&data));
+}
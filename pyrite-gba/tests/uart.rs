@@ -0,0 +1,226 @@
+mod common;
+mod util;
+use common::{read16, still_console, view16, write16};
+use pyrite_gba::sio::{UartHost, UartStream};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+const SIOCNT: u32 = 0x04000128;
+const SIODATA8: u32 = 0x0400012A;
+const RCNT: u32 = 0x04000134;
+
+/// UART mode with 115200 baud and 8 data bits.
+const UART: u16 = 0x3083;
+const UART_CTS: u16 = 0x0004;
+const UART_FIFO: u16 = 0x0100;
+const UART_SEND: u16 = 0x0400;
+const UART_RECEIVE: u16 = 0x0800;
+
+const SEND_FULL: u16 = 0x0010;
+const RECEIVE_EMPTY: u16 = 0x0020;
+const ERROR: u16 = 0x0040;
+
+#[derive(Clone, Default)]
+struct TestHost {
+    sent: Rc<RefCell<Vec<u8>>>,
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+}
+
+impl UartHost for TestHost {
+    fn send(&mut self, byte: u8) {
+        self.sent.borrow_mut().push(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.incoming.borrow_mut().pop_front()
+    }
+}
+
+fn console() -> Gba {
+    let mut gba = still_console();
+    write16(&mut gba, RCNT, 0);
+    gba
+}
+
+fn run_frame(gba: &mut Gba) {
+    gba.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+}
+
+#[test]
+pub fn test_uart_send() {
+    let mut gba = console();
+    let host = TestHost::default();
+    gba.hardware.sio.attach_uart(Box::new(host.clone()));
+    write16(&mut gba, SIOCNT, UART | UART_FIFO | UART_SEND);
+
+    for &byte in b"Pyri" {
+        assert_eq!(view16(&gba, SIOCNT) & SEND_FULL, 0);
+        write16(&mut gba, SIODATA8, byte as u16);
+    }
+    assert_ne!(view16(&gba, SIOCNT) & SEND_FULL, 0);
+
+    run_frame(&mut gba);
+    assert_eq!(host.sent.borrow().as_slice(), b"Pyri");
+    assert_eq!(view16(&gba, SIOCNT) & SEND_FULL, 0);
+
+    // Only 7 bits are sent in 7-bit mode.
+    write16(&mut gba, SIOCNT, (UART & !0x80) | UART_FIFO | UART_SEND);
+    write16(&mut gba, SIODATA8, 0xFF);
+    run_frame(&mut gba);
+    assert_eq!(host.sent.borrow().last(), Some(&0x7F));
+}
+
+#[test]
+pub fn test_uart_cts() {
+    let mut gba = console();
+    write16(&mut gba, SIOCNT, UART | UART_CTS | UART_SEND);
+    write16(&mut gba, SIODATA8, b'!' as u16);
+    run_frame(&mut gba);
+
+    // Nothing on the other side is ready to receive so the byte stays where it is.
+    assert_ne!(view16(&gba, SIOCNT) & SEND_FULL, 0);
+
+    let host = TestHost::default();
+    gba.hardware.sio.attach_uart(Box::new(host.clone()));
+    run_frame(&mut gba);
+    assert_eq!(host.sent.borrow().as_slice(), b"!");
+    assert_eq!(view16(&gba, SIOCNT) & SEND_FULL, 0);
+}
+
+#[test]
+pub fn test_uart_receive() {
+    let mut gba = console();
+    let host = TestHost::default();
+    host.incoming.borrow_mut().extend(b"abcdef");
+    gba.hardware.sio.attach_uart(Box::new(host.clone()));
+    write16(&mut gba, SIOCNT, UART | UART_FIFO | UART_RECEIVE);
+    assert_ne!(view16(&gba, SIOCNT) & RECEIVE_EMPTY, 0);
+    run_frame(&mut gba);
+
+    // The FIFO only has room for 4 bytes and the rest are lost.
+    assert!(host.incoming.borrow().is_empty());
+    assert_eq!(view16(&gba, SIOCNT) & (RECEIVE_EMPTY | ERROR), ERROR);
+
+    // The FIFO is part of the save state.
+    let state = gba.save_state(false).unwrap();
+
+    // Viewing the registers leaves them alone but reads by the CPU don't.
+    assert_eq!(view16(&gba, SIODATA8), b'a' as u16);
+    assert_eq!(view16(&gba, SIODATA8), b'a' as u16);
+    assert_ne!(read16(&mut gba, SIOCNT) & ERROR, 0);
+    assert_eq!(view16(&gba, SIOCNT) & ERROR, 0);
+    for &byte in b"abcd" {
+        assert_eq!(view16(&gba, SIOCNT) & RECEIVE_EMPTY, 0);
+        assert_eq!(read16(&mut gba, SIODATA8), byte as u16);
+    }
+    assert_ne!(view16(&gba, SIOCNT) & RECEIVE_EMPTY, 0);
+
+    gba.load_state(&state).unwrap();
+    assert_eq!(read16(&mut gba, SIODATA8), b'a' as u16);
+    assert_eq!(read16(&mut gba, SIODATA8), b'b' as u16);
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_uart_stream() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    let (near, mut far) = UnixStream::pair().unwrap();
+    let stream = UartStream::new(near.try_clone().unwrap(), near);
+    let mut gba = console();
+    gba.hardware.sio.attach_uart(Box::new(stream));
+    write16(
+        &mut gba,
+        SIOCNT,
+        UART | UART_FIFO | UART_SEND | UART_RECEIVE,
+    );
+
+    far.write_all(b"ping").unwrap();
+    let mut received = Vec::new();
+    for _ in 0..100 {
+        run_frame(&mut gba);
+        while view16(&gba, SIOCNT) & RECEIVE_EMPTY == 0 {
+            received.push(read16(&mut gba, SIODATA8) as u8);
+        }
+        if received.len() >= 4 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(received, b"ping");
+
+    for &byte in b"pong" {
+        write16(&mut gba, SIODATA8, byte as u16);
+    }
+    run_frame(&mut gba);
+    far.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reply = [0u8; 4];
+    far.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"pong");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+pub fn test_uart_pty() {
+    use std::fs::OpenOptions;
+    use std::io::Read;
+
+    let (stream, path) = UartStream::pty().unwrap();
+    let mut gba = console();
+    gba.hardware.sio.attach_uart(Box::new(stream));
+    write16(&mut gba, SIOCNT, UART | UART_FIFO | UART_SEND);
+
+    let mut terminal = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    for &byte in b"\r\n" {
+        write16(&mut gba, SIODATA8, byte as u16);
+    }
+    run_frame(&mut gba);
+
+    // The terminal is raw so the bytes aren't translated.
+    let mut received = [0u8; 2];
+    terminal.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"\r\n");
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_uart_socket_drop() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!("pyrite-uart-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let stream = UartStream::listen_unix(&path).unwrap();
+    let mut gba = console();
+    gba.hardware.sio.attach_uart(Box::new(stream));
+    write16(&mut gba, SIOCNT, UART | UART_RECEIVE);
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(b"x").unwrap();
+    for _ in 0..100 {
+        run_frame(&mut gba);
+        if view16(&gba, SIOCNT) & RECEIVE_EMPTY == 0 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(read16(&mut gba, SIODATA8), b'x' as u16);
+
+    // Dropping the stream closes the connection and takes the socket away.
+    drop(gba.hardware.sio.detach_uart());
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buffer = [0u8; 1];
+    assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    assert!(!path.exists());
+}
@@ -1,3 +1,6 @@
+//! Helpers that are shared by all of the tests. Every test only uses some of them.
+#![allow(dead_code)]
+
 use pyrite_gba::{Gba, GbaAudioOutput, GbaVideoOutput};
 // use pyrite_arm::memory::ArmMemory;
 use std::path::Path;
@@ -30,7 +33,9 @@ pub fn load_rom<P: std::fmt::Display + AsRef<Path>>(gba: &mut Gba, rom_path: P)
     let rom = std::fs::read(rom_path.as_ref()).unwrap_or_else(|err| {
         panic!("failed to load rom from path `{}`: {}", rom_path, err);
     });
-    gba.set_rom(rom);
+    gba.set_rom(rom).unwrap_or_else(|err| {
+        panic!("failed to load rom from path `{}`: {}", rom_path, err);
+    });
 }
 
 /// Steps the GBA until the predicate `pred` returns true.
@@ -0,0 +1,244 @@
+mod common;
+mod util;
+use common::{still_console, view16, write16};
+use pyrite_gba::wireless::{HubServer, LocalHub, RemoteHub, WirelessAdapter, WirelessHub};
+use pyrite_gba::{Gba, NoAudioOutput, NoVideoOutput};
+
+const SIODATA32: u32 = 0x04000120;
+const SIOCNT: u32 = 0x04000128;
+const RCNT: u32 = 0x04000134;
+
+const SIOCNT_START: u16 = 0x0080;
+
+/// Normal 32-bit mode with the 2MHz internal clock.
+const SIOCNT_MASTER: u16 = 0x1003;
+
+/// Normal 32-bit mode with an external clock.
+const SIOCNT_SLAVE: u16 = 0x1000;
+
+const LOGIN_PARTS: [u16; 9] = [
+    0x494E, 0x494E, 0x544E, 0x544E, 0x4E45, 0x4E45, 0x4F44, 0x4F44, 0x8001,
+];
+
+fn console(hub: &LocalHub) -> Gba {
+    let mut gba = still_console();
+    let adapter = WirelessAdapter::new(Box::new(hub.clone())).unwrap();
+    gba.hardware.sio.attach_wireless_adapter(adapter);
+    gba
+}
+
+fn read32(gba: &Gba) -> u32 {
+    (view16(gba, SIODATA32) as u32) | ((view16(gba, SIODATA32 + 2) as u32) << 16)
+}
+
+/// Sends a word to the adapter with the console's clock and returns the word that came back.
+fn transfer(gba: &mut Gba, word: u32) -> u32 {
+    write16(gba, SIOCNT, SIOCNT_MASTER);
+    write16(gba, SIODATA32, word as u16);
+    write16(gba, SIODATA32 + 2, (word >> 16) as u16);
+    write16(gba, SIOCNT, SIOCNT_MASTER | SIOCNT_START);
+    for _ in 0..10000 {
+        if view16(gba, SIOCNT) & SIOCNT_START == 0 {
+            return read32(gba);
+        }
+        gba.step(&mut NoVideoOutput, &mut NoAudioOutput);
+    }
+    panic!("transfer of 0x{:08X} never finished", word);
+}
+
+fn login(gba: &mut Gba) {
+    let mut previous_gba = 0u16;
+    let mut previous_adapter = 0u16;
+    let packets = std::iter::once((LOGIN_PARTS[0], 0)).chain(LOGIN_PARTS.iter().map(|&p| (p, p)));
+    for (data, expected) in packets {
+        let response = transfer(gba, ((!previous_adapter as u32) << 16) | data as u32);
+        assert_eq!(
+            (response >> 16) as u16,
+            expected,
+            "login response to 0x{:04X}",
+            data
+        );
+        assert_eq!(
+            response as u16, !previous_gba,
+            "login response to 0x{:04X}",
+            data
+        );
+        previous_gba = data;
+        previous_adapter = expected;
+    }
+}
+
+/// Sends a command with its parameters and returns the words of the response.
+fn command(gba: &mut Gba, command: u8, params: &[u32]) -> Vec<u32> {
+    let header = 0x9966_0000 | ((params.len() as u32) << 8) | command as u32;
+    assert_eq!(transfer(gba, header), 0x8000_0000);
+    for &param in params.iter() {
+        assert_eq!(transfer(gba, param), 0x8000_0000);
+    }
+
+    let response = transfer(gba, 0x8000_0000);
+    assert_eq!(
+        response >> 16,
+        0x9966,
+        "response to command 0x{:02X}",
+        command
+    );
+    assert_eq!(
+        response as u8,
+        command + 0x80,
+        "response to 0x{:02X}",
+        command
+    );
+    let len = (response >> 8) & 0xFF;
+    (0..len).map(|_| transfer(gba, 0x8000_0000)).collect()
+}
+
+fn adapter_id(gba: &Gba) -> u32 {
+    gba.hardware.sio.wireless_adapter().unwrap().id() as u32
+}
+
+#[test]
+pub fn test_login_and_status() {
+    let hub = LocalHub::new();
+    let mut gba = console(&hub);
+    login(&mut gba);
+
+    assert_eq!(command(&mut gba, 0x10, &[]), []);
+    assert_eq!(command(&mut gba, 0x12, &[]).len(), 1);
+    assert_eq!(command(&mut gba, 0x13, &[]), [adapter_id(&gba)]);
+
+    // Unknown commands get an error response.
+    assert_eq!(transfer(&mut gba, 0x9966_0055), 0x8000_0000);
+    assert_eq!(transfer(&mut gba, 0x8000_0000), 0x9966_01EE);
+    assert_eq!(transfer(&mut gba, 0x8000_0000), 2);
+
+    // Taking over SD through RCNT resets the adapter, which has to be logged into again.
+    write16(&mut gba, RCNT, 0x8000);
+    write16(&mut gba, RCNT, 0);
+    assert_eq!(transfer(&mut gba, 0x9966_0010), 0x0000_FFFF);
+    write16(&mut gba, RCNT, 0x8000);
+    write16(&mut gba, RCNT, 0);
+    login(&mut gba);
+}
+
+#[test]
+pub fn test_host_and_client() {
+    let hub = LocalHub::new();
+    let mut host = console(&hub);
+    let mut client = console(&hub);
+    login(&mut host);
+    login(&mut client);
+    let host_id = adapter_id(&host);
+    let client_id = adapter_id(&client);
+
+    let broadcast = [0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666];
+    command(&mut host, 0x16, &broadcast);
+    command(&mut host, 0x19, &[]);
+    assert_eq!(command(&mut host, 0x13, &[])[0] >> 24, 1);
+
+    command(&mut client, 0x1C, &[]);
+    let hosts = command(&mut client, 0x1D, &[]);
+    assert_eq!(hosts[0], host_id);
+    assert_eq!(hosts[1..], broadcast);
+
+    command(&mut client, 0x1F, &[host_id]);
+    assert_eq!(command(&mut client, 0x20, &[]), [client_id]);
+    command(&mut client, 0x21, &[]);
+    assert_eq!(command(&mut client, 0x13, &[]), [client_id | 0x0401_0000]);
+    assert_eq!(command(&mut host, 0x1A, &[]), [client_id]);
+
+    // The host sends 4 bytes to its clients and the client sends 8 back.
+    command(&mut host, 0x24, &[4, 0xDEADBEEF]);
+    assert_eq!(command(&mut client, 0x26, &[]), [4, 0xDEADBEEF]);
+    assert_eq!(command(&mut client, 0x26, &[]), []);
+    command(&mut client, 0x24, &[8 << 8, 0x0123_4567, 0x89AB_CDEF]);
+    assert_eq!(
+        command(&mut host, 0x26, &[]),
+        [8 << 8, 0x0123_4567, 0x89AB_CDEF]
+    );
+
+    // The client leaves.
+    command(&mut client, 0x30, &[]);
+    assert_eq!(command(&mut host, 0x1A, &[]), []);
+}
+
+#[test]
+pub fn test_wait_for_data() {
+    let hub = LocalHub::new();
+    let mut host = console(&hub);
+    let mut client = console(&hub);
+    login(&mut host);
+    login(&mut client);
+
+    command(&mut host, 0x19, &[]);
+    command(&mut client, 0x1F, &[adapter_id(&host)]);
+    command(&mut client, 0x21, &[]);
+
+    // After the response the adapter has the clock and only uses it once data arrives.
+    command(&mut client, 0x27, &[]);
+    write16(&mut client, SIOCNT, SIOCNT_SLAVE);
+    write16(&mut client, SIODATA32, 0);
+    write16(&mut client, SIODATA32 + 2, 0x8000);
+    write16(&mut client, SIOCNT, SIOCNT_SLAVE | SIOCNT_START);
+    client.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+    assert_ne!(view16(&client, SIOCNT) & SIOCNT_START, 0);
+
+    command(&mut host, 0x24, &[4, 0xCAFEBABE]);
+    client.video_frame(&mut NoVideoOutput, &mut NoAudioOutput);
+    assert_eq!(view16(&client, SIOCNT) & SIOCNT_START, 0);
+    assert_eq!(read32(&client), 0x9966_0028);
+
+    // The console answers as the master again and then reads the data.
+    assert_eq!(transfer(&mut client, 0x9966_00A8), 0x8000_0000);
+    assert_eq!(command(&mut client, 0x26, &[]), [4, 0xCAFEBABE]);
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_remote_hub() {
+    use std::os::unix::net::UnixStream;
+
+    let server = HubServer::new();
+    let connect = || {
+        let (near, far) = UnixStream::pair().unwrap();
+        server.serve(far);
+        RemoteHub::new(near).unwrap()
+    };
+
+    let mut host = connect();
+    let host_id = host.register().unwrap();
+    host.set_broadcast(host_id, [1, 2, 3, 4, 5, 6]).unwrap();
+    host.start_host(host_id).unwrap();
+
+    // A console in this process joins the room through the server.
+    let mut gba = still_console();
+    let adapter = WirelessAdapter::new(Box::new(connect())).unwrap();
+    gba.hardware.sio.attach_wireless_adapter(adapter);
+    login(&mut gba);
+    let client_id = adapter_id(&gba);
+
+    assert_eq!(
+        command(&mut gba, 0x1D, &[]),
+        [host_id as u32, 1, 2, 3, 4, 5, 6]
+    );
+    command(&mut gba, 0x1F, &[host_id as u32]);
+    command(&mut gba, 0x21, &[]);
+    assert_eq!(host.clients(host_id).unwrap(), [(client_id as u16, 0)]);
+
+    command(&mut gba, 0x24, &[2 << 8, 0xBEEF]);
+    let messages = host.receive(host_id).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!((messages[0].slot, messages[0].bytes), (1, 2));
+    assert_eq!(messages[0].words, [0xBEEF]);
+
+    // Unplugging the adapter closes its connection and the server forgets about it.
+    drop(gba.hardware.sio.detach_wireless_adapter());
+    drop(gba);
+    for _ in 0..100 {
+        if host.clients(host_id).unwrap().is_empty() {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("the client is still connected");
+}
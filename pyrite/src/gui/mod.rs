@@ -1,7 +1,13 @@
 use crate::platform::audio::PlatformAudio;
 use crate::platform::opengl::PyriteGL;
+use pyrite_gba::link::RemoteLink;
+use pyrite_gba::rewind::{Rewind, RewindConfig};
+use pyrite_gba::runahead::RunAhead;
 use pyrite_gba::Gba;
 
+/// The most frames that run-ahead can be set to.
+const MAX_RUN_AHEAD_FRAMES: u32 = 4;
+
 // The frame rate of the GBA.
 // Right now 60FPS.
 pub const GBA_FRAMERATE_LIMIT: std::time::Duration = std::time::Duration::from_micros(16600);
@@ -13,6 +19,15 @@ pub struct PyriteGUI {
     modifier_shift: bool,
     modifier_ctrl: bool,
 
+    /// Set while the rewind key is held.
+    rewinding: bool,
+    rewind: Rewind,
+    run_ahead: RunAhead,
+
+    /// Set while the console is linked to other pyrite processes. Rewinding and run-ahead are
+    /// disabled while linked because the other consoles can't go back with this one.
+    link: Option<RemoteLink>,
+
     title_buffer: String,
     gba_frame_counter: FrameCounter,
     gba_frame_timer: Timer,
@@ -20,7 +35,7 @@ pub struct PyriteGUI {
 }
 
 impl PyriteGUI {
-    pub fn new(gba: Box<Gba>) -> PyriteGUI {
+    pub fn new(gba: Box<Gba>, link: Option<RemoteLink>) -> PyriteGUI {
         PyriteGUI {
             gba: gba,
             audio: PlatformAudio::new(),
@@ -29,6 +44,12 @@ impl PyriteGUI {
             modifier_shift: false,
             modifier_ctrl: false,
 
+            rewinding: false,
+            rewind: Rewind::new(RewindConfig::default()),
+            run_ahead: RunAhead::new(0),
+
+            link,
+
             title_buffer: String::new(),
             gba_frame_counter: FrameCounter::new(),
             gba_frame_timer: Timer::new(GBA_FRAMERATE_LIMIT),
@@ -143,6 +164,19 @@ impl PyriteGUI {
                         self.gba.set_key_pressed(KeypadInput::ButtonR, pressed)
                     }
 
+                    Some(VirtualKeyCode::R) => self.rewinding = pressed,
+
+                    Some(VirtualKeyCode::PageUp) if pressed => {
+                        let frames = (self.run_ahead.frames() + 1).min(MAX_RUN_AHEAD_FRAMES);
+                        self.run_ahead.set_frames(frames);
+                        log::info!("run-ahead: {} frames", frames);
+                    }
+                    Some(VirtualKeyCode::PageDown) if pressed => {
+                        let frames = self.run_ahead.frames().saturating_sub(1);
+                        self.run_ahead.set_frames(frames);
+                        log::info!("run-ahead: {} frames", frames);
+                    }
+
                     Some(VirtualKeyCode::Q) => {
                         if self.modifier_shift && self.modifier_ctrl {
                             self.close_requested = true;
@@ -205,7 +239,39 @@ impl PyriteGUI {
             return;
         }
         let frame_start = std::time::Instant::now();
-        self.gba.video_frame(pyrite_gl, &mut self.audio);
+        if let Some(ref mut link) = self.link {
+            if let Err(err) = link.video_frame(&mut self.gba, pyrite_gl, &mut self.audio) {
+                log::error!("error occurred while linked, disconnecting: {}", err);
+                self.link = None;
+            }
+        } else if self.gba.hardware.sio.wireless_adapter().is_some() {
+            // The other adapters can't go back with this one either.
+            self.gba.video_frame(pyrite_gl, &mut self.audio);
+        } else if self.rewinding {
+            // The frame after the snapshot is run so that there is something to show. The
+            // emulator just stays on the same frame once the oldest snapshot has been reached.
+            match self.rewind.rewind(&mut self.gba) {
+                Ok(true) => self.gba.video_frame(pyrite_gl, &mut self.audio),
+                Ok(false) => return,
+                Err(err) => {
+                    log::error!("error occurred while rewinding: {}", err);
+                    self.rewind.clear();
+                    return;
+                }
+            }
+        } else {
+            if let Err(err) = self
+                .run_ahead
+                .run_frame(&mut self.gba, pyrite_gl, &mut self.audio)
+            {
+                log::error!("error occurred while running ahead: {}", err);
+                self.run_ahead.set_frames(0);
+            }
+            if let Err(err) = self.rewind.on_frame(&self.gba) {
+                log::error!("error occurred while saving rewind snapshot: {}", err);
+                self.rewind.clear();
+            }
+        }
         self.gba_frame_counter.add_frame(frame_start.elapsed());
         pyrite_gl.build_frame();
     }
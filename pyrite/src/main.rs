@@ -5,6 +5,10 @@ mod platform;
 #[allow(dead_code)]
 mod util;
 
+use pyrite_gba::lcd::reference::ReferenceRenderer;
+use pyrite_gba::link::{RemoteLink, DEFAULT_REMOTE_SYNC_CYCLES};
+use pyrite_gba::sio::UartStream;
+use pyrite_gba::wireless::{HubServer, RemoteHub, WirelessAdapter, WirelessHub};
 use pyrite_gba::Gba;
 
 fn main() {
@@ -33,7 +37,10 @@ fn run_emulator() -> i32 {
     if let Some(rom_file) = std::env::args().nth(1) {
         match load_binary(&rom_file) {
             Ok(rom_binary) => {
-                gba.set_rom(rom_binary);
+                if let Err(err) = gba.set_rom(rom_binary) {
+                    log::error!("error occurred while loading ROM ({}): {}", rom_file, err);
+                    return 1;
+                }
             }
 
             Err(err) => {
@@ -47,11 +54,227 @@ fn run_emulator() -> i32 {
         return 1;
     }
 
-    let gui = gui::PyriteGUI::new(gba);
+    let mut args: Vec<String> = std::env::args().skip(2).collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--uart") {
+        let uart: Vec<String> = args.drain(index..args.len().min(index + 2)).collect();
+        if let Err(err) = connect_uart(&mut gba, uart.get(1)) {
+            log::error!("error occurred while connecting the UART: {}", err);
+            return 1;
+        }
+    }
+
+    if let Some(index) = args.iter().position(|arg| arg == "--accurate-lcd") {
+        args.remove(index);
+        gba.set_accurate_lcd(true);
+    }
+
+    if let Some(index) = args.iter().position(|arg| arg == "--reference-renderer") {
+        args.remove(index);
+        gba.hardware
+            .lcd
+            .set_renderer(Box::new(ReferenceRenderer::new()));
+    }
+
+    let link = if args
+        .first()
+        .is_some_and(|arg| arg.starts_with("--wireless-"))
+    {
+        if let Err(err) = connect_wireless(&mut gba, &args) {
+            log::error!(
+                "error occurred while connecting the wireless adapter: {}",
+                err
+            );
+            return 1;
+        }
+        None
+    } else {
+        match connect_link(args) {
+            Ok(link) => link,
+            Err(err) => {
+                log::error!("error occurred while connecting the link cable: {}", err);
+                return 1;
+            }
+        }
+    };
+
+    let gui = gui::PyriteGUI::new(gba, link);
     gui.run();
     return 0;
 }
 
+/// Connects to other pyrite processes with a link cable if one of these arguments is passed after
+/// the ROM (ADDRESS is either HOST:PORT or unix:PATH):
+///
+///     --link-host ADDRESS CONSOLES [SYNC_CYCLES]
+///     --link-join ADDRESS
+fn connect_link(args: Vec<String>) -> std::io::Result<Option<RemoteLink>> {
+    use std::io::{Error, ErrorKind};
+
+    let bad_args = || Error::new(ErrorKind::InvalidInput, "bad link cable arguments");
+    let arg = |index: usize| args.get(index).ok_or_else(bad_args);
+
+    match args.get(0).map(String::as_str) {
+        Some("--link-host") => {
+            let address = arg(1)?;
+            let consoles: usize = arg(2)?.parse().map_err(|_| bad_args())?;
+            let sync_cycles = match args.get(3) {
+                Some(sync_cycles) => sync_cycles.parse().map_err(|_| bad_args())?,
+                None => DEFAULT_REMOTE_SYNC_CYCLES,
+            };
+
+            log::info!(
+                "waiting for {} more consoles on {}",
+                consoles.saturating_sub(1),
+                address
+            );
+            if let Some(path) = address.strip_prefix("unix:") {
+                #[cfg(not(unix))]
+                return Err(unsupported_address(path));
+                #[cfg(unix)]
+                {
+                    remove_stale_socket(path)?;
+                    let listener = std::os::unix::net::UnixListener::bind(path)?;
+                    return RemoteLink::host_unix(&listener, consoles, sync_cycles).map(Some);
+                }
+            } else {
+                let listener = std::net::TcpListener::bind(address.as_str())?;
+                return RemoteLink::host_tcp(&listener, consoles, sync_cycles).map(Some);
+            }
+        }
+
+        Some("--link-join") => {
+            let address = arg(1)?;
+            if let Some(path) = address.strip_prefix("unix:") {
+                #[cfg(not(unix))]
+                return Err(unsupported_address(path));
+                #[cfg(unix)]
+                return RemoteLink::join_unix(path).map(Some);
+            } else {
+                return RemoteLink::join_tcp(address.as_str()).map(Some);
+            }
+        }
+
+        Some(_) => return Err(bad_args()),
+        None => return Ok(None),
+    }
+}
+
+/// Removes a socket that an earlier run left behind at `path`. Anything else at `path` is kept and
+/// reported as an address that is already in use.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} already exists and is not a socket", path),
+        )),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// The error for unix:PATH addresses on hosts without Unix domain sockets.
+#[cfg(not(unix))]
+fn unsupported_address(path: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("unsupported address unix:{} (no Unix domain sockets)", path),
+    )
+}
+
+/// Plugs a Wireless Adapter into the console if one of these arguments is passed after the ROM
+/// (ADDRESS is either HOST:PORT or unix:PATH):
+///
+///     --wireless-serve ADDRESS    (runs a hub for other pyrite processes and uses it)
+///     --wireless-join ADDRESS
+fn connect_wireless(gba: &mut Gba, args: &[String]) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    let bad_args = || Error::new(ErrorKind::InvalidInput, "bad wireless adapter arguments");
+    let address = args.get(1).ok_or_else(bad_args)?;
+
+    let hub: Box<dyn WirelessHub> = match args[0].as_str() {
+        "--wireless-serve" => {
+            let server = HubServer::new();
+            if let Some(path) = address.strip_prefix("unix:") {
+                #[cfg(not(unix))]
+                return Err(unsupported_address(path));
+                #[cfg(unix)]
+                {
+                    remove_stale_socket(path)?;
+                    let listener = std::os::unix::net::UnixListener::bind(path)?;
+                    std::thread::spawn(move || {
+                        if let Err(err) = server.serve_unix(&listener) {
+                            log::error!("wireless hub stopped: {}", err);
+                        }
+                    });
+                    Box::new(RemoteHub::connect_unix(path)?)
+                }
+            } else {
+                let listener = std::net::TcpListener::bind(address.as_str())?;
+                let local_address = listener.local_addr()?;
+                std::thread::spawn(move || {
+                    if let Err(err) = server.serve_tcp(&listener) {
+                        log::error!("wireless hub stopped: {}", err);
+                    }
+                });
+                Box::new(RemoteHub::connect_tcp(local_address)?)
+            }
+        }
+
+        "--wireless-join" => {
+            if let Some(path) = address.strip_prefix("unix:") {
+                #[cfg(not(unix))]
+                return Err(unsupported_address(path));
+                #[cfg(unix)]
+                Box::new(RemoteHub::connect_unix(path)?)
+            } else {
+                Box::new(RemoteHub::connect_tcp(address.as_str())?)
+            }
+        }
+
+        _ => return Err(bad_args()),
+    };
+
+    gba.hardware
+        .sio
+        .attach_wireless_adapter(WirelessAdapter::new(hub)?);
+    return Ok(());
+}
+
+/// Connects UART mode to the host if `--uart TARGET` is passed after the ROM. TARGET is either
+/// `pty` for a new pseudo-terminal or unix:PATH for a Unix domain socket to listen on.
+fn connect_uart(gba: &mut Gba, target: Option<&String>) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    let target =
+        target.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "missing UART target"))?;
+    let stream = if target == "pty" {
+        let (stream, path) = UartStream::pty()?;
+        log::info!("UART is connected to {}", path.display());
+        stream
+    } else if let Some(path) = target.strip_prefix("unix:") {
+        #[cfg(not(unix))]
+        return Err(unsupported_address(path));
+        #[cfg(unix)]
+        {
+            remove_stale_socket(path)?;
+            let stream = UartStream::listen_unix(path)?;
+            log::info!("UART is listening on {}", path);
+            stream
+        }
+    } else {
+        return Err(Error::new(ErrorKind::InvalidInput, "bad UART target"));
+    };
+
+    gba.hardware.sio.attach_uart(Box::new(stream));
+    return Ok(());
+}
+
 fn load_binary<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Vec<u8>> {
     use std::fs::File;
     use std::io::prelude::*;
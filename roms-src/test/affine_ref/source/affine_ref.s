@@ -0,0 +1,113 @@
+@ Shows an affine BG2 in mode 2 made out of a single tile where each row uses a different color
+@ (1 through 8) and rewrites the reference point registers in the middle of every frame:
+@
+@   - BG2X is written on line 84. This must not reset the Y reference point so the rows keep
+@     going as if nothing happened.
+@   - BG2Y is set to 3.0 on line 100 so the line after it starts over from row 4.
+@
+@ The status is set to ready during VBlank after a few frames have been drawn like this.
+
+    .arm
+    .section .text
+    .align 2
+    .global main
+
+    .equ TEST_STATUS,       0x02000004
+    .equ STATUS_SETUP,      0xDEADBEEF
+    .equ STATUS_READY,      0xABCDEF01
+
+    .equ REG_DISPCNT,       0x04000000
+    .equ REG_VCOUNT,        0x04000006
+    .equ REG_BG2CNT,        0x0400000C
+    .equ REG_BG2PA,         0x04000020
+    .equ REG_BG2X,          0x04000028
+    .equ REG_BG2Y,          0x0400002C
+    .equ BG_PALETTE,        0x05000000
+    .equ VRAM,              0x06000000
+    .equ VRAM_MAP,          0x06004000
+
+    .macro set_status status
+    ldr r0, =TEST_STATUS
+    ldr r1, =\status
+    str r1, [r0]
+    .endm
+
+    .macro wait_vcount line
+    ldr r0, =REG_VCOUNT
+1:  ldrh r1, [r0]
+    cmp r1, #\line
+    bne 1b
+    .endm
+
+main:
+    set_status STATUS_SETUP
+
+    @ color N is (N * 3, 0, 0) and row N of tile 0 uses color N + 1
+    ldr r0, =BG_PALETTE
+    ldr r1, =VRAM
+    ldr r3, =0x01010101
+    mov r2, #1
+1:  add r4, r2, r2, lsl #1      @ color N
+    mov r6, r2, lsl #1
+    strh r4, [r0, r6]
+    mul r4, r3, r2              @ row N - 1
+    str r4, [r1], #4
+    str r4, [r1], #4
+    add r2, r2, #1
+    cmp r2, #9
+    bne 1b
+
+    @ the 16x16 map is all tile 0
+    ldr r0, =VRAM_MAP
+    mov r1, #0
+    mov r2, #64
+2:  str r1, [r0], #4
+    subs r2, r2, #1
+    bne 2b
+
+    @ 128x128 with wraparound and the map in screen block 8
+    ldr r0, =REG_BG2CNT
+    ldr r1, =0x2800
+    strh r1, [r0]
+
+    @ identity matrix
+    ldr r0, =REG_BG2PA
+    ldr r1, =0x0100
+    mov r2, #0
+    strh r1, [r0]
+    strh r2, [r0, #2]
+    strh r2, [r0, #4]
+    strh r1, [r0, #6]
+
+    @ mode 2 with BG2
+    ldr r0, =REG_DISPCNT
+    ldr r1, =0x0402
+    strh r1, [r0]
+
+    mov r5, #0
+frame:
+    wait_vcount 160
+    cmp r5, #3
+    bne 3f
+    set_status STATUS_READY
+3:  add r5, r5, #1
+
+    ldr r0, =REG_BG2X
+    ldr r2, =REG_BG2Y
+    mov r1, #0
+    str r1, [r0]
+    str r1, [r2]
+
+    wait_vcount 84
+    ldr r0, =REG_BG2X
+    mov r1, #0
+    str r1, [r0]
+
+    wait_vcount 100
+    ldr r0, =REG_BG2Y
+    ldr r1, =0x0300
+    str r1, [r0]
+
+    b frame
+
+    .pool
@@ -0,0 +1,68 @@
+@ Fills the screen in mode 3 with red while forced blank is on. The screen should be white until
+@ forced blank is turned off again. The driver checks for white when the status is ready and for
+@ red when it is break.
+
+    .arm
+    .section .text
+    .align 2
+    .global main
+
+    .equ TEST_STATUS,       0x02000004
+    .equ STATUS_SETUP,      0xDEADBEEF
+    .equ STATUS_READY,      0xABCDEF01
+    .equ STATUS_BREAK,      0xACFEBDBB
+
+    .equ REG_DISPCNT,       0x04000000
+    .equ REG_DISPSTAT,      0x04000004
+    .equ VRAM,              0x06000000
+
+    .macro set_status status
+    ldr r0, =TEST_STATUS
+    ldr r1, =\status
+    str r1, [r0]
+    .endm
+
+main:
+    set_status STATUS_SETUP
+
+    @ mode 3 with BG2 and forced blank
+    ldr r0, =REG_DISPCNT
+    ldr r1, =0x0483
+    strh r1, [r0]
+
+    ldr r0, =VRAM
+    ldr r1, =0x001F001F
+    ldr r2, =(240 * 160 / 2)
+1:  str r1, [r0], #4
+    subs r2, r2, #1
+    bne 1b
+
+    bl busy_render_wait
+    set_status STATUS_READY
+
+    ldr r0, =REG_DISPCNT
+    ldr r1, =0x0403
+    strh r1, [r0]
+    bl busy_render_wait
+    set_status STATUS_BREAK
+
+2:  b 2b
+
+@ Waits for whatever was drawn to the framebuffer to be fully rendered.
+busy_render_wait:
+    ldr r0, =REG_DISPSTAT
+    ldrh r1, [r0]
+    tst r1, #1
+    bne 2f
+1:  ldrh r1, [r0]           @ wait for VBlank if we started in VDraw
+    tst r1, #1
+    beq 1b
+2:  ldrh r1, [r0]           @ wait for VDraw
+    tst r1, #1
+    bne 2b
+3:  ldrh r1, [r0]           @ wait for VBlank
+    tst r1, #1
+    beq 3b
+    bx lr
+
+    .pool
@@ -0,0 +1,102 @@
+@ Draws a red frame 0 and a blue frame 1 in mode 5 and displays frame 1. The driver checks for
+@ blue inside of the 160x128 frame and for the green backdrop outside of it when the status is
+@ ready. Then frame 1 is displayed in mode 4 with an object that uses tile 0. Object tiles below
+@ 512 overlap frame 1 in mode 4 so the object isn't drawn and the driver checks for a blue screen
+@ when the status is break.
+
+    .arm
+    .section .text
+    .align 2
+    .global main
+
+    .equ TEST_STATUS,       0x02000004
+    .equ STATUS_SETUP,      0xDEADBEEF
+    .equ STATUS_READY,      0xABCDEF01
+    .equ STATUS_BREAK,      0xACFEBDBB
+
+    .equ REG_DISPCNT,       0x04000000
+    .equ REG_DISPSTAT,      0x04000004
+    .equ BG_PALETTE,        0x05000000
+    .equ OBJ_PALETTE,       0x05000200
+    .equ VRAM,              0x06000000
+    .equ VRAM_FRAME1,       0x0600A000
+    .equ OAM,               0x07000000
+
+    .macro set_status status
+    ldr r0, =TEST_STATUS
+    ldr r1, =\status
+    str r1, [r0]
+    .endm
+
+    @ Fills `count` words starting at `address` with `value`.
+    .macro fill address, value, count
+    ldr r0, =\address
+    ldr r1, =\value
+    ldr r2, =\count
+1:  str r1, [r0], #4
+    subs r2, r2, #1
+    bne 1b
+    .endm
+
+main:
+    set_status STATUS_SETUP
+
+    @ backdrop is green, 1 is red and 2 is blue
+    ldr r0, =BG_PALETTE
+    ldr r1, =0x001F03E0
+    str r1, [r0]
+    ldr r1, =0x7C00
+    strh r1, [r0, #4]
+
+    @ mode 5 with BG2 showing frame 1
+    ldr r0, =REG_DISPCNT
+    ldr r1, =0x0415
+    strh r1, [r0]
+
+    fill VRAM, 0x001F001F, (160 * 128 / 2)
+    fill VRAM_FRAME1, 0x7C007C00, (160 * 128 / 2)
+
+    bl busy_render_wait
+    set_status STATUS_READY
+
+    fill VRAM, 0x01010101, (240 * 160 / 4)
+    fill VRAM_FRAME1, 0x02020202, (240 * 160 / 4)
+
+    @ a white 8x8 object at (0, 0) using tile 0, which is inside of frame 1
+    ldr r0, =OBJ_PALETTE
+    ldr r1, =0x7FFF
+    strh r1, [r0, #4]
+    ldr r0, =OAM
+    mov r1, #0
+    strh r1, [r0]
+    strh r1, [r0, #2]
+    strh r1, [r0, #4]
+
+    @ mode 4 with BG2 and OBJ showing frame 1
+    ldr r0, =REG_DISPCNT
+    ldr r1, =0x1414
+    strh r1, [r0]
+
+    bl busy_render_wait
+    set_status STATUS_BREAK
+
+2:  b 2b
+
+@ Waits for whatever was drawn to the framebuffer to be fully rendered.
+busy_render_wait:
+    ldr r0, =REG_DISPSTAT
+    ldrh r1, [r0]
+    tst r1, #1
+    bne 2f
+1:  ldrh r1, [r0]           @ wait for VBlank if we started in VDraw
+    tst r1, #1
+    beq 1b
+2:  ldrh r1, [r0]           @ wait for VDraw
+    tst r1, #1
+    bne 2b
+3:  ldrh r1, [r0]           @ wait for VBlank
+    tst r1, #1
+    beq 3b
+    bx lr
+
+    .pool
@@ -0,0 +1,73 @@
+@ Fills the screen in mode 3 with pairs of green and red pixels and turns on GREENSWAP, which
+@ swaps the green components of every pair of pixels. The driver checks the swapped image when
+@ the status is ready and the normal image when it is break.
+
+    .arm
+    .section .text
+    .align 2
+    .global main
+
+    .equ TEST_STATUS,       0x02000004
+    .equ STATUS_SETUP,      0xDEADBEEF
+    .equ STATUS_READY,      0xABCDEF01
+    .equ STATUS_BREAK,      0xACFEBDBB
+
+    .equ REG_DISPCNT,       0x04000000
+    .equ REG_GREENSWAP,     0x04000002
+    .equ REG_DISPSTAT,      0x04000004
+    .equ VRAM,              0x06000000
+
+    .macro set_status status
+    ldr r0, =TEST_STATUS
+    ldr r1, =\status
+    str r1, [r0]
+    .endm
+
+main:
+    set_status STATUS_SETUP
+
+    @ mode 3 with BG2
+    ldr r0, =REG_DISPCNT
+    ldr r1, =0x0403
+    strh r1, [r0]
+
+    @ even pixels are green and odd pixels are red
+    ldr r0, =VRAM
+    ldr r1, =0x001F03E0
+    ldr r2, =(240 * 160 / 2)
+1:  str r1, [r0], #4
+    subs r2, r2, #1
+    bne 1b
+
+    ldr r0, =REG_GREENSWAP
+    mov r1, #1
+    strh r1, [r0]
+    bl busy_render_wait
+    set_status STATUS_READY
+
+    ldr r0, =REG_GREENSWAP
+    mov r1, #0
+    strh r1, [r0]
+    bl busy_render_wait
+    set_status STATUS_BREAK
+
+2:  b 2b
+
+@ Waits for whatever was drawn to the framebuffer to be fully rendered.
+busy_render_wait:
+    ldr r0, =REG_DISPSTAT
+    ldrh r1, [r0]
+    tst r1, #1
+    bne 2f
+1:  ldrh r1, [r0]           @ wait for VBlank if we started in VDraw
+    tst r1, #1
+    beq 1b
+2:  ldrh r1, [r0]           @ wait for VDraw
+    tst r1, #1
+    bne 2b
+3:  ldrh r1, [r0]           @ wait for VBlank
+    tst r1, #1
+    beq 3b
+    bx lr
+
+    .pool